@@ -73,7 +73,7 @@ pub fn wasm_bench(
             #[no_mangle]
             pub extern "C" fn #name(cx: &::wasm_bindgen_test::__rt::Context) {
                 let test_name = concat!(module_path!(), "::", stringify!(#ident));
-                crate::wasm::init_console_log();
+                crate::wasm::init_console();
                 cx.execute_async(test_name, #wrapper_name);
             }
 
@@ -3,6 +3,14 @@ use std::str;
 
 // KVKey is the key we use to store our dag data in the underlying
 // kvstore.
+//
+// Every key starts with a prefix naming its type, and the hash or name comes
+// after it, so keys of different types can never be equal whatever a hash
+// or head name contains: chunk keys start with "c/" and end with "/d" or
+// "/m", and head keys start with "h/". The kv layer reserves "sys/" for
+// store metadata and index::Index uses "i/", neither of which can start a
+// dag key. parse() is the exact inverse of to_string(), so a key read back
+// from the store is only accepted as the type it was written as.
 #[derive(Debug, PartialEq, Eq)]
 pub enum Key<'a> {
     ChunkData(&'a str),
@@ -18,23 +26,18 @@ type ParseError = ();
 impl<'a> Key<'_> {
     #[allow(dead_code)]
     pub fn parse<'b>(s: &'b str) -> Result<Key<'b>, ParseError> {
-        let mut parts = s.split::<'b>('/');
-        let prefix: &str = parts.next().ok_or(())?;
-        let content = parts.next().ok_or(())?;
-        match prefix {
-            "c" => {
-                let suffix = parts.next().ok_or(())?;
-                if parts.next().is_some() {
-                    return Err(());
-                }
-                match suffix {
-                    "d" => Ok(Key::ChunkData(content)),
-                    "m" => Ok(Key::ChunkMeta(content)),
-                    _ => Err(()),
-                }
+        if let Some(rest) = s.strip_prefix("c/") {
+            if let Some(hash) = rest.strip_suffix("/d") {
+                return Ok(Key::ChunkData(hash));
+            }
+            if let Some(hash) = rest.strip_suffix("/m") {
+                return Ok(Key::ChunkMeta(hash));
             }
-            "h" => Ok(Key::Head(content)),
-            _ => Err(()),
+            return Err(());
+        }
+        match s.strip_prefix("h/") {
+            Some(name) => Ok(Key::Head(name)),
+            None => Err(()),
         }
     }
 }
@@ -90,6 +93,41 @@ mod tests {
         test(Ok(Key::Head("")), "h/");
         test(Ok(Key::Head("a")), "h/a");
         test(Ok(Key::Head("ab")), "h/ab");
+        test(Ok(Key::Head("a/b")), "h/a/b");
+        test(Ok(Key::ChunkData("a/m")), "c/a/m/d");
+        test(Err(()), "sys/schemaVersion");
+        test(Err(()), "i/index/6b/k");
+    }
+
+    #[test]
+    fn disjoint() {
+        // Hashes and names that would collide with another type's keys if
+        // they were simply appended to a prefix.
+        let contents = &[
+            "",
+            "c",
+            "d",
+            "m",
+            "h",
+            "h/",
+            "h/a",
+            "c/a/d",
+            "a/d",
+            "a/m",
+            "/",
+            "sys/schemaVersion",
+        ];
+        let mut keys = std::collections::HashMap::new();
+        for c in contents {
+            for k in [Key::ChunkData(c), Key::ChunkMeta(c), Key::Head(c)] {
+                let s = k.to_string();
+                assert!(!s.starts_with("sys/") && !s.starts_with("i/"), "{}", s);
+                assert_eq!(Ok(&k), Key::parse(&s).as_ref(), "{}", s);
+                if let Some(prev) = keys.insert(s.clone(), k) {
+                    panic!("{:?} and {:?} both encode to {}", prev, keys[&s], s);
+                }
+            }
+        }
     }
 
     #[test]
@@ -101,6 +139,9 @@ mod tests {
             Key::ChunkMeta("a".into()),
             Key::Head("".into()),
             Key::Head("a".into()),
+            Key::ChunkData("a/d"),
+            Key::ChunkMeta("h/a"),
+            Key::Head("c/a/d"),
         ];
 
         for c in cases {
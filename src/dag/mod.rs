@@ -24,16 +24,22 @@ mod write;
 
 pub use chunk::Chunk;
 pub use key::Key;
+#[allow(unused_imports)]
+pub use read::MissingPolicy;
 pub use read::{OwnedRead, Read};
+#[allow(unused_imports)]
+pub use store::GcReport;
 pub use store::Store;
 pub use write::Write;
 
 use crate::kv;
 
+#[allow(dead_code)]
 #[derive(Debug)]
 pub enum Error {
     Storage(kv::StoreError),
     CorruptStore,
+    MissingChunk { hash: String },
 }
 
 impl From<kv::StoreError> for Error {
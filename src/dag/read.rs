@@ -3,6 +3,7 @@ use super::key::Key;
 use super::{Error, Result};
 use crate::kv;
 use log::error;
+use std::collections::BTreeMap;
 
 pub struct OwnedRead<'a> {
     kvr: Box<dyn kv::Read + 'a>,
@@ -15,20 +16,39 @@ impl<'a> OwnedRead<'a> {
     }
 
     pub fn read(&'a self) -> Read<'a> {
-        Read {
-            kvr: self.kvr.as_ref(),
-        }
+        Read::new(self.kvr.as_ref())
     }
 }
+
+// What get_chunk() does when a chunk isn't in the store.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MissingPolicy {
+    // Return None. For stores that may legitimately be partial, e.g. while
+    // a sync is still pulling chunks in.
+    Placeholder,
+    // Fail with Error::MissingChunk. For stores that should hold everything
+    // reachable from their heads, where a missing chunk is corruption.
+    Error,
+}
+
 #[allow(dead_code)]
 pub struct Read<'a> {
     kvr: &'a dyn kv::Read,
+    missing: MissingPolicy,
 }
 
 #[allow(dead_code)]
 impl<'a> Read<'_> {
     pub fn new(kvr: &'a dyn kv::Read) -> Read {
-        Read { kvr }
+        Read {
+            kvr,
+            missing: MissingPolicy::Placeholder,
+        }
+    }
+
+    pub fn with_missing_policy(self, missing: MissingPolicy) -> Self {
+        Read { missing, ..self }
     }
 
     pub async fn has_chunk(&self, hash: &str) -> Result<bool> {
@@ -37,7 +57,10 @@ impl<'a> Read<'_> {
 
     pub async fn get_chunk(&self, hash: &str) -> Result<Option<Chunk>> {
         match self.kvr.get(&Key::ChunkData(hash).to_string()).await? {
-            None => Ok(None),
+            None => match self.missing {
+                MissingPolicy::Placeholder => Ok(None),
+                MissingPolicy::Error => Err(Error::MissingChunk { hash: hash.into() }),
+            },
             Some(data) => {
                 let meta = self.kvr.get(&Key::ChunkMeta(hash).to_string()).await?;
                 Ok(Some(Chunk::read(hash.into(), data, meta)))
@@ -57,6 +80,48 @@ impl<'a> Read<'_> {
         }
         Ok(None)
     }
+
+    // Returns the name and hash of every head, sorted by name.
+    pub async fn heads(&self) -> Result<Vec<(String, String)>> {
+        let mut heads = vec![];
+        for (key, value) in self.kvr.scan("h/").await? {
+            let name = match Key::parse(&key) {
+                Ok(Key::Head(name)) => name.to_string(),
+                _ => return Err(Error::CorruptStore),
+            };
+            let hash = String::from_utf8(value).map_err(|e| {
+                error!("Could not decode head: {}: {}", name, e);
+                Error::CorruptStore
+            })?;
+            heads.push((name, hash));
+        }
+        Ok(heads)
+    }
+
+    // Returns every chunk in the store, reachable or not, sorted by hash.
+    // This reads the whole store, so is only meant for debugging.
+    pub async fn chunks(&self) -> Result<Vec<Chunk>> {
+        // Data and meta by hash.
+        type Parts = (Option<Vec<u8>>, Option<Vec<u8>>);
+        let mut chunks: BTreeMap<String, Parts> = BTreeMap::new();
+        for (key, value) in self.kvr.scan("c/").await? {
+            match Key::parse(&key) {
+                Ok(Key::ChunkData(hash)) => chunks.entry(hash.into()).or_default().0 = Some(value),
+                Ok(Key::ChunkMeta(hash)) => chunks.entry(hash.into()).or_default().1 = Some(value),
+                _ => return Err(Error::CorruptStore),
+            }
+        }
+        chunks
+            .into_iter()
+            .map(|(hash, (data, meta))| match data {
+                Some(data) => Ok(Chunk::read(hash, data, meta)),
+                None => {
+                    error!("Chunk {} has meta but no data", hash);
+                    Err(Error::CorruptStore)
+                }
+            })
+            .collect()
+    }
 }
 
 #[cfg(test)]
@@ -77,7 +142,7 @@ mod tests {
             kvw.commit().await.unwrap();
 
             let kvr = kv.read().await.unwrap();
-            let r = Read { kvr: kvr.as_ref() };
+            let r = Read::new(kvr.as_ref());
             assert_eq!(expect_has, r.has_chunk(&hash).await.unwrap());
         }
 
@@ -102,7 +167,7 @@ mod tests {
             kvw.commit().await.unwrap();
 
             let kvr = kv.read().await.unwrap();
-            let r = Read { kvr: kvr.as_ref() };
+            let r = Read::new(kvr.as_ref());
 
             let mut expected = Option::<Chunk>::None;
             let chunk_hash: &str;
@@ -118,4 +183,48 @@ mod tests {
         test(vec![1], &vec![], true).await;
         test(vec![1], &vec!["r1", "r2"], false).await;
     }
+
+    #[async_std::test]
+    async fn test_missing_policy() {
+        let kv = MemStore::new();
+        let kvw = kv.write().await.unwrap();
+        let parent = Chunk::new((vec![1], 0), &["missing"]);
+        kvw.put(&Key::ChunkData(parent.hash()).to_string(), parent.data())
+            .await
+            .unwrap();
+        kvw.put(
+            &Key::ChunkMeta(parent.hash()).to_string(),
+            parent.meta().unwrap(),
+        )
+        .await
+        .unwrap();
+        kvw.commit().await.unwrap();
+
+        let kvr = kv.read().await.unwrap();
+        let child = Read::new(kvr.as_ref())
+            .get_chunk(parent.hash())
+            .await
+            .unwrap()
+            .unwrap()
+            .refs()
+            .unwrap()
+            .next()
+            .unwrap()
+            .to_string();
+
+        // The default is a placeholder.
+        let r = Read::new(kvr.as_ref());
+        assert_eq!(None, r.get_chunk(&child).await.unwrap());
+        let r = r.with_missing_policy(MissingPolicy::Placeholder);
+        assert_eq!(None, r.get_chunk(&child).await.unwrap());
+
+        let r = r.with_missing_policy(MissingPolicy::Error);
+        match r.get_chunk(&child).await {
+            Err(Error::MissingChunk { hash }) => assert_eq!("missing", hash),
+            _ => panic!("Expected MissingChunk"),
+        }
+        // Present chunks are unaffected.
+        assert!(r.has_chunk(parent.hash()).await.unwrap());
+        assert!(r.get_chunk(parent.hash()).await.unwrap().is_some());
+    }
 }
@@ -1,15 +1,64 @@
-use super::read::OwnedRead;
+use super::key::Key;
+use super::read::{OwnedRead, Read};
 use super::write::Write;
-use super::Result;
+use super::{Error, Result};
+use crate::hash::Hash;
 use crate::kv;
+use futures::channel::mpsc;
+use futures::stream::TryStreamExt;
+use log::error;
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+// The version of the format chunks and heads are stored in. Stores written
+// with any other version are refused rather than misread.
+pub const SCHEMA_VERSION: u32 = 1;
+
+// What a gc() did.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GcReport {
+    pub chunks_freed: u64,
+    // The bytes of the chunks freed and of those left, data and meta.
+    pub bytes_freed: u64,
+    pub bytes_left: u64,
+}
 
 pub struct Store {
     kv: Box<dyn kv::Store>,
+    gc_high_water: Option<u64>,
+    gc_events: Option<mpsc::UnboundedSender<GcReport>>,
+    // An estimate of the chunk bytes in the store, high if anything, once
+    // auto gc has measured it. Writes add the chunks they put.
+    size: Rc<Cell<Option<u64>>>,
+    // The size at which auto gc next runs, if above the high-water mark.
+    next_gc: Cell<u64>,
 }
 
 impl Store {
     pub fn new(kv: Box<dyn kv::Store>) -> Store {
-        Store { kv }
+        Store {
+            kv,
+            gc_high_water: None,
+            gc_events: None,
+            size: Rc::new(Cell::new(None)),
+            next_gc: Cell::new(0),
+        }
+    }
+
+    // Like new(), but first checks that kv was written with SCHEMA_VERSION,
+    // recording it if kv has no version yet.
+    pub async fn open(kv: Box<dyn kv::Store>) -> Result<Store> {
+        match kv.get_schema_version().await? {
+            None => kv.set_schema_version(SCHEMA_VERSION).await?,
+            Some(SCHEMA_VERSION) => (),
+            Some(v) => return Err(kv::StoreError::UnsupportedSchema(v).into()),
+        }
+        Ok(Store::new(kv))
+    }
+
+    pub async fn schema_version(&self) -> Result<Option<u32>> {
+        Ok(self.kv.get_schema_version().await?)
     }
 
     #[allow(dead_code)]
@@ -17,7 +66,337 @@ impl Store {
         Ok(OwnedRead::new(self.kv.read().await?))
     }
 
+    // Runs gc() first if auto gc is on and due (see set_auto_gc()).
     pub async fn write(&self) -> Result<Write<'_>> {
-        Ok(Write::new(self.kv.write().await?))
+        if let Some(high_water) = self.gc_high_water {
+            let size = match self.size.get() {
+                Some(size) => size,
+                None => {
+                    let size = self.size_bytes().await?;
+                    self.size.set(Some(size));
+                    size
+                }
+            };
+            if size >= high_water.max(self.next_gc.get()) {
+                let report = self.gc().await?;
+                if let Some(events) = &self.gc_events {
+                    // If the receiver is gone, no one is listening any more.
+                    let _ = events.unbounded_send(report);
+                }
+            }
+        }
+        Ok(Write::new_counting(
+            self.kv.write().await?,
+            self.size.clone(),
+        ))
+    }
+
+    // Causes write() to run gc() before opening a write once the chunks
+    // in the store come to high_water bytes or more, so that storage is
+    // reclaimed without anyone having to ask. The size is measured once and
+    // then estimated from the chunks written. So that a store whose live
+    // chunks alone exceed high_water isn't collected on every write, the
+    // next gc waits until the store has at least doubled from what the
+    // last one left. None (the default) turns auto gc off.
+    #[allow(dead_code)]
+    pub fn set_auto_gc(&mut self, high_water: Option<u64>) {
+        self.gc_high_water = high_water;
+    }
+
+    // Causes the store to send a GcReport to events for each gc() that auto
+    // gc runs, so that embedders can observe it. None (the default) sends
+    // nothing.
+    #[allow(dead_code)]
+    pub fn set_gc_events(&mut self, events: Option<mpsc::UnboundedSender<GcReport>>) {
+        self.gc_events = events;
+    }
+
+    // Returns the bytes of every chunk in the store, data and meta. This
+    // reads every chunk, if one at a time, which is why auto gc measures
+    // the size once and then keeps an estimate.
+    pub async fn size_bytes(&self) -> Result<u64> {
+        let r = self.kv.read().await?;
+        chunk_bytes(r.as_ref()).await
+    }
+
+    // Deletes the chunks that aren't reachable from any head, in one
+    // transaction. Reads at the hash of a commit no head leads to any more
+    // (see db::OwnedRead::new_at()) fail once it has been collected.
+    //
+    // Only the live chunks are read, to follow their refs; the dead ones
+    // are found by their keys. So bytes_freed is what the size was before,
+    // less what is left, and is an estimate, high if anything, when auto gc
+    // has one. Otherwise the size is measured first.
+    pub async fn gc(&self) -> Result<GcReport> {
+        let w = self.kv.write().await?;
+        let size = match self.size.get() {
+            Some(size) => size,
+            None => chunk_bytes(w.as_read()).await?,
+        };
+        let mut report = GcReport::default();
+        let r = Read::new(w.as_read());
+        let mut pending: Vec<String> = r.heads().await?.into_iter().map(|(_, h)| h).collect();
+        let mut live = HashSet::new();
+        while let Some(hash) = pending.pop() {
+            if live.contains(&hash) {
+                continue;
+            }
+            // A store may be partial (see MissingPolicy), so a missing chunk
+            // just has nothing more to keep.
+            if let Some(chunk) = r.get_chunk(&hash).await? {
+                report.bytes_left +=
+                    (chunk.data().len() + chunk.meta().map_or(0, |m| m.len())) as u64;
+                if let Some(refs) = chunk.refs() {
+                    pending.extend(refs.map(String::from));
+                }
+            }
+            live.insert(hash);
+        }
+
+        let mut freed = HashSet::new();
+        for key in w.scan_keys("c/").await? {
+            let hash = match Key::parse(&key) {
+                Ok(Key::ChunkData(hash)) | Ok(Key::ChunkMeta(hash)) => hash,
+                _ => return Err(Error::CorruptStore),
+            };
+            if live.contains(hash) {
+                continue;
+            }
+            if freed.insert(hash.to_string()) {
+                report.chunks_freed += 1;
+            }
+            w.del(&key).await?;
+        }
+        report.bytes_freed = size.saturating_sub(report.bytes_left);
+        w.commit_unit().await?;
+        self.size.set(Some(report.bytes_left));
+        self.next_gc.set(report.bytes_left * 2);
+        Ok(report)
+    }
+
+    // Returns the hash a chunk with data would have, which is the key
+    // put_chunk() stores it under. Refs don't affect it, so this can check
+    // has_chunk() before building the chunk. Every store hashes the same
+    // way (see Hash::of()); this is on Store so that callers needn't know.
+    #[allow(dead_code)]
+    pub fn hash_of(&self, data: &[u8]) -> Hash {
+        Hash::of(data)
+    }
+
+    // Copies the chunks reachable from root that dst doesn't have yet into
+    // dst, in a single dst transaction, and returns how many were copied.
+    // A chunk already in dst is assumed to have its subtree there too, since
+    // chunks are always written together with the chunks they reference.
+    // dst must not be this store.
+    #[allow(dead_code)]
+    pub async fn copy_subtree(&self, root: &str, dst: &Store) -> Result<u64> {
+        let src = self.read().await?;
+        let src = src.read();
+        let mut dst_write = dst.write().await?;
+        let mut pending = vec![root.to_string()];
+        let mut visited = HashSet::new();
+        let mut copied = 0;
+        while let Some(hash) = pending.pop() {
+            if !visited.insert(hash.clone()) || dst_write.read().has_chunk(&hash).await? {
+                continue;
+            }
+            let chunk = match src.get_chunk(&hash).await? {
+                Some(chunk) => chunk,
+                None => {
+                    error!("Chunk {} reachable from {} is missing", hash, root);
+                    return Err(Error::CorruptStore);
+                }
+            };
+            if let Some(refs) = chunk.refs() {
+                pending.extend(refs.map(String::from));
+            }
+            dst_write.put_chunk(&chunk).await?;
+            copied += 1;
+        }
+        dst_write.commit().await?;
+        Ok(copied)
+    }
+}
+
+// Sums the bytes of the chunks r sees, streaming them rather than holding
+// them all at once.
+async fn chunk_bytes(r: &dyn kv::Read) -> Result<u64> {
+    let mut chunks = r.scan_stream("c/");
+    let mut bytes = 0;
+    while let Some((_, value)) = chunks.try_next().await? {
+        bytes += value.len() as u64;
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dag::Chunk;
+    use crate::kv::memstore::MemStore;
+    use crate::prolly;
+
+    #[async_std::test]
+    async fn open() {
+        // A new store gets the current version.
+        let kv = MemStore::new();
+        let store = Store::open(Box::new(kv)).await.unwrap();
+        assert_eq!(
+            Some(SCHEMA_VERSION),
+            store.kv.get_schema_version().await.unwrap()
+        );
+        let store = Store::open(store.kv).await.unwrap();
+
+        // Other versions are refused.
+        store
+            .kv
+            .set_schema_version(SCHEMA_VERSION + 1)
+            .await
+            .unwrap();
+        match Store::open(store.kv).await {
+            Err(Error::Storage(kv::StoreError::UnsupportedSchema(v))) => {
+                assert_eq!(SCHEMA_VERSION + 1, v)
+            }
+            _ => panic!("Expected UnsupportedSchema"),
+        }
+    }
+
+    #[async_std::test]
+    async fn hash_of() {
+        let store = Store::new(Box::new(MemStore::new()));
+        let mut w = store.write().await.unwrap();
+        for (data, refs) in &[(&b""[..], vec![]), (b"data", vec!["r1", "r2"])] {
+            let hash = store.hash_of(data).to_string();
+            assert!(!w.read().has_chunk(&hash).await.unwrap());
+            let chunk = Chunk::new((data.to_vec(), 0), refs);
+            w.put_chunk(&chunk).await.unwrap();
+            assert_eq!(chunk.hash(), hash);
+            assert!(w.read().has_chunk(&hash).await.unwrap());
+        }
+    }
+
+    #[async_std::test]
+    async fn gc() {
+        let store = Store::new(Box::new(MemStore::new()));
+        let mut w = store.write().await.unwrap();
+        let leaf = Chunk::new((vec![1], 0), &[]);
+        let root = Chunk::new((vec![2, 2], 0), &[leaf.hash()]);
+        // Orphans, one reachable only from the other.
+        let orphan_leaf = Chunk::new((vec![3, 3, 3], 0), &[]);
+        let orphan = Chunk::new((vec![4], 0), &[orphan_leaf.hash(), leaf.hash()]);
+        for c in &[&leaf, &root, &orphan_leaf, &orphan] {
+            w.put_chunk(c).await.unwrap();
+        }
+        w.set_head("main", root.hash()).await.unwrap();
+        w.commit().await.unwrap();
+        let size = store.size_bytes().await.unwrap();
+
+        let report = store.gc().await.unwrap();
+        assert_eq!(2, report.chunks_freed);
+        assert_eq!(size, report.bytes_freed + report.bytes_left);
+        assert_eq!(report.bytes_left, store.size_bytes().await.unwrap());
+        let r = store.read().await.unwrap();
+        for (c, live) in &[
+            (&leaf, true),
+            (&root, true),
+            (&orphan_leaf, false),
+            (&orphan, false),
+        ] {
+            assert_eq!(*live, r.read().has_chunk(c.hash()).await.unwrap());
+        }
+        drop(r);
+
+        // Nothing more to free.
+        assert_eq!(0, store.gc().await.unwrap().chunks_freed);
+    }
+
+    #[async_std::test]
+    async fn auto_gc() {
+        let mut store = Store::new(Box::new(MemStore::new()));
+        let (tx, mut rx) = mpsc::unbounded();
+        store.set_auto_gc(Some(20));
+        store.set_gc_events(Some(tx));
+        let put_head = |data: Vec<u8>| {
+            let store = &store;
+            async move {
+                let chunk = Chunk::new((data, 0), &[]);
+                let mut w = store.write().await.unwrap();
+                w.put_chunk(&chunk).await.unwrap();
+                w.set_head("main", chunk.hash()).await.unwrap();
+                w.commit().await.unwrap();
+                chunk
+            }
+        };
+
+        // Below the mark, nothing is collected.
+        let first = put_head(vec![1; 15]).await;
+        let second = put_head(vec![2; 5]).await;
+        assert!(rx.try_recv().is_err());
+
+        // Once it is reached, the next write collects what's orphaned.
+        put_head(vec![3; 5]).await;
+        assert_eq!(
+            GcReport {
+                chunks_freed: 1,
+                bytes_freed: 15,
+                bytes_left: 5,
+            },
+            rx.try_recv().unwrap()
+        );
+        let r = store.read().await.unwrap();
+        assert!(!r.read().has_chunk(first.hash()).await.unwrap());
+        assert!(r.read().has_chunk(second.hash()).await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn copy_subtree() {
+        let src = Store::new(Box::new(MemStore::new()));
+        let mut w = src.write().await.unwrap();
+        let mut map = prolly::Map::new();
+        map.put(b"foo".to_vec(), b"bar".to_vec());
+        let map_hash = map.flush(&mut w).await.unwrap();
+        // Two paths to the map, and a chunk that isn't part of the subtree.
+        let left = Chunk::new((vec![1], 0), &[&map_hash]);
+        let right = Chunk::new((vec![2], 0), &[&map_hash]);
+        let root = Chunk::new((vec![3], 0), &[left.hash(), right.hash()]);
+        let other = Chunk::new((vec![4], 0), &[]);
+        for c in &[&left, &right, &root, &other] {
+            w.put_chunk(c).await.unwrap();
+        }
+        w.commit().await.unwrap();
+
+        let dst = Store::new(Box::new(MemStore::new()));
+        assert_eq!(4, src.copy_subtree(root.hash(), &dst).await.unwrap());
+
+        let dst_read = dst.read().await.unwrap();
+        let r = dst_read.read();
+        assert_eq!(
+            Some(&root),
+            r.get_chunk(root.hash()).await.unwrap().as_ref()
+        );
+        assert_eq!(
+            Some(&left),
+            r.get_chunk(left.hash()).await.unwrap().as_ref()
+        );
+        assert!(!r.has_chunk(other.hash()).await.unwrap());
+        let map = prolly::Map::load(&map_hash, r).await.unwrap();
+        assert_eq!(Some(&b"bar"[..]), map.get(b"foo"));
+        drop(dst_read);
+
+        // Nothing left to copy.
+        assert_eq!(0, src.copy_subtree(root.hash(), &dst).await.unwrap());
+        assert_eq!(1, src.copy_subtree(other.hash(), &dst).await.unwrap());
+
+        // A dangling ref fails the copy without writing anything.
+        let mut w = src.write().await.unwrap();
+        let dangling = Chunk::new((vec![5], 0), &["missing"]);
+        w.put_chunk(&dangling).await.unwrap();
+        w.commit().await.unwrap();
+        assert!(matches!(
+            src.copy_subtree(dangling.hash(), &dst).await,
+            Err(Error::CorruptStore)
+        ));
+        let r = dst.read().await.unwrap();
+        assert!(!r.read().has_chunk(dangling.hash()).await.unwrap());
     }
 }
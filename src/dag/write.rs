@@ -2,14 +2,31 @@ use super::chunk::Chunk;
 use super::key::Key;
 use super::{read, Result};
 use crate::kv;
+use std::cell::Cell;
+use std::rc::Rc;
 
 pub struct Write<'a> {
     kvw: Box<dyn kv::Write + 'a>,
+    // The store's estimate of its chunk bytes, if it keeps one, which
+    // commit() adds the bytes of the chunks put to.
+    size: Option<Rc<Cell<Option<u64>>>>,
+    written: u64,
 }
 
 impl<'a> Write<'_> {
     pub fn new(kvw: Box<dyn kv::Write + 'a>) -> Write {
-        Write { kvw }
+        Write {
+            kvw,
+            size: None,
+            written: 0,
+        }
+    }
+
+    pub(super) fn new_counting(kvw: Box<dyn kv::Write + 'a>, size: Rc<Cell<Option<u64>>>) -> Write {
+        Write {
+            size: Some(size),
+            ..Write::new(kvw)
+        }
     }
 
     pub fn read(&self) -> read::Read {
@@ -17,6 +34,7 @@ impl<'a> Write<'_> {
     }
 
     pub async fn put_chunk(&mut self, c: &Chunk) -> Result<()> {
+        self.written += (c.data().len() + c.meta().map_or(0, |m| m.len())) as u64;
         self.kvw
             .put(&Key::ChunkData(c.hash()).to_string(), c.data())
             .await?;
@@ -35,8 +53,21 @@ impl<'a> Write<'_> {
             .await?)
     }
 
+    // Deletes every chunk and head, along with anything else in the store
+    // but its schema version (see kv::Write::clear()).
+    pub async fn clear(&mut self) -> Result<()> {
+        Ok(self.kvw.clear().await?)
+    }
+
+    // Putting a chunk the store already has counts too, so the estimate
+    // can only run high.
     pub async fn commit(self) -> Result<()> {
-        Ok(self.kvw.commit().await?)
+        let written = self.written;
+        self.kvw.commit_unit().await?;
+        if let Some(size) = self.size {
+            size.set(size.get().map(|s| s + written));
+        }
+        Ok(())
     }
 
     #[allow(dead_code)]
@@ -56,7 +87,7 @@ mod tests {
         async fn test(data: &[u8], refs: &[&str]) {
             let kv = MemStore::new();
             let kvw = kv.write().await.unwrap();
-            let mut w = Write { kvw };
+            let mut w = Write::new(kvw);
 
             let c = Chunk::new((data.to_vec(), 0), refs);
             w.put_chunk(&c).await.unwrap();
@@ -88,7 +119,7 @@ mod tests {
         async fn test(name: &str, hash: &str) {
             let kv = MemStore::new();
             let kvw = kv.write().await.unwrap();
-            let mut w = Write { kvw };
+            let mut w = Write::new(kvw);
             w.set_head(name, hash).await.unwrap();
             assert_eq!(
                 hash,
@@ -110,7 +141,7 @@ mod tests {
             let kv = MemStore::new();
             {
                 let kvw = kv.write().await.unwrap();
-                let mut w = Write { kvw };
+                let mut w = Write::new(kvw);
                 let c = Chunk::new((vec![0, 1], 0), &vec![]);
                 w.put_chunk(&c).await.unwrap();
 
@@ -142,7 +173,7 @@ mod tests {
             let c = Chunk::new((data.to_vec(), 0), refs);
             {
                 let kvw = kv.write().await.unwrap();
-                let mut w = Write { kvw };
+                let mut w = Write::new(kvw);
                 w.put_chunk(&c).await.unwrap();
                 w.set_head(name, c.hash()).await.unwrap();
 
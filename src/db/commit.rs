@@ -81,14 +81,20 @@ impl Commit {
         if basis_hash.is_none() {
             return Ok(None);
         }
-        let basis_hash = basis_hash.unwrap();
+        Ok(Some(
+            Commit::from_hash(&basis_hash.unwrap(), dag_read).await?,
+        ))
+    }
+
+    // Loads the commit with hash, e.g. one a head pointed at earlier.
+    pub async fn from_hash(hash: &str, dag_read: dag::Read<'_>) -> Result<Commit, FromHeadError> {
+        use FromHeadError::*;
         let chunk = dag_read
-            .get_chunk(&basis_hash)
+            .get_chunk(hash)
             .await
             .map_err(GetChunkFailed)?
-            .ok_or(ChunkMissing(basis_hash))?;
-        let commit = Commit::load(chunk).map_err(LoadCommitFailed)?;
-        Ok(Some(commit))
+            .ok_or_else(|| ChunkMissing(hash.into()))?;
+        Commit::load(chunk).map_err(LoadCommitFailed)
     }
 
     pub fn chunk(&self) -> &dag::Chunk {
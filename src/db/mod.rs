@@ -6,4 +6,4 @@ mod write;
 
 pub use read::{NewReadFromHeadError, OwnedRead, Read};
 pub use scan::{ScanBound, ScanKey, ScanOptions};
-pub use write::{CommitError, NewWriteFromHeadError, Write};
+pub use write::{init_db, reset_db, CommitError, InitDBError, NewWriteFromHeadError, Write};
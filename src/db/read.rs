@@ -33,6 +33,25 @@ impl<'a> OwnedRead<'a> {
         Ok(OwnedRead { dag_read, map })
     }
 
+    // Like new_from_head(), but reads the commit with hash rather than the
+    // one a head points at now. Commits are immutable, so reads at the same
+    // hash see the same data however the head has moved since, even in
+    // separate transactions, until a dag::Store::gc() after no head leads
+    // to the commit any more collects it.
+    pub async fn new_at(
+        hash: &str,
+        dag_read: dag::OwnedRead<'a>,
+    ) -> Result<OwnedRead<'a>, NewReadFromHeadError> {
+        use NewReadFromHeadError::*;
+        let commit = Commit::from_hash(hash, dag_read.read())
+            .await
+            .map_err(CommitFromHeadError)?;
+        let map = prolly::Map::load(commit.value_hash(), dag_read.read())
+            .await
+            .map_err(MapLoadError)?;
+        Ok(OwnedRead { dag_read, map })
+    }
+
     pub fn as_read(&'a self) -> Read<'a> {
         Read::new(self.dag_read.read(), &self.map)
     }
@@ -97,4 +116,46 @@ mod tests {
         let val = rr.get("foo".as_bytes());
         assert_eq!(Some("bar".as_bytes()), val);
     }
+
+    #[async_std::test]
+    async fn new_at() {
+        let kv = MemStore::new();
+        let put = |value: &'static str| {
+            let kv = &kv;
+            async move {
+                let dw = dag::Write::new(kv.write().await.unwrap());
+                let mut w = write::Write::new_from_head("main", dw).await.unwrap();
+                w.put(b"foo".to_vec(), value.as_bytes().to_vec());
+                w.commit("main", "", "", 1, "", &[], None).await.unwrap();
+                let dr = dag::OwnedRead::new(kv.read().await.unwrap());
+                dr.read().get_head("main").await.unwrap().unwrap()
+            }
+        };
+        let old = put("bar").await;
+
+        let read_at = |hash: String| {
+            let kv = &kv;
+            async move {
+                let dr = dag::OwnedRead::new(kv.read().await.unwrap());
+                let r = OwnedRead::new_at(&hash, dr).await.unwrap();
+                r.as_read().get(b"foo").map(|v| v.to_vec())
+            }
+        };
+        assert_eq!(Some(b"bar".to_vec()), read_at(old.clone()).await);
+
+        // Reads at the old hash in later transactions still see the old
+        // data once the head has moved on.
+        let new = put("baz").await;
+        assert_eq!(Some(b"bar".to_vec()), read_at(old.clone()).await);
+        assert_eq!(Some(b"bar".to_vec()), read_at(old).await);
+        assert_eq!(Some(b"baz".to_vec()), read_at(new).await);
+
+        let dr = dag::OwnedRead::new(kv.read().await.unwrap());
+        match OwnedRead::new_at("missing", dr).await {
+            Err(NewReadFromHeadError::CommitFromHeadError(FromHeadError::ChunkMissing(h))) => {
+                assert_eq!("missing", h)
+            }
+            _ => panic!("Expected ChunkMissing"),
+        };
+    }
 }
@@ -2,23 +2,23 @@ use crate::prolly;
 
 #[allow(dead_code)]
 pub struct ScanKey<'a> {
-    value: &'a [u8],
-    exclusive: bool,
+    pub value: &'a [u8],
+    pub exclusive: bool,
 }
 
 #[allow(dead_code)]
 pub struct ScanBound<'a> {
     // TODO: Make these two fields exclusive?
-    key: Option<ScanKey<'a>>,
-    index: Option<u64>,
+    pub key: Option<ScanKey<'a>>,
+    pub index: Option<u64>,
 }
 
 #[allow(dead_code)]
 pub struct ScanOptions<'a> {
     // TODO: Make these two fields exclusive?
-    prefix: Option<&'a [u8]>,
-    start: Option<ScanBound<'a>>,
-    limit: Option<u64>,
+    pub prefix: Option<&'a [u8]>,
+    pub start: Option<ScanBound<'a>>,
+    pub limit: Option<u64>,
 }
 
 #[allow(dead_code)]
@@ -85,6 +85,54 @@ impl<'a> Write<'a> {
     }
 }
 
+// Points head_name at a new snapshot commit of the empty map, unless it
+// already exists. Returns the hash the head points at.
+pub async fn init_db(dag_write: dag::Write<'_>, head_name: &str) -> Result<String, InitDBError> {
+    use InitDBError::*;
+    if let Some(hash) = dag_write
+        .read()
+        .get_head(head_name)
+        .await
+        .map_err(GetHeadError)?
+    {
+        dag_write.rollback().await.map_err(DagRollbackError)?;
+        return Ok(hash);
+    }
+    init_head(dag_write, head_name).await
+}
+
+// Deletes everything in the store and then points head_name at a new
+// snapshot commit of the empty map, as init_db() would for a new store, all
+// in dag_write. Returns the hash the head points at.
+pub async fn reset_db(
+    mut dag_write: dag::Write<'_>,
+    head_name: &str,
+) -> Result<String, InitDBError> {
+    dag_write
+        .clear()
+        .await
+        .map_err(InitDBError::DagClearError)?;
+    init_head(dag_write, head_name).await
+}
+
+async fn init_head(mut dag_write: dag::Write<'_>, head_name: &str) -> Result<String, InitDBError> {
+    use InitDBError::*;
+    let value_hash = prolly::Map::empty_hash(&mut dag_write)
+        .await
+        .map_err(FlushError)?;
+    let commit = commit::Commit::new_snapshot("", None, "", 0, "", &value_hash);
+    dag_write
+        .put_chunk(commit.chunk())
+        .await
+        .map_err(DagPutChunkError)?;
+    dag_write
+        .set_head(head_name, commit.chunk().hash())
+        .await
+        .map_err(DagSetHeadError)?;
+    dag_write.commit().await.map_err(DagCommitError)?;
+    Ok(commit.chunk().hash().into())
+}
+
 #[derive(Debug)]
 pub enum NewWriteFromHeadError {
     CommitFromHeadFailed(commit::FromHeadError),
@@ -99,6 +147,18 @@ pub enum CommitError {
     FlushError(prolly::FlushError),
 }
 
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum InitDBError {
+    GetHeadError(dag::Error),
+    DagClearError(dag::Error),
+    DagPutChunkError(dag::Error),
+    DagSetHeadError(dag::Error),
+    DagCommitError(dag::Error),
+    DagRollbackError(dag::Error),
+    FlushError(prolly::FlushError),
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -132,4 +192,30 @@ mod tests {
         let val = r.get("foo".as_bytes());
         assert_eq!(Some("bar".as_bytes()), val);
     }
+
+    #[async_std::test]
+    async fn init() {
+        let kv = MemStore::new();
+        let dw = dag::Write::new(kv.write().await.unwrap());
+        let root = init_db(dw, "main").await.unwrap();
+        assert!(!root.is_empty());
+
+        // The head starts out empty and is left alone on re-init.
+        let dw = dag::Write::new(kv.write().await.unwrap());
+        let mut w = Write::new_from_head("main", dw).await.unwrap();
+        assert_eq!(Some(root.clone()), w.basis_hash);
+        assert_eq!(None, w.as_read().get(b"foo"));
+        w.put(b"foo".to_vec(), b"bar".to_vec());
+        w.commit("main", "", "", 1, "", &[], None).await.unwrap();
+
+        let dw = dag::Write::new(kv.write().await.unwrap());
+        let head = init_db(dw, "main").await.unwrap();
+        assert_ne!(root, head);
+        let dw = dag::Write::new(kv.write().await.unwrap());
+        assert_eq!(head, init_db(dw, "main").await.unwrap());
+
+        // Other heads are independent.
+        let dw = dag::Write::new(kv.write().await.unwrap());
+        assert_eq!(root, init_db(dw, "other").await.unwrap());
+    }
 }
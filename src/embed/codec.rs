@@ -0,0 +1,116 @@
+// Codecs for the payloads of dispatch_with_codec(), for embedders that
+// would rather not send and receive JSON strings.
+//
+// Rpcs take and return JSON, so a codec translates at the edges: it
+// decodes args to JSON before dispatch and encodes the JSON response
+// after. Codecs are registered by name, and each call names the one to
+// use. "json" and "msgpack" are registered up front.
+use crate::embed::msgpack;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+pub trait ValueCodec: Send + Sync {
+    // Decodes args to the JSON the rpc takes.
+    fn decode(&self, args: &[u8]) -> Result<String, String>;
+
+    // Encodes a successful response. Responses are JSON, except for a few
+    // (e.g. from "close") that are plain strings.
+    fn encode(&self, response: String) -> Vec<u8>;
+}
+
+// Passes UTF-8 JSON through as is.
+pub struct Json;
+
+impl ValueCodec for Json {
+    fn decode(&self, args: &[u8]) -> Result<String, String> {
+        String::from_utf8(args.to_vec()).map_err(|e| e.to_string())
+    }
+
+    fn encode(&self, response: String) -> Vec<u8> {
+        response.into_bytes()
+    }
+}
+
+// MessagePack. Responses that aren't JSON are encoded as a msgpack string.
+pub struct MsgPack;
+
+impl ValueCodec for MsgPack {
+    fn decode(&self, args: &[u8]) -> Result<String, String> {
+        msgpack::to_json(args).ok_or_else(|| "Args are not msgpack JSON can represent".into())
+    }
+
+    fn encode(&self, response: String) -> Vec<u8> {
+        msgpack::from_json(response.as_bytes()).unwrap_or_else(|| msgpack::from_str(&response))
+    }
+}
+
+lazy_static! {
+    static ref CODECS: Mutex<HashMap<String, Arc<dyn ValueCodec>>> = {
+        let mut codecs: HashMap<String, Arc<dyn ValueCodec>> = HashMap::new();
+        codecs.insert("json".into(), Arc::new(Json));
+        codecs.insert("msgpack".into(), Arc::new(MsgPack));
+        Mutex::new(codecs)
+    };
+}
+
+// Registers codec under name, replacing any codec already registered
+// under it (including the built-in ones).
+pub fn register_codec(name: &str, codec: Box<dyn ValueCodec>) {
+    CODECS.lock().unwrap().insert(name.into(), Arc::from(codec));
+}
+
+pub fn codec(name: &str) -> Result<Arc<dyn ValueCodec>, String> {
+    CODECS
+        .lock()
+        .map_err(|e| e.to_string())?
+        .get(name)
+        .cloned()
+        .ok_or_else(|| format!("Unknown codec: {}", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reverses the bytes of args and responses, to tell it was used.
+    struct Reversed;
+
+    impl ValueCodec for Reversed {
+        fn decode(&self, args: &[u8]) -> Result<String, String> {
+            Json.decode(&args.iter().rev().copied().collect::<Vec<_>>())
+        }
+
+        fn encode(&self, response: String) -> Vec<u8> {
+            response.into_bytes().into_iter().rev().collect()
+        }
+    }
+
+    #[test]
+    fn codecs() {
+        let json = codec("json").unwrap();
+        assert_eq!(Ok("{}".to_string()), json.decode(b"{}"));
+        assert!(json.decode(&[0xff]).is_err());
+        assert_eq!(b"{}".to_vec(), json.encode("{}".into()));
+
+        let msgpack = codec("msgpack").unwrap();
+        assert_eq!(
+            Ok("{\"a\":1}".to_string()),
+            msgpack.decode(&[0x81, 0xa1, b'a', 0x01])
+        );
+        assert!(msgpack.decode(&[0x81, 0x01, 0x01]).is_err());
+        assert_eq!(
+            vec![0x81, 0xa1, b'a', 0x01],
+            msgpack.encode("{\"a\": 1}".into())
+        );
+        assert_eq!(vec![0xa0], msgpack.encode("".into()));
+
+        assert_eq!(
+            Err("Unknown codec: reversed".to_string()),
+            codec("reversed").map(|_| ())
+        );
+        register_codec("reversed", Box::new(Reversed));
+        let reversed = codec("reversed").unwrap();
+        assert_eq!(Ok("[1]".to_string()), reversed.decode(b"]1["));
+        assert_eq!(b"]1[".to_vec(), reversed.encode("[1]".into()));
+    }
+}
@@ -1,10 +1,13 @@
-use super::dispatch::Request;
+use super::dispatch::{catch_panic, internal_error, Request};
+use super::json::{is_json, project};
 use super::types::*;
 use crate::dag;
 use crate::db;
-use async_fn::{AsyncFn2, AsyncFn3};
+use crate::kv::clock::{Clock, SystemClock};
+use async_fn::{AsyncFn2, AsyncFn3, AsyncFn4};
 use async_std::stream::StreamExt;
 use async_std::sync::{Receiver, RecvError, RwLock};
+use data_encoding::base64;
 use futures::stream::futures_unordered::FuturesUnordered;
 use log::warn;
 use nanoserde::{DeJson, SerJson};
@@ -32,6 +35,77 @@ impl<'a> Transaction<'a> {
 
 type TxnMap<'a> = RwLock<HashMap<u32, RwLock<Transaction<'a>>>>;
 
+// How long an import session can go unused before it is rolled back.
+const IMPORT_SESSION_TIMEOUT_MS: u64 = 60_000;
+
+// The import sessions of a connection. A session is a write transaction in
+// the connection's TxnMap, with the session id as its transaction id, that
+// importChunk requests add entries to until importCommit commits it. This
+// lets a client import more data than it wants to send in one request.
+//
+// The transaction holds the store's write lock, so a client that goes away
+// mid-import would block every other writer. Each request therefore first
+// rolls back any session unused for IMPORT_SESSION_TIMEOUT_MS.
+struct Imports<'c> {
+    clock: &'c dyn Clock,
+    // When each session was last used.
+    last_used: RwLock<HashMap<u32, u64>>,
+}
+
+impl<'c> Imports<'c> {
+    fn new(clock: &'c dyn Clock) -> Imports<'c> {
+        Imports {
+            clock,
+            last_used: RwLock::new(HashMap::new()),
+        }
+    }
+
+    async fn start(&self, session_id: u32) {
+        let now = self.clock.now_ms();
+        self.last_used.write().await.insert(session_id, now);
+    }
+
+    // Records a use of session_id, returning false if there is no such
+    // session.
+    async fn touch(&self, session_id: u32) -> bool {
+        let now = self.clock.now_ms();
+        match self.last_used.write().await.get_mut(&session_id) {
+            Some(last_used) => {
+                *last_used = now;
+                true
+            }
+            None => false,
+        }
+    }
+
+    async fn end(&self, session_id: u32) -> bool {
+        self.last_used.write().await.remove(&session_id).is_some()
+    }
+
+    async fn expire(&self, txns: &TxnMap<'_>) {
+        let now = self.clock.now_ms();
+        let mut last_used = self.last_used.write().await;
+        let expired: Vec<u32> = last_used
+            .iter()
+            .filter(|(_, t)| now.saturating_sub(**t) >= IMPORT_SESSION_TIMEOUT_MS)
+            .map(|(id, _)| *id)
+            .collect();
+        if expired.is_empty() {
+            return;
+        }
+        let mut txns = txns.write().await;
+        for session_id in expired {
+            warn!(
+                "Rolling back import session {}, unused for too long",
+                session_id
+            );
+            last_used.remove(&session_id);
+            // Dropping the transaction rolls it back.
+            txns.remove(&session_id);
+        }
+    }
+}
+
 fn deserialize<T: DeJson>(data: &str) -> Result<T, String> {
     match DeJson::deserialize_json(data) {
         Ok(v) => Ok(v),
@@ -49,23 +123,53 @@ async fn connection_future<'a, 'b>(
     rx: &Receiver<Request>,
     store: &'a dag::Store,
     txns: &'b TxnMap<'a>,
+    imports: &Imports<'_>,
     request: Option<Request>,
 ) -> UnorderedResult {
     let req = match request {
         None => return UnorderedResult::Request(rx.recv().await),
         Some(v) => v,
     };
+    let response = req.response.clone();
+    // Only catches panics in native builds, see catch_panic().
+    match catch_panic(handle_request(store, txns, imports, req)).await {
+        Ok(v) => v,
+        Err(e) => {
+            response.send(Err(e)).await;
+            UnorderedResult::None()
+        }
+    }
+}
+
+async fn handle_request<'a, 'b>(
+    store: &'a dag::Store,
+    txns: &'b TxnMap<'a>,
+    imports: &Imports<'_>,
+    req: Request,
+) -> UnorderedResult {
+    imports.expire(txns).await;
     match req.rpc.as_str() {
+        "open" => execute(do_init, store, txns, req).await,
         "has" => execute_in_txn(do_has, txns, req).await,
         "get" => execute_in_txn(do_get, txns, req).await,
+        "getString" => execute_in_txn(do_get_string, txns, req).await,
+        "scanValues" => execute_in_txn(do_scan_values, txns, req).await,
         "put" => execute_in_txn(do_put, txns, req).await,
+        "putBytes" => execute_in_txn(do_put_bytes, txns, req).await,
         "openTransaction" => execute(do_open, store, txns, req).await,
         "commitTransaction" => execute(do_commit, store, txns, req).await,
         "closeTransaction" => execute(do_abort, store, txns, req).await,
+        "importBegin" => execute_import(do_import_begin, imports, store, txns, req).await,
+        "importChunk" => execute_import(do_import_chunk, imports, store, txns, req).await,
+        "importCommit" => execute_import(do_import_commit, imports, store, txns, req).await,
+        "debugDump" => execute(do_debug_dump, store, txns, req).await,
+        "reset" => execute(do_reset, store, txns, req).await,
         "close" => {
             req.response.send(Ok("".into())).await;
             return UnorderedResult::Stop();
         }
+        #[cfg(test)]
+        "panic" => panic!("{}", req.data),
         _ => {
             req.response
                 .send(Err(format!("Unsupported rpc name {}", req.rpc)))
@@ -76,20 +180,25 @@ async fn connection_future<'a, 'b>(
 }
 
 pub async fn process(store: dag::Store, rx: Receiver<Request>) {
+    process_with_clock(store, rx, &SystemClock).await
+}
+
+async fn process_with_clock(store: dag::Store, rx: Receiver<Request>, clock: &dyn Clock) {
     let txns = RwLock::new(HashMap::new());
+    let imports = Imports::new(clock);
     let mut futures = FuturesUnordered::new();
     let mut recv = true;
 
-    futures.push(connection_future(&rx, &store, &txns, None));
+    futures.push(connection_future(&rx, &store, &txns, &imports, None));
     while let Some(value) = futures.next().await {
         if recv {
-            futures.push(connection_future(&rx, &store, &txns, None));
+            futures.push(connection_future(&rx, &store, &txns, &imports, None));
         }
         match value {
             UnorderedResult::Request(value) => match value {
                 Err(why) => warn!("Dispatch loop recv failed: {}", why),
                 Ok(req) => {
-                    futures.push(connection_future(&rx, &store, &txns, Some(req)));
+                    futures.push(connection_future(&rx, &store, &txns, &imports, Some(req)));
                 }
             },
             UnorderedResult::Stop() => recv = false,
@@ -155,6 +264,66 @@ async fn execute<'a, 'b, T, S, F, E>(
     req.response.send(result).await
 }
 
+// Like execute(), for rpcs that also need the connection's Imports.
+async fn execute_import<'a, 'b, 'c, T, S, F, E>(
+    func: F,
+    imports: &'c Imports<'_>,
+    store: &'a dag::Store,
+    txns: &'b TxnMap<'a>,
+    req: Request,
+) where
+    T: DeJson,
+    S: SerJson,
+    E: std::fmt::Debug,
+    F: AsyncFn4<&'c Imports<'c>, &'a dag::Store, &'b TxnMap<'a>, T, Output = Result<S, E>>,
+{
+    let request: T = match deserialize(&req.data) {
+        Ok(v) => v,
+        Err(e) => return req.response.send(Err(e)).await,
+    };
+
+    let result = func
+        .call(imports, store, txns, request)
+        .await
+        .map(|v| SerJson::serialize_json(&v))
+        .map_err(|e| format!("{:?}", e));
+
+    req.response.send(result).await
+}
+
+async fn do_init<'a, 'b>(
+    store: &'a dag::Store,
+    _: &'b TxnMap<'a>,
+    req: OpenRequest,
+) -> Result<OpenResponse, OpenError> {
+    use OpenError::*;
+    let dag_write = store.write().await.map_err(DagWriteError)?;
+    let head_name = req.default_head.as_deref().unwrap_or("main");
+    let root = db::init_db(dag_write, head_name)
+        .await
+        .map_err(InitDBError)?;
+    Ok(OpenResponse { root })
+}
+
+// Deletes everything in the db and reinitializes the default head, as for
+// "open" on a new db, in one transaction so that a reset is either done or
+// not. Open transactions (and import sessions) are ended first, as they
+// would otherwise hold the store until they are closed.
+async fn do_reset<'a, 'b>(
+    store: &'a dag::Store,
+    txns: &'b TxnMap<'a>,
+    req: ResetRequest,
+) -> Result<OpenResponse, OpenError> {
+    use OpenError::*;
+    txns.write().await.clear();
+    let dag_write = store.write().await.map_err(DagWriteError)?;
+    let head_name = req.default_head.as_deref().unwrap_or("main");
+    let root = db::reset_db(dag_write, head_name)
+        .await
+        .map_err(InitDBError)?;
+    Ok(OpenResponse { root })
+}
+
 async fn do_open<'a, 'b>(
     store: &'a dag::Store,
     txns: &'b TxnMap<'a>,
@@ -226,6 +395,147 @@ async fn do_abort<'a, 'b>(
     Ok(CloseTransactionResponse {})
 }
 
+async fn do_import_begin<'a>(
+    imports: &Imports<'_>,
+    store: &'a dag::Store,
+    txns: &TxnMap<'a>,
+    _: ImportBeginRequest,
+) -> Result<ImportBeginResponse, OpenTransactionError> {
+    let req = OpenTransactionRequest {
+        name: Some("import".into()),
+    };
+    let session_id = do_open(store, txns, req).await?.transaction_id;
+    imports.start(session_id).await;
+    Ok(ImportBeginResponse { session_id })
+}
+
+async fn do_import_chunk<'a>(
+    imports: &Imports<'_>,
+    _: &'a dag::Store,
+    txns: &TxnMap<'a>,
+    req: ImportChunkRequest,
+) -> Result<ImportChunkResponse, ImportChunkError> {
+    use ImportChunkError::*;
+    if !imports.touch(req.session_id).await {
+        return Err(UnknownSession);
+    }
+    // Decode the whole chunk first so that a bad one adds nothing.
+    let entries = req
+        .entries
+        .into_iter()
+        .map(|e| match base64::decode(e.value.as_bytes()) {
+            Ok(value) => Ok((e.key.into_bytes(), value)),
+            Err(e) => Err(InvalidBase64(e.to_string())),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let txns = txns.read().await;
+    let txn = txns.get(&req.session_id).ok_or(UnknownSession)?;
+    match &mut *txn.write().await {
+        Transaction::Write(w) => {
+            for (key, value) in entries {
+                w.put(key, value);
+            }
+        }
+        Transaction::Read(_) => return Err(UnknownSession),
+    }
+    Ok(ImportChunkResponse {})
+}
+
+async fn do_import_commit<'a>(
+    imports: &Imports<'_>,
+    store: &'a dag::Store,
+    txns: &TxnMap<'a>,
+    req: ImportCommitRequest,
+) -> Result<CommitTransactionResponse, CommitTransactionError> {
+    if !imports.end(req.session_id).await {
+        return Err(CommitTransactionError::UnknownTransaction);
+    }
+    let req = CommitTransactionRequest {
+        transaction_id: req.session_id,
+    };
+    do_commit(store, txns, req).await
+}
+
+async fn do_debug_dump<'a>(
+    store: &'a dag::Store,
+    _: &TxnMap<'a>,
+    req: DebugDumpRequest,
+) -> Result<DebugDump, dag::Error> {
+    let schema_version = store.schema_version().await?;
+    let dag_read = store.read().await?;
+    let read = dag_read.read();
+    let heads: Vec<DebugDumpHead> = read
+        .heads()
+        .await?
+        .into_iter()
+        .map(|(name, hash)| DebugDumpHead { name, hash })
+        .collect();
+    let chunks = read.chunks().await?;
+    let stats = DebugDumpStats {
+        heads: heads.len() as u64,
+        chunks: chunks.len() as u64,
+        chunk_bytes: chunks
+            .iter()
+            .map(|c| (c.data().len() + c.meta().map_or(0, |m| m.len())) as u64)
+            .sum(),
+    };
+    let chunks = match req.include_chunks {
+        Some(true) => Some(
+            chunks
+                .iter()
+                .map(|c| DebugDumpChunk {
+                    meta: c.meta().map(base64::encode),
+                    hash: c.hash().into(),
+                    data: base64::encode(c.data()),
+                })
+                .collect(),
+        ),
+        _ => None,
+    };
+    Ok(DebugDump {
+        chunks,
+        schema_version,
+        heads,
+        stats,
+    })
+}
+
+// Writes the chunks and heads of dump, which must include its chunks, into
+// store.
+pub async fn load_debug_dump(store: &dag::Store, dump: &DebugDump) -> Result<(), String> {
+    let chunks = dump
+        .chunks
+        .as_ref()
+        .ok_or("Dump has no chunks; create it with includeChunks")?;
+    let ours = store
+        .schema_version()
+        .await
+        .map_err(|e| format!("{:?}", e))?;
+    if let Some(theirs) = dump.schema_version {
+        if ours != Some(theirs) {
+            return Err(format!("Dump has unsupported schema version {}", theirs));
+        }
+    }
+    let decode =
+        |s: &str| base64::decode(s.as_bytes()).map_err(|e| format!("InvalidBase64({})", e));
+    let mut write = store.write().await.map_err(|e| format!("{:?}", e))?;
+    for c in chunks {
+        let meta = c.meta.as_deref().map(decode).transpose()?;
+        let chunk = dag::Chunk::read(c.hash.clone(), decode(&c.data)?, meta);
+        write
+            .put_chunk(&chunk)
+            .await
+            .map_err(|e| format!("{:?}", e))?;
+    }
+    for head in dump.heads.iter() {
+        write
+            .set_head(&head.name, &head.hash)
+            .await
+            .map_err(|e| format!("{:?}", e))?;
+    }
+    write.commit().await.map_err(|e| format!("{:?}", e))
+}
+
 async fn do_has(txn: &RwLock<Transaction<'_>>, req: HasRequest) -> Result<HasResponse, String> {
     Ok(HasResponse {
         has: txn.read().await.as_read().has(req.key.as_bytes()),
@@ -251,27 +561,110 @@ async fn do_get(txn: &RwLock<Transaction<'_>>, req: GetRequest) -> Result<GetRes
         .await
         .as_read()
         .get(req.key.as_bytes())
-        .map(|buf| String::from_utf8(buf.to_vec()));
-    if let Some(Err(e)) = got {
-        return Err(format!("{:?}", e));
-    }
-    let got = got.map(|r| r.unwrap());
+        .map(base64::encode);
     Ok(GetResponse {
         has: got.is_some(),
         value: got,
     })
 }
 
+// Like get, but returns the value as is rather than base64-encoded, failing
+// if it isn't valid UTF-8.
+async fn do_get_string(
+    txn: &RwLock<Transaction<'_>>,
+    req: GetRequest,
+) -> Result<GetResponse, String> {
+    let got = txn
+        .read()
+        .await
+        .as_read()
+        .get(req.key.as_bytes())
+        .map(|buf| String::from_utf8(buf.to_vec()))
+        .transpose()
+        .map_err(|e| format!("{:?}", e))?;
+    Ok(GetResponse {
+        has: got.is_some(),
+        value: got,
+    })
+}
+
+async fn do_scan_values(
+    txn: &RwLock<Transaction<'_>>,
+    req: ScanValuesRequest,
+) -> Result<ScanValuesResponse, String> {
+    let opts = db::ScanOptions {
+        prefix: req.prefix.as_ref().map(|p| p.as_bytes()),
+        start: req.start.as_ref().map(|start| db::ScanBound {
+            key: start.key.as_ref().map(|key| db::ScanKey {
+                value: key.value.as_bytes(),
+                exclusive: key.exclusive.unwrap_or(false),
+            }),
+            index: start.index,
+        }),
+        limit: req.limit,
+    };
+    let guard = txn.read().await;
+    let read = guard.as_read();
+    let entries = read
+        .scan(opts)
+        .map(|entry| {
+            let key = String::from_utf8(entry.key.to_vec()).map_err(|e| format!("{:?}", e))?;
+            // Values that are JSON can be passed through without the caller
+            // having to decode base64 and then JSON. A projection also cuts
+            // JSON objects down to the fields the caller asked for.
+            let json = match &req.projection {
+                Some(projection) => project(entry.val, &projection.fields),
+                None if is_json(entry.val) => Some(entry.val.to_vec()),
+                None => None,
+            };
+            let value = match json {
+                // Valid JSON is UTF-8, so this only fails on a bug.
+                Some(json) => ScanValue::Json(
+                    String::from_utf8(json).map_err(|e| internal_error(format!("{:?}", e)))?,
+                ),
+                None => ScanValue::Raw(base64::encode(entry.val)),
+            };
+            Ok(ScanValuesEntry { key, value })
+        })
+        .collect::<Result<_, String>>()?;
+    Ok(ScanValuesResponse { entries })
+}
+
 async fn do_put(txn: &RwLock<Transaction<'_>>, req: PutRequest) -> Result<PutResponse, String> {
+    put(txn, req.key, req.value.into_bytes()).await
+}
+
+// Like put, but takes the value base64-encoded, so it can be any bytes
+// rather than only a string.
+async fn do_put_bytes(
+    txn: &RwLock<Transaction<'_>>,
+    req: PutRequest,
+) -> Result<PutResponse, String> {
+    let value =
+        base64::decode(req.value.as_bytes()).map_err(|e| format!("InvalidBase64({})", e))?;
+    put(txn, req.key, value).await
+}
+
+async fn put(
+    txn: &RwLock<Transaction<'_>>,
+    key: String,
+    value: Vec<u8>,
+) -> Result<PutResponse, String> {
     let mut guard = txn.write().await;
     let write = match &mut *guard {
         Transaction::Write(w) => Ok(w),
         Transaction::Read(_) => Err("Specified transaction is read-only".to_string()),
     }?;
-    write.put(req.key.as_bytes().to_vec(), req.value.into_bytes());
+    write.put(key.into_bytes(), value);
     Ok(PutResponse {})
 }
 
+#[derive(Debug)]
+enum OpenError {
+    DagWriteError(dag::Error),
+    InitDBError(db::InitDBError),
+}
+
 #[derive(Debug)]
 #[allow(clippy::enum_variant_names)]
 enum OpenTransactionError {
@@ -293,6 +686,12 @@ enum CloseTransactionError {
     UnknownTransaction,
 }
 
+#[derive(Debug)]
+enum ImportChunkError {
+    UnknownSession,
+    InvalidBase64(String),
+}
+
 trait TransactionRequest {
     fn transaction_id(&self) -> u32;
 }
@@ -310,3 +709,483 @@ macro_rules! impl_transaction_request {
 impl_transaction_request!(HasRequest);
 impl_transaction_request!(GetRequest);
 impl_transaction_request!(PutRequest);
+impl_transaction_request!(ScanValuesRequest);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embed::dispatch::Response;
+    use crate::kv::clock::MockClock;
+    use crate::kv::memstore::MemStore;
+    use async_std::sync::{channel, Sender};
+
+    async fn call(tx: &Sender<Request>, rpc: &str, data: &str) -> Response {
+        let (resp_tx, resp_rx) = channel::<Response>(1);
+        tx.send(Request::new("db".into(), rpc.into(), data.into(), resp_tx))
+            .await;
+        resp_rx.recv().await.unwrap()
+    }
+
+    async fn open_write(tx: &Sender<Request>) -> u32 {
+        let resp = call(tx, "openTransaction", "{\"name\": \"foo\"}")
+            .await
+            .unwrap();
+        let resp: OpenTransactionResponse = DeJson::deserialize_json(&resp).unwrap();
+        resp.transaction_id
+    }
+
+    fn put_request(txn_id: u32, key: &str, value: &[u8]) -> String {
+        format!(
+            "{{\"transactionId\": {}, \"key\": \"{}\", \"value\": \"{}\"}}",
+            txn_id,
+            key,
+            base64::encode(value)
+        )
+    }
+
+    #[async_std::test]
+    async fn panic_is_internal_error() {
+        let (tx, rx) = channel::<Request>(1);
+        let store = dag::Store::new(Box::new(MemStore::new()));
+        futures::join!(process(store, rx), async move {
+            let root = call(&tx, "open", "{}").await.unwrap();
+
+            let err = call(&tx, "panic", "oops").await.unwrap_err();
+            let err: ErrorResponse = DeJson::deserialize_json(&err).unwrap();
+            assert_eq!("internal", err.error.kind);
+            assert_eq!("oops", err.error.message);
+
+            // The connection is still usable.
+            assert_eq!(root, call(&tx, "open", "{}").await.unwrap());
+            let resp = call(&tx, "openTransaction", "{}").await.unwrap();
+            let resp: OpenTransactionResponse = DeJson::deserialize_json(&resp).unwrap();
+            let req = format!(
+                "{{\"transactionId\": {}, \"key\": \"k\"}}",
+                resp.transaction_id
+            );
+            assert_eq!("{\"has\":false}", call(&tx, "has", &req).await.unwrap());
+
+            assert_eq!("", call(&tx, "close", "").await.unwrap());
+        });
+    }
+
+    #[async_std::test]
+    async fn binary_values() {
+        let (tx, rx) = channel::<Request>(1);
+        let store = dag::Store::new(Box::new(MemStore::new()));
+        futures::join!(process(store, rx), async move {
+            call(&tx, "open", "{}").await.unwrap();
+            let txn_id = open_write(&tx).await;
+            let put = |key: &str, value: &[u8]| put_request(txn_id, key, value);
+            let get =
+                |key: &str| format!("{{\"transactionId\": {}, \"key\": \"{}\"}}", txn_id, key);
+
+            call(&tx, "putBytes", &put("bin", &[0xff, 0, 0xc3]))
+                .await
+                .unwrap();
+            // put still takes the value as a plain string.
+            let put_str = format!(
+                "{{\"transactionId\": {}, \"key\": \"str\", \"value\": \"世界\"}}",
+                txn_id
+            );
+            call(&tx, "put", &put_str).await.unwrap();
+
+            // get round-trips any value as base64.
+            assert_eq!(
+                "{\"value\":\"/wDD\",\"has\":true}",
+                call(&tx, "get", &get("bin")).await.unwrap()
+            );
+            assert_eq!(
+                "{\"has\":false}",
+                call(&tx, "get", &get("missing")).await.unwrap()
+            );
+
+            // getString only returns valid UTF-8.
+            assert!(call(&tx, "getString", &get("bin")).await.is_err());
+            assert_eq!(
+                "{\"value\":\"世界\",\"has\":true}",
+                call(&tx, "getString", &get("str")).await.unwrap()
+            );
+            assert_eq!(
+                "{\"has\":false}",
+                call(&tx, "getString", &get("missing")).await.unwrap()
+            );
+
+            let bad = format!(
+                "{{\"transactionId\": {}, \"key\": \"k\", \"value\": \"!\"}}",
+                txn_id
+            );
+            assert!(call(&tx, "putBytes", &bad)
+                .await
+                .unwrap_err()
+                .starts_with("InvalidBase64"));
+
+            assert_eq!("", call(&tx, "close", "").await.unwrap());
+        });
+    }
+
+    #[async_std::test]
+    async fn scan_values() {
+        let (tx, rx) = channel::<Request>(1);
+        let store = dag::Store::new(Box::new(MemStore::new()));
+        futures::join!(process(store, rx), async move {
+            call(&tx, "open", "{}").await.unwrap();
+            let txn_id = open_write(&tx).await;
+            for (key, value) in &[
+                ("a", &b"{\"x\": [1, 2]}"[..]),
+                ("b/1", b"\"str\""),
+                ("b/2", &[0xff, 0, 0xc3]),
+                ("b/3", b"not json"),
+                ("b/4", b"42"),
+                ("c", b"null"),
+            ] {
+                call(&tx, "putBytes", &put_request(txn_id, key, value))
+                    .await
+                    .unwrap();
+            }
+            let scan = |opts: &str| {
+                let req = format!("{{\"transactionId\": {}{}}}", txn_id, opts);
+                let tx = &tx;
+                async move { call(tx, "scanValues", &req).await.unwrap() }
+            };
+
+            assert_eq!(
+                concat!(
+                    "{\"entries\":[",
+                    "{\"key\":\"a\",\"value\":{\"x\": [1, 2]}},",
+                    "{\"key\":\"b/1\",\"value\":\"str\"},",
+                    "{\"key\":\"b/2\",\"raw\":\"/wDD\"},",
+                    "{\"key\":\"b/3\",\"raw\":\"bm90IGpzb24=\"},",
+                    "{\"key\":\"b/4\",\"value\":42},",
+                    "{\"key\":\"c\",\"value\":null}",
+                    "]}"
+                ),
+                scan("").await
+            );
+            assert_eq!(
+                concat!(
+                    "{\"entries\":[",
+                    "{\"key\":\"b/2\",\"raw\":\"/wDD\"},",
+                    "{\"key\":\"b/3\",\"raw\":\"bm90IGpzb24=\"}",
+                    "]}"
+                ),
+                scan(", \"prefix\": \"b/\", \"start\": {\"key\": {\"value\": \"b/1\", \"exclusive\": true}}, \"limit\": 2").await
+            );
+            assert_eq!(
+                "{\"entries\":[{\"key\":\"c\",\"value\":null}]}",
+                scan(", \"start\": {\"index\": 5}").await
+            );
+            assert_eq!("{\"entries\":[]}", scan(", \"prefix\": \"d\"").await);
+
+            assert_eq!("", call(&tx, "close", "").await.unwrap());
+        });
+    }
+
+    #[async_std::test]
+    async fn scan_values_projection() {
+        let (tx, rx) = channel::<Request>(1);
+        let store = dag::Store::new(Box::new(MemStore::new()));
+        futures::join!(process(store, rx), async move {
+            call(&tx, "open", "{}").await.unwrap();
+            let txn_id = open_write(&tx).await;
+            for (key, value) in &[
+                (
+                    "u/1",
+                    &b"{\"id\": 1, \"name\": \"Ann\", \"bio\": \"...\"}"[..],
+                ),
+                (
+                    "u/2",
+                    b"{\"name\": {\"first\": \"Bo\"}, \"id\": 2, \"tags\": []}",
+                ),
+                ("u/3", b"{\"bio\": \"none\"}"),
+                ("u/4", b"[1, 2]"),
+                ("u/5", &[0xff, 0, 0xc3]),
+            ] {
+                call(&tx, "putBytes", &put_request(txn_id, key, value))
+                    .await
+                    .unwrap();
+            }
+
+            let req = format!(
+                "{{\"transactionId\": {}, \"projection\": {{\"fields\": [\"id\", \"name\"]}}}}",
+                txn_id
+            );
+            assert_eq!(
+                concat!(
+                    "{\"entries\":[",
+                    "{\"key\":\"u/1\",\"value\":{\"id\":1,\"name\":\"Ann\"}},",
+                    "{\"key\":\"u/2\",\"value\":{\"name\":{\"first\": \"Bo\"},\"id\":2}},",
+                    "{\"key\":\"u/3\",\"value\":{}},",
+                    "{\"key\":\"u/4\",\"value\":[1, 2]},",
+                    "{\"key\":\"u/5\",\"raw\":\"/wDD\"}",
+                    "]}"
+                ),
+                call(&tx, "scanValues", &req).await.unwrap()
+            );
+
+            assert_eq!("", call(&tx, "close", "").await.unwrap());
+        });
+    }
+
+    #[async_std::test]
+    async fn debug_dump_round_trip() {
+        let dump = |tx: &Sender<Request>, include_chunks: bool| {
+            let req = format!("{{\"includeChunks\": {}}}", include_chunks);
+            let tx = tx.clone();
+            async move { call(&tx, "debugDump", &req).await.unwrap() }
+        };
+
+        let (tx, rx) = channel::<Request>(1);
+        let store = dag::Store::open(Box::new(MemStore::new())).await.unwrap();
+        let (full, summary) = futures::join!(process(store, rx), async move {
+            call(&tx, "open", "{}").await.unwrap();
+            let txn_id = open_write(&tx).await;
+            call(&tx, "putBytes", &put_request(txn_id, "k", b"v"))
+                .await
+                .unwrap();
+            let commit = format!("{{\"transactionId\": {}}}", txn_id);
+            call(&tx, "commitTransaction", &commit).await.unwrap();
+
+            let full = dump(&tx, true).await;
+            let summary = dump(&tx, false).await;
+            assert_eq!("", call(&tx, "close", "").await.unwrap());
+            (full, summary)
+        })
+        .1;
+
+        let parsed: DebugDump = DeJson::deserialize_json(&full).unwrap();
+        assert!(parsed.schema_version.is_some());
+        assert_eq!(1, parsed.heads.len());
+        assert_eq!("main", parsed.heads[0].name);
+        let chunks = parsed.chunks.as_ref().unwrap();
+        assert_eq!(parsed.stats.chunks, chunks.len() as u64);
+        assert!(chunks.iter().any(|c| c.hash == parsed.heads[0].hash));
+
+        // Without chunks, the dump is otherwise the same but can't be loaded.
+        let parsed_summary: DebugDump = DeJson::deserialize_json(&summary).unwrap();
+        assert!(parsed_summary.chunks.is_none());
+        assert_eq!(
+            SerJson::serialize_json(&DebugDump {
+                chunks: None,
+                ..parsed
+            }),
+            summary
+        );
+        let store = dag::Store::open(Box::new(MemStore::new())).await.unwrap();
+        assert!(load_debug_dump(&store, &parsed_summary).await.is_err());
+
+        // A loaded dump has the same contents, and dumps identically.
+        let parsed: DebugDump = DeJson::deserialize_json(&full).unwrap();
+        load_debug_dump(&store, &parsed).await.unwrap();
+        let (tx, rx) = channel::<Request>(1);
+        futures::join!(process(store, rx), async move {
+            assert_eq!(full, dump(&tx, true).await);
+            let resp = call(&tx, "openTransaction", "{}").await.unwrap();
+            let resp: OpenTransactionResponse = DeJson::deserialize_json(&resp).unwrap();
+            let get = format!(
+                "{{\"transactionId\": {}, \"key\": \"k\"}}",
+                resp.transaction_id
+            );
+            assert_eq!(
+                "{\"value\":\"v\",\"has\":true}",
+                call(&tx, "getString", &get).await.unwrap()
+            );
+            assert_eq!("", call(&tx, "close", "").await.unwrap());
+        });
+    }
+
+    #[async_std::test]
+    async fn reset() {
+        let (tx, rx) = channel::<Request>(1);
+        let store = dag::Store::open(Box::new(MemStore::new())).await.unwrap();
+        futures::join!(process(store, rx), async move {
+            let open: OpenResponse =
+                DeJson::deserialize_json(&call(&tx, "open", "{}").await.unwrap()).unwrap();
+            for key in &["a", "b"] {
+                let txn_id = open_write(&tx).await;
+                call(&tx, "putBytes", &put_request(txn_id, key, b"v"))
+                    .await
+                    .unwrap();
+                let commit = format!("{{\"transactionId\": {}}}", txn_id);
+                call(&tx, "commitTransaction", &commit).await.unwrap();
+            }
+            // Open transactions are ended rather than blocking the reset.
+            let write_id = open_write(&tx).await;
+
+            let reset: OpenResponse =
+                DeJson::deserialize_json(&call(&tx, "reset", "{}").await.unwrap()).unwrap();
+            assert_eq!(open.root, reset.root);
+
+            // Only the new head and the chunks it needs are left.
+            let dump = call(&tx, "debugDump", "{\"includeChunks\": true}")
+                .await
+                .unwrap();
+            let dump: DebugDump = DeJson::deserialize_json(&dump).unwrap();
+            assert!(dump.schema_version.is_some());
+            assert_eq!(1, dump.heads.len());
+            assert_eq!(
+                ("main", &reset.root),
+                (&dump.heads[0].name[..], &dump.heads[0].hash)
+            );
+            let chunks = dump.chunks.unwrap();
+            assert_eq!(2, chunks.len());
+            assert!(chunks.iter().any(|c| c.hash == reset.root));
+            assert_eq!("{\"has\":false}", get_string(&tx, "a").await);
+            let put = put_request(write_id, "c", b"v");
+            assert_eq!(
+                format!("No transaction {}", write_id),
+                call(&tx, "putBytes", &put).await.unwrap_err()
+            );
+
+            // The reset db is usable as before.
+            let txn_id = open_write(&tx).await;
+            call(&tx, "putBytes", &put_request(txn_id, "c", b"v"))
+                .await
+                .unwrap();
+            let commit = format!("{{\"transactionId\": {}}}", txn_id);
+            call(&tx, "commitTransaction", &commit).await.unwrap();
+            assert_eq!("{\"value\":\"v\",\"has\":true}", get_string(&tx, "c").await);
+
+            assert_eq!("", call(&tx, "close", "").await.unwrap());
+        });
+    }
+
+    fn import_chunk(session_id: u32, entries: &[(&str, &str)]) -> String {
+        let entries: Vec<String> = entries
+            .iter()
+            .map(|(k, v)| {
+                format!(
+                    "{{\"key\": \"{}\", \"value\": \"{}\"}}",
+                    k,
+                    base64::encode(v.as_bytes())
+                )
+            })
+            .collect();
+        format!(
+            "{{\"sessionId\": {}, \"entries\": [{}]}}",
+            session_id,
+            entries.join(", ")
+        )
+    }
+
+    async fn import_begin(tx: &Sender<Request>) -> u32 {
+        let resp = call(tx, "importBegin", "{}").await.unwrap();
+        let resp: ImportBeginResponse = DeJson::deserialize_json(&resp).unwrap();
+        resp.session_id
+    }
+
+    // Reads key in a read transaction of its own.
+    async fn get_string(tx: &Sender<Request>, key: &str) -> String {
+        let resp = call(tx, "openTransaction", "{}").await.unwrap();
+        let resp: OpenTransactionResponse = DeJson::deserialize_json(&resp).unwrap();
+        let get = format!(
+            "{{\"transactionId\": {}, \"key\": \"{}\"}}",
+            resp.transaction_id, key
+        );
+        let got = call(tx, "getString", &get).await.unwrap();
+        let close = format!("{{\"transactionId\": {}}}", resp.transaction_id);
+        call(tx, "closeTransaction", &close).await.unwrap();
+        got
+    }
+
+    #[async_std::test]
+    async fn import_across_chunks() {
+        let (tx, rx) = channel::<Request>(1);
+        let store = dag::Store::new(Box::new(MemStore::new()));
+        futures::join!(process(store, rx), async move {
+            call(&tx, "open", "{}").await.unwrap();
+            let session_id = import_begin(&tx).await;
+            for chunk in &[
+                &[("a", "1"), ("b", "2")][..],
+                &[("c", "3")],
+                &[],
+                &[("a", "4"), ("d", "5")],
+            ] {
+                assert_eq!(
+                    "{}",
+                    call(&tx, "importChunk", &import_chunk(session_id, chunk))
+                        .await
+                        .unwrap()
+                );
+            }
+
+            // A chunk with a bad value adds none of its entries.
+            let bad = format!(
+                "{{\"sessionId\": {}, \"entries\": [{{\"key\": \"e\", \"value\": \"ZQ==\"}}, {{\"key\": \"f\", \"value\": \"!\"}}]}}",
+                session_id
+            );
+            assert!(call(&tx, "importChunk", &bad)
+                .await
+                .unwrap_err()
+                .starts_with("InvalidBase64"));
+
+            let commit = format!("{{\"sessionId\": {}}}", session_id);
+            assert_eq!("{}", call(&tx, "importCommit", &commit).await.unwrap());
+            for (key, value) in &[("a", "4"), ("b", "2"), ("c", "3"), ("d", "5")] {
+                assert_eq!(
+                    format!("{{\"value\":\"{}\",\"has\":true}}", value),
+                    get_string(&tx, key).await
+                );
+            }
+            assert_eq!("{\"has\":false}", get_string(&tx, "e").await);
+
+            // The session is over.
+            assert_eq!(
+                "UnknownSession",
+                call(&tx, "importChunk", &import_chunk(session_id, &[("x", "")]))
+                    .await
+                    .unwrap_err()
+            );
+            assert_eq!(
+                "UnknownTransaction",
+                call(&tx, "importCommit", &commit).await.unwrap_err()
+            );
+
+            assert_eq!("", call(&tx, "close", "").await.unwrap());
+        });
+    }
+
+    #[async_std::test]
+    async fn abandoned_import_rolls_back() {
+        let (tx, rx) = channel::<Request>(1);
+        let store = dag::Store::new(Box::new(MemStore::new()));
+        let clock = &MockClock::new(0);
+        futures::join!(process_with_clock(store, rx, clock), async move {
+            call(&tx, "open", "{}").await.unwrap();
+            let session_id = import_begin(&tx).await;
+            call(&tx, "importChunk", &import_chunk(session_id, &[("a", "1")]))
+                .await
+                .unwrap();
+
+            // Using the session keeps it alive.
+            for key in &["b", "c"] {
+                clock.advance(IMPORT_SESSION_TIMEOUT_MS - 1);
+                call(&tx, "importChunk", &import_chunk(session_id, &[(key, "2")]))
+                    .await
+                    .unwrap();
+            }
+
+            // Once it has gone unused for too long, the next request rolls it
+            // back, releasing the write lock for other writers.
+            clock.advance(IMPORT_SESSION_TIMEOUT_MS);
+            let txn_id = open_write(&tx).await;
+            call(&tx, "putBytes", &put_request(txn_id, "d", b"3"))
+                .await
+                .unwrap();
+            let commit = format!("{{\"transactionId\": {}}}", txn_id);
+            call(&tx, "commitTransaction", &commit).await.unwrap();
+            for key in &["a", "b", "c"] {
+                assert_eq!("{\"has\":false}", get_string(&tx, key).await);
+            }
+            assert_eq!("{\"value\":\"3\",\"has\":true}", get_string(&tx, "d").await);
+            assert_eq!(
+                "UnknownSession",
+                call(&tx, "importChunk", &import_chunk(session_id, &[("e", "4")]))
+                    .await
+                    .unwrap_err()
+            );
+
+            assert_eq!("", call(&tx, "close", "").await.unwrap());
+        });
+    }
+}
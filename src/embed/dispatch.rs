@@ -1,9 +1,17 @@
 use crate::dag;
+use crate::embed::codec::{self, MsgPack, ValueCodec};
 use crate::embed::connection;
+use crate::embed::types::{DebugDump, ErrorDetail, ErrorResponse};
 use crate::kv::idbstore::IdbStore;
+use crate::kv::memstore::MemStore;
 use async_std::sync::{channel, Receiver, Sender};
+use futures::future::FutureExt;
 use log::warn;
+use nanoserde::{DeJson, SerJson};
+use std::any::Any;
 use std::collections::HashMap;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
 use std::sync::Mutex;
 use wasm_bindgen_futures::spawn_local;
 
@@ -14,7 +22,18 @@ pub struct Request {
     pub response: Sender<Response>,
 }
 
-type Response = Result<String, String>;
+impl Request {
+    pub fn new(db_name: String, rpc: String, data: String, response: Sender<Response>) -> Request {
+        Request {
+            db_name,
+            rpc,
+            data,
+            response,
+        }
+    }
+}
+
+pub type Response = Result<String, String>;
 
 lazy_static! {
     static ref SENDER: Mutex<Sender::<Request>> = {
@@ -30,7 +49,7 @@ async fn dispatch_loop(rx: Receiver<Request>) {
     let mut conns: ConnMap = HashMap::new();
 
     loop {
-        let req = match rx.recv().await {
+        let mut req = match rx.recv().await {
             Ok(req) => req,
             Err(why) => {
                 warn!("Dispatch loop recv failed: {}", why);
@@ -39,9 +58,12 @@ async fn dispatch_loop(rx: Receiver<Request>) {
         };
 
         let response = match req.rpc.as_str() {
-            "open" => Some(do_open(&mut conns, &req).await),
-            "close" => Some(do_close(&mut conns, &req).await),
-            "debug" => Some(do_debug(&conns, &req).await),
+            "open" => catch_panic(async { do_open(&mut conns, &mut req).await.err().map(Err) })
+                .await
+                .unwrap_or_else(|e| Some(Err(e))),
+            "close" => Some(flatten(catch_panic(do_close(&mut conns, &req)).await)),
+            "debugLoad" => Some(flatten(catch_panic(do_debug_load(&mut conns, &req)).await)),
+            "debug" => Some(flatten(catch_panic(do_debug(&conns, &req)).await)),
             _ => None,
         };
         if let Some(response) = response {
@@ -61,12 +83,7 @@ async fn dispatch_loop(rx: Receiver<Request>) {
 
 pub async fn dispatch(db_name: String, rpc: String, data: String) -> Response {
     let (tx, rx) = channel::<Response>(1);
-    let request = Request {
-        db_name,
-        rpc,
-        data,
-        response: tx,
-    };
+    let request = Request::new(db_name, rpc, data, tx);
     match SENDER.lock() {
         Ok(v) => v.send(request).await,
         Err(e) => return Err(e.to_string()),
@@ -77,22 +94,97 @@ pub async fn dispatch(db_name: String, rpc: String, data: String) -> Response {
     }
 }
 
-async fn do_open(conns: &mut ConnMap, req: &Request) -> Response {
+// Like dispatch(), but returns a successful response as MessagePack rather
+// than JSON. Responses that aren't JSON (e.g. from "close") are returned as
+// a msgpack string. Errors are still plain strings.
+pub async fn dispatch_binary(
+    db_name: String,
+    rpc: String,
+    data: String,
+) -> Result<Vec<u8>, String> {
+    let response = dispatch(db_name, rpc, data).await?;
+    Ok(MsgPack.encode(response))
+}
+
+// Like dispatch(), but args and successful responses are encoded with the
+// codec registered under codec_name (see register_codec()). Errors,
+// including args the codec can't decode, are still plain strings.
+pub async fn dispatch_with_codec(
+    codec_name: String,
+    db_name: String,
+    rpc: String,
+    args: Vec<u8>,
+) -> Result<Vec<u8>, String> {
+    let codec = codec::codec(&codec_name)?;
+    let data = codec.decode(&args)?;
+    let response = dispatch(db_name, rpc, data).await?;
+    Ok(codec.encode(response))
+}
+
+// Runs an rpc handler, turning a panic into an internal error so that a
+// bug fails the one request rather than the dispatch loop. This only works
+// in native builds, such as our tests. wasm32 can't unwind, so there a
+// panic always traps (after console_error_panic_hook has logged it, if
+// wasm::init() installed it) and handlers must return an internal_error()
+// instead of panicking for anything that could go wrong at runtime.
+pub async fn catch_panic<T>(f: impl Future<Output = T>) -> Result<T, String> {
+    AssertUnwindSafe(f)
+        .catch_unwind()
+        .await
+        .map_err(|payload| internal_error(panic_message(payload)))
+}
+
+fn flatten(response: Result<Response, String>) -> Response {
+    response.unwrap_or_else(Err)
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(s) => *s,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(s) => s.to_string(),
+            Err(_) => "panic".into(),
+        },
+    }
+}
+
+// The error response for a bug, rather than for a bad request.
+pub fn internal_error(message: String) -> String {
+    SerJson::serialize_json(&ErrorResponse {
+        error: ErrorDetail {
+            kind: "internal".into(),
+            message,
+        },
+    })
+}
+
+// Starts a connection for the db if it isn't running yet. On success the
+// request is passed on to the connection, which initializes the default
+// head.
+async fn do_open(conns: &mut ConnMap, req: &mut Request) -> Result<(), String> {
     if req.db_name.is_empty() {
         return Err("db_name must be non-empty".into());
     }
+    if req.data.is_empty() {
+        req.data = "{}".into();
+    }
     if conns.contains_key(&req.db_name[..]) {
-        return Ok("".into());
+        return Ok(());
     }
     match IdbStore::new(&req.db_name[..]).await {
         Err(e) => Err(format!("Failed to open \"{}\": {}", req.db_name, e)),
-        Ok(v) => {
-            if let Some(kv) = v {
-                let (tx, rx) = channel::<Request>(1);
-                spawn_local(connection::process(dag::Store::new(Box::new(kv)), rx));
-                conns.insert(req.db_name.clone(), tx);
-            }
-            Ok("".into())
+        Ok(None) => Err(format!(
+            "Failed to open \"{}\": IndexedDB is not available",
+            req.db_name
+        )),
+        Ok(Some(kv)) => {
+            let store = dag::Store::open(Box::new(kv))
+                .await
+                .map_err(|e| format!("Failed to open \"{}\": {:?}", req.db_name, e))?;
+            let (tx, rx) = channel::<Request>(1);
+            spawn_local(connection::process(store, rx));
+            conns.insert(req.db_name.clone(), tx);
+            Ok(())
         }
     }
 }
@@ -103,18 +195,39 @@ async fn do_close(conns: &mut ConnMap, req: &Request) -> Response {
         Some(v) => v,
     };
     let (tx2, rx2) = channel::<Response>(1);
-    tx.send(Request {
-        db_name: req.db_name.clone(),
-        rpc: "close".into(),
-        data: "".into(),
-        response: tx2,
-    })
+    tx.send(Request::new(
+        req.db_name.clone(),
+        "close".into(),
+        "".into(),
+        tx2,
+    ))
     .await;
     let _ = rx2.recv().await;
     conns.remove(&req.db_name);
     Ok("".into())
 }
 
+// Opens db_name as an in-memory db holding the contents of a debugDump, so
+// a bug report's snapshot can be inspected with the usual rpcs.
+async fn do_debug_load(conns: &mut ConnMap, req: &Request) -> Response {
+    if req.db_name.is_empty() {
+        return Err("db_name must be non-empty".into());
+    }
+    if conns.contains_key(&req.db_name[..]) {
+        return Err(format!("\"{}\" is already open", req.db_name));
+    }
+    let dump: DebugDump =
+        DeJson::deserialize_json(&req.data).map_err(|e| format!("InvalidJson({})", e))?;
+    let store = dag::Store::open(Box::new(MemStore::new()))
+        .await
+        .map_err(|e| format!("{:?}", e))?;
+    connection::load_debug_dump(&store, &dump).await?;
+    let (tx, rx) = channel::<Request>(1);
+    spawn_local(connection::process(store, rx));
+    conns.insert(req.db_name.clone(), tx);
+    Ok("".into())
+}
+
 async fn do_debug(conns: &ConnMap, req: &Request) -> Response {
     match req.data.as_str() {
         "open_dbs" => Ok(format!("{:?}", conns.keys())),
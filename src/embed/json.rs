@@ -0,0 +1,340 @@
+use crate::embed::msgpack::unescape;
+use std::ops::Range;
+
+// A strict check that bytes are a single JSON value (RFC 8259), used to
+// decide whether a stored value can be embedded as-is in a JSON response.
+// nanoserde only parses into known types, so we can't use it for this.
+pub fn is_json(bytes: &[u8]) -> bool {
+    let mut p = Parser { bytes, pos: 0 };
+    p.skip_ws();
+    if !p.value(0) {
+        return false;
+    }
+    p.skip_ws();
+    p.pos == bytes.len()
+}
+
+// Returns the members of the JSON object bytes whose names are in fields,
+// as an object, in their original order and with their values as they
+// were. Returns bytes unchanged if it is JSON but not an object, and None if
+// it isn't JSON.
+pub fn project(bytes: &[u8], fields: &[String]) -> Option<Vec<u8>> {
+    if !is_json(bytes) {
+        return None;
+    }
+    let mut p = Parser { bytes, pos: 0 };
+    p.skip_ws();
+    let members = match p.members() {
+        Some(members) => members,
+        None => return Some(bytes.to_vec()),
+    };
+    let kept: Vec<Vec<u8>> = members
+        .into_iter()
+        .filter(|(name, _)| {
+            let name = &bytes[name.clone()];
+            match name.contains(&b'\\') {
+                // Unescape names that need it to compare them.
+                true => fields.contains(&unescape(name)),
+                false => fields
+                    .iter()
+                    .any(|f| f.as_bytes() == &name[1..name.len() - 1]),
+            }
+        })
+        .map(|(name, value)| [&bytes[name], b":", &bytes[value]].concat())
+        .collect();
+    Some([&b"{"[..], &kept.join(&b',')[..], b"}"].concat())
+}
+
+// Guards against stack overflow on deeply nested input.
+const MAX_DEPTH: usize = 512;
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, b: u8) -> bool {
+        if self.peek() == Some(b) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn eat_literal(&mut self, literal: &[u8]) -> bool {
+        if self.bytes[self.pos..].starts_with(literal) {
+            self.pos += literal.len();
+            return true;
+        }
+        false
+    }
+
+    fn skip_ws(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    // Returns the spans of the name (with its quotes) and value of each
+    // member of the object at pos, or None if there is no object there. The
+    // input must already be known to be valid.
+    fn members(&mut self) -> Option<Vec<(Range<usize>, Range<usize>)>> {
+        if !self.eat(b'{') {
+            return None;
+        }
+        let mut members = Vec::new();
+        self.skip_ws();
+        if self.eat(b'}') {
+            return Some(members);
+        }
+        loop {
+            self.skip_ws();
+            let name = self.pos;
+            self.string();
+            let name = name..self.pos;
+            self.skip_ws();
+            self.eat(b':');
+            self.skip_ws();
+            let value = self.pos;
+            self.value(1);
+            members.push((name, value..self.pos));
+            self.skip_ws();
+            if self.eat(b'}') {
+                return Some(members);
+            }
+            self.eat(b',');
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> bool {
+        if depth > MAX_DEPTH {
+            return false;
+        }
+        match self.peek() {
+            Some(b'{') => self.object(depth),
+            Some(b'[') => self.array(depth),
+            Some(b'"') => self.string(),
+            Some(b't') => self.eat_literal(b"true"),
+            Some(b'f') => self.eat_literal(b"false"),
+            Some(b'n') => self.eat_literal(b"null"),
+            Some(b'-') | Some(b'0'..=b'9') => self.number(),
+            _ => false,
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> bool {
+        self.pos += 1;
+        self.skip_ws();
+        if self.eat(b'}') {
+            return true;
+        }
+        loop {
+            self.skip_ws();
+            if !self.string() {
+                return false;
+            }
+            self.skip_ws();
+            if !self.eat(b':') {
+                return false;
+            }
+            self.skip_ws();
+            if !self.value(depth + 1) {
+                return false;
+            }
+            self.skip_ws();
+            if self.eat(b'}') {
+                return true;
+            }
+            if !self.eat(b',') {
+                return false;
+            }
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> bool {
+        self.pos += 1;
+        self.skip_ws();
+        if self.eat(b']') {
+            return true;
+        }
+        loop {
+            self.skip_ws();
+            if !self.value(depth + 1) {
+                return false;
+            }
+            self.skip_ws();
+            if self.eat(b']') {
+                return true;
+            }
+            if !self.eat(b',') {
+                return false;
+            }
+        }
+    }
+
+    fn string(&mut self) -> bool {
+        if !self.eat(b'"') {
+            return false;
+        }
+        let start = self.pos;
+        loop {
+            match self.peek() {
+                None => return false,
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some(b'"') | Some(b'\\') | Some(b'/') | Some(b'b') | Some(b'f')
+                        | Some(b'n') | Some(b'r') | Some(b't') => self.pos += 1,
+                        Some(b'u') => {
+                            self.pos += 1;
+                            for _ in 0..4 {
+                                match self.peek() {
+                                    Some(b) if b.is_ascii_hexdigit() => self.pos += 1,
+                                    _ => return false,
+                                }
+                            }
+                        }
+                        _ => return false,
+                    }
+                }
+                Some(b) if b < 0x20 => return false,
+                Some(_) => self.pos += 1,
+            }
+        }
+        let valid_utf8 = std::str::from_utf8(&self.bytes[start..self.pos]).is_ok();
+        self.pos += 1;
+        valid_utf8
+    }
+
+    fn digits(&mut self) -> bool {
+        let start = self.pos;
+        while let Some(b'0'..=b'9') = self.peek() {
+            self.pos += 1;
+        }
+        self.pos > start
+    }
+
+    fn number(&mut self) -> bool {
+        self.eat(b'-');
+        if !self.eat(b'0') && !self.digits() {
+            return false;
+        }
+        if self.eat(b'.') && !self.digits() {
+            return false;
+        }
+        if self.eat(b'e') || self.eat(b'E') {
+            if !self.eat(b'+') {
+                self.eat(b'-');
+            }
+            if !self.digits() {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid() {
+        for s in &[
+            "null",
+            " true ",
+            "false",
+            "0",
+            "-0",
+            "12",
+            "-1.5e10",
+            "1E-2",
+            "1e+2",
+            "\"\"",
+            "\"a\\\"b\\\\c\\u00e9\\n\"",
+            "\"世界\"",
+            "[]",
+            "[1, \"two\", [3], {}]",
+            "{}",
+            "{\"a\": 1, \"b\": {\"c\": [null]}}",
+            "\n\t{ \"a\" : [ ] }\r\n",
+        ] {
+            assert!(is_json(s.as_bytes()), "{}", s);
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        for s in &[
+            "",
+            " ",
+            "nul",
+            "True",
+            "01",
+            "1.",
+            ".5",
+            "1e",
+            "+1",
+            "--1",
+            "\"",
+            "\"\\x\"",
+            "\"\\u12g4\"",
+            "\"a\nb\"",
+            "[",
+            "[1,]",
+            "[1 2]",
+            "{\"a\"}",
+            "{\"a\": 1,}",
+            "{a: 1}",
+            "{\"a\": 1} {}",
+            "1 2",
+            "'a'",
+        ] {
+            assert!(!is_json(s.as_bytes()), "{}", s);
+        }
+        assert!(!is_json(&[b'"', 0xff, b'"']));
+        assert!(!is_json(&[0xff, 0, 0xc3]));
+        assert!(!is_json("[".repeat(MAX_DEPTH + 2).as_bytes()));
+    }
+
+    #[test]
+    fn projection() {
+        let fields: Vec<String> = vec!["id".into(), "name".into(), "a\"b".into()];
+        for (value, projected) in &[
+            (
+                "{\"id\": 1, \"name\": \"x\", \"tags\": [\"a\"]}",
+                "{\"id\":1,\"name\":\"x\"}",
+            ),
+            (
+                " { \"big\" : {\"id\": 2}, \"name\" : {\"first\": \"y\"} } ",
+                "{\"name\":{\"first\": \"y\"}}",
+            ),
+            ("{\"other\": 1}", "{}"),
+            ("{}", "{}"),
+            // Escaped names match by their unescaped value.
+            (
+                "{\"\\u0069d\": 3, \"a\\\"b\": 4}",
+                "{\"\\u0069d\":3,\"a\\\"b\":4}",
+            ),
+            // Values that aren't objects have no fields to drop.
+            ("[{\"id\": 1, \"x\": 2}]", "[{\"id\": 1, \"x\": 2}]"),
+            ("\"id\"", "\"id\""),
+            ("42", "42"),
+        ] {
+            assert_eq!(
+                Some(projected.as_bytes().to_vec()),
+                project(value.as_bytes(), &fields),
+                "{}",
+                value
+            );
+        }
+        assert_eq!(None, project(b"{\"id\": 1", &fields));
+        assert_eq!(None, project(&[0xff], &fields));
+    }
+}
@@ -8,8 +8,12 @@
 //! request/response message passing of byte arrays in and out so that
 //! it can work with a variety of hosts.
 
+mod codec;
 mod connection;
 mod dispatch;
+mod json;
+mod msgpack;
 pub mod types;
 
-pub use dispatch::dispatch;
+pub use codec::{register_codec, Json, MsgPack, ValueCodec};
+pub use dispatch::{dispatch, dispatch_binary, dispatch_with_codec};
@@ -0,0 +1,525 @@
+// Transcodes JSON responses to MessagePack, for embedders that would rather
+// decode large payloads (e.g. scans) with a msgpack decoder than JSON.parse.
+// Responses are built as JSON, so rather than teaching every response type
+// a second encoding we re-encode the finished document.
+//
+// Integers that fit in an i64 or u64 are encoded as msgpack ints, all other
+// numbers as float64. Map keys keep their JSON order.
+//
+// to_json() goes the other way, for args sent as msgpack.
+use crate::embed::json::is_json;
+use nanoserde::SerJson;
+
+// Returns None if json is not a single valid JSON value.
+pub fn from_json(json: &[u8]) -> Option<Vec<u8>> {
+    if !is_json(json) {
+        return None;
+    }
+    let mut t = Transcoder {
+        bytes: json,
+        pos: 0,
+    };
+    let mut out = Vec::with_capacity(json.len());
+    t.value(&mut out);
+    Some(out)
+}
+
+// Decodes the JSON string quoted, quotes included, that is_json() has
+// already accepted.
+pub fn unescape(quoted: &[u8]) -> String {
+    Transcoder {
+        bytes: quoted,
+        pos: 0,
+    }
+    .string()
+}
+
+// Encodes s as a msgpack string, for responses that are not JSON.
+pub fn from_str(s: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(s.len() + 5);
+    write_str(&mut out, s);
+    out
+}
+
+// Decodes msgpack to compact JSON. Returns None if msgpack is not a single
+// value that JSON can represent: map keys must be strings and floats
+// finite, and there are no binary or extension types.
+pub fn to_json(msgpack: &[u8]) -> Option<String> {
+    let mut d = Decoder {
+        bytes: msgpack,
+        pos: 0,
+    };
+    let mut out = String::with_capacity(msgpack.len());
+    d.value(&mut out, 0)?;
+    if d.pos != msgpack.len() {
+        return None;
+    }
+    Some(out)
+}
+
+// Guards against stack overflow on deeply nested input, as is_json() does.
+const MAX_DEPTH: usize = 512;
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Decoder<'_> {
+    fn take(&mut self, n: usize) -> Option<&[u8]> {
+        let end = self.pos.checked_add(n)?;
+        let bytes = self.bytes.get(self.pos..end)?;
+        self.pos = end;
+        Some(bytes)
+    }
+
+    // Reads an n-byte big-endian unsigned int.
+    fn uint(&mut self, n: usize) -> Option<u64> {
+        let mut buf = [0; 8];
+        buf[8 - n..].copy_from_slice(self.take(n)?);
+        Some(u64::from_be_bytes(buf))
+    }
+
+    fn value(&mut self, out: &mut String, depth: usize) -> Option<()> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        let tag = self.take(1)?[0];
+        match tag {
+            0x00..=0x7f => out.push_str(&tag.to_string()),
+            0xe0..=0xff => out.push_str(&(tag as i8).to_string()),
+            0xc0 => out.push_str("null"),
+            0xc2 => out.push_str("false"),
+            0xc3 => out.push_str("true"),
+            0xcc..=0xcf => {
+                let u = self.uint(1 << (tag - 0xcc))?;
+                out.push_str(&u.to_string());
+            }
+            0xd0..=0xd3 => {
+                // Sign-extends the n-byte int.
+                let n = 1 << (tag - 0xd0);
+                let shift = 64 - 8 * n;
+                let i = ((self.uint(n)? << shift) as i64) >> shift;
+                out.push_str(&i.to_string());
+            }
+            0xca | 0xcb => {
+                let f = if tag == 0xca {
+                    f32::from_bits(self.uint(4)? as u32) as f64
+                } else {
+                    f64::from_bits(self.uint(8)?)
+                };
+                if !f.is_finite() {
+                    return None;
+                }
+                out.push_str(&f.to_string());
+            }
+            0xa0..=0xbf => self.string(out, (tag & 0x1f) as usize)?,
+            0xd9..=0xdb => {
+                let n = self.uint(1 << (tag - 0xd9))? as usize;
+                self.string(out, n)?;
+            }
+            0x90..=0x9f => self.array(out, (tag & 0xf) as usize, depth)?,
+            0xdc | 0xdd => {
+                let n = self.uint(2 << (tag - 0xdc))? as usize;
+                self.array(out, n, depth)?;
+            }
+            0x80..=0x8f => self.map(out, (tag & 0xf) as usize, depth)?,
+            0xde | 0xdf => {
+                let n = self.uint(2 << (tag - 0xde))? as usize;
+                self.map(out, n, depth)?;
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn string(&mut self, out: &mut String, n: usize) -> Option<()> {
+        let s = std::str::from_utf8(self.take(n)?).ok()?;
+        out.push_str(&SerJson::serialize_json(&s.to_string()));
+        Some(())
+    }
+
+    fn array(&mut self, out: &mut String, n: usize, depth: usize) -> Option<()> {
+        out.push('[');
+        for i in 0..n {
+            if i > 0 {
+                out.push(',');
+            }
+            self.value(out, depth + 1)?;
+        }
+        out.push(']');
+        Some(())
+    }
+
+    fn map(&mut self, out: &mut String, n: usize, depth: usize) -> Option<()> {
+        out.push('{');
+        for i in 0..n {
+            if i > 0 {
+                out.push(',');
+            }
+            match self.bytes.get(self.pos)? {
+                0xa0..=0xbf | 0xd9..=0xdb => self.value(out, depth + 1)?,
+                _ => return None,
+            }
+            out.push(':');
+            self.value(out, depth + 1)?;
+        }
+        out.push('}');
+        Some(())
+    }
+}
+
+// Walks input that is_json() has already accepted, so it doesn't check for
+// errors itself.
+struct Transcoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Transcoder<'_> {
+    fn peek(&self) -> u8 {
+        self.bytes[self.pos]
+    }
+
+    fn skip_ws(&mut self) {
+        while self.pos < self.bytes.len() && b" \t\n\r".contains(&self.peek()) {
+            self.pos += 1;
+        }
+    }
+
+    fn value(&mut self, out: &mut Vec<u8>) {
+        self.skip_ws();
+        match self.peek() {
+            b'{' => self.object(out),
+            b'[' => self.array(out),
+            b'"' => {
+                let s = self.string();
+                write_str(out, &s);
+            }
+            b't' => {
+                self.pos += 4;
+                out.push(0xc3);
+            }
+            b'f' => {
+                self.pos += 5;
+                out.push(0xc2);
+            }
+            b'n' => {
+                self.pos += 4;
+                out.push(0xc0);
+            }
+            _ => self.number(out),
+        }
+        self.skip_ws();
+    }
+
+    fn object(&mut self, out: &mut Vec<u8>) {
+        self.pos += 1;
+        let mut body = Vec::new();
+        let mut len = 0;
+        self.skip_ws();
+        while self.peek() != b'}' {
+            self.skip_ws();
+            let key = self.string();
+            write_str(&mut body, &key);
+            self.skip_ws();
+            self.pos += 1; // ':'
+            self.value(&mut body);
+            len += 1;
+            if self.peek() == b',' {
+                self.pos += 1;
+            }
+        }
+        self.pos += 1;
+        write_len(out, len, 0x80, 0xde);
+        out.extend(body);
+    }
+
+    fn array(&mut self, out: &mut Vec<u8>) {
+        self.pos += 1;
+        let mut body = Vec::new();
+        let mut len = 0;
+        self.skip_ws();
+        while self.peek() != b']' {
+            self.value(&mut body);
+            len += 1;
+            if self.peek() == b',' {
+                self.pos += 1;
+            }
+        }
+        self.pos += 1;
+        write_len(out, len, 0x90, 0xdc);
+        out.extend(body);
+    }
+
+    fn string(&mut self) -> String {
+        self.pos += 1;
+        let mut s = String::new();
+        let mut start = self.pos;
+        loop {
+            match self.peek() {
+                b'"' => break,
+                b'\\' => {
+                    s.push_str(self.utf8(start));
+                    self.pos += 1;
+                    let c = match self.peek() {
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => self.unicode_escape(),
+                        b => b as char,
+                    };
+                    s.push(c);
+                    self.pos += 1;
+                    start = self.pos;
+                }
+                _ => self.pos += 1,
+            }
+        }
+        s.push_str(self.utf8(start));
+        self.pos += 1;
+        s
+    }
+
+    fn utf8(&self, start: usize) -> &str {
+        std::str::from_utf8(&self.bytes[start..self.pos]).unwrap()
+    }
+
+    // Decodes the \uXXXX escape whose 'u' is at pos, and a following low
+    // surrogate escape if this is a high surrogate. Leaves pos at the last
+    // hex digit consumed. Unpaired surrogates become U+FFFD.
+    fn unicode_escape(&mut self) -> char {
+        let hi = self.hex4(self.pos + 1);
+        self.pos += 4;
+        if (0xd800..0xdc00).contains(&hi) && self.bytes[self.pos + 1..].starts_with(b"\\u") {
+            let lo = self.hex4(self.pos + 3);
+            if (0xdc00..0xe000).contains(&lo) {
+                self.pos += 6;
+                let c = 0x10000 + ((hi - 0xd800) << 10) + (lo - 0xdc00);
+                return std::char::from_u32(c).unwrap();
+            }
+        }
+        std::char::from_u32(hi).unwrap_or('\u{fffd}')
+    }
+
+    fn hex4(&self, at: usize) -> u32 {
+        u32::from_str_radix(std::str::from_utf8(&self.bytes[at..at + 4]).unwrap(), 16).unwrap()
+    }
+
+    fn number(&mut self, out: &mut Vec<u8>) {
+        let start = self.pos;
+        while self.pos < self.bytes.len() && b"+-.0123456789eE".contains(&self.peek()) {
+            self.pos += 1;
+        }
+        let n = self.utf8(start);
+        if let Ok(i) = n.parse::<i64>() {
+            write_int(out, i);
+        } else if let Ok(u) = n.parse::<u64>() {
+            out.push(0xcf);
+            out.extend(&u.to_be_bytes());
+        } else {
+            out.push(0xcb);
+            out.extend(&n.parse::<f64>().unwrap().to_be_bytes());
+        }
+    }
+}
+
+fn write_int(out: &mut Vec<u8>, i: i64) {
+    match i {
+        0..=0x7f => out.push(i as u8),
+        -32..=-1 => out.push(i as i8 as u8),
+        0x80..=0xff => out.extend(&[0xcc, i as u8]),
+        0x100..=0xffff => {
+            out.push(0xcd);
+            out.extend(&(i as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(0xce);
+            out.extend(&(i as u32).to_be_bytes());
+        }
+        -0x80..=-33 => out.extend(&[0xd0, i as i8 as u8]),
+        -0x8000..=-0x81 => {
+            out.push(0xd1);
+            out.extend(&(i as i16).to_be_bytes());
+        }
+        -0x8000_0000..=-0x8001 => {
+            out.push(0xd2);
+            out.extend(&(i as i32).to_be_bytes());
+        }
+        _ if i > 0 => {
+            out.push(0xcf);
+            out.extend(&(i as u64).to_be_bytes());
+        }
+        _ => {
+            out.push(0xd3);
+            out.extend(&i.to_be_bytes());
+        }
+    }
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    let len = s.len();
+    if len < 32 {
+        out.push(0xa0 | len as u8);
+    } else if len <= 0xff {
+        out.extend(&[0xd9, len as u8]);
+    } else if len <= 0xffff {
+        out.push(0xda);
+        out.extend(&(len as u16).to_be_bytes());
+    } else {
+        out.push(0xdb);
+        out.extend(&(len as u32).to_be_bytes());
+    }
+    out.extend(s.as_bytes());
+}
+
+// Writes an array or map header. fix is the fixarray/fixmap tag and
+// tag16 the 16-bit form's tag, which the 32-bit form's tag follows.
+fn write_len(out: &mut Vec<u8>, len: usize, fix: u8, tag16: u8) {
+    if len < 16 {
+        out.push(fix | len as u8);
+    } else if len <= 0xffff {
+        out.push(tag16);
+        out.extend(&(len as u16).to_be_bytes());
+    } else {
+        out.push(tag16 + 1);
+        out.extend(&(len as u32).to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embed::types::{ScanValue, ScanValuesEntry, ScanValuesResponse};
+    use nanoserde::SerJson;
+
+    #[test]
+    fn encoding() {
+        for (json, expected) in &[
+            ("null", vec![0xc0]),
+            (" true ", vec![0xc3]),
+            ("false", vec![0xc2]),
+            ("0", vec![0x00]),
+            ("127", vec![0x7f]),
+            ("128", vec![0xcc, 0x80]),
+            ("65536", vec![0xce, 0, 1, 0, 0]),
+            ("-1", vec![0xff]),
+            ("-33", vec![0xd0, 0xdf]),
+            ("-129", vec![0xd1, 0xff, 0x7f]),
+            (
+                "18446744073709551615",
+                vec![0xcf, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            ),
+            ("1.5", vec![0xcb, 0x3f, 0xf8, 0, 0, 0, 0, 0, 0]),
+            ("1e0", vec![0xcb, 0x3f, 0xf0, 0, 0, 0, 0, 0, 0]),
+            ("\"a\"", vec![0xa1, b'a']),
+            ("\"\\n\\u00e9\"", vec![0xa3, b'\n', 0xc3, 0xa9]),
+            ("\"\\ud83d\\ude00\"", vec![0xa4, 0xf0, 0x9f, 0x98, 0x80]),
+            ("\"\\ud83d\"", vec![0xa3, 0xef, 0xbf, 0xbd]),
+            ("[]", vec![0x90]),
+            ("[1, [2]]", vec![0x92, 0x01, 0x91, 0x02]),
+            ("{\"a\": {}}", vec![0x81, 0xa1, b'a', 0x80]),
+        ] {
+            assert_eq!(
+                Some(expected),
+                from_json(json.as_bytes()).as_ref(),
+                "{}",
+                json
+            );
+        }
+
+        assert_eq!(None, from_json(b"{\"a\"}"));
+        assert_eq!(None, from_json(b""));
+        assert_eq!(vec![0xa0], from_str(""));
+
+        let long = "x".repeat(300);
+        let mut expected = vec![0xda, 0x01, 0x2c];
+        expected.extend(long.as_bytes());
+        assert_eq!(expected, from_str(&long));
+        let many = format!("[{}]", vec!["0"; 20].join(","));
+        assert_eq!(
+            &[0xdc, 0, 20, 0][..],
+            &from_json(many.as_bytes()).unwrap()[..4]
+        );
+    }
+
+    #[test]
+    fn scan_response_round_trip() {
+        let response = ScanValuesResponse {
+            entries: (0..20)
+                .map(|i| ScanValuesEntry {
+                    key: format!("key/{}", i),
+                    value: match i % 3 {
+                        0 => ScanValue::Json(format!(
+                            "{{\"n\":{},\"neg\":-{},\"s\":\"v\\\"{}\",\"l\":[true,null,0.5]}}",
+                            i,
+                            i * 1000 + 1,
+                            "y".repeat(i * 20)
+                        )),
+                        1 => ScanValue::Json(format!("{}", i * 100_000)),
+                        _ => ScanValue::Raw("AAH/".into()),
+                    },
+                })
+                .collect(),
+        };
+        let json = SerJson::serialize_json(&response);
+        assert_eq!(
+            Some(json.clone()),
+            to_json(&from_json(json.as_bytes()).unwrap())
+        );
+    }
+
+    #[test]
+    fn decoding() {
+        for (msgpack, expected) in &[
+            (vec![0xc0], "null"),
+            (vec![0xcd, 0x01, 0x00], "256"),
+            (vec![0xd1, 0xff, 0x7f], "-129"),
+            (
+                vec![0xd3, 0x80, 0, 0, 0, 0, 0, 0, 0],
+                "-9223372036854775808",
+            ),
+            (vec![0xca, 0x3f, 0xc0, 0, 0], "1.5"),
+            (vec![0xa2, b'"', b'\n'], "\"\\\"\\n\""),
+            (vec![0xdc, 0, 2, 0x01, 0x90], "[1,[]]"),
+            (
+                vec![0x82, 0xa1, b'a', 0x80, 0xa1, b'b', 0xc2],
+                "{\"a\":{},\"b\":false}",
+            ),
+        ] {
+            assert_eq!(
+                Some(expected.to_string()),
+                to_json(msgpack),
+                "{:x?}",
+                msgpack
+            );
+        }
+
+        for invalid in &[
+            // Empty, truncated and trailing input.
+            vec![],
+            vec![0xa2, b'a'],
+            vec![0xdc, 0],
+            vec![0xc0, 0xc0],
+            // A non-string map key.
+            vec![0x81, 0x01, 0x02],
+            // Invalid UTF-8.
+            vec![0xa1, 0xff],
+            // Binary, and a float JSON can't represent.
+            vec![0xc4, 0x00],
+            vec![0xcb, 0x7f, 0xf8, 0, 0, 0, 0, 0, 0],
+        ] {
+            assert_eq!(None, to_json(invalid), "{:x?}", invalid);
+        }
+
+        let nested = |depth| {
+            let mut msgpack = vec![0x91; depth];
+            msgpack.push(0xc0);
+            msgpack
+        };
+        assert!(to_json(&nested(MAX_DEPTH)).is_some());
+        assert_eq!(None, to_json(&nested(MAX_DEPTH + 1)));
+    }
+}
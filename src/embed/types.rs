@@ -1,6 +1,17 @@
-#![allow(clippy::redundant_pattern_matching)] // For derive(DeJson).
+#![allow(clippy::redundant_pattern_matching, clippy::question_mark)] // For derive(DeJson).
 
-use nanoserde::{DeJson, SerJson};
+use nanoserde::{DeJson, SerJson, SerJsonState};
+
+#[derive(DeJson, SerJson)]
+pub struct OpenRequest {
+    #[nserde(rename = "defaultHead")]
+    pub default_head: Option<String>, // "main" if not present
+}
+
+#[derive(DeJson, SerJson)]
+pub struct OpenResponse {
+    pub root: String,
+}
 
 #[derive(DeJson, SerJson)]
 pub struct OpenTransactionRequest {
@@ -67,3 +78,153 @@ pub struct PutRequest {
 
 #[derive(DeJson, SerJson)]
 pub struct PutResponse {}
+
+#[derive(DeJson)]
+pub struct ScanValuesRequest {
+    #[nserde(rename = "transactionId")]
+    pub transaction_id: u32,
+    pub prefix: Option<String>,
+    pub start: Option<ScanBound>,
+    pub limit: Option<u64>,
+    pub projection: Option<Projection>,
+}
+
+// Limits the JSON object values a scan returns to the named fields.
+#[derive(DeJson)]
+pub struct Projection {
+    pub fields: Vec<String>,
+}
+
+#[derive(DeJson)]
+pub struct ScanBound {
+    pub key: Option<ScanKey>,
+    pub index: Option<u64>,
+}
+
+#[derive(DeJson)]
+pub struct ScanKey {
+    pub value: String,
+    pub exclusive: Option<bool>,
+}
+
+#[derive(SerJson)]
+pub struct ScanValuesResponse {
+    pub entries: Vec<ScanValuesEntry>,
+}
+
+// Serialized as {"key": ..., "value": <the JSON value>} or, for values that
+// aren't JSON, {"key": ..., "raw": "<base64>"}.
+pub struct ScanValuesEntry {
+    pub key: String,
+    pub value: ScanValue,
+}
+
+pub enum ScanValue {
+    Json(String),
+    Raw(String),
+}
+
+impl SerJson for ScanValuesEntry {
+    fn ser_json(&self, d: usize, s: &mut SerJsonState) {
+        s.out.push_str("{\"key\":");
+        self.key.ser_json(d, s);
+        match &self.value {
+            ScanValue::Json(json) => {
+                s.out.push_str(",\"value\":");
+                s.out.push_str(json);
+            }
+            ScanValue::Raw(raw) => {
+                s.out.push_str(",\"raw\":");
+                raw.ser_json(d, s);
+            }
+        }
+        s.out.push('}');
+    }
+}
+
+#[derive(DeJson)]
+pub struct ImportBeginRequest {}
+
+#[derive(DeJson, SerJson)]
+pub struct ImportBeginResponse {
+    #[nserde(rename = "sessionId")]
+    pub session_id: u32,
+}
+
+#[derive(DeJson)]
+pub struct ImportChunkRequest {
+    #[nserde(rename = "sessionId")]
+    pub session_id: u32,
+    pub entries: Vec<ImportEntry>,
+}
+
+#[derive(DeJson)]
+pub struct ImportEntry {
+    pub key: String,
+    pub value: String, // base64
+}
+
+#[derive(SerJson)]
+pub struct ImportChunkResponse {}
+
+#[derive(DeJson)]
+pub struct ImportCommitRequest {
+    #[nserde(rename = "sessionId")]
+    pub session_id: u32,
+}
+
+#[derive(DeJson)]
+pub struct ResetRequest {
+    #[nserde(rename = "defaultHead")]
+    pub default_head: Option<String>, // "main" if not present
+}
+
+#[derive(DeJson)]
+pub struct DebugDumpRequest {
+    #[nserde(rename = "includeChunks")]
+    pub include_chunks: Option<bool>,
+}
+
+// A snapshot of a store for attaching to bug reports. The debugLoad rpc
+// turns one back into an in-memory db.
+#[derive(DeJson, SerJson)]
+pub struct DebugDump {
+    // Optional fields first to avoid a trailing comma when they're None.
+    pub chunks: Option<Vec<DebugDumpChunk>>, // only present with includeChunks
+    #[nserde(rename = "schemaVersion")]
+    pub schema_version: Option<u32>,
+    pub heads: Vec<DebugDumpHead>,
+    pub stats: DebugDumpStats,
+}
+
+#[derive(DeJson, SerJson)]
+pub struct DebugDumpHead {
+    pub name: String,
+    pub hash: String,
+}
+
+#[derive(DeJson, SerJson)]
+pub struct DebugDumpStats {
+    pub heads: u64,
+    pub chunks: u64,
+    #[nserde(rename = "chunkBytes")]
+    pub chunk_bytes: u64, // data and meta
+}
+
+#[derive(DeJson, SerJson)]
+pub struct DebugDumpChunk {
+    pub meta: Option<String>, // base64
+    pub hash: String,
+    pub data: String, // base64
+}
+
+#[derive(DeJson, SerJson)]
+pub struct ErrorResponse {
+    pub error: ErrorDetail,
+}
+
+#[derive(DeJson, SerJson)]
+pub struct ErrorDetail {
+    pub kind: String,
+    pub message: String,
+}
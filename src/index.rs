@@ -0,0 +1,262 @@
+//! Secondary indexes over a kv store.
+//!
+//! An Index maps index keys, derived from each value by a caller-supplied
+//! extractor, back to the primary keys of the values they came from.
+//! Index entries live in a reserved part of the key space and are written
+//! through the same kv::Write as the primary value, so they commit or roll
+//! back atomically with it.
+//!
+//! An extractor can return any number of index keys for a value, so an
+//! array-valued field fans out to an entry per element, like an IndexedDB
+//! index with multiEntry set. Duplicate keys from one value make a single
+//! entry. IndexedDB's own indexes can't be used for this: they index
+//! properties of structured values through a key path, and our values are
+//! opaque bytes.
+//!
+//! Entries are stored as "i/<index name>/<hex>" with an empty value, where
+//! hex encodes the index key and primary key as a KeyBuilder key. Hex
+//! preserves byte order, so entries sort by index key then primary key and
+//! scanning by a prefix of the index key only visits matching entries.
+use crate::kv::{check_unreserved, Read, StoreError, Write};
+use crate::prolly::KeyBuilder;
+use data_encoding::hex;
+
+const INDEX_PREFIX: &str = "i/";
+
+type Result<T> = std::result::Result<T, StoreError>;
+
+pub struct Index<F: Fn(&[u8]) -> Vec<Vec<u8>>> {
+    name: String,
+    extractor: F,
+}
+
+#[allow(dead_code)]
+impl<F: Fn(&[u8]) -> Vec<Vec<u8>>> Index<F> {
+    pub fn new(name: &str, extractor: F) -> Index<F> {
+        assert!(!name.contains('/'), "Index names cannot contain '/'");
+        Index {
+            name: name.into(),
+            extractor,
+        }
+    }
+
+    // Puts value under key, replacing the index entries of any previous
+    // value with those of the new one.
+    pub async fn put(&self, write: &dyn Write, key: &str, value: &[u8]) -> Result<()> {
+        check_unreserved(key)?;
+        self.del_entries(write, key).await?;
+        for index_key in (self.extractor)(value) {
+            write.put(&self.entry_key(&index_key, key), &[]).await?;
+        }
+        write.put(key, value).await
+    }
+
+    pub async fn del(&self, write: &dyn Write, key: &str) -> Result<()> {
+        check_unreserved(key)?;
+        self.del_entries(write, key).await?;
+        write.del(key).await
+    }
+
+    // Returns the (index key, primary key) pairs whose index key starts with
+    // prefix, sorted by index key then primary key.
+    pub async fn scan_index(
+        &self,
+        read: &dyn Read,
+        prefix: &[u8],
+    ) -> Result<Vec<(Vec<u8>, String)>> {
+        let scan_prefix = format!(
+            "{}{}",
+            self.entry_prefix(),
+            hex::encode(&KeyBuilder::new().push_prefix(prefix).build())
+        );
+        read.scan(&scan_prefix)
+            .await?
+            .iter()
+            .map(|(k, _)| self.parse_entry_key(k))
+            .collect()
+    }
+
+    async fn del_entries(&self, write: &dyn Write, key: &str) -> Result<()> {
+        if let Some(old) = write.get(key).await? {
+            for index_key in (self.extractor)(&old) {
+                write.del(&self.entry_key(&index_key, key)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn entry_prefix(&self) -> String {
+        format!("{}{}/", INDEX_PREFIX, self.name)
+    }
+
+    fn entry_key(&self, index_key: &[u8], key: &str) -> String {
+        let entry = KeyBuilder::new()
+            .push(index_key)
+            .push(key.as_bytes())
+            .build();
+        format!("{}{}", self.entry_prefix(), hex::encode(&entry))
+    }
+
+    fn parse_entry_key(&self, entry_key: &str) -> Result<(Vec<u8>, String)> {
+        let corrupt = || StoreError::Str(format!("Corrupt index entry {}", entry_key));
+        let rest = &entry_key[self.entry_prefix().len()..];
+        let entry = hex::decode(rest.as_bytes()).map_err(|_| corrupt())?;
+        match KeyBuilder::decode(&entry)
+            .map_err(|_| corrupt())?
+            .as_slice()
+        {
+            [index_key, key] => {
+                let key = String::from_utf8(key.clone()).map_err(|_| corrupt())?;
+                Ok((index_key.clone(), key))
+            }
+            _ => Err(corrupt()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::memstore::MemStore;
+    use crate::kv::Store;
+
+    // Indexes comma-separated tags.
+    fn tags(value: &[u8]) -> Vec<Vec<u8>> {
+        value
+            .split(|b| *b == b',')
+            .filter(|t| !t.is_empty())
+            .map(|t| t.to_vec())
+            .collect()
+    }
+
+    fn entries(pairs: &[(&str, &str)]) -> Vec<(Vec<u8>, String)> {
+        pairs
+            .iter()
+            .map(|(i, k)| (i.as_bytes().to_vec(), k.to_string()))
+            .collect()
+    }
+
+    #[async_std::test]
+    async fn put_scan_del() {
+        let store = MemStore::new();
+        let index = Index::new("tags", tags);
+
+        let wt = store.write().await.unwrap();
+        index.put(wt.as_ref(), "k1", b"red,blue").await.unwrap();
+        index.put(wt.as_ref(), "k2", b"blue").await.unwrap();
+        index.put(wt.as_ref(), "k3", b"").await.unwrap();
+        // Index entries are visible inside the transaction.
+        assert_eq!(
+            entries(&[("blue", "k1"), ("blue", "k2")]),
+            index.scan_index(wt.as_read(), b"blue").await.unwrap()
+        );
+        wt.commit().await.unwrap();
+
+        let rt = store.read().await.unwrap();
+        assert_eq!(
+            entries(&[("blue", "k1"), ("blue", "k2"), ("red", "k1")]),
+            index.scan_index(rt.as_ref(), b"").await.unwrap()
+        );
+        assert_eq!(
+            entries(&[("blue", "k1"), ("blue", "k2")]),
+            index.scan_index(rt.as_ref(), b"bl").await.unwrap()
+        );
+        assert_eq!(
+            entries(&[]),
+            index.scan_index(rt.as_ref(), b"green").await.unwrap()
+        );
+        assert_eq!(Some(b"red,blue".to_vec()), rt.get("k1").await.unwrap());
+        drop(rt);
+
+        // Overwriting replaces the old value's entries, deleting removes them.
+        let wt = store.write().await.unwrap();
+        index.put(wt.as_ref(), "k1", b"green").await.unwrap();
+        index.del(wt.as_ref(), "k2").await.unwrap();
+        wt.commit().await.unwrap();
+
+        let rt = store.read().await.unwrap();
+        assert_eq!(
+            entries(&[("green", "k1")]),
+            index.scan_index(rt.as_ref(), b"").await.unwrap()
+        );
+        assert!(!rt.has("k2").await.unwrap());
+        drop(rt);
+
+        // Index updates roll back with the primary write.
+        let wt = store.write().await.unwrap();
+        index.put(wt.as_ref(), "k4", b"red").await.unwrap();
+        wt.rollback().await.unwrap();
+        let rt = store.read().await.unwrap();
+        assert_eq!(
+            entries(&[("green", "k1")]),
+            index.scan_index(rt.as_ref(), b"").await.unwrap()
+        );
+        assert!(!rt.has("k4").await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn multi_entry() {
+        let store = MemStore::new();
+        let index = Index::new("tags", tags);
+
+        let wt = store.write().await.unwrap();
+        index.put(wt.as_ref(), "k", b"c,a,b,a").await.unwrap();
+        wt.commit().await.unwrap();
+
+        let rt = store.read().await.unwrap();
+        assert_eq!(
+            entries(&[("a", "k"), ("b", "k"), ("c", "k")]),
+            index.scan_index(rt.as_ref(), b"").await.unwrap()
+        );
+        for tag in &["a", "b", "c"] {
+            assert_eq!(
+                entries(&[(tag, "k")]),
+                index.scan_index(rt.as_ref(), tag.as_bytes()).await.unwrap()
+            );
+        }
+        drop(rt);
+
+        // Deleting the value removes all of its entries.
+        let wt = store.write().await.unwrap();
+        index.del(wt.as_ref(), "k").await.unwrap();
+        wt.commit().await.unwrap();
+        let rt = store.read().await.unwrap();
+        assert_eq!(
+            entries(&[]),
+            index.scan_index(rt.as_ref(), b"").await.unwrap()
+        );
+    }
+
+    #[async_std::test]
+    async fn binary_and_separate_indexes() {
+        let store = MemStore::new();
+        let bytes = Index::new("bytes", |v: &[u8]| vec![v.to_vec()]);
+        let other = Index::new("other", |_: &[u8]| vec![b"x".to_vec()]);
+
+        let wt = store.write().await.unwrap();
+        bytes.put(wt.as_ref(), "a", &[0, 0xff]).await.unwrap();
+        bytes.put(wt.as_ref(), "b", &[0]).await.unwrap();
+        bytes.put(wt.as_ref(), "c", &[0x2f]).await.unwrap();
+        other.put(wt.as_ref(), "d", b"").await.unwrap();
+        assert!(bytes.put(wt.as_ref(), "i/foo", b"").await.is_err());
+        wt.commit().await.unwrap();
+
+        let rt = store.read().await.unwrap();
+        assert_eq!(
+            vec![
+                (vec![0], "b".to_string()),
+                (vec![0, 0xff], "a".to_string()),
+                (vec![0x2f], "c".to_string()),
+            ],
+            bytes.scan_index(rt.as_ref(), b"").await.unwrap()
+        );
+        assert_eq!(
+            vec![(vec![0, 0xff], "a".to_string())],
+            bytes.scan_index(rt.as_ref(), &[0, 0xff]).await.unwrap()
+        );
+        assert_eq!(
+            entries(&[("x", "d")]),
+            other.scan_index(rt.as_ref(), b"").await.unwrap()
+        );
+    }
+}
@@ -0,0 +1,136 @@
+//! The archive format of Store::archive() and Store::restore_archive(): a
+//! single blob holding every entry of a store, e.g. for a backup or a
+//! support bundle.
+//!
+//! An archive is the magic bytes "RKVA", the format version as a
+//! little-endian u32, then a record per entry in key order. A record is
+//! the key's length as a little-endian u32, the key's UTF-8 bytes, then
+//! the same for the value. Entries are the store's raw keys and values,
+//! so an archive of a dag store holds its heads and chunks as they are.
+use crate::kv::{Result, StoreError};
+use std::convert::TryFrom;
+
+const MAGIC: &[u8] = b"RKVA";
+const VERSION: u32 = 1;
+
+pub fn encode(entries: &[(String, Vec<u8>)]) -> Vec<u8> {
+    let len = entries
+        .iter()
+        .map(|(k, v)| 8 + k.len() + v.len())
+        .sum::<usize>();
+    let mut out = Vec::with_capacity(MAGIC.len() + 4 + len);
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    for (key, value) in entries {
+        for field in &[key.as_bytes(), value] {
+            out.extend_from_slice(&(field.len() as u32).to_le_bytes());
+            out.extend_from_slice(field);
+        }
+    }
+    out
+}
+
+pub fn decode(archive: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut rest = archive
+        .strip_prefix(MAGIC)
+        .ok_or_else(|| corrupt("bad magic"))?;
+    let version = take_u32(&mut rest)?;
+    if version != VERSION {
+        return Err(corrupt(&format!("unsupported version {}", version)));
+    }
+    let mut entries = vec![];
+    while !rest.is_empty() {
+        let len = take_u32(&mut rest)? as usize;
+        let key = String::from_utf8(take(&mut rest, len)?.to_vec())
+            .map_err(|_| corrupt("key is not UTF-8"))?;
+        let len = take_u32(&mut rest)? as usize;
+        entries.push((key, take(&mut rest, len)?.to_vec()));
+    }
+    Ok(entries)
+}
+
+fn corrupt(why: &str) -> StoreError {
+    StoreError::Str(format!("Invalid archive: {}", why))
+}
+
+// Splits the first n bytes off rest.
+fn take<'a>(rest: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+    if rest.len() < n {
+        return Err(corrupt("truncated"));
+    }
+    let (field, after) = rest.split_at(n);
+    *rest = after;
+    Ok(field)
+}
+
+fn take_u32(rest: &mut &[u8]) -> Result<u32> {
+    let bytes = take(rest, 4)?;
+    Ok(u32::from_le_bytes(<[u8; 4]>::try_from(bytes).unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::memstore::MemStore;
+    use crate::kv::{diff_stores, Store};
+
+    #[async_std::test]
+    async fn round_trip() {
+        let store = MemStore::new();
+        store.set_schema_version(3).await.unwrap();
+        for (key, value) in &[("a", &b"1"[..]), ("b/c", b""), ("世界", &[0xff, 0, 0xc3])] {
+            store.put(key, value).await.unwrap();
+        }
+        let archive = store.archive().await.unwrap();
+        assert!(archive.starts_with(b"RKVA\x01\0\0\0"));
+
+        let restored = MemStore::new();
+        restored.put("stale", b"x").await.unwrap();
+        restored.restore_archive(&archive).await.unwrap();
+        assert!(diff_stores(&store, &restored).await.unwrap().is_empty());
+        assert_eq!(archive, restored.archive().await.unwrap());
+
+        // An empty archive leaves only the store's own schema version, which
+        // clear() keeps.
+        let empty = MemStore::new().archive().await.unwrap();
+        assert_eq!(8, empty.len());
+        restored.restore_archive(&empty).await.unwrap();
+        let rt = restored.read().await.unwrap();
+        assert_eq!(vec!["sys/schemaVersion"], rt.scan_keys("").await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn invalid() {
+        let store = MemStore::new();
+        store.put("k", b"v").await.unwrap();
+        let archive = encode(&[("a".into(), b"1".to_vec())]);
+        let mut bad_version = archive.clone();
+        bad_version[4] = 2;
+        let mut bad_key = archive.clone();
+        bad_key[12] = 0xff;
+        for (bytes, why) in &[
+            (&b"RKVB\x01\0\0\0"[..], "bad magic"),
+            (&b"RKVA\x01\0"[..], "truncated"),
+            (&bad_version, "unsupported version 2"),
+            (&archive[..archive.len() - 1], "truncated"),
+            (&bad_key, "key is not UTF-8"),
+        ] {
+            match store.restore_archive(bytes).await {
+                Err(StoreError::Str(s)) => assert_eq!(format!("Invalid archive: {}", why), s),
+                r => panic!("Expected an error for {}, got {:?}", why, r),
+            }
+        }
+        // A failed restore leaves the store as it was.
+        assert_eq!(Some(b"v".to_vec()), store.get("k").await.unwrap());
+
+        // As does an archive of another schema version.
+        store.set_schema_version(1).await.unwrap();
+        let other = MemStore::new();
+        other.set_schema_version(2).await.unwrap();
+        match store.restore_archive(&other.archive().await.unwrap()).await {
+            Err(StoreError::UnsupportedSchema(2)) => (),
+            r => panic!("Expected UnsupportedSchema, got {:?}", r),
+        }
+        assert_eq!(Some(b"v".to_vec()), store.get("k").await.unwrap());
+    }
+}
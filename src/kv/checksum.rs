@@ -0,0 +1,302 @@
+//! A Store that checksums values, to detect values corrupted in storage or
+//! on their way to or from it.
+//!
+//! Chunks are addressed by the hash of their contents, so a corrupt chunk
+//! is detectable. Mutable keys (heads, indexes, the schema version) have no
+//! such check. ChecksummedStore prefixes every value it writes with the
+//! CRC-32 of the value (4 bytes, little-endian), and verifies and strips
+//! it on every read. A mismatch fails the read with
+//! StoreError::ChecksumMismatch.
+//!
+//! Values must be written through the ChecksummedStore to be read through
+//! it. Wrapping a store with existing values makes them all mismatch.
+#[cfg(debug_assertions)]
+use crate::kv::PendingSummary;
+use crate::kv::{CommitReport, IsolationLevel, Read, Result, Store, StoreError, Write};
+use async_trait::async_trait;
+use futures::future::LocalBoxFuture;
+use std::convert::TryFrom;
+
+pub struct ChecksummedStore {
+    inner: Box<dyn Store>,
+}
+
+impl ChecksummedStore {
+    pub fn new(inner: Box<dyn Store>) -> ChecksummedStore {
+        ChecksummedStore { inner }
+    }
+}
+
+// CRC-32 (IEEE), as used by zlib and PNG.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (!(crc & 1)).wrapping_add(1));
+        }
+    }
+    !crc
+}
+
+fn seal(value: &[u8]) -> Vec<u8> {
+    let mut sealed = Vec::with_capacity(value.len() + 4);
+    sealed.extend_from_slice(&crc32(value).to_le_bytes());
+    sealed.extend_from_slice(value);
+    sealed
+}
+
+fn unseal(key: &str, mut sealed: Vec<u8>) -> Result<Vec<u8>> {
+    let checksum = sealed
+        .get(..4)
+        .and_then(|b| <[u8; 4]>::try_from(b).ok())
+        .map(u32::from_le_bytes);
+    match checksum {
+        Some(checksum) if checksum == crc32(&sealed[4..]) => Ok(sealed.split_off(4)),
+        _ => Err(StoreError::ChecksumMismatch { key: key.into() }),
+    }
+}
+
+async fn get(inner: &dyn Read, key: &str) -> Result<Option<Vec<u8>>> {
+    match inner.get(key).await? {
+        Some(sealed) => Ok(Some(unseal(key, sealed)?)),
+        None => Ok(None),
+    }
+}
+
+async fn scan(inner: &dyn Read, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+    inner
+        .scan(prefix)
+        .await?
+        .into_iter()
+        .map(|(key, sealed)| {
+            let value = unseal(&key, sealed)?;
+            Ok((key, value))
+        })
+        .collect()
+}
+
+#[async_trait(?Send)]
+impl Store for ChecksummedStore {
+    async fn read<'a>(&'a self) -> Result<Box<dyn Read + 'a>> {
+        Ok(Box::new(ReadTransaction {
+            inner: self.inner.read().await?,
+        }))
+    }
+
+    async fn write<'a>(&'a self) -> Result<Box<dyn Write + 'a>> {
+        Ok(Box::new(WriteTransaction {
+            inner: self.inner.write().await?,
+        }))
+    }
+
+    fn isolation(&self) -> IsolationLevel {
+        self.inner.isolation()
+    }
+}
+
+struct ReadTransaction<'a> {
+    inner: Box<dyn Read + 'a>,
+}
+
+#[async_trait(?Send)]
+impl Read for ReadTransaction<'_> {
+    async fn has(&self, key: &str) -> Result<bool> {
+        self.inner.has(key).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        get(self.inner.as_ref(), key).await
+    }
+
+    async fn scan(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        scan(self.inner.as_ref(), prefix).await
+    }
+
+    async fn scan_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        self.inner.scan_keys(prefix).await
+    }
+
+    async fn count(&self) -> Result<usize> {
+        self.inner.count().await
+    }
+
+    async fn count_prefix(&self, prefix: &str) -> Result<usize> {
+        self.inner.count_prefix(prefix).await
+    }
+
+    async fn first_key(&self) -> Result<Option<String>> {
+        self.inner.first_key().await
+    }
+
+    async fn last_key(&self) -> Result<Option<String>> {
+        self.inner.last_key().await
+    }
+
+    fn abort(&self) {
+        self.inner.abort();
+    }
+}
+
+struct WriteTransaction<'a> {
+    inner: Box<dyn Write + 'a>,
+}
+
+#[async_trait(?Send)]
+impl Read for WriteTransaction<'_> {
+    async fn has(&self, key: &str) -> Result<bool> {
+        self.inner.has(key).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        get(self.inner.as_read(), key).await
+    }
+
+    async fn scan(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        scan(self.inner.as_read(), prefix).await
+    }
+
+    async fn scan_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        self.inner.scan_keys(prefix).await
+    }
+
+    async fn count(&self) -> Result<usize> {
+        self.inner.count().await
+    }
+
+    async fn count_prefix(&self, prefix: &str) -> Result<usize> {
+        self.inner.count_prefix(prefix).await
+    }
+
+    async fn first_key(&self) -> Result<Option<String>> {
+        self.inner.first_key().await
+    }
+
+    async fn last_key(&self) -> Result<Option<String>> {
+        self.inner.last_key().await
+    }
+}
+
+#[async_trait(?Send)]
+impl Write for WriteTransaction<'_> {
+    fn as_read(&self) -> &dyn Read {
+        self
+    }
+
+    async fn put(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.inner.put(key, &seal(value)).await
+    }
+
+    async fn del(&self, key: &str) -> Result<()> {
+        self.inner.del(key).await
+    }
+
+    async fn reserve(&self, additional: usize) {
+        self.inner.reserve(additional).await
+    }
+
+    #[cfg(debug_assertions)]
+    async fn pending_summary(&self) -> PendingSummary {
+        let mut summary = self.inner.pending_summary().await;
+        summary.bytes -= 4 * summary.puts.len();
+        summary
+    }
+
+    async fn commit(self: Box<Self>) -> Result<CommitReport> {
+        let mut report = self.inner.commit().await?;
+        // Count the values as they were put, without their checksums.
+        report.bytes_written -= 4 * report.puts;
+        Ok(report)
+    }
+
+    async fn commit_and_read<'b>(self: Box<Self>) -> Result<Box<dyn Read + 'b>>
+    where
+        Self: 'b,
+    {
+        Ok(Box::new(ReadTransaction {
+            inner: self.inner.commit_and_read().await?,
+        }))
+    }
+
+    async fn rollback(self: Box<Self>) -> Result<()> {
+        self.inner.rollback().await
+    }
+
+    async fn keep_alive(&self, work: LocalBoxFuture<'_, ()>) -> Result<()> {
+        self.inner.keep_alive(work).await
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::memstore::MemStore;
+    use crate::kv::trait_tests;
+    use std::rc::Rc;
+
+    async fn new_store() -> Box<dyn Store> {
+        Box::new(ChecksummedStore::new(Box::new(MemStore::new())))
+    }
+
+    #[async_std::test]
+    async fn test_checksummed_store() {
+        trait_tests::run_all(&new_store).await;
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(0, crc32(b""));
+        assert_eq!(0xcbf4_3926, crc32(b"123456789"));
+    }
+
+    // Lets the test get at the store under the ChecksummedStore.
+    struct Shared(Rc<MemStore>);
+
+    #[async_trait(?Send)]
+    impl Store for Shared {
+        async fn read<'a>(&'a self) -> Result<Box<dyn Read + 'a>> {
+            self.0.read().await
+        }
+
+        async fn write<'a>(&'a self) -> Result<Box<dyn Write + 'a>> {
+            self.0.write().await
+        }
+
+        fn isolation(&self) -> IsolationLevel {
+            self.0.isolation()
+        }
+    }
+
+    #[async_std::test]
+    async fn corruption() {
+        let inner = Rc::new(MemStore::new());
+        let store = ChecksummedStore::new(Box::new(Shared(inner.clone())));
+        store.put("a", b"value").await.unwrap();
+        store.put("b", b"").await.unwrap();
+        assert_eq!(Some(b"value".to_vec()), store.get("a").await.unwrap());
+        assert_eq!(Some(vec![]), store.get("b").await.unwrap());
+        assert_eq!(seal(b"value"), inner.get("a").await.unwrap().unwrap());
+
+        // Flip a bit of the value.
+        let mut sealed = inner.get("a").await.unwrap().unwrap();
+        sealed[5] ^= 1;
+        inner.put("a", &sealed).await.unwrap();
+        let mismatch = |r: Result<_>| match r {
+            Err(StoreError::ChecksumMismatch { key }) => assert_eq!("a", key),
+            _ => panic!("Expected ChecksumMismatch"),
+        };
+        mismatch(store.get("a").await.map(|_| ()));
+        mismatch(store.read().await.unwrap().scan("").await.map(|_| ()));
+        mismatch(store.write().await.unwrap().get("a").await.map(|_| ()));
+        assert!(store.has("a").await.unwrap());
+
+        // Values too short to have a checksum mismatch too.
+        inner.put("a", b"abc").await.unwrap();
+        mismatch(store.get("a").await.map(|_| ()));
+
+        // Rewriting the value through the store fixes it.
+        store.put("a", b"fixed").await.unwrap();
+        assert_eq!(Some(b"fixed".to_vec()), store.get("a").await.unwrap());
+    }
+}
@@ -0,0 +1,67 @@
+//! A source of the current time, so that code that depends on it (e.g.
+//! expiry in kv::ttl) takes a &dyn Clock rather than reading the time
+//! itself, and tests can control time with a MockClock instead of sleeping.
+use std::cell::Cell;
+
+pub trait Clock {
+    // Milliseconds since the Unix epoch.
+    fn now_ms(&self) -> u64;
+}
+
+// The real time: Date.now() in wasm, where std's clock is unavailable, and
+// the system clock elsewhere.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[cfg(target_arch = "wasm32")]
+    fn now_ms(&self) -> u64 {
+        js_sys::Date::now() as u64
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn now_ms(&self) -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0)
+    }
+}
+
+// A clock that only moves when told to.
+pub struct MockClock {
+    now: Cell<u64>,
+}
+
+impl MockClock {
+    pub fn new(now_ms: u64) -> MockClock {
+        MockClock {
+            now: Cell::new(now_ms),
+        }
+    }
+
+    pub fn advance(&self, ms: u64) {
+        self.now.set(self.now.get() + ms);
+    }
+}
+
+impl Clock for MockClock {
+    fn now_ms(&self) -> u64 {
+        self.now.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clocks() {
+        let clock = MockClock::new(5);
+        assert_eq!(5, clock.now_ms());
+        clock.advance(10);
+        assert_eq!(15, clock.now_ms());
+
+        // Some time after this was written.
+        assert!(SystemClock.now_ms() > 1_600_000_000_000);
+    }
+}
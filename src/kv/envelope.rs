@@ -0,0 +1,169 @@
+//! The value envelope: one header layout for the features that need to
+//! store something alongside a value, so that they share a header rather
+//! than each wrapping the value in one of its own.
+//!
+//! An envelope is a flags byte, then the optional fields its flags say are
+//! present, in the order of their flags, then the value:
+//!
+//! | flag | field     | bytes | encoding                           |
+//! |------|-----------|-------|------------------------------------|
+//! | 0x01 | codec     | 1     | compression codec id               |
+//! | 0x02 | checksum  | 4     | little-endian u32                  |
+//! | 0x04 | expiry_ms | 8     | little-endian u64, ms since epoch  |
+//! | 0x08 | version   | 8     | little-endian u64                  |
+//!
+//! A value with no fields costs one byte. The other flag bits are
+//! reserved, and decode() refuses envelopes that set them, so a reader
+//! never misreads a field it doesn't know as part of the value. What the
+//! fields mean (e.g. what the checksum covers) is up to the feature that
+//! sets them; this module only lays them out.
+//!
+//! ChecksummedStore, kv::ttl and kv::versioned predate the envelope and
+//! keep their own formats, as changing them would change what existing
+//! stores contain.
+use crate::kv::StoreError;
+use std::convert::TryFrom;
+
+type Result<T> = std::result::Result<T, StoreError>;
+
+const CODEC: u8 = 0x01;
+const CHECKSUM: u8 = 0x02;
+const EXPIRY: u8 = 0x04;
+const VERSION: u8 = 0x08;
+const KNOWN_FLAGS: u8 = CODEC | CHECKSUM | EXPIRY | VERSION;
+
+// The optional fields of an envelope. The default has none.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Header {
+    pub codec: Option<u8>,
+    pub checksum: Option<u32>,
+    pub expiry_ms: Option<u64>,
+    pub version: Option<u64>,
+}
+
+impl Header {
+    fn flags(&self) -> u8 {
+        let flag = |present: bool, flag: u8| if present { flag } else { 0 };
+        flag(self.codec.is_some(), CODEC)
+            | flag(self.checksum.is_some(), CHECKSUM)
+            | flag(self.expiry_ms.is_some(), EXPIRY)
+            | flag(self.version.is_some(), VERSION)
+    }
+}
+
+// Wraps value in an envelope with header's fields.
+pub fn encode(header: &Header, value: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(1 + 21 + value.len());
+    out.push(header.flags());
+    if let Some(codec) = header.codec {
+        out.push(codec);
+    }
+    if let Some(checksum) = header.checksum {
+        out.extend_from_slice(&checksum.to_le_bytes());
+    }
+    if let Some(expiry_ms) = header.expiry_ms {
+        out.extend_from_slice(&expiry_ms.to_le_bytes());
+    }
+    if let Some(version) = header.version {
+        out.extend_from_slice(&version.to_le_bytes());
+    }
+    out.extend_from_slice(value);
+    out
+}
+
+// Splits an envelope into its header and the value it wraps. Fails if the
+// envelope is shorter than its flags say or sets a reserved flag.
+pub fn decode(envelope: &[u8]) -> Result<(Header, &[u8])> {
+    let corrupt = |why: &str| StoreError::Str(format!("Corrupt value envelope: {}", why));
+    let (&flags, mut rest) = envelope.split_first().ok_or_else(|| corrupt("empty"))?;
+    if flags & !KNOWN_FLAGS != 0 {
+        return Err(corrupt(&format!("unknown flags {:#04x}", flags)));
+    }
+    let mut take = |n: usize| -> Result<&[u8]> {
+        if rest.len() < n {
+            return Err(corrupt("truncated"));
+        }
+        let (field, after) = rest.split_at(n);
+        rest = after;
+        Ok(field)
+    };
+    let mut header = Header::default();
+    if flags & CODEC != 0 {
+        header.codec = Some(take(1)?[0]);
+    }
+    if flags & CHECKSUM != 0 {
+        header.checksum = Some(u32::from_le_bytes(<[u8; 4]>::try_from(take(4)?).unwrap()));
+    }
+    if flags & EXPIRY != 0 {
+        header.expiry_ms = Some(u64::from_le_bytes(<[u8; 8]>::try_from(take(8)?).unwrap()));
+    }
+    if flags & VERSION != 0 {
+        header.version = Some(u64::from_le_bytes(<[u8; 8]>::try_from(take(8)?).unwrap()));
+    }
+    Ok((header, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        // Every combination of fields.
+        for flags in 0..=KNOWN_FLAGS {
+            let header = Header {
+                codec: Some(3).filter(|_| flags & CODEC != 0),
+                checksum: Some(0xdead_beef).filter(|_| flags & CHECKSUM != 0),
+                expiry_ms: Some(1_600_000_000_000).filter(|_| flags & EXPIRY != 0),
+                version: Some(u64::MAX).filter(|_| flags & VERSION != 0),
+            };
+            for value in &[&b""[..], b"value", &[0xff; 100]] {
+                let envelope = encode(&header, value);
+                assert_eq!(flags, envelope[0]);
+                assert_eq!((header, *value), decode(&envelope).unwrap(), "{:?}", header);
+            }
+        }
+
+        // The layout is fixed.
+        let header = Header {
+            codec: Some(1),
+            version: Some(2),
+            ..Header::default()
+        };
+        assert_eq!(
+            vec![0x09, 1, 2, 0, 0, 0, 0, 0, 0, 0, b'v'],
+            encode(&header, b"v")
+        );
+    }
+
+    #[test]
+    fn plain_value_overhead() {
+        let envelope = encode(&Header::default(), b"value");
+        assert_eq!(b"\0value".to_vec(), envelope);
+        assert_eq!(
+            (Header::default(), &b"value"[..]),
+            decode(&envelope).unwrap()
+        );
+    }
+
+    #[test]
+    fn corrupt() {
+        for envelope in &[
+            &b""[..],
+            // Reserved flags.
+            &[0x10, b'v'],
+            &[0x80],
+            // Fields cut short.
+            &[CODEC],
+            &[CHECKSUM, 1, 2, 3],
+            &[EXPIRY | VERSION, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+        ] {
+            match decode(envelope) {
+                Err(StoreError::Str(s)) => {
+                    assert!(s.starts_with("Corrupt value envelope"), "{}", s)
+                }
+                r => panic!("Expected an error for {:?}, got {:?}", envelope, r),
+            }
+        }
+    }
+}
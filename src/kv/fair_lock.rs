@@ -0,0 +1,287 @@
+//! A FIFO-fair async RwLock, used by the stores in place of async_std's
+//! RwLock when the fair_locking feature is enabled.
+//!
+//! Every read() or write() takes a ticket when first polled, and tickets
+//! are admitted strictly in order: a run of consecutive readers shares the
+//! lock, but a reader that arrives after a waiting writer queues behind it.
+//! So a steady stream of readers can't starve a writer, as it can with
+//! async_std's RwLock.
+//!
+//! The cost is throughput under mixed load: readers that could have shared
+//! the lock with readers already holding it wait for the writer ahead of
+//! them instead, and every release wakes at most the next ticket.
+use std::cell::UnsafeCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll, Waker};
+
+pub struct RwLock<T> {
+    state: Mutex<State>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for RwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+#[derive(Default)]
+struct State {
+    next_ticket: u64,
+    // The oldest ticket that has not been admitted yet.
+    serving: u64,
+    readers: usize,
+    writer: bool,
+    waiters: BTreeMap<u64, Waker>,
+    // Tickets whose acquire was dropped before being admitted.
+    abandoned: BTreeSet<u64>,
+}
+
+impl State {
+    fn try_admit(&mut self, ticket: u64, write: bool) -> bool {
+        if ticket != self.serving || self.writer || (write && self.readers > 0) {
+            return false;
+        }
+        if write {
+            self.writer = true;
+        } else {
+            self.readers += 1;
+        }
+        self.advance();
+        true
+    }
+
+    fn advance(&mut self) {
+        self.serving += 1;
+        while self.abandoned.remove(&self.serving) {
+            self.serving += 1;
+        }
+        self.wake_serving();
+    }
+
+    fn wake_serving(&mut self) {
+        if let Some(waker) = self.waiters.remove(&self.serving) {
+            waker.wake();
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl<T> RwLock<T> {
+    pub fn new(value: T) -> RwLock<T> {
+        RwLock {
+            state: Mutex::new(State::default()),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub async fn read(&self) -> RwLockReadGuard<'_, T> {
+        Acquire::new(self, false).await;
+        RwLockReadGuard { lock: self }
+    }
+
+    pub async fn write(&self) -> RwLockWriteGuard<'_, T> {
+        Acquire::new(self, true).await;
+        RwLockWriteGuard { lock: self }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+struct Acquire<'a, T> {
+    lock: &'a RwLock<T>,
+    write: bool,
+    ticket: Option<u64>,
+    admitted: bool,
+}
+
+impl<'a, T> Acquire<'a, T> {
+    fn new(lock: &'a RwLock<T>, write: bool) -> Acquire<'a, T> {
+        Acquire {
+            lock,
+            write,
+            ticket: None,
+            admitted: false,
+        }
+    }
+}
+
+impl<T> Future for Acquire<'_, T> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.lock.state();
+        let ticket = match self.ticket {
+            Some(t) => t,
+            None => {
+                let t = state.next_ticket;
+                state.next_ticket += 1;
+                t
+            }
+        };
+        let admitted = state.try_admit(ticket, self.write);
+        if !admitted {
+            state.waiters.insert(ticket, cx.waker().clone());
+        }
+        drop(state);
+        self.ticket = Some(ticket);
+        self.admitted = admitted;
+        match admitted {
+            true => Poll::Ready(()),
+            false => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for Acquire<'_, T> {
+    fn drop(&mut self) {
+        // Give up our place in the queue so we don't block those behind us.
+        if let (Some(ticket), false) = (self.ticket, self.admitted) {
+            let mut state = self.lock.state();
+            state.waiters.remove(&ticket);
+            if ticket == state.serving {
+                state.advance();
+            } else {
+                state.abandoned.insert(ticket);
+            }
+        }
+    }
+}
+
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.lock.state();
+        state.readers -= 1;
+        if state.readers == 0 {
+            state.wake_serving();
+        }
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.lock.state();
+        state.writer = false;
+        state.wake_serving();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task::yield_now;
+    use futures::future::FutureExt;
+    use std::cell::Cell;
+
+    #[async_std::test]
+    async fn shared_and_exclusive() {
+        let lock = RwLock::new(1);
+        let r1 = lock.read().await;
+        let r2 = lock.read().await;
+        assert_eq!(2, *r1 + *r2);
+
+        // A writer waits for readers, and later readers wait for it.
+        let mut w = Box::pin(lock.write());
+        assert!((&mut w).now_or_never().is_none());
+        let mut r3 = Box::pin(lock.read());
+        assert!((&mut r3).now_or_never().is_none());
+        drop(r1);
+        assert!((&mut w).now_or_never().is_none());
+        drop(r2);
+        let mut w = w.now_or_never().unwrap();
+        *w = 2;
+        assert!((&mut r3).now_or_never().is_none());
+        drop(w);
+        assert_eq!(2, *r3.now_or_never().unwrap());
+    }
+
+    #[async_std::test]
+    async fn abandoned_acquire() {
+        let lock = RwLock::new(());
+        let r = lock.read().await;
+        let mut w1 = Box::pin(lock.write());
+        assert!((&mut w1).now_or_never().is_none());
+        let mut w2 = Box::pin(lock.write());
+        assert!((&mut w2).now_or_never().is_none());
+
+        // Dropping a queued acquire doesn't block the ones behind it.
+        drop(w1);
+        drop(r);
+        assert!(w2.now_or_never().is_some());
+
+        let r = lock.read().await;
+        let mut w1 = Box::pin(lock.write());
+        assert!((&mut w1).now_or_never().is_none());
+        let mut w2 = Box::pin(lock.write());
+        assert!((&mut w2).now_or_never().is_none());
+        drop(w2);
+        drop(r);
+        assert!(w1.now_or_never().is_some());
+        assert!(lock.write().now_or_never().is_some());
+    }
+
+    #[async_std::test]
+    async fn writer_not_starved() {
+        let lock = RwLock::new(0);
+        let turns = Cell::new(0);
+        let done = Cell::new(false);
+
+        // Each reader repeatedly takes the lock and holds it across a yield,
+        // so that at any time at least one of them holds it.
+        let reader = || async {
+            while !done.get() {
+                let guard = lock.read().await;
+                turns.set(turns.get() + 1);
+                yield_now().await;
+                drop(guard);
+            }
+        };
+        let writer = async {
+            while turns.get() < 10 {
+                yield_now().await;
+            }
+            let queued_at = turns.get();
+            *lock.write().await += 1;
+            done.set(true);
+            queued_at
+        };
+        let (_, _, _, queued_at) = futures::join!(reader(), reader(), reader(), writer);
+        // Only the readers already holding or queued for the lock get to
+        // take it before the writer.
+        assert!(turns.get() - queued_at <= 6, "{}", turns.get() - queued_at);
+        assert_eq!(1, *lock.read().await);
+    }
+}
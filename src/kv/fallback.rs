@@ -0,0 +1,527 @@
+//! A Store that transparently falls back from a primary store to a
+//! secondary one, e.g. from IndexedDB to a MemStore when IndexedDB runs
+//! out of quota.
+//!
+//! While healthy, writes go to the primary and reads try the primary then
+//! the secondary. When the primary fails with an error the caller has
+//! classified as a reason to fall back, the store flips to unhealthy for
+//! the rest of its life: the failed transaction is replayed against the
+//! secondary, subsequent writes go to the secondary, and reads try the
+//! secondary then the primary.
+//!
+//! Consistency caveats:
+//! - The two stores are not updated atomically with respect to each other.
+//!   Once fallen back, the primary keeps whatever it had at the time, so a
+//!   del() of a key that only exists in the primary is not visible: the
+//!   stale value will be read back from the primary.
+//! - Data written to a non-durable secondary (MemStore) is lost when the
+//!   process exits, and a new FallbackStore will start out reading from the
+//!   primary again.
+//! - Reads from inside a write transaction that miss in the store being
+//!   written fall through to a read transaction on the other store, which
+//!   may interleave awaits with the write transaction.
+//!
+//! Every transaction locks the primary before the secondary, whichever of
+//! them it writes to, so transactions started on either side of a fall
+//! back can't deadlock each holding one store and waiting on the other.
+#[cfg(debug_assertions)]
+use crate::kv::PendingSummary;
+use crate::kv::{CommitReport, IsolationLevel, Read, Result, Store, StoreError, Write};
+use async_std::sync::Mutex;
+use async_trait::async_trait;
+use futures::future::LocalBoxFuture;
+use log::warn;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub struct FallbackStore {
+    primary: Box<dyn Store>,
+    secondary: Box<dyn Store>,
+    should_fall_back: Box<dyn Fn(&StoreError) -> bool>,
+    healthy: AtomicBool,
+}
+
+impl FallbackStore {
+    /// Creates a store that uses primary until it fails with an error for
+    /// which should_fall_back returns true, and secondary thereafter.
+    pub fn new(
+        primary: Box<dyn Store>,
+        secondary: Box<dyn Store>,
+        should_fall_back: Box<dyn Fn(&StoreError) -> bool>,
+    ) -> FallbackStore {
+        FallbackStore {
+            primary,
+            secondary,
+            should_fall_back,
+            healthy: AtomicBool::new(true),
+        }
+    }
+
+    /// Returns false once the store has fallen back to the secondary.
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::SeqCst)
+    }
+
+    // Flips to the secondary if err is one we fall back on, otherwise
+    // returns it.
+    fn fall_back(&self, err: StoreError) -> Result<()> {
+        if !(self.should_fall_back)(&err) {
+            return Err(err);
+        }
+        if self.healthy.swap(false, Ordering::SeqCst) {
+            warn!("Primary store failed, falling back to secondary: {}", err);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl Store for FallbackStore {
+    async fn read<'a>(&'a self) -> Result<Box<dyn Read + 'a>> {
+        if self.is_healthy() {
+            match self.primary.read().await {
+                Ok(first) => {
+                    let second = self.secondary.read().await?;
+                    return Ok(Box::new(ReadTransaction { first, second }));
+                }
+                Err(e) => self.fall_back(e)?,
+            }
+        }
+        let second = self.primary.read().await?;
+        let first = self.secondary.read().await?;
+        Ok(Box::new(ReadTransaction { first, second }))
+    }
+
+    async fn write<'a>(&'a self) -> Result<Box<dyn Write + 'a>> {
+        if self.is_healthy() {
+            match self.primary.write().await {
+                Ok(inner) => {
+                    let other = self.secondary.read().await?;
+                    return Ok(Box::new(WriteTransaction::new(self, true, inner, other)));
+                }
+                Err(e) => self.fall_back(e)?,
+            }
+        }
+        let other = self.primary.read().await?;
+        let inner = self.secondary.write().await?;
+        Ok(Box::new(WriteTransaction::new(self, false, inner, other)))
+    }
+
+    // Transactions hold transactions on both stores, so they are only
+    // isolated as well as the weaker store isolates them.
+    fn isolation(&self) -> IsolationLevel {
+        self.primary.isolation().min(self.secondary.isolation())
+    }
+}
+
+struct ReadTransaction<'a> {
+    first: Box<dyn Read + 'a>,
+    second: Box<dyn Read + 'a>,
+}
+
+#[async_trait(?Send)]
+impl Read for ReadTransaction<'_> {
+    async fn has(&self, key: &str) -> Result<bool> {
+        Ok(self.first.has(key).await? || self.second.has(key).await?)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match self.first.get(key).await? {
+            Some(v) => Ok(Some(v)),
+            None => self.second.get(key).await,
+        }
+    }
+
+    async fn scan(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut merged: BTreeMap<String, Vec<u8>> =
+            self.second.scan(prefix).await?.into_iter().collect();
+        merged.extend(self.first.scan(prefix).await?);
+        Ok(merged.into_iter().collect())
+    }
+
+    async fn scan_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut merged: BTreeSet<String> =
+            self.second.scan_keys(prefix).await?.into_iter().collect();
+        merged.extend(self.first.scan_keys(prefix).await?);
+        Ok(merged.into_iter().collect())
+    }
+
+    fn abort(&self) {
+        self.first.abort();
+        self.second.abort();
+    }
+}
+
+struct WriteTransaction<'a> {
+    store: &'a FallbackStore,
+    on_primary: bool,
+    inner: Box<dyn Write + 'a>,
+    other: Box<dyn Read + 'a>,
+    // A copy of the writes made through this transaction, so that they can
+    // be replayed against the secondary if committing to the primary fails.
+    pending: Mutex<HashMap<String, Option<Vec<u8>>>>,
+}
+
+impl<'a> WriteTransaction<'a> {
+    fn new(
+        store: &'a FallbackStore,
+        on_primary: bool,
+        inner: Box<dyn Write + 'a>,
+        other: Box<dyn Read + 'a>,
+    ) -> WriteTransaction<'a> {
+        WriteTransaction {
+            store,
+            on_primary,
+            inner,
+            other,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Read for WriteTransaction<'_> {
+    async fn has(&self, key: &str) -> Result<bool> {
+        if self.pending.lock().await.contains_key(key) {
+            return self.inner.has(key).await;
+        }
+        Ok(self.inner.has(key).await? || self.other.has(key).await?)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        if self.pending.lock().await.contains_key(key) {
+            return self.inner.get(key).await;
+        }
+        match self.inner.get(key).await? {
+            Some(v) => Ok(Some(v)),
+            None => self.other.get(key).await,
+        }
+    }
+
+    async fn scan(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        let pending = self.pending.lock().await;
+        let mut merged: BTreeMap<String, Vec<u8>> = self
+            .other
+            .scan(prefix)
+            .await?
+            .into_iter()
+            .filter(|(k, _)| !pending.contains_key(k))
+            .collect();
+        merged.extend(self.inner.scan(prefix).await?);
+        Ok(merged.into_iter().collect())
+    }
+
+    async fn scan_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let pending = self.pending.lock().await;
+        let mut merged: BTreeSet<String> = self
+            .other
+            .scan_keys(prefix)
+            .await?
+            .into_iter()
+            .filter(|k| !pending.contains_key(k))
+            .collect();
+        merged.extend(self.inner.scan_keys(prefix).await?);
+        Ok(merged.into_iter().collect())
+    }
+}
+
+// Starts a write transaction on the secondary with the writes a failed
+// commit to the primary would have made.
+async fn replay(
+    store: &FallbackStore,
+    pending: HashMap<String, Option<Vec<u8>>>,
+) -> Result<Box<dyn Write + '_>> {
+    let wt = store.secondary.write().await?;
+    for (key, value) in pending.iter() {
+        match value {
+            Some(v) => wt.put(key, v).await?,
+            None => wt.del(key).await?,
+        }
+    }
+    Ok(wt)
+}
+
+#[async_trait(?Send)]
+impl Write for WriteTransaction<'_> {
+    fn as_read(&self) -> &dyn Read {
+        self
+    }
+
+    async fn put(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.inner.put(key, value).await?;
+        self.pending
+            .lock()
+            .await
+            .insert(key.into(), Some(value.to_vec()));
+        Ok(())
+    }
+
+    async fn del(&self, key: &str) -> Result<()> {
+        self.inner.del(key).await?;
+        self.pending.lock().await.insert(key.into(), None);
+        Ok(())
+    }
+
+    async fn reserve(&self, additional: usize) {
+        self.inner.reserve(additional).await;
+        self.pending.lock().await.reserve(additional);
+    }
+
+    #[cfg(debug_assertions)]
+    async fn pending_summary(&self) -> PendingSummary {
+        self.inner.pending_summary().await
+    }
+
+    async fn commit(self: Box<Self>) -> Result<CommitReport> {
+        let WriteTransaction {
+            store,
+            on_primary,
+            inner,
+            other,
+            pending,
+        } = *self;
+        let err = match inner.commit().await {
+            Ok(report) => return Ok(report),
+            Err(e) => e,
+        };
+        if !on_primary {
+            return Err(err);
+        }
+        store.fall_back(err)?;
+
+        // Release our read on the secondary before we try to write to it.
+        drop(other);
+        replay(store, pending.into_inner()).await?.commit().await
+    }
+
+    // Reads as read() would after the commit: from the store committed to,
+    // then the other.
+    async fn commit_and_read<'b>(self: Box<Self>) -> Result<Box<dyn Read + 'b>>
+    where
+        Self: 'b,
+    {
+        let WriteTransaction {
+            store,
+            on_primary,
+            inner,
+            other,
+            pending,
+        } = *self;
+        let err = match inner.commit_and_read().await {
+            Ok(first) => {
+                return Ok(Box::new(ReadTransaction {
+                    first,
+                    second: other,
+                }))
+            }
+            Err(e) => e,
+        };
+        if !on_primary {
+            return Err(err);
+        }
+        store.fall_back(err)?;
+
+        drop(other);
+        let second = store.primary.read().await?;
+        let wt = replay(store, pending.into_inner()).await?;
+        let first = wt.commit_and_read().await?;
+        Ok(Box::new(ReadTransaction { first, second }))
+    }
+
+    async fn rollback(self: Box<Self>) -> Result<()> {
+        self.inner.rollback().await
+    }
+
+    async fn keep_alive(&self, work: LocalBoxFuture<'_, ()>) -> Result<()> {
+        self.inner.keep_alive(work).await
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::memstore::MemStore;
+    use crate::kv::trait_tests;
+    use futures::future::FutureExt;
+    use std::rc::Rc;
+
+    const QUOTA: &str = "QuotaExceededError";
+
+    fn is_quota(e: &StoreError) -> bool {
+        matches!(e, StoreError::Str(s) if s == QUOTA)
+    }
+
+    async fn new_store() -> Box<dyn Store> {
+        Box::new(FallbackStore::new(
+            Box::new(MemStore::new()),
+            Box::new(MemStore::new()),
+            Box::new(is_quota),
+        ))
+    }
+
+    #[async_std::test]
+    async fn test_fallback_store() {
+        trait_tests::run_all(&new_store).await;
+    }
+
+    // A store whose commits fail with a configurable error once failing is
+    // set.
+    struct FailingStore {
+        store: MemStore,
+        failure: Rc<Mutex<Option<String>>>,
+    }
+
+    #[async_trait(?Send)]
+    impl Store for FailingStore {
+        async fn read<'a>(&'a self) -> Result<Box<dyn Read + 'a>> {
+            self.store.read().await
+        }
+
+        async fn write<'a>(&'a self) -> Result<Box<dyn Write + 'a>> {
+            Ok(Box::new(FailingWrite {
+                inner: self.store.write().await?,
+                failure: self.failure.lock().await.clone(),
+            }))
+        }
+
+        fn isolation(&self) -> IsolationLevel {
+            self.store.isolation()
+        }
+    }
+
+    struct FailingWrite<'a> {
+        inner: Box<dyn Write + 'a>,
+        failure: Option<String>,
+    }
+
+    #[async_trait(?Send)]
+    impl Read for FailingWrite<'_> {
+        async fn has(&self, key: &str) -> Result<bool> {
+            self.inner.has(key).await
+        }
+
+        async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+            self.inner.get(key).await
+        }
+
+        async fn scan(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+            self.inner.scan(prefix).await
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl Write for FailingWrite<'_> {
+        fn as_read(&self) -> &dyn Read {
+            self
+        }
+
+        async fn put(&self, key: &str, value: &[u8]) -> Result<()> {
+            self.inner.put(key, value).await
+        }
+
+        async fn del(&self, key: &str) -> Result<()> {
+            self.inner.del(key).await
+        }
+
+        async fn commit(self: Box<Self>) -> Result<CommitReport> {
+            match self.failure {
+                Some(e) => Err(StoreError::Str(e)),
+                None => self.inner.commit().await,
+            }
+        }
+
+        async fn commit_and_read<'b>(self: Box<Self>) -> Result<Box<dyn Read + 'b>>
+        where
+            Self: 'b,
+        {
+            match self.failure {
+                Some(e) => Err(StoreError::Str(e)),
+                None => self.inner.commit_and_read().await,
+            }
+        }
+
+        async fn rollback(self: Box<Self>) -> Result<()> {
+            self.inner.rollback().await
+        }
+    }
+
+    #[async_std::test]
+    async fn falls_back() {
+        let failure = Rc::new(Mutex::new(None));
+        let store = FallbackStore::new(
+            Box::new(FailingStore {
+                store: MemStore::new(),
+                failure: failure.clone(),
+            }),
+            Box::new(MemStore::new()),
+            Box::new(is_quota),
+        );
+
+        store.put("k1", b"v1").await.unwrap();
+        assert!(store.is_healthy());
+        assert_eq!(Some(b"v1".to_vec()), store.primary.get("k1").await.unwrap());
+        assert!(!store.secondary.has("k1").await.unwrap());
+
+        // An error we don't fall back on is returned to the caller.
+        *failure.lock().await = Some("some other error".into());
+        assert!(store.put("k2", b"v2").await.is_err());
+        assert!(store.is_healthy());
+        assert!(!store.has("k2").await.unwrap());
+
+        // A quota error flips us to the secondary, and the failed write
+        // lands there instead.
+        *failure.lock().await = Some(QUOTA.into());
+        let wt = store.write().await.unwrap();
+        wt.put("k2", b"v2").await.unwrap();
+        wt.del("k3").await.unwrap();
+        wt.commit().await.unwrap();
+        assert!(!store.is_healthy());
+        assert!(!store.primary.has("k2").await.unwrap());
+        assert_eq!(
+            Some(b"v2".to_vec()),
+            store.secondary.get("k2").await.unwrap()
+        );
+
+        // Reads see data from both stores.
+        assert_eq!(Some(b"v1".to_vec()), store.get("k1").await.unwrap());
+        assert_eq!(Some(b"v2".to_vec()), store.get("k2").await.unwrap());
+
+        // Subsequent writes go straight to the secondary, and shadow
+        // values in the primary.
+        store.put("k1", b"v1.1").await.unwrap();
+        assert_eq!(Some(b"v1".to_vec()), store.primary.get("k1").await.unwrap());
+        assert_eq!(Some(b"v1.1".to_vec()), store.get("k1").await.unwrap());
+        let wt = store.write().await.unwrap();
+        assert_eq!(Some(b"v1.1".to_vec()), wt.get("k1").await.unwrap());
+        wt.rollback().await.unwrap();
+    }
+
+    #[async_std::test]
+    async fn lock_order() {
+        let store = FallbackStore::new(
+            Box::new(MemStore::new()),
+            Box::new(MemStore::new()),
+            Box::new(is_quota),
+        );
+        store.healthy.store(false, Ordering::SeqCst);
+
+        // A write on the secondary waits for the primary before it locks
+        // the secondary, so a transaction that holds the primary and wants
+        // the secondary can still get it.
+        let primary = store.primary.write().await.unwrap();
+        let mut wt = Box::pin(store.write());
+        assert!((&mut wt).now_or_never().is_none());
+        assert!(store.secondary.read().now_or_never().is_some());
+        drop(primary);
+        assert!(wt.now_or_never().is_some());
+
+        // Likewise for reads.
+        let primary = store.primary.write().await.unwrap();
+        let mut rt = Box::pin(store.read());
+        assert!((&mut rt).now_or_never().is_none());
+        assert!(store.secondary.write().now_or_never().is_some());
+        drop(primary);
+        assert!(rt.now_or_never().is_some());
+    }
+}
@@ -1,14 +1,31 @@
-use crate::kv::{Read, Result, Store, StoreError, Write};
-use async_std::sync::{Arc, Condvar, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+#[cfg(feature = "fair_locking")]
+use crate::kv::fair_lock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use crate::kv::read_cache::CachedRead;
+#[cfg(debug_assertions)]
+use crate::kv::PendingSummary;
+use crate::kv::{
+    check_key_count, check_value_size, end_key_pending, probe_write, scan_keys_pending,
+    scan_pending, CommitReport, HealthReport, IsolationLevel, Read, Result, Store, StoreError,
+    Write, RW_LOCK_ISOLATION,
+};
+use async_std::sync::{Arc, Condvar, Mutex};
+#[cfg(not(feature = "fair_locking"))]
+use async_std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 use async_std::task;
 use async_trait::async_trait;
-use futures::channel::oneshot;
-use futures::future::join_all;
-use log::warn;
+use futures::channel::{mpsc, oneshot};
+use futures::future::{self, join, join_all, FutureExt, LocalBoxFuture};
+use futures::select;
+use futures::stream::{self, LocalBoxStream, StreamExt, TryStreamExt};
+use log::{debug, warn};
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
+use std::ops::Deref;
+use std::rc::Rc;
 use wasm_bindgen::closure::Closure;
 use wasm_bindgen::{JsCast, JsValue};
-use web_sys::{IdbDatabase, IdbTransaction};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{IdbCursorDirection, IdbDatabase, IdbKeyRange, IdbRequest, IdbTransaction};
 
 impl From<String> for StoreError {
     fn from(err: String) -> StoreError {
@@ -18,14 +35,28 @@ impl From<String> for StoreError {
 
 impl From<JsValue> for StoreError {
     fn from(err: JsValue) -> StoreError {
-        // TODO(nate): Pick out a useful subset of this value.
+        // DOMExceptions, the DOMErrors older browsers use, and JS Errors all
+        // have a name and a message.
+        let property = |name: &str| js_sys::Reflect::get(&err, &name.into()).ok();
+        if err.is_object() {
+            let name = property("name").and_then(|v| v.as_string());
+            let message = property("message").and_then(|v| v.as_string());
+            if let (Some(name), Some(message)) = (name, message) {
+                let code = property("code").and_then(|v| v.as_f64()).unwrap_or(0.0) as u16;
+                return StoreError::Dom {
+                    name,
+                    message,
+                    code,
+                };
+            }
+        }
         StoreError::Str(format!("{:?}", err))
     }
 }
 
 impl From<futures::channel::oneshot::Canceled> for StoreError {
     fn from(_e: futures::channel::oneshot::Canceled) -> StoreError {
-        StoreError::Str("oneshot cancelled".into())
+        StoreError::RequestDropped
     }
 }
 
@@ -75,108 +106,901 @@ pub struct IdbStore {
     //
     // It's possible we should have gone the other way and made memstore have the idb
     // interface. However the thing we should not do is have memstore and idbstore work differently.
+    //
+    // The fair_locking feature swaps in a FIFO-fair RwLock (see kv/fair_lock.rs) for
+    // both stores, which restores the no-starvation guarantee at some cost in throughput.
     db: RwLock<IdbDatabase>,
+    name: String,
+    // Set when db was closed because another connection wants to change the
+    // database's version (e.g. a newer client in another tab upgrading it).
+    closed: Rc<Cell<bool>>,
+    auto_reopen: bool,
+    keys: KeyEncoding,
+    max_value_bytes: Option<usize>,
+    max_keys: Option<usize>,
+    scan_window: u32,
+    tx_events: Option<mpsc::UnboundedSender<TxEvent>>,
+    // The id of the last write transaction.
+    last_tx_id: Cell<u64>,
+}
+
+/// A step in the life of an IdbStore write transaction, as sent to the
+/// channel given to IdbStore::set_tx_events(). Each carries the id of its
+/// transaction; a store numbers its write transactions from 1.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TxEvent {
+    /// write() created the IndexedDB transaction.
+    Opened(u64),
+    /// The first request was made on the transaction. Writes are buffered
+    /// until commit(), so a transaction that only writes makes its first
+    /// request there.
+    FirstRequest(u64),
+    /// commit() requested a put of the key. commit() makes its requests
+    /// in key order, so the same writes give the same events.
+    Put(u64, String),
+    /// commit() requested a delete of the key, as for Put.
+    Delete(u64, String),
+    /// IndexedDB completed (committed) the transaction.
+    Committed(u64),
+    /// IndexedDB aborted the transaction, because of rollback() or a failed
+    /// request.
+    Aborted(u64),
+    /// A request on the transaction failed. IndexedDB aborts the
+    /// transaction after this, so Aborted follows.
+    Errored(u64),
+}
+
+// Where a write transaction sends its TxEvents, if anywhere.
+#[derive(Clone)]
+struct TxEvents {
+    sender: Option<mpsc::UnboundedSender<TxEvent>>,
+    id: u64,
+}
+
+impl TxEvents {
+    // event is only called if someone is listening.
+    fn send(&self, event: impl FnOnce(u64) -> TxEvent) {
+        if let Some(sender) = &self.sender {
+            // If the receiver is gone, no one is listening any more.
+            let _ = sender.unbounded_send(event(self.id));
+        }
+    }
+}
+
+/// How an IdbStore represents keys in IndexedDB. A database's encoding is
+/// fixed when it is first opened; opening it later with the other encoding
+/// fails.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum KeyEncoding {
+    /// JS strings, which IndexedDB holds as UTF-16. The default, and the
+    /// only encoding of databases created before encodings were recorded.
+    #[default]
+    String,
+    /// The keys' UTF-8 bytes, as binary (ArrayBuffer) keys. This skips the
+    /// conversion to and from JS strings, and sorts the same way as our
+    /// keys do in Rust.
+    Binary,
+}
+
+/// How IdbStore::new_with_options() opens a database.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct OpenOptions {
+    pub keys: KeyEncoding,
+    /// Whether to check that a transaction can be run on the database once
+    /// it is open, and return Ok(None), as if IndexedDB were unavailable, if
+    /// not. Some browsers (historically Safari in private browsing) let a
+    /// database be opened but then fail every transaction on it; probing
+    /// lets callers fall back to another store up front. Off by default,
+    /// since it costs a transaction.
+    pub probe_transactions: bool,
+}
+
+impl KeyEncoding {
+    // The name recorded in META_STORE.
+    fn name(self) -> &'static str {
+        match self {
+            KeyEncoding::String => "string",
+            KeyEncoding::Binary => "binary",
+        }
+    }
+
+    fn encode(self, key: &str) -> JsValue {
+        match self {
+            KeyEncoding::String => key.into(),
+            KeyEncoding::Binary => js_sys::Uint8Array::from(key.as_bytes()).into(),
+        }
+    }
+
+    fn decode(self, key: JsValue) -> Result<String> {
+        match self {
+            KeyEncoding::String => key.as_string(),
+            KeyEncoding::Binary if key.is_instance_of::<js_sys::ArrayBuffer>() => {
+                String::from_utf8(js_sys::Uint8Array::new(&key).to_vec()).ok()
+            }
+            KeyEncoding::Binary => None,
+        }
+        .ok_or_else(|| StoreError::Str(format!("Invalid {} key {:?}", self.name(), key)))
+    }
+
+    // Returns the range of keys starting with prefix, or None for the empty
+    // prefix, which matches every key.
+    fn prefix_range(self, prefix: &str) -> Result<Option<IdbKeyRange>> {
+        if prefix.is_empty() {
+            return Ok(None);
+        }
+        let upper = match self {
+            // Idb compares string keys by UTF-16 code unit, so every key
+            // with prefix sorts between it and the prefix followed by the
+            // largest code unit (short of keys continuing with U+FFFF
+            // itself, which we don't expect).
+            KeyEncoding::String => format!("{}\u{ffff}", prefix).into(),
+            // Binary keys compare bytewise, and 0xff never occurs in UTF-8.
+            KeyEncoding::Binary => {
+                let mut upper = prefix.as_bytes().to_vec();
+                upper.push(0xff);
+                js_sys::Uint8Array::from(&upper[..]).into()
+            }
+        };
+        Ok(Some(IdbKeyRange::bound(&self.encode(prefix), &upper)?))
+    }
+}
+
+// The browser's storage usage and quota for the whole origin, in bytes.
+#[derive(Debug)]
+pub struct StorageEstimate {
+    pub usage: u64,
+    pub quota: u64,
 }
 
 const OBJECT_STORE: &str = "chunks";
 
+// An append-only log, keyed by sequence numbers IndexedDB generates
+// (autoIncrement), starting at 1. See append().
+const LOG_STORE: &str = "log";
+
+// Facts about the database itself, such as its KeyEncoding.
+const META_STORE: &str = "meta";
+
+// The key in META_STORE of the name of the database's KeyEncoding.
+const KEY_ENCODING_KEY: &str = "keyEncoding";
+
+// The IndexedDB version of our database. Version 1 had only OBJECT_STORE,
+// version 2 added LOG_STORE and version 3 META_STORE.
+const DB_VERSION: u32 = 3;
+
+// Read by keep_alive(). Its value, if any, is ignored.
+const KEEP_ALIVE_KEY: &str = "keep-alive";
+
+thread_local! {
+    // A lock per database name that is being opened on this thread.
+    static OPENING: RefCell<HashMap<String, Rc<Mutex<()>>>> = RefCell::new(HashMap::new());
+}
+
+// Opens the named database at version, or at its current version if None.
+// Sets closed if the database is later closed by a versionchange. If
+// create is false, a database that doesn't exist yet is not created and
+// this fails with NotInitialized.
+//
+// Opens of the same name are serialized, so that a second open doesn't
+// race the first's upgrade of a new database and get blocked by it.
+async fn open_db(
+    name: &str,
+    version: Option<u32>,
+    create: bool,
+    closed: &Rc<Cell<bool>>,
+) -> Result<Option<IdbDatabase>> {
+    let lock = OPENING.with(|opening| opening.borrow_mut().entry(name.into()).or_default().clone());
+    let result = {
+        let _guard = lock.lock().await;
+        open_db_locked(name, version, create, closed).await
+    };
+    // Forget the lock unless someone else is waiting on it.
+    if Rc::strong_count(&lock) == 2 {
+        OPENING.with(|opening| opening.borrow_mut().remove(name));
+    }
+    result
+}
+
+async fn open_db_locked(
+    name: &str,
+    version: Option<u32>,
+    create: bool,
+    closed: &Rc<Cell<bool>>,
+) -> Result<Option<IdbDatabase>> {
+    let factory = match idb_factory()? {
+        Some(f) => f,
+        None => return Ok(None),
+    };
+    let request = match version {
+        Some(v) => factory.open_with_u32(name, v)?,
+        None => factory.open(name)?,
+    };
+    let closed = closed.clone();
+    let missing = Rc::new(Cell::new(false));
+    let missing_copy = missing.clone();
+    let failed: Rc<RefCell<Option<StoreError>>> = Rc::new(RefCell::new(None));
+    let failed_copy = failed.clone();
+    let request_copy = request.clone();
+    let onupgradeneeded = Closure::once(move |event: web_sys::IdbVersionChangeEvent| {
+        // Without a version, an upgrade only happens when the database is
+        // new. Aborting it also deletes the database again.
+        if !create {
+            missing_copy.set(true);
+            if let Some(Err(e)) = request_copy.transaction().map(|tx| tx.abort()) {
+                warn!("Abort of database creation failed: {:?}", e);
+            }
+            return;
+        }
+        let result = match request_copy.result() {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("Error before ugradeneeded: {:?}", e);
+                return;
+            }
+        };
+        let db = web_sys::IdbDatabase::unchecked_from_js(result);
+
+        // Create whatever the database's previous version lacked, skipping
+        // any that exist anyway. Failing to create one fails the open, and
+        // aborting the upgrade leaves the database as it was.
+        let old_version = event.old_version();
+        let mut log_params = web_sys::IdbObjectStoreParameters::new();
+        log_params.auto_increment(true);
+        let stores = [
+            (1.0, OBJECT_STORE, None),
+            (2.0, LOG_STORE, Some(&log_params)),
+            (3.0, META_STORE, None),
+        ];
+        for (version, store, params) in stores.iter() {
+            if old_version >= *version || db.object_store_names().contains(store) {
+                continue;
+            }
+            let created = match params {
+                Some(params) => db.create_object_store_with_optional_parameters(store, params),
+                None => db.create_object_store(store),
+            };
+            if let Err(e) = created {
+                failed_copy.replace(Some(StoreError::Open {
+                    store: store.to_string(),
+                    cause: Box::new(e.into()),
+                }));
+                if let Some(Err(e)) = request_copy.transaction().map(|tx| tx.abort()) {
+                    warn!("Abort of database upgrade failed: {:?}", e);
+                }
+                return;
+            }
+        }
+    });
+    request.set_onupgradeneeded(Some(onupgradeneeded.as_ref().unchecked_ref()));
+    let db: IdbDatabase = match await_request(&request).await {
+        Err(_) if missing.get() => return Err(StoreError::NotInitialized),
+        Err(_) if failed.borrow().is_some() => return Err(failed.take().unwrap()),
+        result => result?.into(),
+    };
+
+    // Close when asked to, so we don't block the other connection, and
+    // note it so the store can reopen.
+    let onversionchange = Closure::once(move |event: web_sys::IdbVersionChangeEvent| {
+        if let Some(target) = event.target() {
+            target.unchecked_into::<IdbDatabase>().close();
+        }
+        closed.set(true);
+    });
+    db.set_onversionchange(Some(onversionchange.into_js_value().unchecked_ref()));
+    Ok(Some(db))
+}
+
+// Whether a trivial readonly transaction can be run on db.
+async fn transactions_work(db: &IdbDatabase) -> bool {
+    match db.transaction_with_str(OBJECT_STORE) {
+        Ok(tx) => count_impl(&tx).await.is_ok(),
+        Err(_) => false,
+    }
+}
+
+// Checks that db's keys are encoded as keys says, recording that they are
+// if db doesn't say yet. Databases from before encodings were recorded
+// used strings, so a database that has data but no encoding is taken to
+// use strings.
+async fn check_key_encoding(db: &IdbDatabase, keys: KeyEncoding) -> Result<()> {
+    let names = js_sys::Array::of2(&META_STORE.into(), &OBJECT_STORE.into());
+    let tx =
+        db.transaction_with_str_sequence_and_mode(&names, web_sys::IdbTransactionMode::Readwrite)?;
+    let meta = tx.object_store(META_STORE)?;
+    let recorded = await_request(&meta.get(&KEY_ENCODING_KEY.into())?).await?;
+    let existing = match recorded.as_string() {
+        Some(name) => Some(name),
+        None if count_impl(&tx).await? > 0 => Some(KeyEncoding::String.name().to_string()),
+        None => None,
+    };
+    match existing {
+        Some(name) if name != keys.name() => Err(StoreError::Str(format!(
+            "Database uses {} keys, not {}",
+            name,
+            keys.name()
+        ))),
+        Some(_) if !recorded.is_undefined() => Ok(()),
+        _ => {
+            let request = meta.put_with_key(&keys.name().into(), &KEY_ENCODING_KEY.into())?;
+            let (put, done) = join(await_request(&request), await_transaction(&tx)).await;
+            put?;
+            done
+        }
+    }
+}
+
+// Returns the KeyEncoding db records, or String if it records none. Fails
+// with NotInitialized if db has no OBJECT_STORE, e.g. because it isn't one
+// of ours.
+async fn recorded_key_encoding(db: &IdbDatabase) -> Result<KeyEncoding> {
+    let stores = db.object_store_names();
+    if !stores.contains(OBJECT_STORE) {
+        return Err(StoreError::NotInitialized);
+    }
+    if !stores.contains(META_STORE) {
+        return Ok(KeyEncoding::String);
+    }
+    let tx = db.transaction_with_str(META_STORE)?;
+    let recorded = await_request(&tx.object_store(META_STORE)?.get(&KEY_ENCODING_KEY.into())?)
+        .await?
+        .as_string();
+    match recorded {
+        Some(name) if name == KeyEncoding::Binary.name() => Ok(KeyEncoding::Binary),
+        Some(name) if name != KeyEncoding::String.name() => {
+            Err(StoreError::Str(format!("Unknown key encoding {}", name)))
+        }
+        _ => Ok(KeyEncoding::String),
+    }
+}
+
+// Returns the IndexedDB factory of the global scope, which is a Window on
+// the main thread and a WorkerGlobalScope in (dedicated, shared or
+// service) Workers. Returns None if there is neither, e.g. in Node, or if
+// IndexedDB is unavailable.
+fn idb_factory() -> Result<Option<web_sys::IdbFactory>> {
+    if let Some(window) = web_sys::window() {
+        return Ok(window.indexed_db()?);
+    }
+    match js_sys::global().dyn_into::<web_sys::WorkerGlobalScope>() {
+        Ok(scope) => Ok(scope.indexed_db()?),
+        Err(_) => Ok(None),
+    }
+}
+
 impl IdbStore {
     pub async fn new(name: &str) -> Result<Option<IdbStore>> {
-        let window = match web_sys::window() {
-            Some(w) => w,
-            None => return Ok(None),
+        IdbStore::new_with_key_encoding(name, KeyEncoding::String).await
+    }
+
+    /// Like new(), with keys stored as keys says. Fails if the database
+    /// already exists with a different encoding.
+    pub async fn new_with_key_encoding(name: &str, keys: KeyEncoding) -> Result<Option<IdbStore>> {
+        let options = OpenOptions {
+            keys,
+            ..OpenOptions::default()
         };
-        let factory = match window.indexed_db()? {
-            Some(f) => f,
+        IdbStore::new_with_options(name, options).await
+    }
+
+    /// Like new(), as options says.
+    pub async fn new_with_options(name: &str, options: OpenOptions) -> Result<Option<IdbStore>> {
+        let OpenOptions {
+            keys,
+            probe_transactions,
+        } = options;
+        let closed = Rc::new(Cell::new(false));
+        let db = match open_db(name, Some(DB_VERSION), true, &closed).await? {
+            Some(db) => db,
             None => return Ok(None),
         };
-        let request = factory.open(name)?;
-        let (callback, receiver) = IdbStore::oneshot_callback();
-        let request_copy = request.clone();
-        let onupgradeneeded = Closure::once(move |_event: web_sys::IdbVersionChangeEvent| {
-            let result = match request_copy.result() {
-                Ok(r) => r,
-                Err(e) => {
-                    warn!("Error before ugradeneeded: {:?}", e);
-                    return;
-                }
-            };
-            let db = web_sys::IdbDatabase::unchecked_from_js(result);
+        if probe_transactions && !transactions_work(&db).await {
+            warn!(
+                "IndexedDB opened {:?} but can't run transactions on it",
+                name
+            );
+            db.close();
+            return Ok(None);
+        }
+        if let Err(e) = check_key_encoding(&db, keys).await {
+            db.close();
+            return Err(e);
+        }
+        Ok(Some(IdbStore {
+            db: RwLock::new(db),
+            name: name.to_string(),
+            closed,
+            auto_reopen: false,
+            keys,
+            max_value_bytes: None,
+            max_keys: None,
+            scan_window: 1,
+            tx_events: None,
+            last_tx_id: Cell::new(0),
+        }))
+    }
 
-            if let Err(e) = db.create_object_store(OBJECT_STORE) {
-                warn!("Create object store failed: {:?}", e);
+    /// Opens the named database as it is, without creating or upgrading it.
+    /// Fails with StoreError::NotInitialized if the database doesn't exist
+    /// or has no chunks. Keys are encoded as the database records, or as
+    /// strings if it records nothing. A database from before the log and
+    /// meta stores can be read and written, but not appended to.
+    pub async fn open_existing(name: &str) -> Result<Option<IdbStore>> {
+        let closed = Rc::new(Cell::new(false));
+        let db = match open_db(name, None, false, &closed).await? {
+            Some(db) => db,
+            None => return Ok(None),
+        };
+        let keys = match recorded_key_encoding(&db).await {
+            Ok(keys) => keys,
+            Err(e) => {
+                db.close();
+                return Err(e);
             }
-        });
-        request.set_onsuccess(Some(callback.as_ref().unchecked_ref()));
-        request.set_onerror(Some(callback.as_ref().unchecked_ref()));
-        request.set_onupgradeneeded(Some(onupgradeneeded.as_ref().unchecked_ref()));
-        receiver.await?;
+        };
         Ok(Some(IdbStore {
-            db: RwLock::new(request.result()?.into()),
+            db: RwLock::new(db),
+            name: name.to_string(),
+            closed,
+            auto_reopen: false,
+            keys,
+            max_value_bytes: None,
+            max_keys: None,
+            scan_window: 1,
+            tx_events: None,
+            last_tx_id: Cell::new(0),
         }))
     }
 
-    /// Returns a oneshot callback and a Receiver to await it being called.
-    ///
-    /// Intended for use with Idb request callbacks, and may be registered for
-    /// multiple callbacks. Await on the Receiver returns when any is invoked.
-    fn oneshot_callback() -> (Closure<dyn FnMut()>, oneshot::Receiver<()>) {
-        let (sender, receiver) = oneshot::channel::<()>();
-        let callback = Closure::once(move || {
-            if sender.send(()).is_err() {
-                warn!("oneshot send failed");
+    /// Returns the names of the object stores in the named database, in
+    /// sorted order, without creating or upgrading it, e.g. for tools that
+    /// inspect databases older versions created. Fails with
+    /// StoreError::NotInitialized if the database doesn't exist.
+    pub async fn object_store_names(name: &str) -> Result<Vec<String>> {
+        let closed = Rc::new(Cell::new(false));
+        let db = match open_db(name, None, false, &closed).await? {
+            Some(db) => db,
+            None => return Err(StoreError::Str("IndexedDB is unavailable".into())),
+        };
+        let list = db.object_store_names();
+        let names = (0..list.length()).filter_map(|i| list.item(i)).collect();
+        db.close();
+        Ok(names)
+    }
+
+    /// Causes the store to reopen the database if its connection was closed
+    /// by a versionchange (another connection upgrading or deleting it),
+    /// instead of failing every later operation with StoreError::StoreClosed.
+    /// The next read(), write(), append() or scan_log() reopens it at its
+    /// new version, then runs on the new connection. Write transactions
+    /// already open when the versionchange arrived are on the old
+    /// connection, so their commit() fails with StoreClosed either way.
+    /// Off by default.
+    pub fn set_auto_reopen(&mut self, auto_reopen: bool) {
+        self.auto_reopen = auto_reopen;
+    }
+
+    /// Causes put() of any single value longer than max to fail with
+    /// StoreError::ValueTooLarge. None (the default) means unlimited.
+    pub fn set_max_value_bytes(&mut self, max: Option<usize>) {
+        self.max_value_bytes = max;
+    }
+
+    /// Causes commit() of a transaction that would leave more than max keys
+    /// in the store to fail with StoreError::KeyQuotaExceeded. None (the
+    /// default) means unlimited.
+    pub fn set_max_keys(&mut self, max: Option<usize>) {
+        self.max_keys = max;
+    }
+
+    /// Sets how many entries scan_stream() on a read transaction reads from
+    /// IndexedDB per round trip. With a window of 1 (the default) it steps a
+    /// cursor one entry at a time; with more it reads up to window entries
+    /// at a time with getAll() and buffers them, trading memory for far
+    /// fewer round trips on large scans. Either way, the stream only reads
+    /// as it is polled.
+    pub fn set_scan_window(&mut self, window: u32) {
+        self.scan_window = window.max(1);
+    }
+
+    /// Causes the store to send the TxEvents of its write transactions to
+    /// events, so that tests can check their order and embedders can log
+    /// them. None (the default) sends nothing.
+    pub fn set_tx_events(&mut self, events: Option<mpsc::UnboundedSender<TxEvent>>) {
+        self.tx_events = events;
+    }
+
+    /// Writes entries into the store in one transaction, e.g. a snapshot
+    /// scanned from a MemStore that a native test built, to set up a
+    /// browser test without going through many rpcs. Unlike
+    /// Store::replace_all(), keys the store has that entries don't are
+    /// kept.
+    pub async fn load_from(&self, entries: &[(String, Vec<u8>)]) -> Result<()> {
+        let wt = self.write().await?;
+        wt.reserve(entries.len()).await;
+        for (key, value) in entries {
+            wt.put(key, value).await?;
+        }
+        wt.commit_unit().await
+    }
+
+    // Returns a read guard on the database, first reopening it if it was
+    // closed by a versionchange and auto_reopen is set. Fails with
+    // StoreClosed if it was closed and auto_reopen isn't set.
+    async fn db_read(&self) -> Result<RwLockReadGuard<'_, IdbDatabase>> {
+        let db = self.db.read().await;
+        if !self.closed.get() {
+            return Ok(db);
+        }
+        if !self.auto_reopen {
+            return Err(StoreError::StoreClosed);
+        }
+        drop(db);
+        self.reopen().await?;
+        Ok(self.db.read().await)
+    }
+
+    // Like db_read(), for writes.
+    async fn db_write(&self) -> Result<RwLockWriteGuard<'_, IdbDatabase>> {
+        let db = self.db.write().await;
+        if !self.closed.get() {
+            return Ok(db);
+        }
+        if !self.auto_reopen {
+            return Err(StoreError::StoreClosed);
+        }
+        drop(db);
+        self.reopen().await?;
+        Ok(self.db.write().await)
+    }
+
+    fn next_tx_events(&self) -> TxEvents {
+        self.last_tx_id.set(self.last_tx_id.get() + 1);
+        TxEvents {
+            sender: self.tx_events.clone(),
+            id: self.last_tx_id.get(),
+        }
+    }
+
+    async fn reopen(&self) -> Result<()> {
+        let mut db = self.db.write().await;
+        // Someone else may have reopened it while we waited.
+        if !self.closed.get() {
+            return Ok(());
+        }
+        // Open at whatever version the other connection left, which may be
+        // newer than ours.
+        *db = match open_db(&self.name, None, true, &self.closed).await? {
+            Some(db) => db,
+            None => return Err(StoreError::Str("IndexedDB is unavailable".into())),
+        };
+        self.closed.set(false);
+        Ok(())
+    }
+
+    /// Appends value to the store's log, returning the sequence number it
+    /// was given. Sequence numbers increase with each append, but may skip
+    /// values (e.g. after a failed append). The log is separate from the
+    /// key-value contents, and is not affected by their transactions.
+    pub async fn append(&self, value: &[u8]) -> Result<u64> {
+        let db = self.db_write().await?;
+        let tx =
+            db.transaction_with_str_and_mode(LOG_STORE, web_sys::IdbTransactionMode::Readwrite)?;
+        let request = tx
+            .object_store(LOG_STORE)?
+            .add(&js_sys::Uint8Array::from(value))?;
+        let (seq, done) = join(await_request(&request), await_transaction(&tx)).await;
+        let seq = seq?;
+        done?;
+        match seq.as_f64() {
+            Some(seq) => Ok(seq as u64),
+            None => Err(StoreError::Str(format!(
+                "Log append returned non-number key {:?}",
+                seq
+            ))),
+        }
+    }
+
+    /// Returns the log entries with sequence numbers from start on, in
+    /// sequence order.
+    pub async fn scan_log(&self, start: u64) -> Result<Vec<(u64, Vec<u8>)>> {
+        let db = self.db_read().await?;
+        let store = db
+            .transaction_with_str(LOG_STORE)?
+            .object_store(LOG_STORE)?;
+        let range = IdbKeyRange::lower_bound(&(start as f64).into())?;
+        let (keys, values) = join(
+            await_request(&store.get_all_keys_with_key(&range)?),
+            await_request(&store.get_all_with_key(&range)?),
+        )
+        .await;
+        let keys = js_sys::Array::from(&keys?);
+        let values = js_sys::Array::from(&values?);
+        let mut entries = Vec::with_capacity(keys.length() as usize);
+        for (seq, value) in keys.iter().zip(values.iter()) {
+            let seq = seq
+                .as_f64()
+                .ok_or_else(|| StoreError::Str(format!("Non-number log key {:?}", seq)))?;
+            entries.push((seq as u64, js_sys::Uint8Array::new(&value).to_vec()));
+        }
+        Ok(entries)
+    }
+
+    /// Returns the browser's estimate of storage used and available for the
+    /// origin (navigator.storage.estimate()), which covers every store and
+    /// other storage the origin has, not just this store. Returns None if
+    /// the browser doesn't support it.
+    pub async fn estimate_quota() -> Result<Option<StorageEstimate>> {
+        let navigator = match web_sys::window() {
+            Some(w) => w.navigator(),
+            None => return Ok(None),
+        };
+        let storage = js_sys::Reflect::get(&navigator, &"storage".into())?;
+        if storage.is_undefined() {
+            return Ok(None);
+        }
+        let storage: web_sys::StorageManager = storage.unchecked_into();
+        let estimate = JsFuture::from(storage.estimate()?).await?;
+        let get = |field: &str| -> Result<u64> {
+            match js_sys::Reflect::get(&estimate, &field.into())?.as_f64() {
+                Some(v) => Ok(v as u64),
+                None => Err(StoreError::Str(format!(
+                    "Storage estimate has no {}",
+                    field
+                ))),
             }
-        });
-        (callback, receiver)
+        };
+        Ok(Some(StorageEstimate {
+            usage: get("usage")?,
+            quota: get("quota")?,
+        }))
     }
 }
 
 #[async_trait(?Send)]
 impl Store for IdbStore {
     async fn read<'a>(&'a self) -> Result<Box<dyn Read + 'a>> {
-        let db_guard = self.db.read().await;
+        let db_guard = self.db_read().await?;
         let tx = db_guard.transaction_with_str(OBJECT_STORE)?;
-        Ok(Box::new(ReadTransaction::new(db_guard, tx)?))
+        Ok(Box::new(CachedRead::new(Box::new(ReadTransaction::new(
+            Box::new(db_guard),
+            tx,
+            self.keys,
+            self.scan_window,
+        )?))))
     }
 
     async fn write<'a>(&'a self) -> Result<Box<dyn Write + 'a>> {
-        let db_guard = self.db.write().await;
+        let db_guard = self.db_write().await?;
         let tx = db_guard
             .transaction_with_str_and_mode(OBJECT_STORE, web_sys::IdbTransactionMode::Readwrite)?;
-        Ok(Box::new(WriteTransaction::new(db_guard, tx)?))
+        Ok(Box::new(WriteTransaction::new(
+            db_guard,
+            tx,
+            self.keys,
+            self.max_value_bytes,
+            self.max_keys,
+            self.scan_window,
+            self.next_tx_events(),
+            self.closed.clone(),
+        )?))
+    }
+
+    fn isolation(&self) -> IsolationLevel {
+        RW_LOCK_ISOLATION
+    }
+
+    // Also checks that the database has our object store, and that the
+    // origin has used less than 90% of its quota.
+    async fn health_check(&self) -> Result<HealthReport> {
+        let object_store_present = self
+            .db_read()
+            .await?
+            .object_store_names()
+            .contains(OBJECT_STORE);
+        let writable = object_store_present && probe_write(self).await;
+        let quota_ok = match IdbStore::estimate_quota().await {
+            Ok(Some(estimate)) => estimate.usage < estimate.quota / 10 * 9,
+            Ok(None) => true,
+            Err(_) => false,
+        };
+        Ok(HealthReport {
+            writable,
+            object_store_present,
+            quota_ok,
+        })
     }
 }
 
+// Fields drop in declaration order, so tx is released before the db guard.
+// Keep it that way: a new write must never be able to start while a
+// transaction from this one is still alive. The guard is usually a read
+// guard, but is the write's guard for WriteTransaction::commit_and_read().
 struct ReadTransaction<'a> {
-    #[allow(dead_code)]
-    db: RwLockReadGuard<'a, IdbDatabase>,
     tx: IdbTransaction,
+    keys: KeyEncoding,
+    scan_window: u32,
+    // Set by abort(), after which reads fail with Cancelled rather than
+    // with whatever error the aborted tx gives them.
+    aborted: Cell<bool>,
+    #[allow(dead_code)]
+    db: Box<dyn Deref<Target = IdbDatabase> + 'a>,
 }
 
-impl ReadTransaction<'_> {
-    fn new(db: RwLockReadGuard<'_, IdbDatabase>, tx: IdbTransaction) -> Result<ReadTransaction> {
-        Ok(ReadTransaction { db, tx })
+impl<'a> ReadTransaction<'a> {
+    fn new(
+        db: Box<dyn Deref<Target = IdbDatabase> + 'a>,
+        tx: IdbTransaction,
+        keys: KeyEncoding,
+        scan_window: u32,
+    ) -> Result<ReadTransaction<'a>> {
+        Ok(ReadTransaction {
+            tx,
+            keys,
+            scan_window,
+            aborted: Cell::new(false),
+            db,
+        })
+    }
+
+    fn or_cancelled(&self, e: StoreError) -> StoreError {
+        match self.aborted.get() {
+            true => StoreError::Cancelled,
+            false => e,
+        }
     }
 }
 
 #[async_trait(?Send)]
 impl Read for ReadTransaction<'_> {
     async fn has(&self, key: &str) -> Result<bool> {
-        has_impl(&self.tx, key).await
+        has_impl(&self.tx, self.keys, key)
+            .await
+            .map_err(|e| self.or_cancelled(e))
     }
 
     async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
-        get_impl(&self.tx, key).await
+        get_impl(&self.tx, self.keys, key)
+            .await
+            .map_err(|e| self.or_cancelled(e))
+    }
+
+    async fn scan(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        scan_impl(&self.tx, self.keys, prefix)
+            .await
+            .map_err(|e| self.or_cancelled(e))
+    }
+
+    // Entries are read with a cursor, or in batches with a scan window, as
+    // the stream is polled. Binary keys are in the same order in idb as in
+    // scan(); string keys are put in that order by utf8_order().
+    fn scan_stream<'a>(&'a self, prefix: &'a str) -> LocalBoxStream<'a, Result<(String, Vec<u8>)>> {
+        let entries = match self.scan_window {
+            window if window > 1 => scan_stream_batched(&self.tx, self.keys, prefix, window),
+            _ => scan_stream_impl(&self.tx, self.keys, prefix),
+        };
+        let entries = match self.keys {
+            KeyEncoding::Binary => entries,
+            KeyEncoding::String => utf8_order(entries, false),
+        };
+        // Entries already read (e.g. the rest of a batch) aren't returned
+        // after an abort either. The stream ends at its first error.
+        entries
+            .scan(false, move |failed, entry| {
+                let entry = match (*failed, self.aborted.get()) {
+                    (true, _) => return future::ready(None),
+                    (false, true) => Err(StoreError::Cancelled),
+                    (false, false) => entry,
+                };
+                *failed = entry.is_err();
+                future::ready(Some(entry))
+            })
+            .boxed_local()
+    }
+
+    async fn scan_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        scan_keys_impl(&self.tx, self.keys, prefix)
+            .await
+            .map_err(|e| self.or_cancelled(e))
+    }
+
+    async fn count(&self) -> Result<usize> {
+        count_impl(&self.tx).await.map_err(|e| self.or_cancelled(e))
+    }
+
+    async fn count_prefix(&self, prefix: &str) -> Result<usize> {
+        count_prefix_impl(&self.tx, self.keys, prefix)
+            .await
+            .map_err(|e| self.or_cancelled(e))
+    }
+
+    async fn first_key(&self) -> Result<Option<String>> {
+        end_key_impl(&self.tx, self.keys, false, |_| false)
+            .await
+            .map_err(|e| self.or_cancelled(e))
+    }
+
+    async fn last_key(&self) -> Result<Option<String>> {
+        end_key_impl(&self.tx, self.keys, true, |_| false)
+            .await
+            .map_err(|e| self.or_cancelled(e))
+    }
+
+    // Aborting fails the tx's pending requests. It throws if the tx has
+    // already finished (e.g. it auto-committed while idle), but later
+    // reads fail either way.
+    fn abort(&self) {
+        self.aborted.set(true);
+        if let Err(e) = self.tx.abort() {
+            debug!("Abort of read transaction failed: {:?}", e);
+        }
     }
 }
 
-async fn has_impl(tx: &IdbTransaction, key: &str) -> Result<bool> {
-    let request = tx.object_store(OBJECT_STORE)?.count_with_key(&key.into())?;
-    let (callback, receiver) = IdbStore::oneshot_callback();
-    request.set_onsuccess(Some(callback.as_ref().unchecked_ref()));
-    request.set_onerror(Some(callback.as_ref().unchecked_ref()));
-    receiver.await?;
-    let result = request.result()?;
+/// Waits for request to finish, returning its result if it succeeded and
+/// its error if it failed.
+pub async fn await_request(request: &IdbRequest) -> Result<JsValue> {
+    // Only one of the callbacks will be called, so they share a sender.
+    let (sender, receiver) = oneshot::channel::<bool>();
+    let sender = Rc::new(RefCell::new(Some(sender)));
+    let callback = |succeeded: bool| {
+        let sender = sender.clone();
+        Closure::once(move || {
+            if let Some(sender) = sender.borrow_mut().take() {
+                if sender.send(succeeded).is_err() {
+                    warn!("oneshot send failed");
+                }
+            }
+        })
+    };
+    let onsuccess: Closure<dyn FnMut()> = callback(true);
+    let onerror: Closure<dyn FnMut()> = callback(false);
+    request.set_onsuccess(Some(onsuccess.as_ref().unchecked_ref()));
+    request.set_onerror(Some(onerror.as_ref().unchecked_ref()));
+
+    if receiver.await? {
+        return Ok(request.result()?);
+    }
+    Err(match request.error()? {
+        Some(e) => JsValue::from(e).into(),
+        None => StoreError::Str("Request failed".into()),
+    })
+}
+
+// Waits for tx to complete, failing if it aborts. Like await_request(), the
+// callbacks are installed when this is first polled, so it must be polled
+// (e.g. joined with the tx's requests) before the tx can finish.
+async fn await_transaction(tx: &IdbTransaction) -> Result<()> {
+    let (sender, receiver) = oneshot::channel::<bool>();
+    let sender = Rc::new(RefCell::new(Some(sender)));
+    let callback = |completed: bool| {
+        let sender = sender.clone();
+        Closure::once(move || {
+            if let Some(sender) = sender.borrow_mut().take() {
+                if sender.send(completed).is_err() {
+                    warn!("oneshot send failed");
+                }
+            }
+        })
+    };
+    let oncomplete: Closure<dyn FnMut()> = callback(true);
+    let onabort: Closure<dyn FnMut()> = callback(false);
+    tx.set_oncomplete(Some(oncomplete.as_ref().unchecked_ref()));
+    tx.set_onabort(Some(onabort.as_ref().unchecked_ref()));
+
+    if receiver.await? {
+        return Ok(());
+    }
+    Err(match tx.error() {
+        Some(e) => JsValue::from(e).into(),
+        None => StoreError::Str("Transaction aborted".into()),
+    })
+}
+
+async fn has_impl(tx: &IdbTransaction, keys: KeyEncoding, key: &str) -> Result<bool> {
+    let request = tx
+        .object_store(OBJECT_STORE)?
+        .count_with_key(&keys.encode(key))?;
+    let result = await_request(&request).await?;
     Ok(match result.as_f64() {
         Some(v) if v >= 1.0 => true,
         Some(_) => false,
@@ -187,18 +1011,343 @@ async fn has_impl(tx: &IdbTransaction, key: &str) -> Result<bool> {
     })
 }
 
-async fn get_impl(tx: &IdbTransaction, key: &str) -> Result<Option<Vec<u8>>> {
-    let request = tx.object_store(OBJECT_STORE)?.get(&key.into())?;
-    let (callback, receiver) = IdbStore::oneshot_callback();
-    request.set_onsuccess(Some(callback.as_ref().unchecked_ref()));
-    request.set_onerror(Some(callback.as_ref().unchecked_ref()));
-    receiver.await?;
-    Ok(match request.result()? {
+async fn count_impl(tx: &IdbTransaction) -> Result<usize> {
+    await_count(&tx.object_store(OBJECT_STORE)?.count()?).await
+}
+
+// Counts the keys in the prefix's range, without reading them.
+async fn count_prefix_impl(
+    tx: &IdbTransaction,
+    encoding: KeyEncoding,
+    prefix: &str,
+) -> Result<usize> {
+    let store = tx.object_store(OBJECT_STORE)?;
+    let request = match encoding.prefix_range(prefix)? {
+        None => store.count()?,
+        Some(range) => store.count_with_key(&range)?,
+    };
+    await_count(&request).await
+}
+
+async fn await_count(request: &IdbRequest) -> Result<usize> {
+    let result = await_request(request).await?;
+    match result.as_f64() {
+        Some(v) => Ok(v as usize),
+        None => Err(StoreError::Str(format!(
+            "IdbStore.count returned non-float {:?}",
+            result
+        ))),
+    }
+}
+
+async fn get_impl(tx: &IdbTransaction, keys: KeyEncoding, key: &str) -> Result<Option<Vec<u8>>> {
+    let request = tx.object_store(OBJECT_STORE)?.get(&keys.encode(key))?;
+    Ok(match await_request(&request).await? {
         v if v.is_undefined() => None,
         v => Some(js_sys::Uint8Array::new(&v).to_vec()),
     })
 }
 
+async fn scan_impl(
+    tx: &IdbTransaction,
+    encoding: KeyEncoding,
+    prefix: &str,
+) -> Result<Vec<(String, Vec<u8>)>> {
+    let store = tx.object_store(OBJECT_STORE)?;
+    let (keys, values) = match encoding.prefix_range(prefix)? {
+        None => (store.get_all_keys()?, store.get_all()?),
+        Some(range) => (
+            store.get_all_keys_with_key(&range)?,
+            store.get_all_with_key(&range)?,
+        ),
+    };
+    let (keys, values) = join(await_request(&keys), await_request(&values)).await;
+    let keys = js_sys::Array::from(&keys?);
+    let values = js_sys::Array::from(&values?);
+    let mut entries = Vec::with_capacity(keys.length() as usize);
+    for (key, value) in keys.iter().zip(values.iter()) {
+        entries.push((
+            encoding.decode(key)?,
+            js_sys::Uint8Array::new(&value).to_vec(),
+        ));
+    }
+    // Sort by UTF-8 rather than UTF-16 so that we order string keys the same
+    // way as MemStore.
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(entries)
+}
+
+thread_local! {
+    static SCAN_ROUND_TRIPS: Cell<u64> = const { Cell::new(0) };
+}
+
+/// The number of requests scan streams have made to IndexedDB on this
+/// thread (one per cursor step, or per batch with a scan window), to check
+/// that streams are read lazily and in as few round trips as expected.
+pub fn scan_round_trips() -> u64 {
+    SCAN_ROUND_TRIPS.with(|n| n.get())
+}
+
+enum Cursor {
+    Failed(StoreError),
+    Opened(IdbRequest),
+    // The request and the cursor it last returned.
+    At(IdbRequest, web_sys::IdbCursorWithValue),
+    Done,
+}
+
+// Streams the entries starting with prefix in idb's key order, only moving
+// the cursor when the stream is polled. Like any other read, polling must
+// not wait on anything else in between, or the transaction finishes.
+fn scan_stream_impl<'a>(
+    tx: &IdbTransaction,
+    encoding: KeyEncoding,
+    prefix: &str,
+) -> LocalBoxStream<'a, Result<(String, Vec<u8>)>> {
+    let open = || -> Result<IdbRequest> {
+        let store = tx.object_store(OBJECT_STORE)?;
+        Ok(match encoding.prefix_range(prefix)? {
+            None => store.open_cursor()?,
+            Some(range) => store.open_cursor_with_range(&range)?,
+        })
+    };
+    let start = match open() {
+        Ok(request) => Cursor::Opened(request),
+        Err(e) => Cursor::Failed(e),
+    };
+    stream::unfold(start, move |cursor| async move {
+        let request = match cursor {
+            Cursor::Done => return None,
+            Cursor::Failed(e) => return Some((Err(e), Cursor::Done)),
+            Cursor::Opened(request) => request,
+            Cursor::At(request, cursor) => match cursor.continue_() {
+                Ok(()) => request,
+                Err(e) => return Some((Err(e.into()), Cursor::Done)),
+            },
+        };
+        SCAN_ROUND_TRIPS.with(|n| n.set(n.get() + 1));
+        let step = async {
+            let result = await_request(&request).await?;
+            if result.is_null() {
+                return Ok(None);
+            }
+            let cursor: web_sys::IdbCursorWithValue = result.unchecked_into();
+            let key = encoding.decode(cursor.key()?)?;
+            let value = js_sys::Uint8Array::new(&cursor.value()?).to_vec();
+            Ok(Some(((key, value), cursor)))
+        };
+        match step.await {
+            Ok(Some((entry, cursor))) => Some((Ok(entry), Cursor::At(request, cursor))),
+            Ok(None) => None,
+            Err(e) => Some((Err(e), Cursor::Done)),
+        }
+    })
+    .boxed_local()
+}
+
+// Puts entries streamed in idb's order of string keys, by UTF-16 code unit,
+// into the order of scan(), by UTF-8 byte (which is code point order), or
+// the reverse of both if descending. The orders only disagree about keys
+// that first differ at a character of U+D800 or above, where UTF-16 has
+// surrogates. Every key that shares what comes before a key's first such
+// character is next to it in both orders, so those runs of keys are
+// buffered and sorted; other keys pass straight through, only being read
+// as the stream is polled.
+fn utf8_order<'a, V: 'a>(
+    entries: LocalBoxStream<'a, Result<(String, V)>>,
+    descending: bool,
+) -> LocalBoxStream<'a, Result<(String, V)>> {
+    // The entries, an entry read past the end of the last run, and the
+    // sorted run being returned.
+    let start = (entries, None, Vec::new().into_iter());
+    stream::unfold(start, move |(mut entries, mut held, mut run)| async move {
+        if let Some(entry) = run.next() {
+            return Some((Ok(entry), (entries, held, run)));
+        }
+        let entry = match held.take() {
+            Some(entry) => entry,
+            None => match entries.next().await? {
+                Ok(entry) => entry,
+                Err(e) => return Some((Err(e), (entries, None, run))),
+            },
+        };
+        let split = match entry.0.find(|c| c > '\u{d7ff}') {
+            Some(split) => split,
+            None => return Some((Ok(entry), (entries, None, run))),
+        };
+        let shared = entry.0[..split].to_string();
+        let mut sorted = vec![entry];
+        while let Some(next) = entries.next().await {
+            match next {
+                Ok(next) if next.0.starts_with(&shared) => sorted.push(next),
+                Ok(next) => {
+                    held = Some(next);
+                    break;
+                }
+                Err(e) => return Some((Err(e), (entries, None, run))),
+            }
+        }
+        match descending {
+            true => sorted.sort_by(|a, b| b.0.cmp(&a.0)),
+            false => sorted.sort_by(|a, b| a.0.cmp(&b.0)),
+        }
+        let mut run = sorted.into_iter();
+        let first = run.next().map(Ok);
+        first.map(|first| (first, (entries, held, run)))
+    })
+    .boxed_local()
+}
+
+// What scan_stream_batched() reads next: the range of the entries after
+// the last batch, or nothing once a batch came back short.
+enum Batch {
+    Next(Option<IdbKeyRange>),
+    Done,
+}
+
+// Like scan_stream_impl(), but reads up to window entries per round trip
+// with getAllKeys() and getAll(), only reading the next batch once the
+// stream has been polled past the last one.
+fn scan_stream_batched<'a>(
+    tx: &IdbTransaction,
+    encoding: KeyEncoding,
+    prefix: &str,
+    window: u32,
+) -> LocalBoxStream<'a, Result<(String, Vec<u8>)>> {
+    let range = match encoding.prefix_range(prefix) {
+        Ok(range) => range,
+        Err(e) => return stream::once(async { Err(e) }).boxed_local(),
+    };
+    // Later batches start after the last key read, and end where the
+    // prefix's range does.
+    let upper = match range.as_ref().map(|r| r.upper()).transpose() {
+        Ok(upper) => upper,
+        Err(e) => return stream::once(async { Err(e.into()) }).boxed_local(),
+    };
+    let tx = tx.clone();
+    stream::unfold(Batch::Next(range), move |batch| {
+        let (tx, upper) = (tx.clone(), upper.clone());
+        async move {
+            let range = match batch {
+                Batch::Done => return None,
+                Batch::Next(range) => range,
+            };
+            SCAN_ROUND_TRIPS.with(|n| n.set(n.get() + 1));
+            match read_batch(&tx, encoding, range, upper.as_ref(), window).await {
+                Ok((entries, next)) => Some((Ok(entries), next)),
+                Err(e) => Some((Err(e), Batch::Done)),
+            }
+        }
+    })
+    .map_ok(|entries| stream::iter(entries.into_iter().map(Ok)))
+    .try_flatten()
+    .boxed_local()
+}
+
+// Reads the first window entries in range, returning them and what to
+// read after them. upper is the upper bound of the whole scan, if any.
+async fn read_batch(
+    tx: &IdbTransaction,
+    encoding: KeyEncoding,
+    range: Option<IdbKeyRange>,
+    upper: Option<&JsValue>,
+    window: u32,
+) -> Result<(Vec<(String, Vec<u8>)>, Batch)> {
+    let store = tx.object_store(OBJECT_STORE)?;
+    let query = range.map_or_else(JsValue::undefined, JsValue::from);
+    let (keys, values) = join(
+        await_request(&store.get_all_keys_with_key_and_limit(&query, window)?),
+        await_request(&store.get_all_with_key_and_limit(&query, window)?),
+    )
+    .await;
+    let keys = js_sys::Array::from(&keys?);
+    let values = js_sys::Array::from(&values?);
+    let next = match keys.length() {
+        n if n < window => Batch::Done,
+        n => {
+            let last = keys.get(n - 1);
+            Batch::Next(Some(match upper {
+                None => IdbKeyRange::lower_bound_with_open(&last, true)?,
+                Some(upper) => IdbKeyRange::bound_with_lower_open(&last, upper, true)?,
+            }))
+        }
+    };
+    let mut entries = Vec::with_capacity(keys.length() as usize);
+    for (key, value) in keys.iter().zip(values.iter()) {
+        entries.push((
+            encoding.decode(key)?,
+            js_sys::Uint8Array::new(&value).to_vec(),
+        ));
+    }
+    Ok((entries, next))
+}
+
+// Like scan_impl(), but only asks idb for keys, so values are never read
+// out of the database or copied into wasm memory.
+async fn scan_keys_impl(
+    tx: &IdbTransaction,
+    encoding: KeyEncoding,
+    prefix: &str,
+) -> Result<Vec<String>> {
+    let store = tx.object_store(OBJECT_STORE)?;
+    let request = match encoding.prefix_range(prefix)? {
+        None => store.get_all_keys()?,
+        Some(range) => store.get_all_keys_with_key(&range)?,
+    };
+    let mut keys = js_sys::Array::from(&await_request(&request).await?)
+        .iter()
+        .map(|key| encoding.decode(key))
+        .collect::<Result<Vec<String>>>()?;
+    // As in scan_impl(), sort by UTF-8.
+    keys.sort();
+    Ok(keys)
+}
+
+// Returns the first (or, if last, the last) key that skip() doesn't match,
+// with a key cursor opened from that end, usually reading a single key.
+// String keys go through utf8_order(), which only reads further when the
+// keys at that end have characters where UTF-16 and UTF-8 order differ.
+async fn end_key_impl(
+    tx: &IdbTransaction,
+    encoding: KeyEncoding,
+    last: bool,
+    skip: impl Fn(&str) -> bool,
+) -> Result<Option<String>> {
+    let direction = match last {
+        true => IdbCursorDirection::Prev,
+        false => IdbCursorDirection::Next,
+    };
+    let request = tx
+        .object_store(OBJECT_STORE)?
+        .open_key_cursor_with_range_and_direction(&JsValue::UNDEFINED, direction)?;
+    let keys = stream::try_unfold(None, move |cursor: Option<web_sys::IdbCursor>| {
+        let request = request.clone();
+        async move {
+            if let Some(cursor) = cursor {
+                cursor.continue_()?;
+            }
+            let result = await_request(&request).await?;
+            if result.is_null() {
+                return Ok(None);
+            }
+            let cursor: web_sys::IdbCursor = result.unchecked_into();
+            let key = encoding.decode(cursor.key()?)?;
+            Ok(Some(((key, ()), Some(cursor))))
+        }
+    })
+    .boxed_local();
+    let mut keys = match encoding {
+        KeyEncoding::Binary => keys,
+        KeyEncoding::String => utf8_order(keys, last),
+    };
+    while let Some((key, ())) = keys.try_next().await? {
+        if !skip(&key) {
+            return Ok(Some(key));
+        }
+    }
+    Ok(None)
+}
+
 #[derive(PartialEq, Eq, Debug)]
 enum WriteState {
     Open,
@@ -207,44 +1356,86 @@ enum WriteState {
     Errored,
 }
 
+// As for ReadTransaction, the db guard must be the last field so that it
+// is released only after everything tied to tx has been dropped.
 struct WriteTransaction<'a> {
-    #[allow(dead_code)]
-    db: RwLockWriteGuard<'a, IdbDatabase>,
     tx: IdbTransaction,
+    keys: KeyEncoding,
     pending: Mutex<HashMap<String, Option<Vec<u8>>>>,
+    // Whether the store has a key, for the keys reads in this transaction
+    // looked up in the store. Deleting a key the store doesn't have only
+    // needs its pending write dropped, so that e.g. a put then del of a new
+    // key makes no write requests on commit.
+    known: RefCell<HashMap<String, bool>>,
     pair: Arc<(Mutex<WriteState>, Condvar)>,
     callbacks: Vec<Closure<dyn FnMut()>>,
+    max_value_bytes: Option<usize>,
+    max_keys: Option<usize>,
+    // For the read of commit_and_read().
+    scan_window: u32,
+    events: TxEvents,
+    // Whether a request has been made on tx yet.
+    requested: Cell<bool>,
+    // The store's flag for db having been closed by a versionchange.
+    closed: Rc<Cell<bool>>,
+    #[allow(dead_code)]
+    db: RwLockWriteGuard<'a, IdbDatabase>,
 }
 
 impl WriteTransaction<'_> {
-    fn new(db: RwLockWriteGuard<'_, IdbDatabase>, tx: IdbTransaction) -> Result<WriteTransaction> {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        db: RwLockWriteGuard<'_, IdbDatabase>,
+        tx: IdbTransaction,
+        keys: KeyEncoding,
+        max_value_bytes: Option<usize>,
+        max_keys: Option<usize>,
+        scan_window: u32,
+        events: TxEvents,
+        closed: Rc<Cell<bool>>,
+    ) -> Result<WriteTransaction> {
+        events.send(TxEvent::Opened);
         let mut wt = WriteTransaction {
-            db,
             tx,
+            keys,
             pair: Arc::new((Mutex::new(WriteState::Open), Condvar::new())),
             pending: Mutex::new(HashMap::new()),
+            known: RefCell::new(HashMap::new()),
             callbacks: Vec::with_capacity(3),
+            max_value_bytes,
+            max_keys,
+            scan_window,
+            events,
+            requested: Cell::new(false),
+            closed,
+            db,
         };
 
         let tx = &wt.tx;
-        let callback = wt.tx_callback(WriteState::Committed);
+        let callback = wt.tx_callback(WriteState::Committed, TxEvent::Committed);
         tx.set_oncomplete(Some(callback.as_ref().unchecked_ref()));
         wt.callbacks.push(callback);
 
-        let callback = wt.tx_callback(WriteState::Aborted);
+        let callback = wt.tx_callback(WriteState::Aborted, TxEvent::Aborted);
         tx.set_onabort(Some(callback.as_ref().unchecked_ref()));
         wt.callbacks.push(callback);
 
-        let callback = wt.tx_callback(WriteState::Errored);
+        let callback = wt.tx_callback(WriteState::Errored, TxEvent::Errored);
         tx.set_onerror(Some(callback.as_ref().unchecked_ref()));
         wt.callbacks.push(callback);
 
         Ok(wt)
     }
 
-    fn tx_callback(&self, new_state: WriteState) -> Closure<dyn FnMut()> {
+    fn tx_callback(
+        &self,
+        new_state: WriteState,
+        event: fn(u64) -> TxEvent,
+    ) -> Closure<dyn FnMut()> {
         let pair = self.pair.clone();
+        let events = self.events.clone();
         Closure::once(move || {
+            events.send(event);
             task::block_on(async move {
                 let (lock, cv) = &*pair;
                 let mut state = lock.lock().await;
@@ -253,6 +1444,149 @@ impl WriteTransaction<'_> {
             });
         })
     }
+
+    // Makes the pending writes and waits for the transaction to finish.
+    async fn apply(&self) -> Result<CommitReport> {
+        // Define rollback() to succeed if no writes have occurred, even if
+        // the underlying transaction has exited. Users who expose themselves
+        // to this would notice if they performed any reads after exposing
+        // themselves to a situation where the transaction would autocommit.
+        let mut pending = self.pending.lock().await;
+        if pending.is_empty() {
+            return Ok(CommitReport::default());
+        }
+        // Requests on a closed connection fail with an InvalidStateError
+        // that doesn't say why.
+        if self.closed.get() {
+            return Err(StoreError::StoreClosed);
+        }
+        let mut abort_guard = AbortOnDrop {
+            tx: &self.tx,
+            armed: true,
+        };
+
+        // Deleting a key the store doesn't have would do nothing, so those
+        // deletes are dropped, however the key came to be pending. They
+        // still count in the report, as they do for other stores. With
+        // max_keys, whether each put adds a key is needed too.
+        let report = CommitReport::new(&pending);
+        let checked = pending
+            .iter()
+            .filter(|(_, value)| value.is_none() || self.max_keys.is_some());
+        let existed = self
+            .existed(checked.map(|(key, _)| key.clone()).collect())
+            .await?;
+        pending.retain(|key, value| value.is_some() || existed[key]);
+        if pending.is_empty() {
+            abort_guard.armed = false;
+            return Ok(report);
+        }
+        if self.max_keys.is_some() {
+            let added: isize = pending
+                .iter()
+                .map(|(key, value)| match (value.is_some(), existed[key]) {
+                    (true, false) => 1,
+                    (false, true) => -1,
+                    _ => 0,
+                })
+                .sum();
+            // Only a commit that adds keys can exceed the quota.
+            if added > 0 {
+                check_key_count(count_impl(self.tx()).await?, added, self.max_keys)?;
+            }
+        }
+
+        // HashMap order varies from run to run. Sorting makes the requests,
+        // and so anything observed of them, the same for the same writes.
+        let mut entries: Vec<_> = pending.iter().collect();
+        entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
+
+        let store = self.tx().object_store(OBJECT_STORE)?;
+        let mut requests = Vec::with_capacity(entries.len());
+        for (key, value) in entries {
+            requests.push(match value {
+                Some(v) => {
+                    self.events.send(|id| TxEvent::Put(id, key.clone()));
+                    store.put_with_key(&js_sys::Uint8Array::from(&v[..]), &self.keys.encode(key))?
+                }
+                None => {
+                    self.events.send(|id| TxEvent::Delete(id, key.clone()));
+                    store.delete(&self.keys.encode(key))?
+                }
+            });
+        }
+        let results = join_all(requests.iter().map(await_request)).await;
+
+        // A failed request aborts the transaction. Wait for that to finish
+        // before returning so our callbacks are still around to see it.
+        let (lock, cv) = &*self.pair;
+        let state = cv
+            .wait_until(lock.lock().await, |state| *state != WriteState::Open)
+            .await;
+        abort_guard.armed = false;
+        if let Some(e) = self.tx.error() {
+            return Err(format!("{:?}", e).into());
+        }
+        if let Some(Err(e)) = results.into_iter().find(|r| r.is_err()) {
+            return Err(e);
+        }
+        if *state != WriteState::Committed {
+            return Err(StoreError::Str("Transaction aborted".into()));
+        }
+        Ok(report)
+    }
+
+    // Returns whether the store had each of keys before this transaction:
+    // what this transaction's reads found where they looked the key up,
+    // and otherwise what requests for the rest, made all at once, find.
+    async fn existed(&self, keys: Vec<String>) -> Result<HashMap<String, bool>> {
+        let mut existed = HashMap::with_capacity(keys.len());
+        let mut unknown = vec![];
+        for key in keys {
+            match self.known.borrow().get(&key) {
+                Some(has) => {
+                    existed.insert(key, *has);
+                }
+                None => unknown.push(key),
+            }
+        }
+        if unknown.is_empty() {
+            return Ok(existed);
+        }
+        let tx = self.tx();
+        let found = join_all(unknown.iter().map(|key| has_impl(tx, self.keys, key))).await;
+        for (key, has) in unknown.into_iter().zip(found) {
+            existed.insert(key, has?);
+        }
+        Ok(existed)
+    }
+
+    // Returns tx to make a request on, noting the first request.
+    fn tx(&self) -> &IdbTransaction {
+        if !self.requested.replace(true) {
+            self.events.send(TxEvent::FirstRequest);
+        }
+        &self.tx
+    }
+}
+
+// Aborts tx on drop while armed. commit() arms one for as long as the tx
+// may still commit, so that if its future is dropped part way through (e.g.
+// the task is cancelled) the requests it has issued are rolled back rather
+// than left to auto-commit without anyone seeing the result.
+struct AbortOnDrop<'a> {
+    tx: &'a IdbTransaction,
+    armed: bool,
+}
+
+impl Drop for AbortOnDrop<'_> {
+    fn drop(&mut self) {
+        if self.armed {
+            if let Err(e) = self.tx.abort() {
+                warn!("Abort of cancelled commit failed: {:?}", e);
+            }
+        }
+    }
 }
 
 #[async_trait(?Send)]
@@ -261,7 +1595,11 @@ impl Read for WriteTransaction<'_> {
         match self.pending.lock().await.get(key) {
             Some(Some(_)) => Ok(true),
             Some(None) => Ok(false),
-            None => has_impl(&self.tx, key).await,
+            None => {
+                let has = has_impl(self.tx(), self.keys, key).await?;
+                self.known.borrow_mut().insert(key.into(), has);
+                Ok(has)
+            }
         }
     }
 
@@ -269,9 +1607,43 @@ impl Read for WriteTransaction<'_> {
         match self.pending.lock().await.get(key) {
             Some(Some(v)) => Ok(Some(v.to_vec())),
             Some(None) => Ok(None),
-            None => get_impl(&self.tx, key).await,
+            None => {
+                let value = get_impl(self.tx(), self.keys, key).await?;
+                self.known.borrow_mut().insert(key.into(), value.is_some());
+                Ok(value)
+            }
         }
     }
+
+    async fn scan(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        let pending = self.pending.lock().await;
+        Ok(scan_pending(
+            scan_impl(self.tx(), self.keys, prefix).await?,
+            &pending,
+            prefix,
+        ))
+    }
+
+    async fn scan_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let pending = self.pending.lock().await;
+        Ok(scan_keys_pending(
+            scan_keys_impl(self.tx(), self.keys, prefix).await?,
+            &pending,
+            prefix,
+        ))
+    }
+
+    async fn first_key(&self) -> Result<Option<String>> {
+        let pending = self.pending.lock().await;
+        let base = end_key_impl(self.tx(), self.keys, false, |k| pending.contains_key(k)).await?;
+        Ok(end_key_pending(base, &pending, false))
+    }
+
+    async fn last_key(&self) -> Result<Option<String>> {
+        let pending = self.pending.lock().await;
+        let base = end_key_impl(self.tx(), self.keys, true, |k| pending.contains_key(k)).await?;
+        Ok(end_key_pending(base, &pending, true))
+    }
 }
 
 #[async_trait(?Send)]
@@ -283,6 +1655,7 @@ impl Write for WriteTransaction<'_> {
     // We hold writes in memory until the API user calls commit
     // to ensure that we don't let partial transactions auto-commit.
     async fn put(&self, key: &str, value: &[u8]) -> Result<()> {
+        check_value_size(value, self.max_value_bytes)?;
         self.pending
             .lock()
             .await
@@ -291,46 +1664,48 @@ impl Write for WriteTransaction<'_> {
     }
 
     async fn del(&self, key: &str) -> Result<()> {
-        self.pending.lock().await.insert(key.into(), None);
+        let mut pending = self.pending.lock().await;
+        if self.known.borrow().get(key) == Some(&false) {
+            pending.remove(key);
+        } else {
+            pending.insert(key.into(), None);
+        }
         Ok(())
     }
 
-    async fn commit(self: Box<Self>) -> Result<()> {
-        // Define rollback() to succeed if no writes have occurred, even if
-        // the underlying transaction has exited. Users who expose themselves
-        // to this would notice if they performed any reads after exposing
-        // themselves to a situation where the transaction would autocommit.
-        let pending = self.pending.lock().await;
-        if pending.is_empty() {
-            return Ok(());
-        }
+    async fn reserve(&self, additional: usize) {
+        self.pending.lock().await.reserve(additional);
+    }
 
-        let store = self.tx.object_store(OBJECT_STORE)?;
-        let mut callbacks = Vec::with_capacity(pending.len());
-        let mut requests: Vec<oneshot::Receiver<()>> = Vec::with_capacity(pending.len());
-        for (key, value) in pending.iter() {
-            let request = match value {
-                Some(v) => store.put_with_key(&js_sys::Uint8Array::from(&v[..]), &key.into())?,
-                None => store.delete(&key.into())?,
-            };
-            let (callback, receiver) = IdbStore::oneshot_callback();
-            request.set_onsuccess(Some(callback.as_ref().unchecked_ref()));
-            callbacks.push(callback);
-            requests.push(receiver);
-        }
-        join_all(requests).await;
+    #[cfg(debug_assertions)]
+    async fn pending_summary(&self) -> PendingSummary {
+        PendingSummary::new(&*self.pending.lock().await)
+    }
 
-        let (lock, cv) = &*self.pair;
-        let state = cv
-            .wait_until(lock.lock().await, |state| *state != WriteState::Open)
-            .await;
-        if let Some(e) = self.tx.error() {
-            return Err(format!("{:?}", e).into());
-        }
-        if *state != WriteState::Committed {
-            return Err(StoreError::Str("Transaction aborted".into()));
-        }
-        Ok(())
+    async fn commit(self: Box<Self>) -> Result<CommitReport> {
+        self.apply().await
+    }
+
+    // Idb ends the transaction once it commits, so the read is a new
+    // readonly transaction, started under the write's hold on the database.
+    async fn commit_and_read<'b>(self: Box<Self>) -> Result<Box<dyn Read + 'b>>
+    where
+        Self: 'b,
+    {
+        self.apply().await?;
+        let WriteTransaction {
+            keys,
+            scan_window,
+            db,
+            ..
+        } = *self;
+        let tx = db.transaction_with_str(OBJECT_STORE)?;
+        Ok(Box::new(CachedRead::new(Box::new(ReadTransaction::new(
+            Box::new(db),
+            tx,
+            keys,
+            scan_window,
+        )?))))
     }
 
     async fn rollback(self: Box<Self>) -> Result<()> {
@@ -358,6 +1733,36 @@ impl Write for WriteTransaction<'_> {
         }
         Ok(())
     }
+
+    // Idb commits a transaction as soon as control returns to the event loop
+    // with no requests outstanding on it. Writes are buffered until commit(),
+    // so they are safe, but a read after awaiting something else (a timer,
+    // fetch, another store) fails once the transaction has ended. To prevent
+    // that we keep a get of KEEP_ALIVE_KEY outstanding for as long as work
+    // runs, issuing a new one each time the last completes.
+    //
+    // Tradeoffs: this generates a steady stream of no-op requests, and holds
+    // the transaction, and so our exclusive lock on the database, open for
+    // as long as work takes. Use it for short sections only.
+    async fn keep_alive(&self, work: LocalBoxFuture<'_, ()>) -> Result<()> {
+        let mut work = work.fuse();
+        loop {
+            let mut ping = get_impl(self.tx(), self.keys, KEEP_ALIVE_KEY)
+                .boxed_local()
+                .fuse();
+            select! {
+                () = work => {
+                    // Let the outstanding request finish so its callbacks
+                    // aren't dropped while idb can still call them.
+                    ping.await?;
+                    return Ok(());
+                }
+                result = ping => {
+                    result?;
+                }
+            }
+        }
+    }
 }
 
 mod tests {
@@ -0,0 +1,159 @@
+//! The write transaction of Store::write_lazy(), which only starts a real
+//! write transaction at the first put() or del().
+//!
+//! Until then each read uses a read transaction of its own, so a handle
+//! that turns out to only read never takes the store's write lock and
+//! doesn't block other writers. The price is isolation: reads before the
+//! first write can see writes other transactions commit in between, and
+//! the first write can be based on values that have since changed. From
+//! the first write on, the handle is an ordinary write transaction.
+#[cfg(debug_assertions)]
+use crate::kv::PendingSummary;
+use crate::kv::{CommitReport, Read, Result, Store, Write};
+use async_std::sync::Mutex;
+use async_trait::async_trait;
+use futures::future::LocalBoxFuture;
+
+pub struct LazyWrite<'a, S: Store + ?Sized> {
+    store: &'a S,
+    // The real write transaction, once there has been a write.
+    inner: Mutex<Option<Box<dyn Write + 'a>>>,
+}
+
+impl<'a, S: Store + ?Sized> LazyWrite<'a, S> {
+    pub fn new(store: &'a S) -> LazyWrite<'a, S> {
+        LazyWrite {
+            store,
+            inner: Mutex::new(None),
+        }
+    }
+}
+
+// Runs a read against the write transaction if there is one, otherwise
+// against a new read transaction.
+macro_rules! with_read {
+    ($self:ident, $tx:ident => $read:expr) => {{
+        let inner = $self.inner.lock().await;
+        match inner.as_ref() {
+            Some($tx) => $read.await,
+            None => {
+                let $tx = $self.store.read().await?;
+                $read.await
+            }
+        }
+    }};
+}
+
+// Runs a write against the write transaction, starting it if need be.
+macro_rules! with_write {
+    ($self:ident, $tx:ident => $write:expr) => {{
+        let mut inner = $self.inner.lock().await;
+        if inner.is_none() {
+            *inner = Some($self.store.write().await?);
+        }
+        let $tx = inner.as_ref().unwrap();
+        $write.await
+    }};
+}
+
+#[async_trait(?Send)]
+impl<S: Store + ?Sized> Read for LazyWrite<'_, S> {
+    async fn has(&self, key: &str) -> Result<bool> {
+        with_read!(self, tx => tx.has(key))
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        with_read!(self, tx => tx.get(key))
+    }
+
+    async fn scan(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        with_read!(self, tx => tx.scan(prefix))
+    }
+
+    async fn scan_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        with_read!(self, tx => tx.scan_keys(prefix))
+    }
+
+    async fn count(&self) -> Result<usize> {
+        with_read!(self, tx => tx.count())
+    }
+
+    async fn count_prefix(&self, prefix: &str) -> Result<usize> {
+        with_read!(self, tx => tx.count_prefix(prefix))
+    }
+
+    async fn first_key(&self) -> Result<Option<String>> {
+        with_read!(self, tx => tx.first_key())
+    }
+
+    async fn last_key(&self) -> Result<Option<String>> {
+        with_read!(self, tx => tx.last_key())
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: Store + ?Sized> Write for LazyWrite<'_, S> {
+    fn as_read(&self) -> &dyn Read {
+        self
+    }
+
+    async fn put(&self, key: &str, value: &[u8]) -> Result<()> {
+        with_write!(self, tx => tx.put(key, value))
+    }
+
+    async fn del(&self, key: &str) -> Result<()> {
+        with_write!(self, tx => tx.del(key))
+    }
+
+    // Only a started write transaction has a buffer to size; a hint isn't
+    // worth starting one for.
+    async fn reserve(&self, additional: usize) {
+        if let Some(tx) = self.inner.lock().await.as_ref() {
+            tx.reserve(additional).await;
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    async fn pending_summary(&self) -> PendingSummary {
+        match self.inner.lock().await.as_ref() {
+            Some(tx) => tx.pending_summary().await,
+            None => PendingSummary::default(),
+        }
+    }
+
+    async fn commit(self: Box<Self>) -> Result<CommitReport> {
+        match self.inner.into_inner() {
+            Some(tx) => tx.commit().await,
+            None => Ok(CommitReport::default()),
+        }
+    }
+
+    // Without a write there is nothing to commit, so a new read transaction
+    // sees the same state.
+    async fn commit_and_read<'b>(self: Box<Self>) -> Result<Box<dyn Read + 'b>>
+    where
+        Self: 'b,
+    {
+        match self.inner.into_inner() {
+            Some(tx) => tx.commit_and_read().await,
+            None => self.store.read().await,
+        }
+    }
+
+    async fn rollback(self: Box<Self>) -> Result<()> {
+        match self.inner.into_inner() {
+            Some(tx) => tx.rollback().await,
+            None => Ok(()),
+        }
+    }
+
+    async fn keep_alive(&self, work: LocalBoxFuture<'_, ()>) -> Result<()> {
+        match self.inner.lock().await.as_ref() {
+            Some(tx) => tx.keep_alive(work).await,
+            None => {
+                work.await;
+                Ok(())
+            }
+        }
+    }
+}
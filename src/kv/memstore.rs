@@ -1,19 +1,53 @@
-use crate::kv::{Read, Result, Store, Write};
-use async_std::sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+#[cfg(feature = "fair_locking")]
+use crate::kv::fair_lock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+#[cfg(debug_assertions)]
+use crate::kv::PendingSummary;
+use crate::kv::{
+    check_key_count, check_value_size, end_key_pending, scan_keys_pending, scan_pending,
+    CommitReport, IsolationLevel, Read, Result, Store, Write, RW_LOCK_ISOLATION,
+};
+use async_std::sync::Mutex;
+#[cfg(not(feature = "fair_locking"))]
+use async_std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 use async_trait::async_trait;
+#[cfg(test)]
+use std::cell::Cell;
 use std::collections::HashMap;
 
 pub struct MemStore {
     map: RwLock<HashMap<String, Vec<u8>>>,
+    max_value_bytes: Option<usize>,
+    max_keys: Option<usize>,
 }
 
 impl MemStore {
     pub fn new() -> MemStore {
+        MemStore::with_capacity(0)
+    }
+
+    /// Creates a store with room for capacity keys before it has to grow,
+    /// for bulk loads of known size.
+    pub fn with_capacity(capacity: usize) -> MemStore {
         MemStore {
-            map: RwLock::new(HashMap::new()),
+            map: RwLock::new(HashMap::with_capacity(capacity)),
+            max_value_bytes: None,
+            max_keys: None,
         }
     }
 
+    /// Causes put() of any single value longer than max to fail with
+    /// StoreError::ValueTooLarge. None (the default) means unlimited.
+    pub fn set_max_value_bytes(&mut self, max: Option<usize>) {
+        self.max_value_bytes = max;
+    }
+
+    /// Causes commit() of a transaction that would leave more than max keys
+    /// in the store to fail with StoreError::KeyQuotaExceeded. None (the
+    /// default) means unlimited.
+    pub fn set_max_keys(&mut self, max: Option<usize>) {
+        self.max_keys = max;
+    }
+
     pub async fn new_async() -> Box<dyn Store> {
         Box::new(MemStore::new())
     }
@@ -34,8 +68,45 @@ impl Store for MemStore {
 
     async fn write<'a>(&'a self) -> Result<Box<dyn Write + 'a>> {
         let guard = self.map.write().await;
-        Ok(Box::new(WriteTransaction::new(guard)))
+        Ok(Box::new(WriteTransaction::new(
+            guard,
+            self.max_value_bytes,
+            self.max_keys,
+        )))
     }
+
+    fn isolation(&self) -> IsolationLevel {
+        RW_LOCK_ISOLATION
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    // The number of values scans have copied out of maps, so tests can check
+    // that scan_keys() copies none.
+    static VALUES_SCANNED: Cell<usize> = const { Cell::new(0) };
+}
+
+fn scan_map(map: &HashMap<String, Vec<u8>>, prefix: &str) -> Vec<(String, Vec<u8>)> {
+    let mut entries: Vec<(String, Vec<u8>)> = map
+        .iter()
+        .filter(|(k, _)| k.starts_with(prefix))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    #[cfg(test)]
+    VALUES_SCANNED.with(|n| n.set(n.get() + entries.len()));
+    entries
+}
+
+fn scan_map_keys(map: &HashMap<String, Vec<u8>>, prefix: &str) -> Vec<String> {
+    let mut keys: Vec<String> = map
+        .keys()
+        .filter(|k| k.starts_with(prefix))
+        .cloned()
+        .collect();
+    keys.sort();
+    keys
 }
 
 struct ReadTransaction<'a> {
@@ -60,20 +131,76 @@ impl Read for ReadTransaction<'_> {
             Some(v) => Ok(Some(v.to_vec())),
         }
     }
+
+    async fn scan(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        Ok(scan_map(&self.map, prefix))
+    }
+
+    async fn scan_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        Ok(scan_map_keys(&self.map, prefix))
+    }
+
+    async fn count(&self) -> Result<usize> {
+        Ok(self.map.len())
+    }
+
+    async fn count_prefix(&self, prefix: &str) -> Result<usize> {
+        Ok(self.map.keys().filter(|k| k.starts_with(prefix)).count())
+    }
+
+    async fn first_key(&self) -> Result<Option<String>> {
+        Ok(self.map.keys().min().cloned())
+    }
+
+    async fn last_key(&self) -> Result<Option<String>> {
+        Ok(self.map.keys().max().cloned())
+    }
 }
 
 struct WriteTransaction<'a> {
     map: RwLockWriteGuard<'a, HashMap<String, Vec<u8>>>,
+    // HashMap::new() doesn't allocate, so the buffer costs nothing until
+    // the first put() or del(), and a transaction that only reads never
+    // allocates it.
     pending: Mutex<HashMap<String, Option<Vec<u8>>>>,
+    max_value_bytes: Option<usize>,
+    max_keys: Option<usize>,
 }
 
 impl WriteTransaction<'_> {
-    fn new(map: RwLockWriteGuard<'_, HashMap<String, Vec<u8>>>) -> WriteTransaction {
+    fn new(
+        map: RwLockWriteGuard<'_, HashMap<String, Vec<u8>>>,
+        max_value_bytes: Option<usize>,
+        max_keys: Option<usize>,
+    ) -> WriteTransaction {
         WriteTransaction {
             map,
             pending: Mutex::new(HashMap::new()),
+            max_value_bytes,
+            max_keys,
         }
     }
+
+    // Applies the pending writes to the map.
+    async fn apply(&mut self) -> Result<CommitReport> {
+        let pending = self.pending.lock().await;
+        let added: isize = pending
+            .iter()
+            .map(|(k, v)| match (v.is_some(), self.map.contains_key(k)) {
+                (true, false) => 1,
+                (false, true) => -1,
+                _ => 0,
+            })
+            .sum();
+        check_key_count(self.map.len(), added, self.max_keys)?;
+        for item in pending.iter() {
+            match item.1 {
+                Some(v) => self.map.insert(item.0.clone(), v.clone()),
+                None => self.map.remove(item.0),
+            };
+        }
+        Ok(CommitReport::new(&pending))
+    }
 }
 
 #[async_trait(?Send)]
@@ -93,6 +220,32 @@ impl Read for WriteTransaction<'_> {
             None => Ok(self.map.get(key).map(|v| v.to_vec())),
         }
     }
+
+    async fn scan(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        let pending = self.pending.lock().await;
+        Ok(scan_pending(scan_map(&self.map, prefix), &pending, prefix))
+    }
+
+    async fn scan_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let pending = self.pending.lock().await;
+        Ok(scan_keys_pending(
+            scan_map_keys(&self.map, prefix),
+            &pending,
+            prefix,
+        ))
+    }
+
+    async fn first_key(&self) -> Result<Option<String>> {
+        let pending = self.pending.lock().await;
+        let base = self.map.keys().filter(|k| !pending.contains_key(*k)).min();
+        Ok(end_key_pending(base.cloned(), &pending, false))
+    }
+
+    async fn last_key(&self) -> Result<Option<String>> {
+        let pending = self.pending.lock().await;
+        let base = self.map.keys().filter(|k| !pending.contains_key(*k)).max();
+        Ok(end_key_pending(base.cloned(), &pending, true))
+    }
 }
 
 #[async_trait(?Send)]
@@ -102,6 +255,7 @@ impl Write for WriteTransaction<'_> {
     }
 
     async fn put(&self, key: &str, value: &[u8]) -> Result<()> {
+        check_value_size(value, self.max_value_bytes)?;
         self.pending
             .lock()
             .await
@@ -114,15 +268,28 @@ impl Write for WriteTransaction<'_> {
         Ok(())
     }
 
-    async fn commit(mut self: Box<Self>) -> Result<()> {
-        let pending = self.pending.lock().await;
-        for item in pending.iter() {
-            match item.1 {
-                Some(v) => self.map.insert(item.0.clone(), v.clone()),
-                None => self.map.remove(item.0),
-            };
-        }
-        Ok(())
+    async fn reserve(&self, additional: usize) {
+        self.pending.lock().await.reserve(additional);
+    }
+
+    #[cfg(debug_assertions)]
+    async fn pending_summary(&self) -> PendingSummary {
+        PendingSummary::new(&*self.pending.lock().await)
+    }
+
+    async fn commit(mut self: Box<Self>) -> Result<CommitReport> {
+        self.apply().await
+    }
+
+    // The transaction still holds the map, and reads from it, so it serves
+    // as the read.
+    async fn commit_and_read<'b>(mut self: Box<Self>) -> Result<Box<dyn Read + 'b>>
+    where
+        Self: 'b,
+    {
+        self.apply().await?;
+        self.pending.lock().await.clear();
+        Ok(self)
     }
 
     async fn rollback(self: Box<Self>) -> Result<()> {
@@ -136,10 +303,204 @@ impl Write for WriteTransaction<'_> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::kv::trait_tests;
+    use crate::kv::{diff_stores, trait_tests, HealthReport, StoreDiff, StoreError};
 
     #[async_std::test]
     async fn test_memstore() {
         trait_tests::run_all(&MemStore::new_async).await;
     }
+
+    #[async_std::test]
+    async fn reserve() {
+        let store = MemStore::with_capacity(1000);
+        let capacity = store.map.read().await.capacity();
+        assert!(capacity >= 1000);
+
+        let wt = WriteTransaction::new(store.map.write().await, None, None);
+        wt.reserve(1000).await;
+        let pending_capacity = wt.pending.lock().await.capacity();
+        assert!(pending_capacity >= 1000);
+        for i in 0..1000 {
+            wt.put(&format!("k{}", i), b"v").await.unwrap();
+        }
+        // Neither the buffer nor the map had to grow.
+        assert_eq!(pending_capacity, wt.pending.lock().await.capacity());
+        assert_eq!(1000, Box::new(wt).commit().await.unwrap().puts);
+        assert_eq!(capacity, store.map.read().await.capacity());
+        assert_eq!(1000, store.read().await.unwrap().count().await.unwrap());
+        assert_eq!(Some(b"v".to_vec()), store.get("k999").await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn read_only_write_allocates_no_pending() {
+        let store = MemStore::new();
+        store.put("a", b"1").await.unwrap();
+
+        let wt = WriteTransaction::new(store.map.write().await, None, None);
+        assert_eq!(Some(b"1".to_vec()), wt.get("a").await.unwrap());
+        assert!(!wt.has("b").await.unwrap());
+        assert_eq!(1, wt.scan("").await.unwrap().len());
+        assert_eq!(0, wt.pending.lock().await.capacity());
+        assert_eq!(
+            CommitReport::default(),
+            Box::new(wt).commit().await.unwrap()
+        );
+
+        let wt = WriteTransaction::new(store.map.write().await, None, None);
+        wt.put("b", b"2").await.unwrap();
+        assert!(wt.pending.lock().await.capacity() > 0);
+        assert_eq!(1, Box::new(wt).commit().await.unwrap().puts);
+        assert_eq!(2, store.read().await.unwrap().count().await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn divergent_stores() {
+        let (a, b) = (MemStore::new(), MemStore::new());
+        assert!(diff_stores(&a, &b).await.unwrap().is_empty());
+
+        for (key, value) in &[("same", "1"), ("changed", "a"), ("only/a", "x"), ("z", "")] {
+            a.put(key, value.as_bytes()).await.unwrap();
+        }
+        for (key, value) in &[("same", "1"), ("changed", "b"), ("only/b", "y"), ("z", "z")] {
+            b.put(key, value.as_bytes()).await.unwrap();
+        }
+        let diff = diff_stores(&a, &b).await.unwrap();
+        assert_eq!(
+            StoreDiff {
+                only_a: vec!["only/a".into()],
+                only_b: vec!["only/b".into()],
+                differing: vec!["changed".into(), "z".into()],
+            },
+            diff
+        );
+        assert!(!diff.is_empty());
+
+        // The other way around.
+        let diff = diff_stores(&b, &a).await.unwrap();
+        assert_eq!(
+            (vec!["only/b".to_string()], vec!["only/a".to_string()]),
+            (diff.only_a, diff.only_b)
+        );
+    }
+
+    #[async_std::test]
+    async fn scan_keys_skips_values() {
+        let store = MemStore::new();
+        store.put("a", &[0; 1 << 20]).await.unwrap();
+        store.put("b", b"1").await.unwrap();
+        let scanned = || VALUES_SCANNED.with(|n| n.get());
+
+        let rt = store.read().await.unwrap();
+        let before = scanned();
+        assert_eq!(vec!["a", "b"], rt.scan_keys("").await.unwrap());
+        assert_eq!(before, scanned());
+        assert_eq!(2, rt.scan("").await.unwrap().len());
+        assert_eq!(before + 2, scanned());
+        drop(rt);
+
+        let wt = store.write().await.unwrap();
+        wt.put("c", b"2").await.unwrap();
+        let before = scanned();
+        assert_eq!(vec!["a", "b", "c"], wt.scan_keys("").await.unwrap());
+        assert_eq!(before, scanned());
+    }
+
+    // A store whose write transactions can't be started.
+    struct ReadOnly(MemStore);
+
+    #[async_trait(?Send)]
+    impl Store for ReadOnly {
+        async fn read<'a>(&'a self) -> Result<Box<dyn Read + 'a>> {
+            self.0.read().await
+        }
+
+        async fn write<'a>(&'a self) -> Result<Box<dyn Write + 'a>> {
+            Err(StoreError::Str("read-only".into()))
+        }
+
+        fn isolation(&self) -> IsolationLevel {
+            self.0.isolation()
+        }
+    }
+
+    #[test]
+    fn isolation_level() {
+        // See the comment on IdbStore::db.
+        let want = match cfg!(feature = "fair_locking") {
+            true => IsolationLevel::StrictlySerializable,
+            false => IsolationLevel::Serializable,
+        };
+        assert_eq!(want, MemStore::new().isolation());
+    }
+
+    #[async_std::test]
+    async fn health_check_unwritable() {
+        let store = ReadOnly(MemStore::new());
+        assert_eq!(
+            HealthReport {
+                writable: false,
+                object_store_present: true,
+                quota_ok: true,
+            },
+            store.health_check().await.unwrap()
+        );
+    }
+
+    #[async_std::test]
+    async fn max_value_bytes() {
+        let mut store = MemStore::new();
+        store.put("big", &[0; 1 << 20]).await.unwrap();
+
+        store.set_max_value_bytes(Some(3));
+        store.put("k", b"abc").await.unwrap();
+        let wt = store.write().await.unwrap();
+        match wt.put("k", b"abcd").await {
+            Err(StoreError::ValueTooLarge { len: 4, max: 3 }) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        wt.commit().await.unwrap();
+        assert_eq!(Some(b"abc".to_vec()), store.get("k").await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn max_keys() {
+        let mut store = MemStore::new();
+        store.put("a", b"1").await.unwrap();
+        store.set_max_keys(Some(3));
+
+        // Overwrites and deletes of missing keys don't count.
+        let wt = store.write().await.unwrap();
+        wt.put("a", b"2").await.unwrap();
+        wt.put("b", b"1").await.unwrap();
+        wt.put("c", b"1").await.unwrap();
+        wt.del("x").await.unwrap();
+        wt.commit().await.unwrap();
+        assert_eq!(3, store.read().await.unwrap().count().await.unwrap());
+
+        let wt = store.write().await.unwrap();
+        wt.put("d", b"1").await.unwrap();
+        wt.put("c", b"2").await.unwrap();
+        match wt.commit().await {
+            Err(StoreError::KeyQuotaExceeded { count: 4, max: 3 }) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        let rt = store.read().await.unwrap();
+        assert!(!rt.has("d").await.unwrap());
+        assert_eq!(Some(b"1".to_vec()), rt.get("c").await.unwrap());
+        drop(rt);
+
+        // Replacing a key stays within the quota.
+        let wt = store.write().await.unwrap();
+        wt.del("a").await.unwrap();
+        wt.put("d", b"1").await.unwrap();
+        wt.commit().await.unwrap();
+
+        // Stores over a lowered quota can still shrink.
+        store.set_max_keys(Some(1));
+        store.put("e", b"1").await.unwrap_err();
+        let wt = store.write().await.unwrap();
+        wt.del("b").await.unwrap();
+        wt.commit().await.unwrap();
+        assert_eq!(2, store.read().await.unwrap().count().await.unwrap());
+    }
 }
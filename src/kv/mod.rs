@@ -1,33 +1,375 @@
+pub mod archive;
+pub mod checksum;
+pub mod clock;
+pub mod envelope;
+mod fair_lock;
+pub mod fallback;
 pub mod idbstore;
+mod lazy_write;
 pub mod memstore;
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg(test)]
+mod model_tests;
+pub mod opfs;
+mod read_cache;
+pub mod tee;
+pub mod ttl;
+pub mod versioned;
+pub mod watchdog;
 
 use async_trait::async_trait;
+use futures::future::{join_all, LocalBoxFuture, TryFutureExt};
+use futures::stream::{self, LocalBoxStream, StreamExt};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::TryFrom;
 use std::fmt;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub enum StoreError {
     Str(String),
+    ValueTooLarge {
+        len: usize,
+        max: usize,
+    },
+    UnsupportedSchema(u32),
+    // The store's database doesn't exist, or was never set up as a store.
+    NotInitialized,
+    KeyQuotaExceeded {
+        count: usize,
+        max: usize,
+    },
+    VersionMismatch {
+        expected: Option<u64>,
+        actual: Option<u64>,
+    },
+    // A DOMException (or other JS Error) from the browser. name identifies
+    // the kind of error, e.g. "QuotaExceededError"; code is the legacy
+    // numeric code, 0 for errors that have none.
+    Dom {
+        name: String,
+        message: String,
+        code: u16,
+    },
+    ChecksumMismatch {
+        key: String,
+    },
+    WriteLockTimeout,
+    // The store's database connection was closed (e.g. by a versionchange)
+    // while the store or a transaction of it was still in use.
+    StoreClosed,
+    // The transaction was aborted with Read::abort().
+    Cancelled,
+    // An IdbStore request's callbacks were dropped before either fired, so
+    // whether it succeeded is unknown. IndexedDB fires success or error
+    // for every request, even when its transaction aborts, so this means
+    // the request was lost rather than that it failed, e.g. because the
+    // callbacks were freed while the page was being torn down. It is worth
+    // retrying in a new transaction.
+    RequestDropped,
+    // Opening the store's database failed to set it up, e.g. because
+    // creating one of its object stores failed. The open's upgrade is
+    // rolled back.
+    Open {
+        store: String,
+        cause: Box<StoreError>,
+    },
 }
 
 impl fmt::Display for StoreError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             StoreError::Str(s) => write!(f, "{}", s),
+            StoreError::ValueTooLarge { len, max } => {
+                write!(f, "Value of {} bytes exceeds max of {} bytes", len, max)
+            }
+            StoreError::UnsupportedSchema(v) => write!(f, "Unsupported schema version {}", v),
+            StoreError::NotInitialized => write!(f, "Store is not initialized"),
+            StoreError::KeyQuotaExceeded { count, max } => {
+                write!(
+                    f,
+                    "Commit would leave {} keys, exceeding max of {}",
+                    count, max
+                )
+            }
+            StoreError::VersionMismatch { expected, actual } => {
+                write!(f, "Expected version {:?} but found {:?}", expected, actual)
+            }
+            StoreError::Dom {
+                name,
+                message,
+                code,
+            } => write!(f, "{}: {} (code {})", name, message, code),
+            StoreError::ChecksumMismatch { key } => write!(f, "Checksum mismatch for {}", key),
+            StoreError::WriteLockTimeout => write!(f, "Timed out waiting for the write lock"),
+            StoreError::StoreClosed => write!(f, "Store's database connection is closed"),
+            StoreError::Cancelled => write!(f, "Transaction was aborted"),
+            StoreError::RequestDropped => write!(f, "Request was dropped before it finished"),
+            StoreError::Open { store, cause } => {
+                write!(f, "Creating object store {} failed: {}", store, cause)
+            }
         }
     }
 }
 
 type Result<T> = std::result::Result<T, StoreError>;
 
+// What a write transaction's commit applied to the store. Each key counts
+// once however many times it was written in the transaction, as only its
+// final put or del is applied.
+#[derive(Debug, Default, PartialEq)]
+pub struct CommitReport {
+    pub puts: usize,
+    pub dels: usize,
+    // The total size of the values put.
+    pub bytes_written: usize,
+}
+
+impl CommitReport {
+    fn new(pending: &HashMap<String, Option<Vec<u8>>>) -> CommitReport {
+        let mut report = CommitReport::default();
+        for value in pending.values() {
+            match value {
+                Some(v) => {
+                    report.puts += 1;
+                    report.bytes_written += v.len();
+                }
+                None => report.dels += 1,
+            }
+        }
+        report
+    }
+}
+
+// The writes a write transaction would apply on commit, for logging what it
+// is about to commit. Only the keys are listed, to bound its size; bytes is
+// the total size of the values put, as in CommitReport.
+#[cfg(debug_assertions)]
+#[derive(Debug, Default, PartialEq)]
+pub struct PendingSummary {
+    pub puts: Vec<String>,
+    pub dels: Vec<String>,
+    pub bytes: usize,
+}
+
+#[cfg(debug_assertions)]
+impl PendingSummary {
+    fn new(pending: &HashMap<String, Option<Vec<u8>>>) -> PendingSummary {
+        let mut summary = PendingSummary::default();
+        for (key, value) in pending.iter() {
+            match value {
+                Some(v) => {
+                    summary.puts.push(key.clone());
+                    summary.bytes += v.len();
+                }
+                None => summary.dels.push(key.clone()),
+            }
+        }
+        summary.puts.sort();
+        summary.dels.sort();
+        summary
+    }
+}
+
+// The guarantee a Store makes about how its transactions interleave, as
+// reported by Store::isolation(). Levels are ordered from weakest to
+// strongest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IsolationLevel {
+    // Transactions appear to run one at a time in some order, but not
+    // necessarily the order in which they were started: a transaction
+    // waiting to start can be overtaken by one that began waiting later.
+    Serializable,
+    // Serializable, in an order consistent with the order in which
+    // transactions were started.
+    StrictlySerializable,
+}
+
+// The level of the stores that serialize transactions with an RwLock
+// (MemStore, IdbStore, OpfsStore). async_std's RwLock isn't fair, so
+// waiters can start in any order; the fair_locking lock admits them in
+// the order they arrived. See the comment on IdbStore::db.
+#[cfg(not(feature = "fair_locking"))]
+const RW_LOCK_ISOLATION: IsolationLevel = IsolationLevel::Serializable;
+#[cfg(feature = "fair_locking")]
+const RW_LOCK_ISOLATION: IsolationLevel = IsolationLevel::StrictlySerializable;
+
+// What Store::health_check() found. Each field is true if its check
+// passed.
+#[derive(Debug, PartialEq)]
+pub struct HealthReport {
+    // A write transaction could be started, written to and rolled back.
+    pub writable: bool,
+    // The store's storage exists, e.g. IdbStore's object store. Stores with
+    // nothing to check report whether they can be read.
+    pub object_store_present: bool,
+    // The store has room to grow. Stores without a quota report true.
+    pub quota_ok: bool,
+}
+
+// Reserved key the write probe of Store::health_check() puts, and rolls
+// back.
+const HEALTH_CHECK_KEY: &str = "sys/healthCheck";
+
+// Whether a write transaction on store can be started, written to and
+// rolled back.
+async fn probe_write<S: Store + ?Sized>(store: &S) -> bool {
+    match store.write().await {
+        Ok(wt) => wt.put(HEALTH_CHECK_KEY, b"").await.is_ok() && wt.rollback().await.is_ok(),
+        Err(_) => false,
+    }
+}
+
+// How many keys Read::has_many() checks at once.
+const HAS_MANY_BATCH: usize = 256;
+
+// Reserved key under which a store records the version of the data format
+// it was written with.
+const SCHEMA_VERSION_KEY: &str = "sys/schemaVersion";
+
+// Key prefixes that the layers over a kv store write their own records
+// under: dag chunks and heads, our sys/ keys, secondary index entries, and
+// the records of the versioned and ttl modules.
+const RESERVED_PREFIXES: &[&str] = &["c/", "h/", "sys/", "i/", "v/", "x/"];
+
+// Fails if key is in a reserved namespace, so that a helper taking a
+// caller's key can't read or clobber another layer's records, including
+// another helper's.
+pub(crate) fn check_unreserved(key: &str) -> Result<()> {
+    match RESERVED_PREFIXES.iter().find(|p| key.starts_with(*p)) {
+        Some(prefix) => Err(StoreError::Str(format!(
+            "Key {} is in the reserved {} namespace",
+            key, prefix
+        ))),
+        None => Ok(()),
+    }
+}
+
+// Guards against accidentally huge writes (e.g. from a serialization bug)
+// in stores that were configured with a max_value_bytes.
+fn check_value_size(value: &[u8], max: Option<usize>) -> Result<()> {
+    match max {
+        Some(max) if value.len() > max => Err(StoreError::ValueTooLarge {
+            len: value.len(),
+            max,
+        }),
+        _ => Ok(()),
+    }
+}
+
+// Enforces the key quota of stores configured with a max_keys. count is the
+// number of keys before a commit and added the net number it would add.
+// Commits that don't add keys always succeed, so a store that is over its
+// quota (e.g. because it was lowered) can still be trimmed.
+fn check_key_count(count: usize, added: isize, max: Option<usize>) -> Result<()> {
+    match max {
+        Some(max) if added > 0 && count + added as usize > max => {
+            Err(StoreError::KeyQuotaExceeded {
+                count: count + added as usize,
+                max,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+// Applies a write transaction's pending changes to the result of scanning
+// the underlying store for prefix.
+fn scan_pending(
+    base: Vec<(String, Vec<u8>)>,
+    pending: &HashMap<String, Option<Vec<u8>>>,
+    prefix: &str,
+) -> Vec<(String, Vec<u8>)> {
+    let mut merged: BTreeMap<String, Vec<u8>> = base.into_iter().collect();
+    for (key, value) in pending.iter().filter(|(k, _)| k.starts_with(prefix)) {
+        match value {
+            Some(v) => merged.insert(key.clone(), v.clone()),
+            None => merged.remove(key),
+        };
+    }
+    merged.into_iter().collect()
+}
+
+// Like scan_pending(), for the result of scanning keys only.
+fn scan_keys_pending(
+    base: Vec<String>,
+    pending: &HashMap<String, Option<Vec<u8>>>,
+    prefix: &str,
+) -> Vec<String> {
+    let mut merged: BTreeSet<String> = base.into_iter().collect();
+    for (key, value) in pending.iter().filter(|(k, _)| k.starts_with(prefix)) {
+        match value {
+            Some(_) => merged.insert(key.clone()),
+            None => merged.remove(key),
+        };
+    }
+    merged.into_iter().collect()
+}
+
+// Returns the first (or, if last, the last) key a write transaction sees,
+// given the first (or last) of the underlying store's keys that aren't in
+// pending.
+fn end_key_pending(
+    base: Option<String>,
+    pending: &HashMap<String, Option<Vec<u8>>>,
+    last: bool,
+) -> Option<String> {
+    let puts = pending
+        .iter()
+        .filter(|(_, v)| v.is_some())
+        .map(|(k, _)| k.clone());
+    let keys = base.into_iter().chain(puts);
+    match last {
+        true => keys.max(),
+        false => keys.min(),
+    }
+}
+
 #[async_trait(?Send)]
 pub trait Store {
     async fn read<'a>(&'a self) -> Result<Box<dyn Read + 'a>>;
     async fn write<'a>(&'a self) -> Result<Box<dyn Write + 'a>>;
 
+    // The isolation level the store's transactions provide. There's no
+    // default: a store reports the level it actually implements, and a
+    // store that wraps others reports the weakest of theirs.
+    fn isolation(&self) -> IsolationLevel;
+
+    // Like write(), but only starts the write transaction at the first put()
+    // or del(), so a handle that only reads doesn't block other writers.
+    // Reads before then aren't isolated from other writers; see
+    // kv/lazy_write.rs.
+    async fn write_lazy<'a>(&'a self) -> Result<Box<dyn Write + 'a>> {
+        Ok(Box::new(lazy_write::LazyWrite::new(self)))
+    }
+
+    // Checks that the store is usable, so that embedders can verify it at
+    // startup before trusting it. Failed checks are reported in the
+    // HealthReport, not as errors. The store is left unchanged.
+    async fn health_check(&self) -> Result<HealthReport> {
+        let object_store_present = self.read().await.is_ok();
+        Ok(HealthReport {
+            writable: probe_write(self).await,
+            object_store_present,
+            quota_ok: true,
+        })
+    }
+
+    // Like write(), but fails with WriteLockTimeout if the transaction can't
+    // be started within timeout, e.g. because a long read holds the store,
+    // so that callers can report being busy instead of waiting forever.
+    async fn try_write_timeout<'a>(&'a self, timeout: Duration) -> Result<Box<dyn Write + 'a>> {
+        match async_std::future::timeout(timeout, self.write()).await {
+            Ok(result) => result,
+            Err(_) => Err(StoreError::WriteLockTimeout),
+        }
+    }
+
     async fn put(&self, key: &str, value: &[u8]) -> Result<()> {
         let wt = self.write().await?;
         wt.put(key, value).await?;
-        Ok(wt.commit().await?)
+        Ok(wt.commit_unit().await?)
     }
 
     async fn has(&self, key: &str) -> Result<bool> {
@@ -37,12 +379,142 @@ pub trait Store {
     async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
         Ok(self.read().await?.get(key).await?)
     }
+
+    async fn get_schema_version(&self) -> Result<Option<u32>> {
+        self.get(SCHEMA_VERSION_KEY)
+            .await?
+            .map(|bytes| parse_schema_version(&bytes))
+            .transpose()
+    }
+
+    async fn set_schema_version(&self, version: u32) -> Result<()> {
+        self.put(SCHEMA_VERSION_KEY, &version.to_le_bytes()).await
+    }
+
+    // Replaces the contents of the store with entries in one write tx, so
+    // readers see either the old contents or the new, never a mix.
+    async fn replace_all(&self, entries: Vec<(String, Vec<u8>)>) -> Result<()> {
+        let wt = self.write().await?;
+        wt.clear().await?;
+        for (key, value) in entries.iter() {
+            wt.put(key, value).await?;
+        }
+        wt.commit_unit().await
+    }
+
+    // Returns every entry in the store, heads and chunks included, as a
+    // single blob (see kv::archive), e.g. for a backup.
+    async fn archive(&self) -> Result<Vec<u8>> {
+        Ok(archive::encode(&self.read().await?.scan("").await?))
+    }
+
+    // Replaces the contents of the store with those of an archive in one
+    // write tx, as replace_all() does. Fails without changing anything if
+    // the archive is invalid or of a different schema version than the
+    // store.
+    async fn restore_archive(&self, bytes: &[u8]) -> Result<()> {
+        let entries = archive::decode(bytes)?;
+        let wt = self.write().await?;
+        let ours = wt.get(SCHEMA_VERSION_KEY).await?;
+        let theirs = entries.iter().find(|(k, _)| k == SCHEMA_VERSION_KEY);
+        if let (Some(ours), Some((_, theirs))) = (ours, theirs) {
+            if &ours != theirs {
+                wt.rollback().await?;
+                return Err(StoreError::UnsupportedSchema(parse_schema_version(theirs)?));
+            }
+        }
+        wt.reserve(entries.len()).await;
+        wt.clear().await?;
+        for (key, value) in entries.iter() {
+            wt.put(key, value).await?;
+        }
+        wt.commit_unit().await
+    }
+}
+
+fn parse_schema_version(bytes: &[u8]) -> Result<u32> {
+    match <[u8; 4]>::try_from(bytes) {
+        Ok(bytes) => Ok(u32::from_le_bytes(bytes)),
+        Err(_) => Err(StoreError::Str(format!(
+            "Corrupt schema version {:?}",
+            bytes
+        ))),
+    }
 }
 
 #[async_trait(?Send)]
 pub trait Read {
     async fn has(&self, key: &str) -> Result<bool>;
     async fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    // Returns all entries whose key starts with prefix, sorted by key.
+    async fn scan(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>>;
+
+    // Returns the entries scan(prefix) would, as a stream. Stores that can
+    // read entries incrementally should override this to only read them as
+    // the stream is polled; by default they are all read on the first poll.
+    fn scan_stream<'a>(&'a self, prefix: &'a str) -> LocalBoxStream<'a, Result<(String, Vec<u8>)>> {
+        self.scan(prefix)
+            .map_ok(|entries| stream::iter(entries.into_iter().map(Ok)))
+            .try_flatten_stream()
+            .boxed_local()
+    }
+
+    // Returns the keys scan(prefix) would, without their values. Stores
+    // that can list keys without reading values should override this.
+    async fn scan_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        Ok(self
+            .scan(prefix)
+            .await?
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect())
+    }
+
+    // Returns the number of keys. Stores that can count without reading
+    // every value should override this.
+    async fn count(&self) -> Result<usize> {
+        Ok(self.scan("").await?.len())
+    }
+
+    // Returns the number of keys starting with prefix, e.g. the items of a
+    // list. Stores that can count a range of keys without listing them
+    // should override this.
+    async fn count_prefix(&self, prefix: &str) -> Result<usize> {
+        Ok(self.scan_keys(prefix).await?.len())
+    }
+
+    // Returns whether each of keys is present, in the order of keys, e.g.
+    // to find which of a set of chunks a store lacks without reading them.
+    // The has()es of a batch are made at once, so stores whose reads are
+    // requests (e.g. IdbStore) have a batch in flight at a time.
+    async fn has_many(&self, keys: &[&str]) -> Result<Vec<bool>> {
+        let mut present = Vec::with_capacity(keys.len());
+        for batch in keys.chunks(HAS_MANY_BATCH) {
+            for has in join_all(batch.iter().map(|key| self.has(key))).await {
+                present.push(has?);
+            }
+        }
+        Ok(present)
+    }
+
+    // Returns the smallest key, or None if there are no keys, e.g. for the
+    // ends of a range shown in a UI. Stores that can find it without
+    // listing every key should override this, as should last_key().
+    async fn first_key(&self) -> Result<Option<String>> {
+        Ok(self.scan_keys("").await?.into_iter().next())
+    }
+
+    // Returns the largest key, or None if there are no keys.
+    async fn last_key(&self) -> Result<Option<String>> {
+        Ok(self.scan_keys("").await?.pop())
+    }
+
+    // Aborts the transaction, so that its reads in progress (e.g. a long
+    // scan_stream() the caller no longer needs) and any later reads fail
+    // with StoreError::Cancelled. Stores whose reads can't be interrupted
+    // ignore it, which is the default.
+    fn abort(&self) {}
 }
 
 #[async_trait(?Send)]
@@ -52,12 +524,183 @@ pub trait Write: Read {
     async fn put(&self, key: &str, value: &[u8]) -> Result<()>;
     async fn del(&self, key: &str) -> Result<()>;
 
-    async fn commit(self: Box<Self>) -> Result<()>;
+    // Hints that `additional` more keys are about to be written, e.g. by a
+    // bulk load of known size, so implementations that buffer writes can
+    // preallocate rather than growing the buffer repeatedly. The default
+    // ignores the hint.
+    async fn reserve(&self, _additional: usize) {}
+
+    // Like put(), returning the value key had before, including one put
+    // earlier in this transaction.
+    async fn put_returning(&self, key: &str, value: &[u8]) -> Result<Option<Vec<u8>>> {
+        let old = self.get(key).await?;
+        self.put(key, value).await?;
+        Ok(old)
+    }
+
+    // Adds delta to the counter at key and returns its new value. Counters
+    // are stored as 8-byte little-endian i64s, and a missing one counts as
+    // 0. As this reads and writes within the transaction, concurrent
+    // increments can't lose each other's updates, and increments earlier in
+    // the transaction are included.
+    async fn increment(&self, key: &str, delta: i64) -> Result<i64> {
+        let current = match self.get(key).await? {
+            None => 0,
+            Some(v) => match <[u8; 8]>::try_from(&v[..]) {
+                Ok(bytes) => i64::from_le_bytes(bytes),
+                Err(_) => {
+                    return Err(StoreError::Str(format!(
+                        "Value of {} is not a counter: {} bytes",
+                        key,
+                        v.len()
+                    )))
+                }
+            },
+        };
+        let new = current.checked_add(delta).ok_or_else(|| {
+            StoreError::Str(format!("Counter {} overflowed adding {}", key, delta))
+        })?;
+        self.put(key, &new.to_le_bytes()).await?;
+        Ok(new)
+    }
+
+    // Summarizes the writes commit() would apply, in debug builds only.
+    // Transactions that don't buffer their writes have none to report.
+    #[cfg(debug_assertions)]
+    async fn pending_summary(&self) -> PendingSummary {
+        PendingSummary::default()
+    }
+
+    // Returns what the commit applied to the store.
+    async fn commit(self: Box<Self>) -> Result<CommitReport>;
     async fn rollback(self: Box<Self>) -> Result<()>;
+
+    // Commits, and returns a read transaction that sees the store as the
+    // commit left it. The write's hold on the store passes to the read
+    // without being released in between, so no other writer can commit in
+    // the gap. The hold stays exclusive, since none of our locks can be
+    // downgraded: until the read is dropped, other reads wait as well as
+    // writes, so callers should drop it promptly.
+    async fn commit_and_read<'a>(self: Box<Self>) -> Result<Box<dyn Read + 'a>>
+    where
+        Self: 'a;
+
+    // Like commit(), for callers that don't need the report.
+    async fn commit_unit(self: Box<Self>) -> Result<()> {
+        self.commit().await.map(|_| ())
+    }
+
+    // Deletes every key except the store's schema version, which describes
+    // the store rather than its contents. Only the keys are read, not the
+    // values.
+    async fn clear(&self) -> Result<()> {
+        self.del_prefix("").await.map(|_| ())
+    }
+
+    // Deletes every key starting with prefix, except the store's schema
+    // version as for clear(), returning how many were deleted. Like any
+    // other write, the deletes are visible at once within the transaction
+    // and only reach the store on commit.
+    async fn del_prefix(&self, prefix: &str) -> Result<u64> {
+        let mut deleted = 0;
+        for key in self.scan_keys(prefix).await? {
+            if key != SCHEMA_VERSION_KEY {
+                self.del(&key).await?;
+                deleted += 1;
+            }
+        }
+        Ok(deleted)
+    }
+
+    // Keeps the transaction from ending while work that doesn't use it is
+    // awaited. Only stores whose transactions end on their own when idle
+    // need to do anything here; by default work is simply awaited.
+    async fn keep_alive(&self, work: LocalBoxFuture<'_, ()>) -> Result<()> {
+        work.await;
+        Ok(())
+    }
+}
+
+// Runs f in a write transaction on store, committing the transaction if f
+// succeeds and rolling it back if f fails, so that it is always finished.
+// f's error is what's returned, even if the rollback also fails. This is a
+// function rather than a Store method because its type parameters would
+// keep Box<dyn Store> from having it.
+pub async fn write_txn<T, F>(store: &dyn Store, f: F) -> Result<T>
+where
+    F: for<'w> FnOnce(&'w dyn Write) -> LocalBoxFuture<'w, Result<T>>,
+{
+    let wt = store.write().await?;
+    let result = f(wt.as_ref()).await;
+    match result {
+        Ok(value) => {
+            wt.commit_unit().await?;
+            Ok(value)
+        }
+        Err(e) => {
+            if let Err(rollback_err) = wt.rollback().await {
+                log::warn!("Rollback after failed write_txn failed: {}", rollback_err);
+            }
+            Err(e)
+        }
+    }
+}
+
+// The keys on which two stores differ, as found by diff_stores(). Each
+// list is sorted.
+#[derive(Debug, Default, PartialEq)]
+pub struct StoreDiff {
+    pub only_a: Vec<String>,
+    pub only_b: Vec<String>,
+    // Keys both stores have, with different values.
+    pub differing: Vec<String>,
+}
+
+impl StoreDiff {
+    pub fn is_empty(&self) -> bool {
+        self.only_a.is_empty() && self.only_b.is_empty() && self.differing.is_empty()
+    }
+}
+
+// Compares the contents of two stores, e.g. a local store and a server's
+// snapshot imported into a MemStore, when tracking down how they diverged.
+// Each store is read in one read transaction, with every value in memory
+// at once, so this is for debugging rather than routine use.
+pub async fn diff_stores(a: &dyn Store, b: &dyn Store) -> Result<StoreDiff> {
+    let mut a_entries = a.read().await?.scan("").await?;
+    let mut b_entries = b.read().await?.scan("").await?;
+    // Stores agree on which keys a scan returns, but not necessarily on
+    // their order (e.g. IdbStore orders String keys as JS strings).
+    a_entries.sort_unstable_by(|x, y| x.0.cmp(&y.0));
+    b_entries.sort_unstable_by(|x, y| x.0.cmp(&y.0));
+
+    let mut diff = StoreDiff::default();
+    let mut a_iter = a_entries.into_iter().peekable();
+    let mut b_iter = b_entries.into_iter().peekable();
+    loop {
+        let order = match (a_iter.peek(), b_iter.peek()) {
+            (Some((ka, _)), Some((kb, _))) => ka.cmp(kb),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => break,
+        };
+        match order {
+            Ordering::Less => diff.only_a.push(a_iter.next().unwrap().0),
+            Ordering::Greater => diff.only_b.push(b_iter.next().unwrap().0),
+            Ordering::Equal => {
+                let (key, va) = a_iter.next().unwrap();
+                let (_, vb) = b_iter.next().unwrap();
+                if va != vb {
+                    diff.differing.push(key);
+                }
+            }
+        }
+    }
+    Ok(diff)
 }
 
 pub mod trait_tests {
-    use super::Store;
+    use super::{Read, Store};
     use std::future::Future;
 
     pub async fn run_all<F, T>(new_store: F)
@@ -77,6 +720,55 @@ pub mod trait_tests {
         write_transaction(&mut *s).await;
         s = new_store().await;
         isolation(&mut *s).await;
+        s = new_store().await;
+        scan(&mut *s).await;
+        s = new_store().await;
+        keep_alive(&mut *s).await;
+        s = new_store().await;
+        schema_version(&mut *s).await;
+        s = new_store().await;
+        count(&mut *s).await;
+        s = new_store().await;
+        count_prefix(&mut *s).await;
+        s = new_store().await;
+        has_many(&mut *s).await;
+        s = new_store().await;
+        first_last_key(&mut *s).await;
+        s = new_store().await;
+        read_your_writes(&mut *s).await;
+        s = new_store().await;
+        uncommitted_writes(&mut *s).await;
+        s = new_store().await;
+        concurrent_writers(&mut *s).await;
+        s = new_store().await;
+        replace_all(&mut *s).await;
+        s = new_store().await;
+        del_prefix(&mut *s).await;
+        s = new_store().await;
+        commit_report(&mut *s).await;
+        s = new_store().await;
+        write_txn(&mut *s).await;
+        s = new_store().await;
+        put_returning(&mut *s).await;
+        s = new_store().await;
+        scan_stream(&mut *s).await;
+        s = new_store().await;
+        try_write_timeout(&mut *s).await;
+        s = new_store().await;
+        write_lazy(&mut *s).await;
+        #[cfg(debug_assertions)]
+        {
+            s = new_store().await;
+            pending_summary(&mut *s).await;
+        }
+        s = new_store().await;
+        health_check(&mut *s).await;
+        s = new_store().await;
+        increment(&mut *s).await;
+        s = new_store().await;
+        serializability(&mut *s).await;
+        s = new_store().await;
+        commit_and_read(&mut *s).await;
     }
 
     pub async fn store(store: &mut dyn Store) {
@@ -157,6 +849,748 @@ pub mod trait_tests {
         assert_eq!(Some(b"new value".to_vec()), rt.get("k2").await.unwrap());
     }
 
+    pub async fn scan(store: &mut dyn Store) {
+        fn entries(kvs: &[(&str, &str)]) -> Vec<(String, Vec<u8>)> {
+            kvs.iter()
+                .map(|(k, v)| (k.to_string(), v.as_bytes().to_vec()))
+                .collect()
+        }
+
+        // Checks that scan_keys() returns the keys of scan().
+        async fn check_keys(read: &dyn Read) {
+            for prefix in &["", "a", "a/", "b", "c"] {
+                let keys: Vec<String> = read
+                    .scan(prefix)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|(k, _)| k)
+                    .collect();
+                assert_eq!(keys, read.scan_keys(prefix).await.unwrap(), "{}", prefix);
+            }
+        }
+
+        assert_eq!(
+            entries(&[]),
+            store.read().await.unwrap().scan("").await.unwrap()
+        );
+
+        store.put("b", b"1").await.unwrap();
+        store.put("a/2", b"2").await.unwrap();
+        store.put("a/1", b"3").await.unwrap();
+        store.put("a", b"4").await.unwrap();
+
+        let rt = store.read().await.unwrap();
+        assert_eq!(
+            entries(&[("a", "4"), ("a/1", "3"), ("a/2", "2"), ("b", "1")]),
+            rt.scan("").await.unwrap()
+        );
+        assert_eq!(
+            entries(&[("a/1", "3"), ("a/2", "2")]),
+            rt.scan("a/").await.unwrap()
+        );
+        assert_eq!(entries(&[("b", "1")]), rt.scan("b").await.unwrap());
+        assert_eq!(entries(&[]), rt.scan("c").await.unwrap());
+        check_keys(rt.as_ref()).await;
+        drop(rt);
+
+        // Pending writes are reflected in scans within a write tx.
+        let wt = store.write().await.unwrap();
+        wt.put("a/0", b"5").await.unwrap();
+        wt.put("a/2", b"6").await.unwrap();
+        wt.del("a/1").await.unwrap();
+        wt.put("c", b"7").await.unwrap();
+        assert_eq!(
+            entries(&[("a/0", "5"), ("a/2", "6")]),
+            wt.scan("a/").await.unwrap()
+        );
+        assert_eq!(
+            entries(&[
+                ("a", "4"),
+                ("a/0", "5"),
+                ("a/2", "6"),
+                ("b", "1"),
+                ("c", "7")
+            ]),
+            wt.scan("").await.unwrap()
+        );
+        check_keys(wt.as_read()).await;
+        wt.rollback().await.unwrap();
+        assert_eq!(
+            entries(&[("a/1", "3"), ("a/2", "2")]),
+            store.read().await.unwrap().scan("a/").await.unwrap()
+        );
+    }
+
+    pub async fn keep_alive(store: &mut dyn Store) {
+        store.put("foo", b"bar").await.unwrap();
+
+        let wt = store.write().await.unwrap();
+        wt.put("baz", b"qux").await.unwrap();
+        let mut ran = false;
+        wt.keep_alive(Box::pin(async {
+            for _ in 0..10 {
+                async_std::task::yield_now().await;
+            }
+            ran = true;
+        }))
+        .await
+        .unwrap();
+        assert!(ran);
+
+        // The transaction is still usable, for reads of both the store and
+        // its own writes, and for committing.
+        assert_eq!(Some(b"bar".to_vec()), wt.get("foo").await.unwrap());
+        assert_eq!(Some(b"qux".to_vec()), wt.get("baz").await.unwrap());
+        wt.commit().await.unwrap();
+        assert_eq!(Some(b"qux".to_vec()), store.get("baz").await.unwrap());
+    }
+
+    pub async fn schema_version(store: &mut dyn Store) {
+        assert_eq!(None, store.get_schema_version().await.unwrap());
+        store.set_schema_version(1).await.unwrap();
+        assert_eq!(Some(1), store.get_schema_version().await.unwrap());
+        store.set_schema_version(0xdead_beef).await.unwrap();
+        assert_eq!(Some(0xdead_beef), store.get_schema_version().await.unwrap());
+    }
+
+    pub async fn count(store: &mut dyn Store) {
+        assert_eq!(0, store.read().await.unwrap().count().await.unwrap());
+        store.put("a", b"1").await.unwrap();
+        store.put("b", b"2").await.unwrap();
+        store.put("b", b"3").await.unwrap();
+        assert_eq!(2, store.read().await.unwrap().count().await.unwrap());
+
+        // Pending writes are counted within a write tx.
+        let wt = store.write().await.unwrap();
+        wt.put("c", b"4").await.unwrap();
+        wt.put("d", b"5").await.unwrap();
+        wt.del("a").await.unwrap();
+        wt.del("e").await.unwrap();
+        assert_eq!(3, wt.count().await.unwrap());
+        wt.rollback().await.unwrap();
+        assert_eq!(2, store.read().await.unwrap().count().await.unwrap());
+    }
+
+    pub async fn count_prefix(store: &mut dyn Store) {
+        for key in &["a", "b/1", "b/2", "b/3", "bb", "c"] {
+            store.put(key, b"v").await.unwrap();
+        }
+        let rt = store.read().await.unwrap();
+        for (prefix, count) in &[("", 6), ("b", 4), ("b/", 3), ("b/2", 1), ("d", 0)] {
+            assert_eq!(*count, rt.count_prefix(prefix).await.unwrap(), "{}", prefix);
+        }
+        drop(rt);
+
+        // Pending writes are counted within a write tx.
+        let wt = store.write().await.unwrap();
+        wt.put("b/4", b"v").await.unwrap();
+        wt.put("b/5", b"v").await.unwrap();
+        wt.put("b/1", b"w").await.unwrap();
+        wt.del("b/2").await.unwrap();
+        wt.del("b/6").await.unwrap();
+        wt.put("c/1", b"v").await.unwrap();
+        assert_eq!(4, wt.count_prefix("b/").await.unwrap());
+        assert_eq!(8, wt.count_prefix("").await.unwrap());
+        wt.rollback().await.unwrap();
+        assert_eq!(
+            3,
+            store
+                .read()
+                .await
+                .unwrap()
+                .count_prefix("b/")
+                .await
+                .unwrap()
+        );
+    }
+
+    pub async fn has_many(store: &mut dyn Store) {
+        // More keys than fit in a batch, every third present.
+        let keys: Vec<String> = (0..600).map(|i| format!("k{}", i)).collect();
+        let wt = store.write().await.unwrap();
+        for key in keys.iter().step_by(3) {
+            wt.put(key, b"v").await.unwrap();
+        }
+        wt.commit().await.unwrap();
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        let expected: Vec<bool> = (0..600).map(|i| i % 3 == 0).collect();
+        let rt = store.read().await.unwrap();
+        assert_eq!(expected, rt.has_many(&keys).await.unwrap());
+        assert_eq!(
+            vec![true, false, true],
+            rt.has_many(&["k0", "missing", "k0"]).await.unwrap()
+        );
+        assert!(rt.has_many(&[]).await.unwrap().is_empty());
+        drop(rt);
+
+        // Pending writes count within a write tx.
+        let wt = store.write().await.unwrap();
+        wt.put("k1", b"v").await.unwrap();
+        wt.del("k0").await.unwrap();
+        assert_eq!(
+            vec![false, true, false],
+            wt.has_many(&["k0", "k1", "k2"]).await.unwrap()
+        );
+        wt.rollback().await.unwrap();
+    }
+
+    pub async fn first_last_key(store: &mut dyn Store) {
+        async fn ends(read: &dyn Read) -> (Option<String>, Option<String>) {
+            (
+                read.first_key().await.unwrap(),
+                read.last_key().await.unwrap(),
+            )
+        }
+        let some = |first: &str, last: &str| (Some(first.to_string()), Some(last.to_string()));
+
+        assert_eq!((None, None), ends(&*store.read().await.unwrap()).await);
+        let wt = store.write().await.unwrap();
+        assert_eq!((None, None), ends(wt.as_read()).await);
+        // Sorted by UTF-8, not by UTF-16 as idb string keys are.
+        for key in &["b", "c", "d", "\u{ffff}", "\u{10000}"] {
+            wt.put(key, b"v").await.unwrap();
+        }
+        wt.commit().await.unwrap();
+        assert_eq!(
+            some("b", "\u{10000}"),
+            ends(&*store.read().await.unwrap()).await
+        );
+
+        // Pending writes count within a write tx.
+        let wt = store.write().await.unwrap();
+        wt.put("a", b"v").await.unwrap();
+        wt.put("\u{10001}", b"v").await.unwrap();
+        assert_eq!(some("a", "\u{10001}"), ends(wt.as_read()).await);
+        for key in &["a", "b", "\u{10001}", "\u{10000}"] {
+            wt.del(key).await.unwrap();
+        }
+        assert_eq!(some("c", "\u{ffff}"), ends(wt.as_read()).await);
+        for key in &["c", "d", "\u{ffff}"] {
+            wt.del(key).await.unwrap();
+        }
+        assert_eq!((None, None), ends(wt.as_read()).await);
+        wt.put("c", b"v").await.unwrap();
+        assert_eq!(some("c", "c"), ends(wt.as_read()).await);
+        wt.rollback().await.unwrap();
+        assert_eq!(
+            some("b", "\u{10000}"),
+            ends(&*store.read().await.unwrap()).await
+        );
+    }
+
+    pub async fn read_your_writes(store: &mut dyn Store) {
+        store.put("a", b"1").await.unwrap();
+        store.put("b", b"2").await.unwrap();
+
+        // Every read method sees the tx's own writes, including a key
+        // deleted and put again.
+        let wt = store.write().await.unwrap();
+        wt.put("a", b"3").await.unwrap();
+        wt.del("b").await.unwrap();
+        wt.put("c", b"4").await.unwrap();
+        wt.del("c").await.unwrap();
+        wt.put("c", b"5").await.unwrap();
+        assert_eq!(Some(b"3".to_vec()), wt.get("a").await.unwrap());
+        assert!(!wt.has("b").await.unwrap());
+        assert_eq!(None, wt.get("b").await.unwrap());
+        assert!(wt.has("c").await.unwrap());
+        assert_eq!(
+            vec![
+                ("a".to_string(), b"3".to_vec()),
+                ("c".into(), b"5".to_vec())
+            ],
+            wt.scan("").await.unwrap()
+        );
+        assert_eq!(2, wt.count().await.unwrap());
+        wt.commit().await.unwrap();
+
+        let rt = store.read().await.unwrap();
+        assert_eq!(Some(b"3".to_vec()), rt.get("a").await.unwrap());
+        assert!(!rt.has("b").await.unwrap());
+        assert_eq!(Some(b"5".to_vec()), rt.get("c").await.unwrap());
+    }
+
+    pub async fn uncommitted_writes(store: &mut dyn Store) {
+        use futures::join;
+
+        store.put("k", b"committed").await.unwrap();
+        let store: &dyn Store = store;
+
+        // A reader waiting on a write tx doesn't see its writes once it is
+        // rolled back...
+        let wt = store.write().await.unwrap();
+        wt.put("k", b"rolled back").await.unwrap();
+        wt.put("new", b"rolled back").await.unwrap();
+        let (read, _) = join!(
+            async {
+                let rt = store.read().await.unwrap();
+                (rt.get("k").await.unwrap(), rt.has("new").await.unwrap())
+            },
+            async {
+                async_std::task::yield_now().await;
+                wt.rollback().await.unwrap();
+            }
+        );
+        assert_eq!((Some(b"committed".to_vec()), false), read);
+
+        // or if it is dropped without committing.
+        let wt = store.write().await.unwrap();
+        wt.put("k", b"dropped").await.unwrap();
+        drop(wt);
+        assert_eq!(Some(b"committed".to_vec()), store.get("k").await.unwrap());
+    }
+
+    pub async fn concurrent_writers(store: &mut dyn Store) {
+        use futures::join;
+
+        // Each writer increments a counter several times, reading it and
+        // yielding before writing it back. If write txs weren't serialized
+        // some increments would be lost.
+        const INCREMENTS: u8 = 5;
+        let store: &dyn Store = store;
+        let writer = || async move {
+            for _ in 0..INCREMENTS {
+                let wt = store.write().await.unwrap();
+                let n = wt.get("n").await.unwrap().map_or(0, |v| v[0]);
+                wt.keep_alive(Box::pin(async_std::task::yield_now()))
+                    .await
+                    .unwrap();
+                wt.put("n", &[n + 1]).await.unwrap();
+                wt.commit().await.unwrap();
+            }
+        };
+        join!(writer(), writer(), writer());
+        assert_eq!(Some(vec![3 * INCREMENTS]), store.get("n").await.unwrap());
+    }
+
+    pub async fn replace_all(store: &mut dyn Store) {
+        use futures::join;
+
+        fn entries(kvs: &[(&str, &str)]) -> Vec<(String, Vec<u8>)> {
+            kvs.iter()
+                .map(|(k, v)| (k.to_string(), v.as_bytes().to_vec()))
+                .collect()
+        }
+        let old = entries(&[("a", "1"), ("b", "2"), ("c", "3")]);
+        let new = entries(&[("b", "4"), ("d", "5")]);
+
+        store.set_schema_version(7).await.unwrap();
+        for (k, v) in old.iter() {
+            store.put(k, v).await.unwrap();
+        }
+        let store: &dyn Store = store;
+        let contents = || async move {
+            let mut all = store.read().await.unwrap().scan("").await.unwrap();
+            all.retain(|(k, _)| k != super::SCHEMA_VERSION_KEY);
+            all
+        };
+        assert_eq!(old, contents().await);
+
+        // Readers racing the replacement see all of one or the other.
+        let (before, replaced, after) =
+            join!(contents(), store.replace_all(new.clone()), contents());
+        replaced.unwrap();
+        for seen in &[before, after] {
+            assert!(seen == &old || seen == &new, "{:?}", seen);
+        }
+        assert_eq!(new, contents().await);
+        assert_eq!(Some(7), store.get_schema_version().await.unwrap());
+
+        // Within a tx, clear() is visible at once and rolls back like any
+        // other write.
+        let wt = store.write().await.unwrap();
+        wt.clear().await.unwrap();
+        assert_eq!(0, wt.scan("b").await.unwrap().len());
+        wt.rollback().await.unwrap();
+        assert_eq!(new, contents().await);
+    }
+
+    pub async fn commit_report(store: &mut dyn Store) {
+        let report = |puts, dels, bytes_written| super::CommitReport {
+            puts,
+            dels,
+            bytes_written,
+        };
+        assert_eq!(
+            report(0, 0, 0),
+            store.write().await.unwrap().commit().await.unwrap()
+        );
+
+        // Each key counts once, for its final write.
+        let wt = store.write().await.unwrap();
+        wt.put("a", b"1").await.unwrap();
+        wt.put("a", b"22").await.unwrap();
+        wt.put("b", b"333").await.unwrap();
+        wt.put("c", b"4").await.unwrap();
+        wt.del("c").await.unwrap();
+        assert_eq!(report(2, 1, 5), wt.commit().await.unwrap());
+
+        // A clear() followed by a put of a cleared key is a put.
+        let wt = store.write().await.unwrap();
+        wt.clear().await.unwrap();
+        wt.put("a", b"55555").await.unwrap();
+        assert_eq!(report(1, 1, 5), wt.commit().await.unwrap());
+        assert_eq!(
+            vec!["a"],
+            store.read().await.unwrap().scan_keys("").await.unwrap()
+        );
+    }
+
+    pub async fn write_lazy(store: &mut dyn Store) {
+        use async_std::future::timeout;
+        use std::time::Duration;
+
+        let dur = Duration::from_millis(200);
+        store.put("a", b"1").await.unwrap();
+
+        // A handle that only reads doesn't block writers, and sees what
+        // they commit.
+        let lazy = store.write_lazy().await.unwrap();
+        assert_eq!(Some(b"1".to_vec()), lazy.get("a").await.unwrap());
+        assert!(!lazy.has("b").await.unwrap());
+        let wt = timeout(dur, store.write())
+            .await
+            .expect("lazy write should not block writers")
+            .unwrap();
+        wt.put("b", b"2").await.unwrap();
+        wt.commit().await.unwrap();
+        assert_eq!(vec!["a", "b"], lazy.scan_keys("").await.unwrap());
+        assert_eq!(super::CommitReport::default(), lazy.commit().await.unwrap());
+
+        // From the first write on it is an ordinary write transaction.
+        let lazy = store.write_lazy().await.unwrap();
+        lazy.put("c", b"3").await.unwrap();
+        assert!(timeout(dur, store.write()).await.is_err());
+        assert_eq!(Some(b"3".to_vec()), lazy.get("c").await.unwrap());
+        assert_eq!(3, lazy.count().await.unwrap());
+        assert_eq!(1, lazy.commit().await.unwrap().puts);
+        assert!(store.has("c").await.unwrap());
+
+        let lazy = store.write_lazy().await.unwrap();
+        lazy.del("a").await.unwrap();
+        lazy.rollback().await.unwrap();
+        assert!(store.has("a").await.unwrap());
+    }
+
+    pub async fn try_write_timeout(store: &mut dyn Store) {
+        use super::StoreError;
+        use std::time::Duration;
+
+        let dur = Duration::from_millis(50);
+        let rt = store.read().await.unwrap();
+        match store.try_write_timeout(dur).await {
+            Err(StoreError::WriteLockTimeout) => (),
+            r => panic!("Expected WriteLockTimeout, got {:?}", r.map(|_| ())),
+        }
+        drop(rt);
+
+        // The timed out attempt doesn't hold the lock.
+        let wt = store.try_write_timeout(dur).await.unwrap();
+        wt.put("k", b"v").await.unwrap();
+        wt.commit().await.unwrap();
+        assert!(store.has("k").await.unwrap());
+    }
+
+    pub async fn scan_stream(store: &mut dyn Store) {
+        use futures::stream::{StreamExt, TryStreamExt};
+
+        for key in &["a", "b/1", "b/2", "b/3", "c"] {
+            store.put(key, key.as_bytes()).await.unwrap();
+        }
+        let rt = store.read().await.unwrap();
+        for prefix in &["", "b/", "d"] {
+            let entries: Vec<_> = rt.scan_stream(prefix).try_collect().await.unwrap();
+            assert_eq!(rt.scan(prefix).await.unwrap(), entries);
+        }
+        let first: Vec<_> = rt.scan_stream("b/").take(2).try_collect().await.unwrap();
+        assert_eq!(rt.scan("b/").await.unwrap()[..2], first[..]);
+        drop(rt);
+
+        // Write transactions include their pending writes.
+        let wt = store.write().await.unwrap();
+        wt.put("b/0", b"0").await.unwrap();
+        wt.del("b/2").await.unwrap();
+        let keys: Vec<String> = wt
+            .scan_stream("b/")
+            .map_ok(|(k, _)| k)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(vec!["b/0", "b/1", "b/3"], keys);
+    }
+
+    #[cfg(debug_assertions)]
+    pub async fn pending_summary(store: &mut dyn Store) {
+        use super::PendingSummary;
+
+        store.put("a", b"1").await.unwrap();
+        let wt = store.write().await.unwrap();
+        assert_eq!(PendingSummary::default(), wt.pending_summary().await);
+
+        wt.put("c", b"333").await.unwrap();
+        wt.put("b", b"4444").await.unwrap();
+        wt.del("a").await.unwrap();
+        wt.del("d").await.unwrap();
+        // Only a key's last write counts.
+        wt.put("e", b"5").await.unwrap();
+        wt.del("e").await.unwrap();
+        wt.del("b").await.unwrap();
+        wt.put("b", b"22").await.unwrap();
+        assert_eq!(
+            PendingSummary {
+                puts: vec!["b".into(), "c".into()],
+                dels: vec!["a".into(), "d".into(), "e".into()],
+                bytes: 5,
+            },
+            wt.pending_summary().await
+        );
+        wt.rollback().await.unwrap();
+    }
+
+    pub async fn health_check(store: &mut dyn Store) {
+        use super::HealthReport;
+
+        store.put("a", b"1").await.unwrap();
+        assert_eq!(
+            HealthReport {
+                writable: true,
+                object_store_present: true,
+                quota_ok: true,
+            },
+            store.health_check().await.unwrap()
+        );
+        // The write probe was rolled back.
+        let rt = store.read().await.unwrap();
+        assert_eq!(vec!["a"], rt.scan_keys("").await.unwrap());
+    }
+
+    pub async fn increment(store: &mut dyn Store) {
+        let wt = store.write().await.unwrap();
+        assert_eq!(5, wt.increment("n", 5).await.unwrap());
+        assert_eq!(
+            Some(5i64.to_le_bytes().to_vec()),
+            wt.get("n").await.unwrap()
+        );
+        // Increments in one transaction accumulate.
+        assert_eq!(7, wt.increment("n", 2).await.unwrap());
+        assert_eq!(-3, wt.increment("n", -10).await.unwrap());
+        assert_eq!(-3, wt.increment("n", 0).await.unwrap());
+        wt.commit().await.unwrap();
+
+        let wt = store.write().await.unwrap();
+        assert_eq!(-2, wt.increment("n", 1).await.unwrap());
+        assert_eq!(-1, wt.increment("m", -1).await.unwrap());
+        wt.rollback().await.unwrap();
+        assert_eq!(
+            Some((-3i64).to_le_bytes().to_vec()),
+            store.get("n").await.unwrap()
+        );
+        assert!(!store.has("m").await.unwrap());
+
+        // Values that aren't counters, and overflow, are errors that leave
+        // the value as it was.
+        store.put("s", b"abc").await.unwrap();
+        store.put("max", &i64::MAX.to_le_bytes()).await.unwrap();
+        let wt = store.write().await.unwrap();
+        assert!(wt.increment("s", 1).await.is_err());
+        assert!(wt.increment("max", 1).await.is_err());
+        assert_eq!(-1, wt.increment("max", i64::MIN).await.unwrap());
+        wt.commit().await.unwrap();
+        assert_eq!(Some(b"abc".to_vec()), store.get("s").await.unwrap());
+    }
+
+    pub async fn serializability(store: &mut dyn Store) {
+        use super::IsolationLevel;
+        use async_std::task::yield_now;
+        use futures::future::{join_all, FutureExt};
+        use std::convert::TryFrom;
+
+        // Every level is at least serializable, which is what's checked
+        // here; strictness depends on the order waiters are woken in, which
+        // the store doesn't expose.
+        assert!(store.isolation() >= IsolationLevel::Serializable);
+
+        // Transfers move 1 from "a" to "b", yielding between the two halves
+        // so that they interleave with other transactions if the store lets
+        // them. Checks read both counters, also yielding in between. Under
+        // serializability no check sees half a transfer and no transfer is
+        // lost.
+        async fn counter(read: &dyn Read, key: &str) -> i64 {
+            let bytes = read.get(key).await.unwrap().unwrap_or_else(|| vec![0; 8]);
+            i64::from_le_bytes(<[u8; 8]>::try_from(bytes.as_slice()).unwrap())
+        }
+        let store: &dyn Store = store;
+        let transfer = || async move {
+            let wt = store.write().await.unwrap();
+            wt.increment("a", -1).await.unwrap();
+            yield_now().await;
+            wt.increment("b", 1).await.unwrap();
+            wt.commit().await.unwrap();
+        };
+        let check = || async move {
+            let rt = store.read().await.unwrap();
+            let a = counter(rt.as_ref(), "a").await;
+            yield_now().await;
+            assert_eq!(0, a + counter(rt.as_ref(), "b").await);
+        };
+        let mut txs = Vec::new();
+        for _ in 0..10 {
+            txs.push(transfer().boxed_local());
+            txs.push(check().boxed_local());
+        }
+        join_all(txs).await;
+
+        let rt = store.read().await.unwrap();
+        assert_eq!(-10, counter(rt.as_ref(), "a").await);
+        assert_eq!(10, counter(rt.as_ref(), "b").await);
+    }
+
+    pub async fn commit_and_read(store: &mut dyn Store) {
+        use async_std::future::timeout;
+        use async_std::task::sleep;
+        use std::time::Duration;
+
+        store.put("a", b"1").await.unwrap();
+        store.put("b", b"2").await.unwrap();
+        let wt = store.write().await.unwrap();
+        wt.put("b", b"3").await.unwrap();
+        wt.put("c", b"4").await.unwrap();
+        wt.del("a").await.unwrap();
+        let rt = wt.commit_and_read().await.unwrap();
+        assert!(!rt.has("a").await.unwrap());
+        assert_eq!(Some(b"3".to_vec()), rt.get("b").await.unwrap());
+        assert_eq!(
+            vec![
+                ("b".to_string(), b"3".to_vec()),
+                ("c".to_string(), b"4".to_vec())
+            ],
+            rt.scan("").await.unwrap()
+        );
+
+        // No other writer gets in while the read is held, and nor does any
+        // other reader.
+        let dur = Duration::from_millis(20);
+        assert!(store.try_write_timeout(dur).await.is_err());
+        assert!(timeout(dur, store.read()).await.is_err());
+
+        // A reader waiting on it starts once it's dropped, and sees the
+        // commit.
+        let store = &*store;
+        let (got, _) = futures::join!(
+            async { store.read().await.unwrap().get("b").await.unwrap() },
+            async move {
+                sleep(dur).await;
+                drop(rt);
+            }
+        );
+        assert_eq!(Some(b"3".to_vec()), got);
+        store.try_write_timeout(dur).await.unwrap();
+
+        // A write with nothing to commit reads the store as it was.
+        let rt = store
+            .write()
+            .await
+            .unwrap()
+            .commit_and_read()
+            .await
+            .unwrap();
+        assert_eq!(2, rt.count().await.unwrap());
+    }
+
+    pub async fn put_returning(store: &mut dyn Store) {
+        store.put("a", b"1").await.unwrap();
+
+        let wt = store.write().await.unwrap();
+        assert_eq!(
+            Some(b"1".to_vec()),
+            wt.put_returning("a", b"2").await.unwrap()
+        );
+        assert_eq!(None, wt.put_returning("b", b"3").await.unwrap());
+        // Earlier writes in the transaction are returned.
+        assert_eq!(
+            Some(b"2".to_vec()),
+            wt.put_returning("a", b"4").await.unwrap()
+        );
+        assert_eq!(
+            Some(b"3".to_vec()),
+            wt.put_returning("b", b"5").await.unwrap()
+        );
+        wt.del("a").await.unwrap();
+        assert_eq!(None, wt.put_returning("a", b"6").await.unwrap());
+        wt.commit().await.unwrap();
+
+        let rt = store.read().await.unwrap();
+        assert_eq!(Some(b"6".to_vec()), rt.get("a").await.unwrap());
+        assert_eq!(Some(b"5".to_vec()), rt.get("b").await.unwrap());
+    }
+
+    pub async fn write_txn(store: &mut dyn Store) {
+        use super::{StoreError, Write};
+        use futures::future::FutureExt;
+
+        let result = super::write_txn(&*store, |wt: &dyn Write| {
+            async move {
+                wt.put("a", b"1").await?;
+                wt.get("a").await
+            }
+            .boxed_local()
+        })
+        .await;
+        assert_eq!(Some(b"1".to_vec()), result.unwrap());
+        assert_eq!(Some(b"1".to_vec()), store.get("a").await.unwrap());
+
+        let result: super::Result<()> = super::write_txn(&*store, |wt: &dyn Write| {
+            async move {
+                wt.put("a", b"2").await?;
+                wt.put("b", b"2").await?;
+                Err(StoreError::Str("failed".into()))
+            }
+            .boxed_local()
+        })
+        .await;
+        match result {
+            Err(StoreError::Str(s)) if s == "failed" => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert_eq!(Some(b"1".to_vec()), store.get("a").await.unwrap());
+        assert!(!store.has("b").await.unwrap());
+    }
+
+    pub async fn del_prefix(store: &mut dyn Store) {
+        store.set_schema_version(1).await.unwrap();
+        for key in &["a", "a/1", "a/2", "ab", "b"] {
+            store.put(key, b"v").await.unwrap();
+        }
+
+        // Pending writes are taken into account, and the deletes are visible
+        // at once.
+        let wt = store.write().await.unwrap();
+        wt.put("a/3", b"v").await.unwrap();
+        wt.del("a/2").await.unwrap();
+        assert_eq!(2, wt.del_prefix("a/").await.unwrap());
+        assert_eq!(vec!["a", "ab"], wt.scan_keys("a").await.unwrap());
+        assert!(!wt.has("a/1").await.unwrap());
+        assert_eq!(0, wt.del_prefix("a/").await.unwrap());
+        wt.rollback().await.unwrap();
+        assert!(store.has("a/1").await.unwrap());
+
+        let wt = store.write().await.unwrap();
+        assert_eq!(2, wt.del_prefix("a/").await.unwrap());
+        wt.commit().await.unwrap();
+        assert_eq!(
+            vec!["a", "ab", "b", super::SCHEMA_VERSION_KEY],
+            store.read().await.unwrap().scan_keys("").await.unwrap()
+        );
+
+        // Even the empty prefix leaves the schema version.
+        let wt = store.write().await.unwrap();
+        assert_eq!(3, wt.del_prefix("").await.unwrap());
+        wt.commit().await.unwrap();
+        assert_eq!(1, store.read().await.unwrap().count().await.unwrap());
+        assert_eq!(Some(1), store.get_schema_version().await.unwrap());
+    }
+
     pub async fn isolation(store: &mut dyn Store) {
         use async_std::future::timeout;
         use log::error;
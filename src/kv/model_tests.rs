@@ -0,0 +1,249 @@
+//! Randomized tests of Store transaction semantics against a reference
+//! model.
+//!
+//! A case is a sequence of Ops generated from a seed, so a failure
+//! reproduces from its seed alone. The model is a BTreeMap of the committed
+//! state, plus a copy of it per open transaction that the transaction's own
+//! writes are applied to; every read is checked against its transaction's
+//! copy. Ops that don't apply in the current state (e.g. Commit with no
+//! write transaction open) are skipped rather than never generated, so any
+//! subsequence of a case is a valid case too, and a failing case shrinks by
+//! dropping ops.
+//!
+//! Stores serialize writers against readers, so a write transaction is only
+//! opened, and a write made outside of one, while no read transaction is
+//! open. Otherwise the case would deadlock.
+use crate::kv::memstore::MemStore;
+use crate::kv::{Read, Store, StoreError, Write};
+use async_std::task::block_on;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::BTreeMap;
+
+// Keys that prefix each other, so that scans have something to exclude.
+const KEYS: [&str; 5] = ["a", "ab", "b", "ba", "c"];
+const PREFIXES: [&str; 4] = ["", "a", "b", "x"];
+
+#[derive(Clone, Debug, PartialEq)]
+enum Op {
+    // Opens a write transaction.
+    Write,
+    // Opens a read transaction.
+    Read,
+    // Drops the open read transaction at this index, modulo their number.
+    EndRead(usize),
+    // Writes KEYS[i] in the write transaction, or directly to the store if
+    // none is open. The value is the u8 repeated u8 % 3 times.
+    Put(usize, u8),
+    Del(usize),
+    Commit,
+    Rollback,
+    // Reads KEYS[i], or scans PREFIXES[i], in every open transaction, and in
+    // a new read transaction if no write transaction is open.
+    Get(usize),
+    Scan(usize),
+}
+
+type Model = BTreeMap<String, Vec<u8>>;
+
+struct Failure(String);
+
+impl From<StoreError> for Failure {
+    fn from(e: StoreError) -> Failure {
+        Failure(e.to_string())
+    }
+}
+
+fn gen_ops(seed: u64, len: usize) -> Vec<Op> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..len)
+        .map(|_| {
+            let key = rng.gen_range(0, KEYS.len());
+            match rng.gen_range(0, 12) {
+                0 => Op::Write,
+                1 => Op::Read,
+                2 => Op::EndRead(rng.gen_range(0, 4)),
+                3..=5 => Op::Put(key, rng.gen()),
+                6 => Op::Del(key),
+                7 => Op::Commit,
+                8 => Op::Rollback,
+                9 | 10 => Op::Get(key),
+                _ => Op::Scan(rng.gen_range(0, PREFIXES.len())),
+            }
+        })
+        .collect()
+}
+
+fn value(v: u8) -> Vec<u8> {
+    vec![v; (v % 3) as usize]
+}
+
+async fn check(read: &dyn Read, model: &Model, op: &Op) -> Result<(), Failure> {
+    match *op {
+        Op::Get(k) => {
+            let key = KEYS[k];
+            let (got, has) = (read.get(key).await?, read.has(key).await?);
+            if got.as_ref() != model.get(key) || has != model.contains_key(key) {
+                return Err(Failure(format!(
+                    "get {:?} = {:?} and has = {}, model has {:?}",
+                    key,
+                    got,
+                    has,
+                    model.get(key)
+                )));
+            }
+        }
+        Op::Scan(p) => {
+            let prefix = PREFIXES[p];
+            let want: Vec<(String, Vec<u8>)> = model
+                .iter()
+                .filter(|(k, _)| k.starts_with(prefix))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect();
+            let got = read.scan(prefix).await?;
+            if got != want {
+                return Err(Failure(format!(
+                    "scan {:?} = {:?}, model has {:?}",
+                    prefix, got, want
+                )));
+            }
+            let keys = read.scan_keys(prefix).await?;
+            if keys.iter().ne(want.iter().map(|(k, _)| k)) {
+                return Err(Failure(format!("scan_keys {:?} = {:?}", prefix, keys)));
+            }
+            let count = read.count().await?;
+            if count != model.len() {
+                return Err(Failure(format!(
+                    "count = {}, model has {}",
+                    count,
+                    model.len()
+                )));
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+async fn run_ops(store: &dyn Store, ops: &[Op]) -> Result<(), Failure> {
+    let mut committed = Model::new();
+    let mut write: Option<(Box<dyn Write + '_>, Model)> = None;
+    let mut reads: Vec<(Box<dyn Read + '_>, Model)> = Vec::new();
+    for op in ops {
+        match *op {
+            Op::Write if write.is_none() && reads.is_empty() => {
+                write = Some((store.write().await?, committed.clone()));
+            }
+            Op::Read if write.is_none() => {
+                reads.push((store.read().await?, committed.clone()));
+            }
+            Op::EndRead(i) if !reads.is_empty() => {
+                let i = i % reads.len();
+                reads.remove(i);
+            }
+            Op::Put(k, v) => match write.as_mut() {
+                Some((wt, model)) => {
+                    wt.put(KEYS[k], &value(v)).await?;
+                    model.insert(KEYS[k].into(), value(v));
+                }
+                None if reads.is_empty() => {
+                    store.put(KEYS[k], &value(v)).await?;
+                    committed.insert(KEYS[k].into(), value(v));
+                }
+                None => {}
+            },
+            Op::Del(k) => match write.as_mut() {
+                Some((wt, model)) => {
+                    wt.del(KEYS[k]).await?;
+                    model.remove(KEYS[k]);
+                }
+                None if reads.is_empty() => {
+                    let wt = store.write().await?;
+                    wt.del(KEYS[k]).await?;
+                    wt.commit().await?;
+                    committed.remove(KEYS[k]);
+                }
+                None => {}
+            },
+            Op::Commit => {
+                if let Some((wt, model)) = write.take() {
+                    wt.commit().await?;
+                    committed = model;
+                }
+            }
+            Op::Rollback => {
+                if let Some((wt, _)) = write.take() {
+                    wt.rollback().await?;
+                }
+            }
+            Op::Get(_) | Op::Scan(_) => {
+                if let Some((wt, model)) = write.as_ref() {
+                    check(wt.as_read(), model, op).await?;
+                }
+                for (rt, model) in reads.iter() {
+                    check(rt.as_ref(), model, op).await?;
+                }
+                if write.is_none() {
+                    check(store.read().await?.as_ref(), &committed, op).await?;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Dropping an open write transaction rolls it back.
+    drop(write);
+    drop(reads);
+    check(store.read().await?.as_ref(), &committed, &Op::Scan(0)).await
+}
+
+// Returns a smaller case that still fails, by repeatedly dropping runs of
+// ops, halving the length of the runs until single ops can't be dropped.
+fn shrink(mut ops: Vec<Op>, fails: impl Fn(&[Op]) -> bool) -> Vec<Op> {
+    let mut run = ops.len() / 2;
+    while run > 0 {
+        let mut i = 0;
+        while i < ops.len() {
+            let mut candidate = ops.clone();
+            candidate.drain(i..(i + run).min(ops.len()));
+            if fails(&candidate) {
+                ops = candidate;
+            } else {
+                i += run;
+            }
+        }
+        run /= 2;
+    }
+    ops
+}
+
+fn check_store(new_store: fn() -> Box<dyn Store>, cases: u64, len: usize) {
+    let result = |ops: &[Op]| block_on(run_ops(&*new_store(), ops));
+    for seed in 0..cases {
+        let ops = gen_ops(seed, len);
+        if result(&ops).is_err() {
+            let ops = shrink(ops, |ops| result(ops).is_err());
+            let Failure(msg) = result(&ops).err().unwrap();
+            panic!("Seed {} failed: {}\nShrunk to: {:?}", seed, msg, ops);
+        }
+    }
+}
+
+#[test]
+fn memstore() {
+    check_store(|| Box::new(MemStore::new()), 300, 60);
+}
+
+#[test]
+fn gen_ops_is_deterministic() {
+    assert_eq!(gen_ops(7, 100), gen_ops(7, 100));
+    assert_ne!(gen_ops(7, 100), gen_ops(8, 100));
+}
+
+#[test]
+fn shrink_drops_unneeded_ops() {
+    let ops = gen_ops(1, 60);
+    assert!(ops.contains(&Op::Commit));
+    let shrunk = shrink(ops, |ops| ops.contains(&Op::Commit));
+    assert_eq!(vec![Op::Commit], shrunk);
+}
@@ -0,0 +1,709 @@
+//! A Store backed by the Origin Private File System (OPFS), for datasets
+//! large enough that IndexedDB's per-request overhead dominates.
+//!
+//! OPFS files can be read and written synchronously through a
+//! FileSystemSyncAccessHandle, which browsers only offer in Workers and
+//! which locks the file for as long as it is open. The store keeps all of
+//! its data in one such file, a segment of records:
+//!
+//!   put:    0x01, key length (u32 LE), key, value length (u32 LE), value
+//!   del:    0x02, key length (u32 LE), key
+//!   commit: 0x03
+//!
+//! A commit appends the transaction's puts and dels followed by a commit
+//! record, then flushes the file. Opening the store replays the segment
+//! into an in-memory index of where each key's current value is, and drops
+//! any records after the last commit record, so a commit cut short by a
+//! crash is rolled back. Replay only reads record headers and keys, seeking
+//! past values, which are read from the file when asked for; only keys and
+//! their locations are held in memory.
+//!
+//! Records have no checksum. Replay recognizes a torn commit by a record
+//! that runs past the end of the file or has an unknown tag, but a commit
+//! whose bytes were reordered or corrupted in place, rather than cut
+//! short, can replay without error and read back wrong values.
+//!
+//! The segment only grows: overwritten and deleted values keep their space
+//! until it is compacted, which is not implemented yet.
+#[cfg(feature = "fair_locking")]
+use crate::kv::fair_lock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+#[cfg(debug_assertions)]
+use crate::kv::PendingSummary;
+use crate::kv::{
+    scan_keys_pending, scan_pending, CommitReport, IsolationLevel, Read, Result, Store, StoreError,
+    Write, RW_LOCK_ISOLATION,
+};
+use async_std::sync::Mutex;
+#[cfg(not(feature = "fair_locking"))]
+use async_std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use async_trait::async_trait;
+use log::warn;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+
+// The parts of the OPFS API we use, which our version of web-sys predates.
+#[wasm_bindgen]
+extern "C" {
+    type StorageManager;
+
+    #[wasm_bindgen(method, js_name = getDirectory)]
+    fn get_directory(this: &StorageManager) -> js_sys::Promise;
+
+    #[wasm_bindgen(js_name = FileSystemDirectoryHandle)]
+    type DirectoryHandle;
+
+    #[wasm_bindgen(method, js_name = getFileHandle)]
+    fn get_file_handle(this: &DirectoryHandle, name: &str, options: &JsValue) -> js_sys::Promise;
+
+    #[wasm_bindgen(js_name = FileSystemFileHandle)]
+    type FileHandle;
+
+    #[wasm_bindgen(method, js_name = createSyncAccessHandle)]
+    fn create_sync_access_handle(this: &FileHandle) -> js_sys::Promise;
+
+    #[wasm_bindgen(js_name = FileSystemSyncAccessHandle)]
+    type SyncAccessHandle;
+
+    #[wasm_bindgen(method, catch)]
+    fn read(
+        this: &SyncAccessHandle,
+        buffer: &mut [u8],
+        options: &JsValue,
+    ) -> std::result::Result<f64, JsValue>;
+
+    #[wasm_bindgen(method, catch)]
+    fn write(
+        this: &SyncAccessHandle,
+        buffer: &[u8],
+        options: &JsValue,
+    ) -> std::result::Result<f64, JsValue>;
+
+    #[wasm_bindgen(method, catch, js_name = getSize)]
+    fn get_size(this: &SyncAccessHandle) -> std::result::Result<f64, JsValue>;
+
+    #[wasm_bindgen(method, catch)]
+    fn truncate(this: &SyncAccessHandle, size: f64) -> std::result::Result<(), JsValue>;
+
+    #[wasm_bindgen(method, catch)]
+    fn flush(this: &SyncAccessHandle) -> std::result::Result<(), JsValue>;
+
+    #[wasm_bindgen(method)]
+    fn close(this: &SyncAccessHandle);
+}
+
+const PUT: u8 = 1;
+const DEL: u8 = 2;
+const COMMIT: u8 = 3;
+
+// The file a Segment lives in: an OPFS sync access handle, or memory in
+// tests.
+trait SegmentFile {
+    fn size(&self) -> Result<u64>;
+    // Fills buf from the file starting at offset at.
+    fn read_at(&self, buf: &mut [u8], at: u64) -> Result<()>;
+    fn write_at(&self, data: &[u8], at: u64) -> Result<()>;
+    fn truncate(&self, len: u64) -> Result<()>;
+    fn flush(&self) -> Result<()>;
+}
+
+// Releases the file's lock when dropped.
+struct AccessHandle(SyncAccessHandle);
+
+// The {at} options of read() and write().
+fn at_options(at: u64) -> Result<JsValue> {
+    let options = js_sys::Object::new();
+    js_sys::Reflect::set(&options, &"at".into(), &(at as f64).into())?;
+    Ok(options.into())
+}
+
+impl SegmentFile for AccessHandle {
+    fn size(&self) -> Result<u64> {
+        Ok(self.0.get_size()? as u64)
+    }
+
+    fn read_at(&self, buf: &mut [u8], at: u64) -> Result<()> {
+        let read = self.0.read(buf, &at_options(at)?)? as usize;
+        if read != buf.len() {
+            return Err(StoreError::Str(format!(
+                "Short read at {}: {} of {} bytes",
+                at,
+                read,
+                buf.len()
+            )));
+        }
+        Ok(())
+    }
+
+    fn write_at(&self, data: &[u8], at: u64) -> Result<()> {
+        let written = self.0.write(data, &at_options(at)?)? as usize;
+        if written != data.len() {
+            return Err(StoreError::Str(format!(
+                "Short write at {}: {} of {} bytes",
+                at,
+                written,
+                data.len()
+            )));
+        }
+        Ok(())
+    }
+
+    fn truncate(&self, len: u64) -> Result<()> {
+        Ok(self.0.truncate(len as f64)?)
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(self.0.flush()?)
+    }
+}
+
+impl Drop for AccessHandle {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+// Returns obj[name], or None if either is undefined or null.
+fn property(obj: &JsValue, name: &str) -> Option<JsValue> {
+    if obj.is_undefined() || obj.is_null() {
+        return None;
+    }
+    js_sys::Reflect::get(obj, &name.into())
+        .ok()
+        .filter(|v| !v.is_undefined() && !v.is_null())
+}
+
+// Opens the file name in the origin's OPFS root, creating it if need be.
+// Returns None if OPFS or sync access handles are unavailable.
+async fn open_access_handle(name: &str) -> Result<Option<AccessHandle>> {
+    let storage = match property(&js_sys::global(), "navigator")
+        .and_then(|navigator| property(&navigator, "storage"))
+    {
+        Some(storage) if property(&storage, "getDirectory").is_some() => storage,
+        _ => return Ok(None),
+    };
+    let storage: StorageManager = storage.unchecked_into();
+    let root: DirectoryHandle = JsFuture::from(storage.get_directory())
+        .await?
+        .unchecked_into();
+    let options = js_sys::Object::new();
+    js_sys::Reflect::set(&options, &"create".into(), &true.into())?;
+    let file = JsFuture::from(root.get_file_handle(name, &options)).await?;
+    // Only Workers have sync access handles.
+    if property(&file, "createSyncAccessHandle").is_none() {
+        return Ok(None);
+    }
+    let file: FileHandle = file.unchecked_into();
+    let handle = JsFuture::from(file.create_sync_access_handle()).await?;
+    Ok(Some(AccessHandle(handle.unchecked_into())))
+}
+
+// Where a value is in the segment.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Location {
+    offset: u64,
+    len: usize,
+}
+
+enum Record {
+    Put(String, Location),
+    Del(String),
+    Commit,
+}
+
+// Reads records from a segment file, from its start.
+struct RecordReader<'a> {
+    file: &'a dyn SegmentFile,
+    size: u64,
+    pos: u64,
+}
+
+impl RecordReader<'_> {
+    // Reads len bytes at pos and advances past them, or returns None if the
+    // file ends first.
+    fn read(&mut self, len: usize) -> Result<Option<Vec<u8>>> {
+        if !self.fits(len) {
+            return Ok(None);
+        }
+        let mut buf = vec![0; len];
+        self.file.read_at(&mut buf, self.pos)?;
+        self.pos += len as u64;
+        Ok(Some(buf))
+    }
+
+    fn read_len(&mut self) -> Result<Option<usize>> {
+        Ok(self
+            .read(4)?
+            .and_then(|b| <[u8; 4]>::try_from(b.as_slice()).ok())
+            .map(|b| u32::from_le_bytes(b) as usize))
+    }
+
+    fn fits(&self, len: usize) -> bool {
+        matches!(self.pos.checked_add(len as u64), Some(end) if end <= self.size)
+    }
+
+    // Reads the next record, skipping over a put's value rather than reading
+    // it. Returns None at the end of the file, or at a torn or corrupt
+    // record.
+    fn record(&mut self) -> Result<Option<Record>> {
+        let tag = match self.read(1)? {
+            Some(tag) => tag[0],
+            None => return Ok(None),
+        };
+        if tag == COMMIT {
+            return Ok(Some(Record::Commit));
+        }
+        if tag != PUT && tag != DEL {
+            return Ok(None);
+        }
+        let key = match self.read_len()? {
+            Some(len) => self.read(len)?,
+            None => None,
+        };
+        let key = match key.map(String::from_utf8) {
+            Some(Ok(key)) => key,
+            _ => return Ok(None),
+        };
+        if tag == DEL {
+            return Ok(Some(Record::Del(key)));
+        }
+        match self.read_len()? {
+            Some(len) if self.fits(len) => {
+                let location = Location {
+                    offset: self.pos,
+                    len,
+                };
+                self.pos += len as u64;
+                Ok(Some(Record::Put(key, location)))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+// Returns the index of the committed records in file, and the length of
+// the part of file they make up.
+fn replay(file: &dyn SegmentFile, size: u64) -> Result<(HashMap<String, Location>, u64)> {
+    let mut index = HashMap::new();
+    let mut uncommitted = vec![];
+    let mut reader = RecordReader { file, size, pos: 0 };
+    let mut end = 0;
+    while let Some(record) = reader.record()? {
+        match record {
+            Record::Put(key, location) => uncommitted.push((key, Some(location))),
+            Record::Del(key) => uncommitted.push((key, None)),
+            Record::Commit => {
+                for (key, location) in uncommitted.drain(..) {
+                    match location {
+                        Some(location) => index.insert(key, location),
+                        None => index.remove(&key),
+                    };
+                }
+                end = reader.pos;
+            }
+        }
+    }
+    Ok((index, end))
+}
+
+fn push_bytes(buf: &mut Vec<u8>, bytes: &[u8]) -> Result<()> {
+    let len = u32::try_from(bytes.len())
+        .map_err(|_| StoreError::Str(format!("Too long for OPFS: {} bytes", bytes.len())))?;
+    buf.extend_from_slice(&len.to_le_bytes());
+    buf.extend_from_slice(bytes);
+    Ok(())
+}
+
+struct Segment {
+    file: Box<dyn SegmentFile>,
+    index: HashMap<String, Location>,
+    // The length of the committed records, where the next commit goes.
+    end: u64,
+}
+
+impl Segment {
+    fn open(file: Box<dyn SegmentFile>) -> Result<Segment> {
+        let size = file.size()?;
+        let (index, end) = replay(file.as_ref(), size)?;
+        if end < size {
+            warn!("Dropping {} bytes of uncommitted OPFS records", size - end);
+            file.truncate(end)?;
+        }
+        Ok(Segment { file, index, end })
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match self.index.get(key) {
+            None => Ok(None),
+            Some(location) => {
+                let mut value = vec![0; location.len];
+                self.file.read_at(&mut value, location.offset)?;
+                Ok(Some(value))
+            }
+        }
+    }
+
+    fn scan_keys(&self, prefix: &str) -> Vec<String> {
+        let mut keys: Vec<String> = self
+            .index
+            .keys()
+            .filter(|k| k.starts_with(prefix))
+            .cloned()
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut entries = vec![];
+        for key in self.scan_keys(prefix) {
+            let value = self.get(&key)?.unwrap_or_default();
+            entries.push((key, value));
+        }
+        Ok(entries)
+    }
+
+    fn commit(&mut self, pending: &HashMap<String, Option<Vec<u8>>>) -> Result<()> {
+        let mut records = vec![];
+        let mut updates = vec![];
+        for (key, value) in pending.iter() {
+            match value {
+                Some(value) => {
+                    records.push(PUT);
+                    push_bytes(&mut records, key.as_bytes())?;
+                    push_bytes(&mut records, value)?;
+                    let location = Location {
+                        offset: self.end + (records.len() - value.len()) as u64,
+                        len: value.len(),
+                    };
+                    updates.push((key, Some(location)));
+                }
+                None => {
+                    records.push(DEL);
+                    push_bytes(&mut records, key.as_bytes())?;
+                    updates.push((key, None));
+                }
+            }
+        }
+        records.push(COMMIT);
+
+        let written = self
+            .file
+            .write_at(&records, self.end)
+            .and_then(|()| self.file.flush());
+        if let Err(e) = written {
+            // Drop whatever part of the commit made it to the file. If this
+            // fails too, the next open() drops it instead.
+            if let Err(e) = self.file.truncate(self.end) {
+                warn!("Truncating failed OPFS commit failed: {}", e);
+            }
+            return Err(e);
+        }
+        self.end += records.len() as u64;
+        for (key, location) in updates {
+            match location {
+                Some(location) => self.index.insert(key.clone(), location),
+                None => self.index.remove(key),
+            };
+        }
+        Ok(())
+    }
+}
+
+pub struct OpfsStore {
+    segment: RwLock<Segment>,
+}
+
+impl OpfsStore {
+    /// Opens the store kept in the file name in the origin's OPFS root,
+    /// creating it if need be. Returns None where OPFS or its synchronous
+    /// access handles are unavailable, e.g. outside Workers or in older
+    /// browsers, so callers can fall back to IdbStore. The file stays
+    /// locked until the store is dropped, so opening a store that is
+    /// already open (e.g. in another tab) fails.
+    pub async fn new(name: &str) -> Result<Option<OpfsStore>> {
+        match open_access_handle(name).await? {
+            Some(handle) => Ok(Some(OpfsStore::open(Box::new(handle))?)),
+            None => Ok(None),
+        }
+    }
+
+    fn open(file: Box<dyn SegmentFile>) -> Result<OpfsStore> {
+        Ok(OpfsStore {
+            segment: RwLock::new(Segment::open(file)?),
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl Store for OpfsStore {
+    async fn read<'a>(&'a self) -> Result<Box<dyn Read + 'a>> {
+        Ok(Box::new(ReadTransaction {
+            segment: self.segment.read().await,
+        }))
+    }
+
+    async fn write<'a>(&'a self) -> Result<Box<dyn Write + 'a>> {
+        Ok(Box::new(WriteTransaction {
+            segment: self.segment.write().await,
+            pending: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    fn isolation(&self) -> IsolationLevel {
+        RW_LOCK_ISOLATION
+    }
+}
+
+struct ReadTransaction<'a> {
+    segment: RwLockReadGuard<'a, Segment>,
+}
+
+#[async_trait(?Send)]
+impl Read for ReadTransaction<'_> {
+    async fn has(&self, key: &str) -> Result<bool> {
+        Ok(self.segment.index.contains_key(key))
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.segment.get(key)
+    }
+
+    async fn scan(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        self.segment.scan(prefix)
+    }
+
+    async fn scan_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        Ok(self.segment.scan_keys(prefix))
+    }
+
+    async fn count(&self) -> Result<usize> {
+        Ok(self.segment.index.len())
+    }
+
+    async fn count_prefix(&self, prefix: &str) -> Result<usize> {
+        Ok(self
+            .segment
+            .index
+            .keys()
+            .filter(|k| k.starts_with(prefix))
+            .count())
+    }
+}
+
+struct WriteTransaction<'a> {
+    segment: RwLockWriteGuard<'a, Segment>,
+    pending: Mutex<HashMap<String, Option<Vec<u8>>>>,
+}
+
+#[async_trait(?Send)]
+impl Read for WriteTransaction<'_> {
+    async fn has(&self, key: &str) -> Result<bool> {
+        match self.pending.lock().await.get(key) {
+            Some(value) => Ok(value.is_some()),
+            None => Ok(self.segment.index.contains_key(key)),
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match self.pending.lock().await.get(key) {
+            Some(value) => Ok(value.clone()),
+            None => self.segment.get(key),
+        }
+    }
+
+    async fn scan(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        let pending = self.pending.lock().await;
+        Ok(scan_pending(self.segment.scan(prefix)?, &pending, prefix))
+    }
+
+    async fn scan_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let pending = self.pending.lock().await;
+        Ok(scan_keys_pending(
+            self.segment.scan_keys(prefix),
+            &pending,
+            prefix,
+        ))
+    }
+}
+
+#[async_trait(?Send)]
+impl Write for WriteTransaction<'_> {
+    fn as_read(&self) -> &dyn Read {
+        self
+    }
+
+    async fn put(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.pending
+            .lock()
+            .await
+            .insert(key.into(), Some(value.to_vec()));
+        Ok(())
+    }
+
+    async fn del(&self, key: &str) -> Result<()> {
+        self.pending.lock().await.insert(key.into(), None);
+        Ok(())
+    }
+
+    async fn reserve(&self, additional: usize) {
+        self.pending.lock().await.reserve(additional);
+    }
+
+    #[cfg(debug_assertions)]
+    async fn pending_summary(&self) -> PendingSummary {
+        PendingSummary::new(&*self.pending.lock().await)
+    }
+
+    async fn commit(mut self: Box<Self>) -> Result<CommitReport> {
+        let pending = self.pending.lock().await;
+        if !pending.is_empty() {
+            self.segment.commit(&pending)?;
+        }
+        Ok(CommitReport::new(&pending))
+    }
+
+    // As in MemStore, the transaction still holds the segment, so it serves
+    // as the read once its pending writes are in the segment.
+    async fn commit_and_read<'b>(mut self: Box<Self>) -> Result<Box<dyn Read + 'b>>
+    where
+        Self: 'b,
+    {
+        {
+            let mut pending = self.pending.lock().await;
+            if !pending.is_empty() {
+                self.segment.commit(&pending)?;
+            }
+            pending.clear();
+        }
+        Ok(self)
+    }
+
+    async fn rollback(self: Box<Self>) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::trait_tests;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    // A file in memory, which survives the store so it can be reopened, and
+    // the number of bytes read from it.
+    #[derive(Clone, Default)]
+    struct MemFile(Rc<RefCell<Vec<u8>>>, Rc<Cell<usize>>);
+
+    impl SegmentFile for MemFile {
+        fn size(&self) -> Result<u64> {
+            Ok(self.0.borrow().len() as u64)
+        }
+
+        fn read_at(&self, buf: &mut [u8], at: u64) -> Result<()> {
+            let at = at as usize;
+            buf.copy_from_slice(&self.0.borrow()[at..at + buf.len()]);
+            self.1.set(self.1.get() + buf.len());
+            Ok(())
+        }
+
+        fn write_at(&self, data: &[u8], at: u64) -> Result<()> {
+            let mut file = self.0.borrow_mut();
+            let at = at as usize;
+            if file.len() < at + data.len() {
+                file.resize(at + data.len(), 0);
+            }
+            file[at..at + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+
+        fn truncate(&self, len: u64) -> Result<()> {
+            self.0.borrow_mut().resize(len as usize, 0);
+            Ok(())
+        }
+
+        fn flush(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    async fn new_store() -> Box<dyn Store> {
+        Box::new(OpfsStore::open(Box::new(MemFile::default())).unwrap())
+    }
+
+    #[async_std::test]
+    async fn test_opfs_store() {
+        trait_tests::run_all(&new_store).await;
+    }
+
+    #[async_std::test]
+    async fn reopen() {
+        let file = MemFile::default();
+        let store = OpfsStore::open(Box::new(file.clone())).unwrap();
+        store.put("a", b"1").await.unwrap();
+        store.put("b", b"2").await.unwrap();
+        let wt = store.write().await.unwrap();
+        wt.put("a", b"3").await.unwrap();
+        wt.del("b").await.unwrap();
+        wt.put("c", b"").await.unwrap();
+        wt.commit().await.unwrap();
+        let wt = store.write().await.unwrap();
+        wt.put("d", b"4").await.unwrap();
+        wt.rollback().await.unwrap();
+        drop(store);
+
+        let store = OpfsStore::open(Box::new(file)).unwrap();
+        let rt = store.read().await.unwrap();
+        assert_eq!(
+            vec![("a".to_string(), b"3".to_vec()), ("c".to_string(), vec![])],
+            rt.scan("").await.unwrap()
+        );
+    }
+
+    #[async_std::test]
+    async fn torn_commit() {
+        let file = MemFile::default();
+        let store = OpfsStore::open(Box::new(file.clone())).unwrap();
+        store.put("a", b"1").await.unwrap();
+        let committed = file.0.borrow().len();
+        store.put("b", b"2").await.unwrap();
+        drop(store);
+
+        // Every prefix of the second commit is dropped on open.
+        let full = file.0.borrow().clone();
+        for len in committed..full.len() {
+            file.0.replace(full[..len].to_vec());
+            let store = OpfsStore::open(Box::new(file.clone())).unwrap();
+            assert_eq!(committed, file.0.borrow().len());
+            assert_eq!(Some(b"1".to_vec()), store.get("a").await.unwrap());
+            assert!(!store.has("b").await.unwrap());
+
+            // And later commits land after the last good one.
+            store.put("c", b"3").await.unwrap();
+            drop(store);
+            let store = OpfsStore::open(Box::new(file.clone())).unwrap();
+            assert_eq!(Some(b"3".to_vec()), store.get("c").await.unwrap());
+        }
+    }
+
+    #[async_std::test]
+    async fn open_skips_values() {
+        let file = MemFile::default();
+        let store = OpfsStore::open(Box::new(file.clone())).unwrap();
+        let value = vec![7; 1 << 20];
+        store.put("big", &value).await.unwrap();
+        store.put("small", b"1").await.unwrap();
+        drop(store);
+
+        // Opening only reads the records' headers and keys.
+        file.1.set(0);
+        let store = OpfsStore::open(Box::new(file.clone())).unwrap();
+        assert!(file.1.get() < 64, "read {} bytes", file.1.get());
+        assert_eq!(Some(value), store.get("big").await.unwrap());
+        assert_eq!(Some(b"1".to_vec()), store.get("small").await.unwrap());
+    }
+}
@@ -0,0 +1,134 @@
+//! Memoizes get() and has() within a read transaction.
+//!
+//! A read transaction sees a consistent snapshot, so once a key has been
+//! fetched its value can't change for the rest of the transaction, and
+//! repeated gets of it (e.g. of the same chunk during a prolly traversal)
+//! can be served from memory. Values stay cached until the transaction is
+//! dropped, so this trades memory for round trips; it is meant for stores
+//! like IdbStore where each get is an asynchronous request.
+use crate::kv::{Read, Result};
+use async_trait::async_trait;
+use futures::stream::LocalBoxStream;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+pub struct CachedRead<'a> {
+    inner: Box<dyn Read + 'a>,
+    cache: RefCell<HashMap<String, Option<Vec<u8>>>>,
+}
+
+impl<'a> CachedRead<'a> {
+    pub fn new(inner: Box<dyn Read + 'a>) -> CachedRead<'a> {
+        CachedRead {
+            inner,
+            cache: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Read for CachedRead<'_> {
+    async fn has(&self, key: &str) -> Result<bool> {
+        if let Some(value) = self.cache.borrow().get(key) {
+            return Ok(value.is_some());
+        }
+        self.inner.has(key).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.cache.borrow().get(key) {
+            return Ok(value.clone());
+        }
+        let value = self.inner.get(key).await?;
+        self.cache.borrow_mut().insert(key.into(), value.clone());
+        Ok(value)
+    }
+
+    async fn scan(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        self.inner.scan(prefix).await
+    }
+
+    fn scan_stream<'b>(&'b self, prefix: &'b str) -> LocalBoxStream<'b, Result<(String, Vec<u8>)>> {
+        self.inner.scan_stream(prefix)
+    }
+
+    async fn scan_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        self.inner.scan_keys(prefix).await
+    }
+
+    async fn count(&self) -> Result<usize> {
+        self.inner.count().await
+    }
+
+    async fn count_prefix(&self, prefix: &str) -> Result<usize> {
+        self.inner.count_prefix(prefix).await
+    }
+
+    async fn first_key(&self) -> Result<Option<String>> {
+        self.inner.first_key().await
+    }
+
+    async fn last_key(&self) -> Result<Option<String>> {
+        self.inner.last_key().await
+    }
+
+    // Drops the cache too, so that later gets fail like other reads.
+    fn abort(&self) {
+        self.cache.borrow_mut().clear();
+        self.inner.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::memstore::MemStore;
+    use crate::kv::Store;
+    use std::cell::Cell;
+
+    // Counts the gets that reach the underlying store.
+    struct CountingRead<'a> {
+        inner: Box<dyn Read + 'a>,
+        gets: &'a Cell<usize>,
+    }
+
+    #[async_trait(?Send)]
+    impl Read for CountingRead<'_> {
+        async fn has(&self, key: &str) -> Result<bool> {
+            self.inner.has(key).await
+        }
+
+        async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+            self.gets.set(self.gets.get() + 1);
+            self.inner.get(key).await
+        }
+
+        async fn scan(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+            self.inner.scan(prefix).await
+        }
+    }
+
+    #[async_std::test]
+    async fn duplicate_gets() {
+        let store = MemStore::new();
+        store.put("k", b"v").await.unwrap();
+        let gets = Cell::new(0);
+        let rt = CachedRead::new(Box::new(CountingRead {
+            inner: store.read().await.unwrap(),
+            gets: &gets,
+        }));
+
+        for _ in 0..3 {
+            assert_eq!(Some(b"v".to_vec()), rt.get("k").await.unwrap());
+            assert!(rt.has("k").await.unwrap());
+        }
+        assert_eq!(1, gets.get());
+
+        // Misses are cached too.
+        for _ in 0..3 {
+            assert_eq!(None, rt.get("missing").await.unwrap());
+            assert!(!rt.has("missing").await.unwrap());
+        }
+        assert_eq!(2, gets.get());
+    }
+}
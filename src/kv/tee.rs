@@ -0,0 +1,316 @@
+//! A Store that copies every committed write to a second store, e.g. to
+//! keep a live backup of an IndexedDB store.
+//!
+//! Reads, and reads within write transactions, only use the source store.
+//! On commit the transaction's writes are committed to the source first,
+//! then replayed against the backup in a write transaction of its own.
+//!
+//! Consistency caveats:
+//! - The two stores are not updated atomically. If the backup write fails
+//!   the source keeps the data regardless, and the backup misses it.
+//!   TeeMode::Strict reports the failure to the caller; TeeMode::BestEffort
+//!   only logs it.
+//! - Writes made directly to either store, rather than through the
+//!   TeeStore, are not copied.
+#[cfg(debug_assertions)]
+use crate::kv::PendingSummary;
+use crate::kv::{CommitReport, IsolationLevel, Read, Result, Store, Write};
+use async_std::sync::Mutex;
+use async_trait::async_trait;
+use futures::future::LocalBoxFuture;
+use log::warn;
+use std::collections::HashMap;
+
+/// What a TeeStore does when copying a commit to the backup fails.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TeeMode {
+    /// commit() fails, although the source has committed.
+    Strict,
+    /// The failure is logged and commit() succeeds.
+    BestEffort,
+}
+
+pub struct TeeStore {
+    source: Box<dyn Store>,
+    backup: Box<dyn Store>,
+    mode: TeeMode,
+}
+
+impl TeeStore {
+    /// Creates a store that reads from and writes to source, and copies
+    /// committed writes to backup.
+    pub fn new(source: Box<dyn Store>, backup: Box<dyn Store>, mode: TeeMode) -> TeeStore {
+        TeeStore {
+            source,
+            backup,
+            mode,
+        }
+    }
+
+    async fn copy_to_backup(&self, pending: HashMap<String, Option<Vec<u8>>>) -> Result<()> {
+        let wt = self.backup.write().await?;
+        for (key, value) in pending.iter() {
+            match value {
+                Some(v) => wt.put(key, v).await?,
+                None => wt.del(key).await?,
+            }
+        }
+        wt.commit_unit().await
+    }
+
+    // Copies a commit's writes to backup, unless there were none. Failures
+    // only fail the commit in TeeMode::Strict.
+    async fn back_up(&self, pending: HashMap<String, Option<Vec<u8>>>) -> Result<()> {
+        if pending.is_empty() {
+            return Ok(());
+        }
+        match self.copy_to_backup(pending).await {
+            Ok(()) => Ok(()),
+            Err(e) if self.mode == TeeMode::BestEffort => {
+                warn!("Copying commit to backup store failed: {}", e);
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Store for TeeStore {
+    async fn read<'a>(&'a self) -> Result<Box<dyn Read + 'a>> {
+        self.source.read().await
+    }
+
+    async fn write<'a>(&'a self) -> Result<Box<dyn Write + 'a>> {
+        Ok(Box::new(WriteTransaction {
+            store: self,
+            inner: self.source.write().await?,
+            pending: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    // Transactions only run against source; the backup is written after
+    // the fact, outside of them.
+    fn isolation(&self) -> IsolationLevel {
+        self.source.isolation()
+    }
+}
+
+struct WriteTransaction<'a> {
+    store: &'a TeeStore,
+    inner: Box<dyn Write + 'a>,
+    // A copy of the writes made through this transaction, to replay against
+    // the backup once the source has committed them.
+    pending: Mutex<HashMap<String, Option<Vec<u8>>>>,
+}
+
+#[async_trait(?Send)]
+impl Read for WriteTransaction<'_> {
+    async fn has(&self, key: &str) -> Result<bool> {
+        self.inner.has(key).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.inner.get(key).await
+    }
+
+    async fn scan(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        self.inner.scan(prefix).await
+    }
+
+    async fn scan_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        self.inner.scan_keys(prefix).await
+    }
+
+    async fn count(&self) -> Result<usize> {
+        self.inner.count().await
+    }
+
+    async fn count_prefix(&self, prefix: &str) -> Result<usize> {
+        self.inner.count_prefix(prefix).await
+    }
+
+    async fn first_key(&self) -> Result<Option<String>> {
+        self.inner.first_key().await
+    }
+
+    async fn last_key(&self) -> Result<Option<String>> {
+        self.inner.last_key().await
+    }
+}
+
+#[async_trait(?Send)]
+impl Write for WriteTransaction<'_> {
+    fn as_read(&self) -> &dyn Read {
+        self
+    }
+
+    async fn put(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.inner.put(key, value).await?;
+        self.pending
+            .lock()
+            .await
+            .insert(key.into(), Some(value.to_vec()));
+        Ok(())
+    }
+
+    async fn del(&self, key: &str) -> Result<()> {
+        self.inner.del(key).await?;
+        self.pending.lock().await.insert(key.into(), None);
+        Ok(())
+    }
+
+    async fn reserve(&self, additional: usize) {
+        self.inner.reserve(additional).await;
+        self.pending.lock().await.reserve(additional);
+    }
+
+    #[cfg(debug_assertions)]
+    async fn pending_summary(&self) -> PendingSummary {
+        self.inner.pending_summary().await
+    }
+
+    async fn commit(self: Box<Self>) -> Result<CommitReport> {
+        let WriteTransaction {
+            store,
+            inner,
+            pending,
+        } = *self;
+        let report = inner.commit().await?;
+        store.back_up(pending.into_inner()).await?;
+        Ok(report)
+    }
+
+    // The backup is written while the read holds source, as commit() does
+    // once source is committed.
+    async fn commit_and_read<'b>(self: Box<Self>) -> Result<Box<dyn Read + 'b>>
+    where
+        Self: 'b,
+    {
+        let WriteTransaction {
+            store,
+            inner,
+            pending,
+        } = *self;
+        let read = inner.commit_and_read().await?;
+        store.back_up(pending.into_inner()).await?;
+        Ok(read)
+    }
+
+    async fn rollback(self: Box<Self>) -> Result<()> {
+        self.inner.rollback().await
+    }
+
+    async fn keep_alive(&self, work: LocalBoxFuture<'_, ()>) -> Result<()> {
+        self.inner.keep_alive(work).await
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::memstore::MemStore;
+    use crate::kv::{trait_tests, StoreError};
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    async fn new_store() -> Box<dyn Store> {
+        Box::new(TeeStore::new(
+            Box::new(MemStore::new()),
+            Box::new(MemStore::new()),
+            TeeMode::Strict,
+        ))
+    }
+
+    #[async_std::test]
+    async fn test_tee_store() {
+        trait_tests::run_all(&new_store).await;
+    }
+
+    // A store whose writes fail while failing is set.
+    struct FlakyStore {
+        store: Rc<MemStore>,
+        failing: Rc<Cell<bool>>,
+    }
+
+    #[async_trait(?Send)]
+    impl Store for FlakyStore {
+        async fn read<'a>(&'a self) -> Result<Box<dyn Read + 'a>> {
+            self.store.read().await
+        }
+
+        async fn write<'a>(&'a self) -> Result<Box<dyn Write + 'a>> {
+            match self.failing.get() {
+                true => Err(StoreError::Str("flaky".into())),
+                false => self.store.write().await,
+            }
+        }
+
+        fn isolation(&self) -> IsolationLevel {
+            self.store.isolation()
+        }
+    }
+
+    fn new_tee(mode: TeeMode) -> (TeeStore, Rc<MemStore>, Rc<MemStore>, Rc<Cell<bool>>) {
+        let (source, backup) = (Rc::new(MemStore::new()), Rc::new(MemStore::new()));
+        let failing = Rc::new(Cell::new(false));
+        let store = TeeStore::new(
+            Box::new(FlakyStore {
+                store: source.clone(),
+                failing: Rc::new(Cell::new(false)),
+            }),
+            Box::new(FlakyStore {
+                store: backup.clone(),
+                failing: failing.clone(),
+            }),
+            mode,
+        );
+        (store, source, backup, failing)
+    }
+
+    #[async_std::test]
+    async fn best_effort() {
+        let (store, source, backup, failing) = new_tee(TeeMode::BestEffort);
+
+        // Committed writes reach both stores; rolled back ones neither.
+        store.put("k1", b"v1").await.unwrap();
+        let wt = store.write().await.unwrap();
+        wt.put("k2", b"v2").await.unwrap();
+        wt.rollback().await.unwrap();
+        for s in &[&source, &backup] {
+            assert_eq!(Some(b"v1".to_vec()), s.get("k1").await.unwrap());
+            assert!(!s.has("k2").await.unwrap());
+        }
+
+        // While the backup fails, commits still succeed.
+        failing.set(true);
+        store.put("k2", b"v2").await.unwrap();
+        failing.set(false);
+        let wt = store.write().await.unwrap();
+        wt.put("k3", b"v3").await.unwrap();
+        wt.del("k1").await.unwrap();
+        wt.commit().await.unwrap();
+        assert_eq!(Some(b"v2".to_vec()), source.get("k2").await.unwrap());
+        assert!(!backup.has("k2").await.unwrap());
+        for s in &[&source, &backup] {
+            assert!(!s.has("k1").await.unwrap());
+            assert_eq!(Some(b"v3".to_vec()), s.get("k3").await.unwrap());
+        }
+
+        // Reads come only from the source.
+        backup.put("k4", b"v4").await.unwrap();
+        assert!(!store.has("k4").await.unwrap());
+        assert_eq!(Some(b"v2".to_vec()), store.get("k2").await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn strict() {
+        let (store, source, backup, failing) = new_tee(TeeMode::Strict);
+        failing.set(true);
+        assert!(store.put("k", b"v").await.is_err());
+        // The source has committed regardless.
+        assert!(source.has("k").await.unwrap());
+        assert!(!backup.has("k").await.unwrap());
+    }
+}
@@ -0,0 +1,149 @@
+//! Values that expire a fixed time after they're written.
+//!
+//! put_with_ttl() records an expiry time as "x/<key>", an 8 byte
+//! little-endian count of milliseconds since the Unix epoch, through the
+//! same kv::Write as the value. get_unexpired() treats a value whose expiry
+//! has passed as absent; expired values stay in the store until
+//! del_expired() removes them. Time comes from a Clock, so tests can use a
+//! MockClock.
+use crate::kv::clock::Clock;
+use crate::kv::{check_unreserved, Read, StoreError, Write};
+use std::convert::TryFrom;
+
+const EXPIRY_PREFIX: &str = "x/";
+
+type Result<T> = std::result::Result<T, StoreError>;
+
+// Puts value under key, to expire ttl_ms from now.
+pub async fn put_with_ttl(
+    write: &dyn Write,
+    clock: &dyn Clock,
+    key: &str,
+    value: &[u8],
+    ttl_ms: u64,
+) -> Result<()> {
+    check_unreserved(key)?;
+    let expiry = clock.now_ms().saturating_add(ttl_ms);
+    write.put(key, value).await?;
+    write.put(&expiry_key(key), &expiry.to_le_bytes()).await
+}
+
+// Returns the value of key, or None if it has none or it has expired. Values
+// written without an expiry never expire.
+pub async fn get_unexpired(
+    read: &dyn Read,
+    clock: &dyn Clock,
+    key: &str,
+) -> Result<Option<Vec<u8>>> {
+    check_unreserved(key)?;
+    match get_expiry(read, key).await? {
+        Some(expiry) if expiry <= clock.now_ms() => Ok(None),
+        _ => read.get(key).await,
+    }
+}
+
+// Deletes every expired value and its expiry, returning how many values
+// were deleted.
+pub async fn del_expired(write: &dyn Write, clock: &dyn Clock) -> Result<usize> {
+    let now = clock.now_ms();
+    let mut deleted = 0;
+    for (expiry_key, bytes) in write.as_read().scan(EXPIRY_PREFIX).await? {
+        let key = &expiry_key[EXPIRY_PREFIX.len()..];
+        if parse_expiry(key, &bytes)? <= now {
+            write.del(key).await?;
+            write.del(&expiry_key).await?;
+            deleted += 1;
+        }
+    }
+    Ok(deleted)
+}
+
+async fn get_expiry(read: &dyn Read, key: &str) -> Result<Option<u64>> {
+    match read.get(&expiry_key(key)).await? {
+        None => Ok(None),
+        Some(bytes) => parse_expiry(key, &bytes).map(Some),
+    }
+}
+
+fn parse_expiry(key: &str, bytes: &[u8]) -> Result<u64> {
+    match <[u8; 8]>::try_from(bytes) {
+        Ok(bytes) => Ok(u64::from_le_bytes(bytes)),
+        Err(_) => Err(StoreError::Str(format!(
+            "Corrupt expiry for {}: {:?}",
+            key, bytes
+        ))),
+    }
+}
+
+fn expiry_key(key: &str) -> String {
+    format!("{}{}", EXPIRY_PREFIX, key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::clock::MockClock;
+    use crate::kv::memstore::MemStore;
+    use crate::kv::Store;
+
+    #[async_std::test]
+    async fn expiry() {
+        let store = MemStore::new();
+        let clock = MockClock::new(1000);
+        let wt = store.write().await.unwrap();
+        put_with_ttl(wt.as_ref(), &clock, "short", b"a", 10)
+            .await
+            .unwrap();
+        put_with_ttl(wt.as_ref(), &clock, "long", b"b", 100)
+            .await
+            .unwrap();
+        wt.put("forever", b"c").await.unwrap();
+        wt.commit().await.unwrap();
+
+        let (store, clock) = (&store, &clock);
+        let get = move |key| async move {
+            let rt = store.read().await.unwrap();
+            get_unexpired(rt.as_ref(), clock, key).await.unwrap()
+        };
+        assert_eq!(Some(b"a".to_vec()), get("short").await);
+        clock.advance(9);
+        assert_eq!(Some(b"a".to_vec()), get("short").await);
+        clock.advance(1);
+        assert_eq!(None, get("short").await);
+        assert_eq!(Some(b"b".to_vec()), get("long").await);
+        assert_eq!(Some(b"c".to_vec()), get("forever").await);
+
+        // Only the expired value is removed.
+        let wt = store.write().await.unwrap();
+        assert_eq!(1, del_expired(wt.as_ref(), clock).await.unwrap());
+        wt.commit().await.unwrap();
+        assert!(!store.has("short").await.unwrap());
+        assert!(!store.has("x/short").await.unwrap());
+        assert!(store.has("long").await.unwrap());
+
+        clock.advance(1000);
+        assert_eq!(None, get("long").await);
+        assert_eq!(Some(b"c".to_vec()), get("forever").await);
+        assert!(
+            get_unexpired(store.read().await.unwrap().as_ref(), clock, "x/long")
+                .await
+                .is_err()
+        );
+    }
+
+    #[async_std::test]
+    async fn reserved_keys() {
+        let store = MemStore::new();
+        let clock = MockClock::new(1000);
+        let wt = store.write().await.unwrap();
+        // Keys in another layer's namespace are rejected too, e.g. an expiry
+        // on "v/k" would otherwise overwrite the version record of "k".
+        for key in &["x/k", "v/k", "i/t/k", "c/k/d", "h/main", "sys/k"] {
+            assert!(put_with_ttl(wt.as_ref(), &clock, key, b"a", 10)
+                .await
+                .is_err());
+            assert!(get_unexpired(wt.as_read(), &clock, key).await.is_err());
+        }
+        assert_eq!(Vec::<String>::new(), wt.scan_keys("").await.unwrap());
+    }
+}
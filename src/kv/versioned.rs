@@ -0,0 +1,188 @@
+//! Per-key versions for optimistic concurrency over plain (not
+//! content-addressed) data.
+//!
+//! Each key written with put_if_version() has a version that starts at 1
+//! and increases by one with every write. A writer reads a value and its
+//! version with get_versioned(), and its later write only succeeds if the
+//! version is unchanged, so concurrent modifications are detected rather
+//! than silently overwritten.
+//!
+//! Versions are stored as "v/<key>" with an 8 byte little-endian value,
+//! through the same kv::Write as the value, so they commit or roll back
+//! atomically with it. Only writes made through put_if_version() and
+//! del_if_version() bump a key's version.
+use crate::kv::{check_unreserved, Read, StoreError, Write};
+use std::convert::TryFrom;
+
+const VERSION_PREFIX: &str = "v/";
+
+type Result<T> = std::result::Result<T, StoreError>;
+
+// Returns the value of key and its version, or None if key has no value.
+pub async fn get_versioned(read: &dyn Read, key: &str) -> Result<Option<(Vec<u8>, u64)>> {
+    check_unreserved(key)?;
+    match read.get(key).await? {
+        None => Ok(None),
+        Some(value) => Ok(Some((value, get_version(read, key).await?.unwrap_or(0)))),
+    }
+}
+
+// Puts value under key if key's current version is expected, where None
+// means key must have no value. Returns the new version, or fails with
+// StoreError::VersionMismatch.
+pub async fn put_if_version(
+    write: &dyn Write,
+    key: &str,
+    value: &[u8],
+    expected: Option<u64>,
+) -> Result<u64> {
+    let version = next_version(write, key, expected).await?;
+    write.put(key, value).await?;
+    write.put(&version_key(key), &version.to_le_bytes()).await?;
+    Ok(version)
+}
+
+// Deletes key if its current version is expected. The version itself is
+// kept, so a later put doesn't reuse versions a reader may have seen.
+pub async fn del_if_version(write: &dyn Write, key: &str, expected: u64) -> Result<()> {
+    let version = next_version(write, key, Some(expected)).await?;
+    write.del(key).await?;
+    write.put(&version_key(key), &version.to_le_bytes()).await
+}
+
+// Checks key's version against expected, returning the version to write.
+async fn next_version(write: &dyn Write, key: &str, expected: Option<u64>) -> Result<u64> {
+    check_unreserved(key)?;
+    let read = write.as_read();
+    let stored = get_version(read, key).await?;
+    let actual = match read.has(key).await? {
+        true => Some(stored.unwrap_or(0)),
+        false => None,
+    };
+    if actual != expected {
+        return Err(StoreError::VersionMismatch { expected, actual });
+    }
+    Ok(stored.unwrap_or(0) + 1)
+}
+
+async fn get_version(read: &dyn Read, key: &str) -> Result<Option<u64>> {
+    match read.get(&version_key(key)).await? {
+        None => Ok(None),
+        Some(bytes) => match <[u8; 8]>::try_from(bytes.as_slice()) {
+            Ok(bytes) => Ok(Some(u64::from_le_bytes(bytes))),
+            Err(_) => Err(StoreError::Str(format!(
+                "Corrupt version for {}: {:?}",
+                key, bytes
+            ))),
+        },
+    }
+}
+
+fn version_key(key: &str) -> String {
+    format!("{}{}", VERSION_PREFIX, key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::memstore::MemStore;
+    use crate::kv::Store;
+
+    #[async_std::test]
+    async fn put_if_version_checks() {
+        let store = MemStore::new();
+        let wt = store.write().await.unwrap();
+        assert_eq!(None, get_versioned(wt.as_read(), "k").await.unwrap());
+        assert_eq!(
+            1,
+            put_if_version(wt.as_ref(), "k", b"a", None).await.unwrap()
+        );
+        // Versions are visible inside the transaction.
+        assert_eq!(
+            Some((b"a".to_vec(), 1)),
+            get_versioned(wt.as_read(), "k").await.unwrap()
+        );
+        wt.commit().await.unwrap();
+
+        // A write against the current version succeeds...
+        let wt = store.write().await.unwrap();
+        assert_eq!(
+            2,
+            put_if_version(wt.as_ref(), "k", b"b", Some(1))
+                .await
+                .unwrap()
+        );
+        wt.commit().await.unwrap();
+
+        // and one against a stale version, or expecting no value, fails and
+        // changes nothing.
+        let wt = store.write().await.unwrap();
+        for expected in &[Some(1), Some(3), None] {
+            match put_if_version(wt.as_ref(), "k", b"c", *expected).await {
+                Err(StoreError::VersionMismatch {
+                    expected: e,
+                    actual: Some(2),
+                }) => assert_eq!(*expected, e),
+                r => panic!("unexpected result: {:?}", r),
+            }
+        }
+        wt.commit().await.unwrap();
+        let rt = store.read().await.unwrap();
+        assert_eq!(
+            Some((b"b".to_vec(), 2)),
+            get_versioned(rt.as_ref(), "k").await.unwrap()
+        );
+        assert!(get_versioned(rt.as_ref(), "v/k").await.is_err());
+    }
+
+    #[async_std::test]
+    async fn versions_survive_delete() {
+        let store = MemStore::new();
+        let wt = store.write().await.unwrap();
+        put_if_version(wt.as_ref(), "k", b"a", None).await.unwrap();
+        assert!(del_if_version(wt.as_ref(), "k", 2).await.is_err());
+        del_if_version(wt.as_ref(), "k", 1).await.unwrap();
+        assert_eq!(None, get_versioned(wt.as_read(), "k").await.unwrap());
+        assert!(put_if_version(wt.as_ref(), "k", b"b", Some(1))
+            .await
+            .is_err());
+        // Versions keep counting up rather than starting over.
+        assert_eq!(
+            3,
+            put_if_version(wt.as_ref(), "k", b"b", None).await.unwrap()
+        );
+        wt.commit().await.unwrap();
+
+        // Values written without this module read as version 0, and their
+        // writes are rolled back with the transaction.
+        store.put("plain", b"x").await.unwrap();
+        let wt = store.write().await.unwrap();
+        assert_eq!(
+            Some((b"x".to_vec(), 0)),
+            get_versioned(wt.as_read(), "plain").await.unwrap()
+        );
+        put_if_version(wt.as_ref(), "plain", b"y", Some(0))
+            .await
+            .unwrap();
+        wt.rollback().await.unwrap();
+        let rt = store.read().await.unwrap();
+        assert_eq!(
+            Some((b"x".to_vec(), 0)),
+            get_versioned(rt.as_ref(), "plain").await.unwrap()
+        );
+    }
+
+    #[async_std::test]
+    async fn reserved_keys() {
+        let store = MemStore::new();
+        let wt = store.write().await.unwrap();
+        // Keys in another layer's namespace are rejected too, e.g. a version
+        // on "x/k" would otherwise overwrite the expiry of "k".
+        for key in &["v/k", "x/k", "i/t/k", "c/k/d", "h/main", "sys/k"] {
+            assert!(put_if_version(wt.as_ref(), key, b"a", None).await.is_err());
+            assert!(del_if_version(wt.as_ref(), key, 0).await.is_err());
+            assert!(get_versioned(wt.as_read(), key).await.is_err());
+        }
+        assert_eq!(Vec::<String>::new(), wt.scan_keys("").await.unwrap());
+    }
+}
@@ -0,0 +1,353 @@
+//! A Store that watches for transactions held open too long.
+//!
+//! A transaction left open by mistake blocks every writer, and an IdbStore
+//! write transaction that sits idle can end on its own. WatchdogStore starts
+//! a timer with each transaction and, if the transaction is still open when
+//! it fires, logs a warning and counts it in expired(). With set_abort(),
+//! the transaction also fails from then on: its reads and writes return
+//! StoreError::Cancelled, and its commit rolls back instead. The timer is
+//! cancelled when the transaction is committed, rolled back or dropped.
+#[cfg(debug_assertions)]
+use crate::kv::PendingSummary;
+use crate::kv::{CommitReport, IsolationLevel, Read, Result, Store, StoreError, Write};
+use async_std::task;
+use async_trait::async_trait;
+use futures::future::{abortable, AbortHandle, LocalBoxFuture};
+use futures::stream::{self, LocalBoxStream, StreamExt};
+use log::warn;
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::Duration;
+
+pub struct WatchdogStore {
+    inner: Box<dyn Store>,
+    threshold: Duration,
+    abort: bool,
+    expired: Rc<Cell<u64>>,
+}
+
+impl WatchdogStore {
+    pub fn new(inner: Box<dyn Store>, threshold: Duration) -> WatchdogStore {
+        WatchdogStore {
+            inner,
+            threshold,
+            abort: false,
+            expired: Rc::new(Cell::new(0)),
+        }
+    }
+
+    /// Causes transactions still open at the threshold to fail from then
+    /// on, rather than only being warned about. Off by default.
+    pub fn set_abort(&mut self, abort: bool) {
+        self.abort = abort;
+    }
+
+    /// The number of transactions that were still open at the threshold.
+    pub fn expired(&self) -> u64 {
+        self.expired.get()
+    }
+
+    fn watch(&self, kind: &'static str) -> Watch {
+        let timed_out = Rc::new(Cell::new(false));
+        let (timer, handle) = abortable(task::sleep(self.threshold));
+        let (threshold, expired) = (self.threshold, self.expired.clone());
+        let flag = timed_out.clone();
+        task::spawn_local(async move {
+            if timer.await.is_ok() {
+                warn!("{} transaction open for more than {:?}", kind, threshold);
+                expired.set(expired.get() + 1);
+                flag.set(true);
+            }
+        });
+        Watch {
+            handle,
+            timed_out,
+            abort: self.abort,
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Store for WatchdogStore {
+    async fn read<'a>(&'a self) -> Result<Box<dyn Read + 'a>> {
+        let inner = self.inner.read().await?;
+        Ok(Box::new(ReadTransaction {
+            inner,
+            watch: self.watch("Read"),
+        }))
+    }
+
+    async fn write<'a>(&'a self) -> Result<Box<dyn Write + 'a>> {
+        let inner = self.inner.write().await?;
+        Ok(Box::new(WriteTransaction {
+            inner,
+            watch: self.watch("Write"),
+        }))
+    }
+
+    fn isolation(&self) -> IsolationLevel {
+        self.inner.isolation()
+    }
+}
+
+// A transaction's timer, cancelled on drop.
+struct Watch {
+    handle: AbortHandle,
+    timed_out: Rc<Cell<bool>>,
+    abort: bool,
+}
+
+impl Watch {
+    // Fails if the transaction timed out and should be aborted.
+    fn check(&self) -> Result<()> {
+        match self.abort && self.timed_out.get() {
+            true => Err(StoreError::Cancelled),
+            false => Ok(()),
+        }
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+struct ReadTransaction<'a> {
+    inner: Box<dyn Read + 'a>,
+    watch: Watch,
+}
+
+impl ReadTransaction<'_> {
+    // Like Watch::check(), also aborting the inner transaction so that it
+    // doesn't hold on to the store.
+    fn check(&self) -> Result<()> {
+        let result = self.watch.check();
+        if result.is_err() {
+            self.inner.abort();
+        }
+        result
+    }
+}
+
+#[async_trait(?Send)]
+impl Read for ReadTransaction<'_> {
+    async fn has(&self, key: &str) -> Result<bool> {
+        self.check()?;
+        self.inner.has(key).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.check()?;
+        self.inner.get(key).await
+    }
+
+    async fn scan(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        self.check()?;
+        self.inner.scan(prefix).await
+    }
+
+    fn scan_stream<'a>(&'a self, prefix: &'a str) -> LocalBoxStream<'a, Result<(String, Vec<u8>)>> {
+        match self.check() {
+            Ok(()) => self.inner.scan_stream(prefix),
+            Err(e) => stream::once(async { Err(e) }).boxed_local(),
+        }
+    }
+
+    async fn scan_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        self.check()?;
+        self.inner.scan_keys(prefix).await
+    }
+
+    async fn count(&self) -> Result<usize> {
+        self.check()?;
+        self.inner.count().await
+    }
+
+    async fn count_prefix(&self, prefix: &str) -> Result<usize> {
+        self.check()?;
+        self.inner.count_prefix(prefix).await
+    }
+
+    async fn first_key(&self) -> Result<Option<String>> {
+        self.check()?;
+        self.inner.first_key().await
+    }
+
+    async fn last_key(&self) -> Result<Option<String>> {
+        self.check()?;
+        self.inner.last_key().await
+    }
+
+    fn abort(&self) {
+        self.inner.abort();
+    }
+}
+
+struct WriteTransaction<'a> {
+    inner: Box<dyn Write + 'a>,
+    watch: Watch,
+}
+
+#[async_trait(?Send)]
+impl Read for WriteTransaction<'_> {
+    async fn has(&self, key: &str) -> Result<bool> {
+        self.watch.check()?;
+        self.inner.has(key).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.watch.check()?;
+        self.inner.get(key).await
+    }
+
+    async fn scan(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        self.watch.check()?;
+        self.inner.scan(prefix).await
+    }
+
+    async fn scan_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        self.watch.check()?;
+        self.inner.scan_keys(prefix).await
+    }
+
+    async fn count(&self) -> Result<usize> {
+        self.watch.check()?;
+        self.inner.count().await
+    }
+
+    async fn count_prefix(&self, prefix: &str) -> Result<usize> {
+        self.watch.check()?;
+        self.inner.count_prefix(prefix).await
+    }
+
+    async fn first_key(&self) -> Result<Option<String>> {
+        self.watch.check()?;
+        self.inner.first_key().await
+    }
+
+    async fn last_key(&self) -> Result<Option<String>> {
+        self.watch.check()?;
+        self.inner.last_key().await
+    }
+}
+
+#[async_trait(?Send)]
+impl Write for WriteTransaction<'_> {
+    fn as_read(&self) -> &dyn Read {
+        self
+    }
+
+    async fn put(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.watch.check()?;
+        self.inner.put(key, value).await
+    }
+
+    async fn del(&self, key: &str) -> Result<()> {
+        self.watch.check()?;
+        self.inner.del(key).await
+    }
+
+    async fn reserve(&self, additional: usize) {
+        self.inner.reserve(additional).await
+    }
+
+    #[cfg(debug_assertions)]
+    async fn pending_summary(&self) -> PendingSummary {
+        self.inner.pending_summary().await
+    }
+
+    async fn commit(self: Box<Self>) -> Result<CommitReport> {
+        if let Err(e) = self.watch.check() {
+            self.inner.rollback().await?;
+            return Err(e);
+        }
+        self.inner.commit().await
+    }
+
+    async fn commit_and_read<'b>(self: Box<Self>) -> Result<Box<dyn Read + 'b>>
+    where
+        Self: 'b,
+    {
+        if let Err(e) = self.watch.check() {
+            self.inner.rollback().await?;
+            return Err(e);
+        }
+        // The read keeps the write's timer, as it keeps its hold on the
+        // store.
+        Ok(Box::new(ReadTransaction {
+            inner: self.inner.commit_and_read().await?,
+            watch: self.watch,
+        }))
+    }
+
+    async fn rollback(self: Box<Self>) -> Result<()> {
+        self.inner.rollback().await
+    }
+
+    async fn keep_alive(&self, work: LocalBoxFuture<'_, ()>) -> Result<()> {
+        self.inner.keep_alive(work).await
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::memstore::MemStore;
+    use crate::kv::trait_tests;
+
+    async fn new_store() -> Box<dyn Store> {
+        Box::new(WatchdogStore::new(
+            Box::new(MemStore::new()),
+            Duration::from_secs(60),
+        ))
+    }
+
+    #[async_std::test]
+    async fn test_watchdog_store() {
+        trait_tests::run_all(&new_store).await;
+    }
+
+    #[async_std::test]
+    async fn slow_transactions() {
+        let threshold = Duration::from_millis(20);
+        let mut store = WatchdogStore::new(Box::new(MemStore::new()), threshold);
+
+        // Quick transactions, finished one way or another, aren't counted.
+        store.put("k", b"1").await.unwrap();
+        store.write().await.unwrap().rollback().await.unwrap();
+        drop(store.read().await.unwrap());
+        task::sleep(threshold * 3).await;
+        assert_eq!(0, store.expired());
+
+        // A slow one is counted, but by default still works.
+        let wt = store.write().await.unwrap();
+        task::sleep(threshold * 3).await;
+        assert_eq!(1, store.expired());
+        wt.put("k", b"2").await.unwrap();
+        wt.commit().await.unwrap();
+        assert_eq!(Some(b"2".to_vec()), store.get("k").await.unwrap());
+
+        // With abort, it fails from then on and doesn't commit.
+        store.set_abort(true);
+        let wt = store.write().await.unwrap();
+        wt.put("k", b"3").await.unwrap();
+        task::sleep(threshold * 3).await;
+        assert_eq!(2, store.expired());
+        let cancelled = |r: Result<()>| match r {
+            Err(StoreError::Cancelled) => (),
+            r => panic!("Expected Cancelled, got {:?}", r),
+        };
+        cancelled(wt.put("k", b"4").await);
+        cancelled(wt.get("k").await.map(|_| ()));
+        cancelled(wt.commit().await.map(|_| ()));
+        assert_eq!(Some(b"2".to_vec()), store.get("k").await.unwrap());
+
+        let rt = store.read().await.unwrap();
+        task::sleep(threshold * 3).await;
+        assert_eq!(3, store.expired());
+        cancelled(rt.get("k").await.map(|_| ()));
+        cancelled(rt.scan_stream("").next().await.unwrap().map(|_| ()));
+    }
+}
@@ -9,6 +9,7 @@ mod dag;
 mod db;
 pub mod embed;
 mod hash;
+mod index;
 
 #[cfg(not(default))]
 pub mod kv;
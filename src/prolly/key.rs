@@ -0,0 +1,172 @@
+// KeyBuilder encodes multiple variable-length components into a single
+// byte key such that comparing encoded keys bytewise gives the same order
+// as comparing their component lists element by element.
+//
+// Each component is written with 0x00 bytes escaped as 0x00 0xff and
+// followed by a 0x00 0x01 terminator. Because the terminator sorts below
+// both an escaped 0x00 and any other byte, a component always sorts before
+// any longer component it is a prefix of, and the encoding of a leading
+// component is a prefix of exactly those keys that start with it, so
+// scanning by the first component(s) works as expected.
+const ESCAPE: u8 = 0x00;
+const ESCAPED_ZERO: u8 = 0xff;
+const TERMINATOR: u8 = 0x01;
+
+#[derive(Default)]
+pub struct KeyBuilder {
+    buf: Vec<u8>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum DecodeError {
+    InvalidEscape(u8),
+    UnterminatedComponent,
+}
+
+impl KeyBuilder {
+    pub fn new() -> KeyBuilder {
+        KeyBuilder::default()
+    }
+
+    pub fn push(self, component: &[u8]) -> KeyBuilder {
+        let mut kb = self.push_prefix(component);
+        kb.buf.push(ESCAPE);
+        kb.buf.push(TERMINATOR);
+        kb
+    }
+
+    // Appends component without its terminator, so the built key is a
+    // prefix of exactly those keys whose next component starts with it.
+    pub fn push_prefix(mut self, component: &[u8]) -> KeyBuilder {
+        for b in component {
+            self.buf.push(*b);
+            if *b == ESCAPE {
+                self.buf.push(ESCAPED_ZERO);
+            }
+        }
+        self
+    }
+
+    pub fn build(self) -> Vec<u8> {
+        self.buf
+    }
+
+    // Splits a key built by KeyBuilder back into its components.
+    pub fn decode(key: &[u8]) -> Result<Vec<Vec<u8>>, DecodeError> {
+        let mut components = Vec::new();
+        let mut current = Vec::new();
+        let mut it = key.iter();
+        while let Some(b) = it.next() {
+            if *b != ESCAPE {
+                current.push(*b);
+                continue;
+            }
+            match it.next() {
+                Some(&ESCAPED_ZERO) => current.push(ESCAPE),
+                Some(&TERMINATOR) => components.push(std::mem::take(&mut current)),
+                Some(b) => return Err(DecodeError::InvalidEscape(*b)),
+                None => return Err(DecodeError::UnterminatedComponent),
+            }
+        }
+        if !current.is_empty() {
+            return Err(DecodeError::UnterminatedComponent);
+        }
+        Ok(components)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(components: &[&[u8]]) -> Vec<u8> {
+        components
+            .iter()
+            .fold(KeyBuilder::new(), |kb, c| kb.push(c))
+            .build()
+    }
+
+    #[test]
+    fn round_trip() {
+        fn test(components: &[&[u8]]) {
+            let decoded = KeyBuilder::decode(&build(components)).unwrap();
+            assert_eq!(components, decoded.as_slice());
+        }
+
+        test(&[]);
+        test(&[b""]);
+        test(&[b"", b""]);
+        test(&[b"index", b"name", b"rowid"]);
+        test(&[&[0], &[0, 0], &[0xff]]);
+        test(&[&[0, 1], &[0, 0xff], &[1, 0]]);
+        test(&[&[0xff, 0], b"", &[0, 0xff, 0, 1]]);
+    }
+
+    #[test]
+    fn decode_errors() {
+        fn test(key: &[u8], expected: DecodeError) {
+            assert_eq!(Err(expected), KeyBuilder::decode(key));
+        }
+
+        test(b"a", DecodeError::UnterminatedComponent);
+        test(&[b'a', 0], DecodeError::UnterminatedComponent);
+        test(&[b'a', 0, 2], DecodeError::InvalidEscape(2));
+        test(&[0, 1, b'a'], DecodeError::UnterminatedComponent);
+    }
+
+    #[test]
+    fn order() {
+        // Sorted by component order.
+        let cases: Vec<Vec<&[u8]>> = vec![
+            vec![],
+            vec![b""],
+            vec![b"", b""],
+            vec![b"", &[0]],
+            vec![&[0]],
+            vec![&[0], b""],
+            vec![&[0, 0]],
+            vec![&[0, 1]],
+            vec![&[0, 0xff]],
+            vec![&[1]],
+            vec![b"a"],
+            vec![b"a", b""],
+            vec![b"a", b"b"],
+            vec![b"a", &[0xff]],
+            vec![&[b'a', 0]],
+            vec![&[b'a', 0, 0xff]],
+            vec![b"ab"],
+            vec![&[0xff]],
+            vec![&[0xff], &[0]],
+            vec![&[0xff, 0]],
+            vec![&[0xff, 0xff]],
+        ];
+        for (i, a) in cases.iter().enumerate() {
+            for (j, b) in cases.iter().enumerate() {
+                assert_eq!(i.cmp(&j), build(a).cmp(&build(b)), "{:?} vs {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn prefix_scan() {
+        // The encoding of a first component prefixes exactly the keys that
+        // start with that component.
+        let prefix = build(&[b"a"]);
+        assert!(build(&[b"a"]).starts_with(&prefix));
+        assert!(build(&[b"a", b"b"]).starts_with(&prefix));
+        assert!(build(&[b"a", &[0]]).starts_with(&prefix));
+        assert!(!build(&[b"ab"]).starts_with(&prefix));
+        assert!(!build(&[&[b'a', 0]]).starts_with(&prefix));
+        assert!(!build(&[&[b'a', 0, 1]]).starts_with(&prefix));
+        assert!(!build(&[b"", b"a"]).starts_with(&prefix));
+
+        // A partial component prefixes the keys whose component starts
+        // with it, including across escaped zeros.
+        let prefix = KeyBuilder::new().push_prefix(&[b'a', 0]).build();
+        assert!(build(&[&[b'a', 0]]).starts_with(&prefix));
+        assert!(build(&[&[b'a', 0, 0]]).starts_with(&prefix));
+        assert!(build(&[&[b'a', 0, b'b'], b"c"]).starts_with(&prefix));
+        assert!(!build(&[b"a"]).starts_with(&prefix));
+        assert!(!build(&[b"a", &[0]]).starts_with(&prefix));
+    }
+}
@@ -76,6 +76,30 @@ impl Leaf {
         }
     }
 
+    // The number of entries, read from the flatbuffer's entries vector
+    // rather than by iterating them.
+    pub fn len(s: Option<&Self>) -> usize {
+        s.and_then(|leaf| leaf::get_root_as_leaf(leaf.chunk.data()).entries())
+            .map_or(0, |e| e.len())
+    }
+
+    // Returns the value of key, found by binary search as the entries are
+    // sorted.
+    pub fn get<'a>(s: Option<&'a Self>, key: &[u8]) -> Option<&'a [u8]> {
+        let entries = s.and_then(|leaf| leaf::get_root_as_leaf(leaf.chunk.data()).entries())?;
+        let (mut lo, mut hi) = (0, entries.len());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let entry = Entry::from(entries.get(mid));
+            match entry.key.cmp(key) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return Some(entry.val),
+            }
+        }
+        None
+    }
+
     pub fn iter(s: Option<&Self>) -> impl Iterator<Item = Entry<'_>> {
         let root = s.map(|leaf| leaf::get_root_as_leaf(leaf.chunk.data()));
         LeafIter {
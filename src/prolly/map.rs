@@ -6,6 +6,7 @@ use super::Entry;
 use crate::dag;
 use crate::dag::Read;
 use crate::dag::Write;
+use futures::stream::{Stream, TryStreamExt};
 use std::collections::btree_map::Iter as BTreeMapIter;
 use std::collections::BTreeMap;
 use std::iter::{Iterator, Peekable};
@@ -15,6 +16,12 @@ type Hash = String;
 pub struct Map {
     base: Option<Leaf>,
     pending: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    // Whether there have been any put()s or del()s since the last flush (or
+    // load). A clean map can skip writing to the store on flush.
+    dirty: bool,
+    // The number of entries with pending changes applied, kept up to date by
+    // put() and del() so that len() doesn't have to iterate.
+    len: usize,
 }
 
 #[derive(Debug)]
@@ -36,9 +43,36 @@ impl From<leaf::LoadError> for LoadError {
     }
 }
 
+// The shape of a flushed map, for tuning chunking. Level 0 is the leaves.
+#[derive(Debug, PartialEq)]
+pub struct TreeProfile {
+    pub depth: usize,
+    pub chunks_per_level: Vec<usize>,
+    pub avg_chunk_bytes_per_level: Vec<usize>,
+}
+
+// A chunk of a flushed map, for tools that draw the tree. Level 0 is the
+// leaves. key_range is the first and last key in the chunk, or None if it
+// has no entries.
+#[derive(Debug, PartialEq)]
+pub struct ChunkInfo {
+    pub hash: Hash,
+    pub level: usize,
+    pub key_range: Option<(Vec<u8>, Vec<u8>)>,
+    pub entry_count: usize,
+    pub byte_size: usize,
+}
+
 #[derive(Debug)]
 pub enum FlushError {
     Storage(dag::Error),
+    // A flushed chunk refers to a chunk that is neither in the store nor
+    // was put in this transaction, so the tree would be unreadable.
+    #[allow(dead_code)]
+    DanglingReference {
+        parent: Hash,
+        child: Hash,
+    },
 }
 
 impl From<dag::Error> for FlushError {
@@ -52,6 +86,9 @@ impl Map {
         Map {
             base: None,
             pending: BTreeMap::new(),
+            // A new map has never been written, so it needs a flush.
+            dirty: true,
+            len: 0,
         }
     }
 
@@ -59,28 +96,98 @@ impl Map {
         let chunk = read.get_chunk(hash).await?;
         let chunk = chunk.ok_or(LoadError::UnknownHash)?;
         let base = Leaf::load(chunk)?;
-        Ok(Map {
+        let map = Map {
+            len: Leaf::len(Some(&base)),
             base: base.into(),
             pending: BTreeMap::new(),
-        })
+            dirty: false,
+        };
+        map.check_invariants();
+        Ok(map)
+    }
+
+    // Builds a map of entries as they arrive, e.g. from the server, without
+    // collecting them first. The result is as if each entry were put in
+    // turn, so they needn't be sorted and a later entry for a key wins. Like
+    // put(), this only buffers the entries; the chunk is built at flush.
+    // Fails with the first error from entries.
+    #[allow(dead_code)]
+    pub async fn from_stream<S, E>(entries: S) -> Result<Map, E>
+    where
+        S: Stream<Item = Result<(Vec<u8>, Vec<u8>), E>>,
+    {
+        let mut map = Map::new();
+        futures::pin_mut!(entries);
+        while let Some((key, val)) = entries.try_next().await? {
+            map.pending.insert(key, Some(val));
+        }
+        map.len = map.pending.len();
+        map.check_invariants();
+        Ok(map)
     }
 
-    // TODO: improve has and get to not scan entire base, but use binary search.
     pub fn has(&self, key: &[u8]) -> bool {
-        self.iter().any(|e| e.key == key)
+        self.get(key).is_some()
     }
 
+    // A pending change to key wins; otherwise the base is binary searched.
     pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
-        self.iter().find(|e| e.key == key).map(|e| e.val)
+        match self.pending.get(key) {
+            Some(val) => val.as_deref(),
+            None => Leaf::get(self.base.as_ref(), key),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
     }
 
     pub fn put(&mut self, key: Vec<u8>, val: Vec<u8>) {
-        self.pending.insert(key, Some(val));
+        if !self.replace(key, Some(val)) {
+            self.len += 1;
+        }
+        self.dirty = true;
     }
 
     #[allow(dead_code)]
     pub fn del(&mut self, key: Vec<u8>) {
-        self.pending.insert(key, None);
+        if self.replace(key, None) {
+            self.len -= 1;
+        }
+        self.dirty = true;
+    }
+
+    // Sets the pending change to key, returning whether key was in the map
+    // before, to keep len up to date without a walk of the map.
+    fn replace(&mut self, key: Vec<u8>, val: Option<Vec<u8>>) -> bool {
+        let in_base = Leaf::get(self.base.as_ref(), &key).is_some();
+        self.pending
+            .insert(key, val)
+            .map_or(in_base, |old| old.is_some())
+    }
+
+    // Deletes the keys in [from, to). Unlike a del() per key, which looks
+    // each key up, this finds them all in one pass over the map. The map is
+    // a single leaf, rebuilt once at the next flush either way.
+    #[allow(dead_code)]
+    pub fn remove_range(&mut self, from: &[u8], to: &[u8]) {
+        let keys: Vec<Vec<u8>> = self
+            .iter()
+            .skip_while(|e| e.key < from)
+            .take_while(|e| e.key < to)
+            .map(|e| e.key.to_vec())
+            .collect();
+        self.len -= keys.len();
+        for key in keys {
+            self.pending.insert(key, None);
+        }
+        self.dirty = true;
     }
 
     pub fn iter(&self) -> impl Iterator<Item = Entry<'_>> {
@@ -90,14 +197,165 @@ impl Map {
         }
     }
 
+    // Returns copies of the keys, in order. Meant for small maps that a
+    // caller wants all of at once; iter() (or a db scan) goes through a
+    // large map without copying it.
+    #[allow(dead_code)]
+    pub fn keys(&self) -> Vec<Vec<u8>> {
+        self.iter().map(|e| e.key.to_vec()).collect()
+    }
+
+    // Like keys(), for the values, in the order of their keys.
+    #[allow(dead_code)]
+    pub fn values(&self) -> Vec<Vec<u8>> {
+        self.iter().map(|e| e.val.to_vec()).collect()
+    }
+
     pub async fn flush(&mut self, write: &mut Write<'_>) -> Result<Hash, FlushError> {
+        // Nothing has changed since the base was written, so there is
+        // nothing to put.
+        if let (false, Some(base)) = (self.dirty, &self.base) {
+            return Ok(base.chunk().hash().into());
+        }
+
         // TODO: Consider locking during this
+        // The chunk (and so the hash) must only depend on the map's contents,
+        // never on the order in which entries were put. iter() guarantees
+        // this by merging the sorted base with the sorted pending BTreeMap;
+        // don't introduce a HashMap here.
+        self.check_invariants();
         let new_base = Leaf::new(self.iter());
         write.put_chunk(new_base.chunk()).await?;
+        check_refs(new_base.chunk(), write).await?;
         self.base = Some(new_base);
         self.pending.clear();
+        self.dirty = false;
+        self.check_invariants();
         Ok(self.base.as_ref().unwrap().chunk().hash().into())
     }
+
+    // Like flush(), also returning the shape of the flushed tree. What is
+    // written is the same as for flush().
+    #[allow(dead_code)]
+    pub async fn flush_profiled(
+        &mut self,
+        write: &mut Write<'_>,
+    ) -> Result<(Hash, TreeProfile), FlushError> {
+        let hash = self.flush(write).await?;
+        // The map is a single leaf, so the tree is one level of one chunk.
+        let leaf_bytes = self.base.as_ref().unwrap().chunk().data().len();
+        let profile = TreeProfile {
+            depth: 1,
+            chunks_per_level: vec![1],
+            avg_chunk_bytes_per_level: vec![leaf_bytes],
+        };
+        Ok((hash, profile))
+    }
+
+    // Describes the chunks of the map as last flushed or loaded, root
+    // first, without its pending changes. A map that was never flushed has
+    // none. The map is a single leaf, held in memory once loaded, so
+    // there is nothing to read.
+    #[allow(dead_code)]
+    pub fn chunks(&self) -> Vec<ChunkInfo> {
+        let base = match &self.base {
+            Some(base) => base,
+            None => return vec![],
+        };
+        let mut keys = Leaf::iter(Some(base)).map(|e| e.key);
+        let first = keys.next();
+        let key_range = first.map(|first| (first.to_vec(), keys.last().unwrap_or(first).to_vec()));
+        vec![ChunkInfo {
+            hash: base.chunk().hash().into(),
+            level: 0,
+            key_range,
+            entry_count: Leaf::len(Some(base)),
+            byte_size: base.chunk().data().len(),
+        }]
+    }
+
+    // Writes the empty map and returns its hash, the value of a new
+    // database. The hash only depends on the leaf encoding of no entries,
+    // so it is the same for every store and every run (the test pins it);
+    // changing it is a format change.
+    pub async fn empty_hash(write: &mut Write<'_>) -> Result<Hash, FlushError> {
+        Map::new().flush(write).await
+    }
+
+    // Returns the hash the map was last flushed (or loaded) at, or None if
+    // it has changed since or has never been flushed.
+    #[allow(dead_code)]
+    pub fn root_hash(&self) -> Option<Hash> {
+        match (self.dirty, &self.base) {
+            (false, Some(base)) => Some(base.chunk().hash().into()),
+            _ => None,
+        }
+    }
+
+    // Returns whether the two maps have the same contents, by flushing
+    // them as needed and comparing root hashes: the chunks only depend on
+    // the contents (see flush()), so equal contents mean equal hashes.
+    #[allow(dead_code)]
+    pub async fn equals(
+        &mut self,
+        other: &mut Map,
+        write: &mut Write<'_>,
+    ) -> Result<bool, FlushError> {
+        Ok(self.flush(write).await? == other.flush(write).await?)
+    }
+
+    // Panics if the map is in a state no sequence of operations should be
+    // able to produce, to catch bugs where they happen rather than when the
+    // data is next written. This walks the whole map, so it only runs in
+    // debug builds, and then only on load and around flush rather than
+    // after every put or del, which would make building a map quadratic.
+    //
+    // The map is currently a single leaf, so there is no tree shape (depth,
+    // balance, child key ranges) to check yet, only key order.
+    #[cfg(debug_assertions)]
+    fn check_invariants(&self) {
+        fn check_sorted<'a>(keys: impl Iterator<Item = &'a [u8]>, what: &str) {
+            let mut prev: Option<&[u8]> = None;
+            for key in keys {
+                if let Some(prev) = prev {
+                    assert!(
+                        prev < key,
+                        "{} keys out of order: {:?} >= {:?}",
+                        what,
+                        prev,
+                        key
+                    );
+                }
+                prev = Some(key);
+            }
+        }
+        check_sorted(Leaf::iter(self.base.as_ref()).map(|e| e.key), "Base");
+        check_sorted(self.iter().map(|e| e.key), "Map");
+        assert!(
+            self.dirty || self.pending.is_empty(),
+            "Clean map has pending changes"
+        );
+        assert_eq!(self.iter().count(), self.len, "Map len is wrong");
+    }
+
+    #[cfg(not(debug_assertions))]
+    fn check_invariants(&self) {}
+}
+
+// Fails with DanglingReference if chunk refers to a chunk that write can't
+// see.
+async fn check_refs(chunk: &dag::Chunk, write: &Write<'_>) -> Result<(), FlushError> {
+    if let Some(refs) = chunk.refs() {
+        for child in refs {
+            if !write.read().has_chunk(child).await? {
+                return Err(FlushError::DanglingReference {
+                    parent: chunk.hash().into(),
+                    child: child.into(),
+                });
+            }
+        }
+    }
+    Ok(())
 }
 
 // Iter provides iteration over the map with pending changes applied.
@@ -175,6 +433,7 @@ mod tests {
     use super::*;
     use crate::dag::Store;
     use crate::kv::memstore::MemStore;
+    use futures::stream;
 
     fn make_map(mut base: Option<Vec<&str>>, pending: Vec<&str>, deleted: Vec<&str>) -> Map {
         let entries = base.as_mut().map(|entries| {
@@ -188,6 +447,8 @@ mod tests {
         let mut map = Map {
             base,
             pending: BTreeMap::new(),
+            dirty: true,
+            len: 0,
         };
         for p in pending {
             let mut v = p.as_bytes().to_vec();
@@ -198,6 +459,7 @@ mod tests {
         for p in deleted {
             map.pending.insert(p.as_bytes().to_vec(), None);
         }
+        map.len = map.iter().count();
         map
     }
 
@@ -499,4 +761,452 @@ mod tests {
         )
         .await;
     }
+
+    #[async_std::test]
+    async fn keys_and_values() {
+        fn bytes(v: &[&str]) -> Vec<Vec<u8>> {
+            v.iter().map(|s| s.as_bytes().to_vec()).collect()
+        }
+        // Pending values are the reverse of their keys.
+        let mut map = make_map(vec!["cc", "aa", "ee"].into(), vec!["dd", "ab"], vec!["ee"]);
+        let keys = bytes(&["aa", "ab", "cc", "dd"]);
+        let values = bytes(&["aa", "ba", "cc", "dd"]);
+        assert_eq!(keys, map.keys());
+        assert_eq!(values, map.values());
+
+        let store = Store::new(Box::new(MemStore::new()));
+        let mut write = store.write().await.unwrap();
+        let hash = map.flush(&mut write).await.unwrap();
+        write.commit().await.unwrap();
+        let read = store.read().await.unwrap();
+        let map = Map::load(&hash, read.read()).await.unwrap();
+        assert_eq!(keys, map.keys());
+        assert_eq!(values, map.values());
+
+        assert!(Map::new().keys().is_empty());
+        assert!(Map::new().values().is_empty());
+    }
+
+    #[async_std::test]
+    async fn remove_range() {
+        let key = |i: usize| format!("k{:04}", i).into_bytes();
+        let store = Store::new(Box::new(MemStore::new()));
+        let mut write = store.write().await.unwrap();
+        let mut map = Map::new();
+        for i in (0..1000).step_by(2) {
+            map.put(key(i), key(i));
+        }
+        map.flush(&mut write).await.unwrap();
+        // Some of the range is pending, including a pending delete.
+        for i in (1..1000).step_by(2) {
+            map.put(key(i), key(i));
+        }
+        map.del(key(301));
+
+        map.remove_range(&key(300), &key(700));
+        let expected: Vec<Vec<u8>> = (0..300).chain(700..1000).map(key).collect();
+        assert_eq!(expected, map.keys());
+        assert_eq!(600, map.len());
+
+        let hash = map.flush(&mut write).await.unwrap();
+        let mut map = Map::load(&hash, write.read()).await.unwrap();
+        map.check_invariants();
+        assert_eq!(expected, map.keys());
+        assert_eq!(600, map.len());
+
+        // Empty and inverted ranges remove nothing, and neither does a
+        // range between keys.
+        map.remove_range(&key(10), &key(10));
+        map.remove_range(&key(20), &key(10));
+        map.remove_range(b"k0010a", b"k0011");
+        assert_eq!(expected, map.keys());
+
+        // Open-ended in effect.
+        map.remove_range(b"", b"\xff");
+        assert!(map.is_empty());
+        assert!(map.keys().is_empty());
+    }
+
+    #[async_std::test]
+    async fn flush_clean() {
+        let kv = MemStore::new();
+        let store = Store::new(Box::new(kv));
+        let mut map = Map::new();
+        map.put(b"foo".to_vec(), b"bar".to_vec());
+        let mut write = store.write().await.unwrap();
+        let hash = map.flush(&mut write).await.unwrap();
+        write.commit().await.unwrap();
+
+        // Flushing again without mutating should not touch the store at all,
+        // so flushing into an empty store should leave it empty.
+        let empty = Store::new(Box::new(MemStore::new()));
+        let mut write = empty.write().await.unwrap();
+        assert_eq!(hash, map.flush(&mut write).await.unwrap());
+        assert!(!write.read().has_chunk(&hash).await.unwrap());
+        write.commit().await.unwrap();
+
+        // The same goes for a freshly loaded map.
+        let read = store.read().await.unwrap();
+        let mut map2 = Map::load(&hash, read.read()).await.unwrap();
+        let mut write = empty.write().await.unwrap();
+        assert_eq!(hash, map2.flush(&mut write).await.unwrap());
+        assert!(!write.read().has_chunk(&hash).await.unwrap());
+
+        // But mutating it again should.
+        map.del(b"foo".to_vec());
+        let hash2 = map.flush(&mut write).await.unwrap();
+        assert_ne!(hash, hash2);
+        assert!(write.read().has_chunk(&hash2).await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn flush_deterministic() {
+        let keys: Vec<String> = (0..100).map(|i| format!("key{}", i)).collect();
+        let store = Store::new(Box::new(MemStore::new()));
+        let mut write = store.write().await.unwrap();
+
+        let mut m1 = Map::new();
+        for k in keys.iter() {
+            m1.put(k.as_bytes().to_vec(), k.as_bytes().to_vec());
+        }
+        let h1 = m1.flush(&mut write).await.unwrap();
+
+        // Same contents, built in reverse order, with an intermediate flush
+        // and a key that comes and goes along the way.
+        let mut m2 = Map::new();
+        for (i, k) in keys.iter().rev().enumerate() {
+            m2.put(k.as_bytes().to_vec(), k.as_bytes().to_vec());
+            if i == keys.len() / 2 {
+                m2.put(b"gone".to_vec(), b"gone".to_vec());
+                m2.flush(&mut write).await.unwrap();
+            }
+        }
+        m2.del(b"gone".to_vec());
+        let h2 = m2.flush(&mut write).await.unwrap();
+
+        assert_eq!(h1, h2);
+    }
+
+    #[async_std::test]
+    async fn from_stream() {
+        let store = Store::new(Box::new(MemStore::new()));
+        let mut write = store.write().await.unwrap();
+
+        // Unsorted, and with a key that is overwritten.
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = (0..100)
+            .map(|i| (i * 37) % 100)
+            .chain(vec![5])
+            .map(|i| (format!("key{}", i).into_bytes(), vec![i as u8]))
+            .collect();
+        let mut expected = Map::new();
+        for (key, val) in entries.iter().cloned() {
+            expected.put(key, val);
+        }
+        let mut map = Map::from_stream(stream::iter(entries.into_iter().map(Ok::<_, ()>)))
+            .await
+            .unwrap();
+        assert_eq!(100, map.len());
+        assert_eq!(expected.keys(), map.keys());
+        assert_eq!(expected.values(), map.values());
+        assert_eq!(
+            expected.flush(&mut write).await.unwrap(),
+            map.flush(&mut write).await.unwrap()
+        );
+
+        let empty = Map::from_stream(stream::empty::<Result<_, ()>>())
+            .await
+            .unwrap();
+        assert!(empty.is_empty());
+
+        // An error ends the build.
+        let entries = vec![
+            Ok((b"a".to_vec(), b"1".to_vec())),
+            Err("lost"),
+            Ok((b"b".to_vec(), b"2".to_vec())),
+        ];
+        assert_eq!(
+            Err("lost"),
+            Map::from_stream(stream::iter(entries))
+                .await
+                .map(|m| m.len())
+        );
+    }
+
+    #[async_std::test]
+    async fn len() {
+        let store = Store::new(Box::new(MemStore::new()));
+        let mut write = store.write().await.unwrap();
+
+        let mut map = Map::new();
+        assert_eq!((0, true), (map.len(), map.is_empty()));
+        map.put(b"a".to_vec(), b"1".to_vec());
+        map.put(b"b".to_vec(), b"2".to_vec());
+        assert_eq!((2, false), (map.len(), map.is_empty()));
+
+        // Overwrites don't change len, whether the key is pending or flushed.
+        map.put(b"a".to_vec(), b"3".to_vec());
+        assert_eq!(2, map.len());
+        let hash = map.flush(&mut write).await.unwrap();
+        assert_eq!(2, map.len());
+        map.put(b"b".to_vec(), b"4".to_vec());
+        assert_eq!(2, map.len());
+
+        // Deleting a missing key, or one twice, only counts once.
+        map.del(b"a".to_vec());
+        map.del(b"a".to_vec());
+        map.del(b"missing".to_vec());
+        assert_eq!(1, map.len());
+        map.put(b"a".to_vec(), b"5".to_vec());
+        map.put(b"c".to_vec(), b"6".to_vec());
+        assert_eq!(3, map.len());
+        let hash2 = map.flush(&mut write).await.unwrap();
+        assert_eq!(3, map.len());
+
+        let map = Map::load(&hash, write.read()).await.unwrap();
+        assert_eq!(2, map.len());
+        let mut map = Map::load(&hash2, write.read()).await.unwrap();
+        assert_eq!(3, map.len());
+        for key in &[b"a", b"b", b"c"] {
+            map.del(key.to_vec());
+        }
+        assert_eq!((0, true), (map.len(), map.is_empty()));
+        map.flush(&mut write).await.unwrap();
+        assert!(map.is_empty());
+    }
+
+    #[async_std::test]
+    async fn large() {
+        let store = Store::new(Box::new(MemStore::new()));
+        let mut write = store.write().await.unwrap();
+        let key = |i: usize| format!("k{:06}", i).into_bytes();
+        const N: usize = 100_000;
+
+        // Puts and dels look keys up rather than walking the map, so this
+        // is quick even in debug builds.
+        let mut map = Map::new();
+        for i in (0..N).step_by(2) {
+            map.put(key(i), key(i));
+        }
+        assert_eq!(N / 2, map.len());
+        map.flush(&mut write).await.unwrap();
+        for i in 0..N {
+            map.put(key(i), b"new".to_vec());
+        }
+        for i in (0..N).step_by(4) {
+            map.del(key(i));
+        }
+        assert_eq!(N - N / 4, map.len());
+        assert_eq!(None, map.get(&key(0)));
+        assert_eq!(Some(&b"new"[..]), map.get(&key(1)));
+        assert!(map.has(&key(2)));
+        assert!(!map.has(&key(N)));
+        map.flush(&mut write).await.unwrap();
+        assert_eq!(N - N / 4, map.len());
+        assert_eq!(Some(&b"new"[..]), map.get(&key(N - 1)));
+        assert!(!map.has(&key(4)));
+    }
+
+    #[async_std::test]
+    async fn invariants_hold() {
+        let store = Store::new(Box::new(MemStore::new()));
+        let mut write = store.write().await.unwrap();
+
+        // Empty.
+        let mut map = Map::new();
+        map.check_invariants();
+        map.flush(&mut write).await.unwrap();
+        map.del(b"missing".to_vec());
+        map.flush(&mut write).await.unwrap();
+
+        // Single key, put over and deleted.
+        map.put(b"a".to_vec(), b"1".to_vec());
+        map.flush(&mut write).await.unwrap();
+        map.put(b"a".to_vec(), b"2".to_vec());
+        map.del(b"a".to_vec());
+        map.flush(&mut write).await.unwrap();
+        assert_eq!(0, map.iter().count());
+
+        // Adjacent keys, split between base and pending.
+        let keys: Vec<&[u8]> = vec![b"", b"\0", b"\0\0", b"\x01", b"a", b"a\0", b"a\xff", b"b"];
+        for key in keys.iter().step_by(2) {
+            map.put(key.to_vec(), key.to_vec());
+        }
+        let hash = map.flush(&mut write).await.unwrap();
+        for key in keys.iter().skip(1).step_by(2) {
+            map.put(key.to_vec(), key.to_vec());
+        }
+        assert_eq!(keys, map.iter().map(|e| e.key).collect::<Vec<_>>());
+        map.flush(&mut write).await.unwrap();
+
+        let map = Map::load(&hash, write.read()).await.unwrap();
+        map.check_invariants();
+    }
+
+    #[async_std::test]
+    async fn equals() {
+        let store = Store::new(Box::new(MemStore::new()));
+        let mut write = store.write().await.unwrap();
+
+        let mut m1 = Map::new();
+        assert_eq!(None, m1.root_hash());
+        m1.put(b"a".to_vec(), b"1".to_vec());
+        m1.put(b"b".to_vec(), b"2".to_vec());
+        let hash = m1.flush(&mut write).await.unwrap();
+        assert_eq!(Some(hash.clone()), m1.root_hash());
+
+        // Built independently, in another order.
+        let mut m2 = Map::new();
+        m2.put(b"b".to_vec(), b"2".to_vec());
+        m2.put(b"a".to_vec(), b"1".to_vec());
+        assert!(m1.equals(&mut m2, &mut write).await.unwrap());
+        assert_eq!(Some(hash.clone()), m2.root_hash());
+
+        // A changed map has no root hash until it's flushed again.
+        m2.put(b"b".to_vec(), b"3".to_vec());
+        assert_eq!(None, m2.root_hash());
+        assert!(!m1.equals(&mut m2, &mut write).await.unwrap());
+        m2.put(b"b".to_vec(), b"2".to_vec());
+        assert!(m2.equals(&mut m1, &mut write).await.unwrap());
+
+        let mut m3 = Map::new();
+        m3.put(b"a".to_vec(), b"1".to_vec());
+        assert!(!m1.equals(&mut m3, &mut write).await.unwrap());
+
+        // A loaded map has the hash it was loaded at.
+        write.commit().await.unwrap();
+        let read = store.read().await.unwrap();
+        let loaded = Map::load(&hash, read.read()).await.unwrap();
+        assert_eq!(Some(hash), loaded.root_hash());
+    }
+
+    #[async_std::test]
+    async fn flush_profiled() {
+        let store = Store::new(Box::new(MemStore::new()));
+        let mut write = store.write().await.unwrap();
+        let mut map = Map::new();
+        let mut profiled = Map::new();
+        for i in 0..100u8 {
+            map.put(vec![i], vec![i; 10]);
+            profiled.put(vec![i], vec![i; 10]);
+        }
+
+        let hash = map.flush(&mut write).await.unwrap();
+        let (profiled_hash, profile) = profiled.flush_profiled(&mut write).await.unwrap();
+        assert_eq!(hash, profiled_hash);
+        let chunk = write.read().get_chunk(&hash).await.unwrap().unwrap();
+        assert_eq!(
+            TreeProfile {
+                depth: 1,
+                chunks_per_level: vec![1],
+                avg_chunk_bytes_per_level: vec![chunk.data().len()],
+            },
+            profile
+        );
+        // At least the keys and values.
+        assert!(profile.avg_chunk_bytes_per_level[0] > 100 * 11);
+    }
+
+    #[async_std::test]
+    async fn chunks() {
+        let store = Store::new(Box::new(MemStore::new()));
+        let mut write = store.write().await.unwrap();
+        let mut map = Map::new();
+        assert!(map.chunks().is_empty());
+
+        let hash = map.flush(&mut write).await.unwrap();
+        let empty = ChunkInfo {
+            hash,
+            level: 0,
+            key_range: None,
+            entry_count: 0,
+            byte_size: map.base.as_ref().unwrap().chunk().data().len(),
+        };
+        assert_eq!(vec![empty], map.chunks());
+
+        for key in &["b", "a", "c"] {
+            map.put(key.as_bytes().to_vec(), b"value".to_vec());
+        }
+        let hash = map.flush(&mut write).await.unwrap();
+        let chunk = write.read().get_chunk(&hash).await.unwrap().unwrap();
+        let expected = vec![ChunkInfo {
+            hash: hash.clone(),
+            level: 0,
+            key_range: Some((b"a".to_vec(), b"c".to_vec())),
+            entry_count: 3,
+            byte_size: chunk.data().len(),
+        }];
+        assert_eq!(expected, map.chunks());
+
+        // Pending changes aren't in any chunk yet.
+        map.put(b"d".to_vec(), b"value".to_vec());
+        assert_eq!(expected, map.chunks());
+        let map = Map::load(&hash, write.read()).await.unwrap();
+        assert_eq!(expected, map.chunks());
+    }
+
+    #[async_std::test]
+    async fn empty_hash() {
+        let store = Store::new(Box::new(MemStore::new()));
+        let mut write = store.write().await.unwrap();
+        let hash = Map::empty_hash(&mut write).await.unwrap();
+        assert_eq!("jdh7scesonbpmik7k00vnb4ska0goree", hash);
+        write.commit().await.unwrap();
+
+        let read = store.read().await.unwrap();
+        let map = Map::load(&hash, read.read()).await.unwrap();
+        assert!(map.is_empty());
+        assert_eq!(0, map.iter().count());
+        assert_eq!(Some(hash), map.root_hash());
+    }
+
+    #[async_std::test]
+    async fn dangling_reference() {
+        let store = Store::new(Box::new(MemStore::new()));
+        let mut write = store.write().await.unwrap();
+
+        // Leaves have no children, so build the parents by hand.
+        let child = dag::Chunk::new((vec![1], 0), &[]);
+        let parent = dag::Chunk::new((vec![2], 0), &[child.hash()]);
+        write.put_chunk(&parent).await.unwrap();
+        match check_refs(&parent, &write).await {
+            Err(FlushError::DanglingReference {
+                parent: p,
+                child: c,
+            }) => {
+                assert_eq!(parent.hash(), p);
+                assert_eq!(child.hash(), c);
+            }
+            r => panic!("Expected DanglingReference, got {:?}", r),
+        }
+
+        write.put_chunk(&child).await.unwrap();
+        check_refs(&parent, &write).await.unwrap();
+        check_refs(&child, &write).await.unwrap();
+    }
+
+    #[cfg(debug_assertions)]
+    #[async_std::test]
+    #[should_panic(expected = "Base keys out of order")]
+    async fn invariants_unsorted_base() {
+        let store = Store::new(Box::new(MemStore::new()));
+        let mut write = store.write().await.unwrap();
+        let entries = vec![
+            Entry {
+                key: b"b",
+                val: b"",
+            },
+            Entry {
+                key: b"a",
+                val: b"",
+            },
+        ];
+        let mut map = Map {
+            base: Some(Leaf::new(entries.into_iter())),
+            pending: BTreeMap::new(),
+            dirty: false,
+            len: 2,
+        };
+        map.put(b"c".to_vec(), b"".to_vec());
+        map.flush(&mut write).await.unwrap();
+    }
 }
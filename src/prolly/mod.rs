@@ -1,10 +1,12 @@
 mod buzhash;
 mod chunker;
+mod key;
 mod leaf;
 #[allow(unused_imports)]
 mod leaf_generated;
 mod map;
 
+pub use key::KeyBuilder;
 pub use map::{FlushError, LoadError, Map};
 
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
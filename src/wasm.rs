@@ -9,13 +9,22 @@ use crate::kv::idbstore::IdbStore;
 use crate::kv::Store;
 use crate::prolly::Map;
 
-// Use `wee_alloc` as the global allocator.
+// The global allocator is selected by feature. `wee_alloc` (the default)
+// is the smallest, `dlmalloc` is faster for allocation-heavy work like
+// prolly flushes, and with neither the target's default allocator is used.
+#[cfg(all(feature = "wee_alloc", feature = "dlmalloc"))]
+compile_error!("At most one of the wee_alloc and dlmalloc features may be enabled");
+
+#[cfg(feature = "wee_alloc")]
 #[global_allocator]
 static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
+#[cfg(feature = "dlmalloc")]
+#[global_allocator]
+static ALLOC: dlmalloc::GlobalDlmalloc = dlmalloc::GlobalDlmalloc;
+
 #[wasm_bindgen]
 pub async fn exercise_prolly() {
-    init_panic_hook();
     let kv = IdbStore::new("foo").await.unwrap().unwrap();
     let store = dag::Store::new(Box::new(kv));
     let mut write = store.write().await.unwrap();
@@ -27,7 +36,6 @@ pub async fn exercise_prolly() {
 
 #[cfg(not(default))]
 pub async fn new_idbstore(name: String) -> Option<Box<dyn Store>> {
-    init_panic_hook();
     match IdbStore::new(&name).await {
         Ok(Some(v)) => Some(Box::new(v)),
         _ => None,
@@ -36,25 +44,156 @@ pub async fn new_idbstore(name: String) -> Option<Box<dyn Store>> {
 
 #[wasm_bindgen]
 pub async fn dispatch(db_name: String, rpc: String, args: String) -> Result<String, JsValue> {
-    init_panic_hook();
     match embed::dispatch(db_name, rpc, args).await {
         Err(v) => Err(JsValue::from_str(&v[..])),
         Ok(v) => Ok(v),
     }
 }
 
+// Like dispatch, but successful responses are MessagePack-encoded, which is
+// cheaper to decode than JSON for large responses like scans.
+#[wasm_bindgen]
+pub async fn dispatch_binary(
+    db_name: String,
+    rpc: String,
+    args: String,
+) -> Result<Vec<u8>, JsValue> {
+    match embed::dispatch_binary(db_name, rpc, args).await {
+        Err(v) => Err(JsValue::from_str(&v[..])),
+        Ok(v) => Ok(v),
+    }
+}
+
+// Like dispatch, but args and responses are encoded with the named codec,
+// one of the built-in "json" and "msgpack" or one the embedder registered
+// with embed::register_codec.
+#[wasm_bindgen]
+pub async fn dispatch_with_codec(
+    codec: String,
+    db_name: String,
+    rpc: String,
+    args: Vec<u8>,
+) -> Result<Vec<u8>, JsValue> {
+    match embed::dispatch_with_codec(codec, db_name, rpc, args).await {
+        Err(v) => Err(JsValue::from_str(&v[..])),
+        Ok(v) => Ok(v),
+    }
+}
+
+// Where log records go.
+pub enum LogSink {
+    // The browser console, via console_log.
+    Console,
+    // An embedder-provided logger, e.g. one that forwards to the host's
+    // own logging.
+    Custom(Box<dyn log::Log>),
+    // Nowhere.
+    None,
+}
+
+pub struct InitOptions {
+    // Whether to install console_error_panic_hook (when the feature is
+    // enabled), which logs panics to the console before they trap.
+    pub panic_hook: bool,
+    pub log_sink: LogSink,
+    pub log_level: log::LevelFilter,
+}
+
+impl Default for InitOptions {
+    fn default() -> InitOptions {
+        InitOptions {
+            panic_hook: true,
+            log_sink: LogSink::Console,
+            log_level: log::LevelFilter::Info,
+        }
+    }
+}
+
 static INIT: Once = Once::new();
 
-pub fn init_console_log() {
+// Installs the panic hook and logger per options. Nothing is installed
+// implicitly: embedders that don't call this get no panic hook and no
+// logging. Only the first call has any effect, since the logger is
+// process-wide.
+pub fn init(options: InitOptions) {
     INIT.call_once(|| {
-        if let Err(e) = console_log::init_with_level(log::Level::Info) {
-            web_sys::console::error_1(&format!("Error registering console_log: {}", e).into());
+        #[cfg(feature = "console_error_panic_hook")]
+        if options.panic_hook {
+            console_error_panic_hook::set_once();
+        }
+        let level = options.log_level;
+        let result = match options.log_sink {
+            LogSink::Console => level
+                .to_level()
+                .map_or(Ok(()), console_log::init_with_level),
+            // The logger lives for the rest of the process anyway.
+            LogSink::Custom(logger) => {
+                log::set_logger(Box::leak(logger)).map(|()| log::set_max_level(level))
+            }
+            LogSink::None => Ok(()),
+        };
+        if let Err(e) = result {
+            web_sys::console::error_1(&format!("Error registering logger: {}", e).into());
         }
     });
 }
 
-fn init_panic_hook() {
-    #[cfg(feature = "console_error_panic_hook")]
-    console_error_panic_hook::set_once();
-    init_console_log();
+// init() with the default options, for JavaScript callers: the panic hook
+// and console logging at Info.
+#[wasm_bindgen(js_name = initConsole)]
+pub fn init_console() {
+    init(InitOptions::default());
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    lazy_static! {
+        static ref RECORDS: Mutex<Vec<String>> = Mutex::new(vec![]);
+    }
+
+    struct TestSink;
+
+    impl log::Log for TestSink {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            RECORDS
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", record.level(), record.args()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[async_std::test]
+    async fn custom_log_sink() {
+        init(InitOptions {
+            panic_hook: false,
+            log_sink: LogSink::Custom(Box::new(TestSink)),
+            log_level: log::LevelFilter::Warn,
+        });
+        let kv = crate::kv::memstore::MemStore::new();
+        kv.put("h/main", &[0xff]).await.unwrap();
+        let store = dag::Store::new(Box::new(kv));
+        assert!(store
+            .read()
+            .await
+            .unwrap()
+            .read()
+            .get_head("main")
+            .await
+            .is_err());
+
+        let records = RECORDS.lock().unwrap();
+        assert!(records
+            .iter()
+            .any(|r| r.starts_with("ERROR Could not decode head: main")));
+    }
 }
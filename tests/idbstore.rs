@@ -1,21 +1,34 @@
 // Run tests with `wasm-pack test --chrome --headless`.
 pub mod idbstore {
+    use async_std::task::sleep;
+    use core::time::Duration;
+    use futures::channel::{mpsc, oneshot};
+    use futures::future::{self, FutureExt};
+    use futures::{pin_mut, select_biased};
     use rand::Rng;
-    use replicache_client::kv::{trait_tests, Store};
+    use replicache_client::kv::idbstore::{
+        await_request, scan_round_trips, IdbStore, KeyEncoding, OpenOptions, TxEvent,
+    };
+    use replicache_client::kv::{trait_tests, CommitReport, IsolationLevel, Store, StoreError};
     use replicache_client::wasm;
     use std::boxed::Box;
+    use wasm_bindgen::closure::Closure;
+    use wasm_bindgen::{JsCast, JsValue};
     use wasm_bindgen_test::wasm_bindgen_test_configure;
     use wasm_bindgen_test::*;
 
     wasm_bindgen_test_configure!(run_in_browser);
 
-    async fn new_store() -> Box<dyn Store> {
+    fn random_name() -> String {
         let mut rng = rand::thread_rng();
-        let name = std::iter::repeat(())
+        std::iter::repeat(())
             .map(|_| rng.sample(rand::distributions::Alphanumeric))
             .take(12)
-            .collect();
-        wasm::new_idbstore(name)
+            .collect()
+    }
+
+    async fn new_store() -> Box<dyn Store> {
+        wasm::new_idbstore(random_name())
             .await
             .expect("IdbStore::new failed")
     }
@@ -25,8 +38,616 @@ pub mod idbstore {
         trait_tests::run_all(&new_store).await;
     }
 
+    async fn new_binary_store() -> Box<dyn Store> {
+        Box::new(
+            IdbStore::new_with_key_encoding(&random_name(), KeyEncoding::Binary)
+                .await
+                .unwrap()
+                .unwrap(),
+        )
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_idbstore_binary_keys() {
+        trait_tests::run_all(&new_binary_store).await;
+    }
+
+    #[wasm_bindgen_test]
+    async fn isolation_level() {
+        // See the comment on IdbStore::db.
+        let want = match cfg!(feature = "fair_locking") {
+            true => IsolationLevel::StrictlySerializable,
+            false => IsolationLevel::Serializable,
+        };
+        assert_eq!(want, new_store().await.isolation());
+    }
+
+    #[wasm_bindgen_test]
+    async fn key_encoding_fixed() {
+        let open = |name: String, keys| async move {
+            IdbStore::new_with_key_encoding(&name, keys)
+                .await
+                .map(Option::unwrap)
+        };
+
+        // An encoding is recorded on first open, and other encodings are
+        // rejected after that.
+        let name = random_name();
+        let store = open(name.clone(), KeyEncoding::Binary).await.unwrap();
+        store.put("\u{e9}", b"v").await.unwrap();
+        drop(store);
+        assert!(open(name.clone(), KeyEncoding::String).await.is_err());
+        assert!(IdbStore::new(&name).await.is_err());
+        let store = open(name, KeyEncoding::Binary).await.unwrap();
+        assert_eq!(
+            vec!["\u{e9}"],
+            store.read().await.unwrap().scan_keys("").await.unwrap()
+        );
+
+        let name = random_name();
+        IdbStore::new(&name).await.unwrap().unwrap();
+        assert!(open(name.clone(), KeyEncoding::Binary).await.is_err());
+        assert!(open(name, KeyEncoding::String).await.is_ok());
+    }
+
+    #[wasm_bindgen_test]
+    async fn lazy_scan_stream() {
+        use futures::stream::{StreamExt, TryStreamExt};
+
+        for store in [new_store().await, new_binary_store().await] {
+            let wt = store.write().await.unwrap();
+            for i in 0..20 {
+                wt.put(&format!("k{:02}", i), b"v").await.unwrap();
+            }
+            wt.commit().await.unwrap();
+
+            let rt = store.read().await.unwrap();
+            let before = scan_round_trips();
+            let keys: Vec<String> = rt
+                .scan_stream("k")
+                .take(5)
+                .map_ok(|(k, _)| k)
+                .try_collect()
+                .await
+                .unwrap();
+            assert_eq!(vec!["k00", "k01", "k02", "k03", "k04"], keys);
+            assert_eq!(before + 5, scan_round_trips());
+        }
+    }
+
+    #[wasm_bindgen_test]
+    async fn scan_stream_utf8_order() {
+        use futures::stream::TryStreamExt;
+
+        // UTF-16, which idb orders string keys by, puts characters from
+        // U+10000 before those from U+E000 to U+FFFF; UTF-8 doesn't.
+        let store = new_store().await;
+        let wt = store.write().await.unwrap();
+        for key in &[
+            "a",
+            "a\u{e000}",
+            "a\u{e000}b",
+            "a\u{ffff}",
+            "a\u{10000}",
+            "a\u{10000}\u{e000}",
+            "a\u{10000}\u{10000}",
+            "b",
+            "\u{e000}",
+            "\u{10000}",
+        ] {
+            wt.put(key, b"v").await.unwrap();
+        }
+        wt.commit().await.unwrap();
+        let rt = store.read().await.unwrap();
+        for prefix in &["", "a", "a\u{10000}", "\u{10000}"] {
+            let entries: Vec<_> = rt.scan_stream(prefix).try_collect().await.unwrap();
+            assert_eq!(rt.scan(prefix).await.unwrap(), entries);
+        }
+    }
+
+    #[wasm_bindgen_test]
+    async fn scan_window() {
+        use futures::stream::TryStreamExt;
+
+        for &keys in &[KeyEncoding::Binary, KeyEncoding::String] {
+            let name = random_name();
+            let open = || IdbStore::new_with_key_encoding(&name, keys);
+            let store = open().await.unwrap().unwrap();
+            let wt = store.write().await.unwrap();
+            for i in 0..250 {
+                wt.put(&format!("k{:03}", i), b"v").await.unwrap();
+            }
+            wt.put("z", b"v").await.unwrap();
+            wt.commit().await.unwrap();
+
+            // Returns the entries starting with "k" and the round trips it
+            // took to read them with the given window.
+            let scan = |window| async move {
+                let mut store = open().await.unwrap().unwrap();
+                store.set_scan_window(window);
+                let rt = store.read().await.unwrap();
+                let before = scan_round_trips();
+                let entries: Vec<_> = rt.scan_stream("k").try_collect().await.unwrap();
+                (entries, scan_round_trips() - before)
+            };
+            let (by_one, one_trips) = scan(1).await;
+            let (by_hundred, hundred_trips) = scan(100).await;
+            assert_eq!(250, by_one.len());
+            assert_eq!(by_one, by_hundred);
+            // A cursor needs a step per entry, plus one to find the end.
+            assert_eq!(251, one_trips);
+            assert_eq!(3, hundred_trips);
+
+            // A batch that ends exactly at the end of the range takes one
+            // more round trip to find the end.
+            let (by_fifty, fifty_trips) = scan(50).await;
+            assert_eq!(by_one, by_fifty);
+            assert_eq!(6, fifty_trips);
+        }
+    }
+
+    #[wasm_bindgen_test]
+    async fn abort_read() {
+        use futures::stream::StreamExt;
+
+        let cancelled = |r: std::result::Result<(), StoreError>| match r {
+            Err(StoreError::Cancelled) => (),
+            r => panic!("Expected Cancelled, got {:?}", r),
+        };
+        for &(keys, window) in &[
+            (KeyEncoding::Binary, 1),
+            (KeyEncoding::Binary, 2),
+            (KeyEncoding::String, 1),
+            (KeyEncoding::String, 2),
+        ] {
+            let mut store = IdbStore::new_with_key_encoding(&random_name(), keys)
+                .await
+                .unwrap()
+                .unwrap();
+            store.set_scan_window(window);
+            let wt = store.write().await.unwrap();
+            for i in 0..10 {
+                wt.put(&format!("k{}", i), b"v").await.unwrap();
+            }
+            wt.commit().await.unwrap();
+
+            let rt = store.read().await.unwrap();
+            assert_eq!(Some(b"v".to_vec()), rt.get("k0").await.unwrap());
+            let mut entries = rt.scan_stream("k");
+            assert_eq!("k0", entries.next().await.unwrap().unwrap().0);
+            rt.abort();
+            cancelled(entries.next().await.unwrap().map(|_| ()));
+            assert!(entries.next().await.is_none());
+            drop(entries);
+            cancelled(rt.get("k0").await.map(|_| ()));
+            cancelled(rt.scan("").await.map(|_| ()));
+            drop(rt);
+
+            // Other transactions are unaffected.
+            assert_eq!(10, store.read().await.unwrap().count().await.unwrap());
+        }
+    }
+
+    // Returns a store that sends its TxEvents to the receiver.
+    async fn new_store_with_events() -> (IdbStore, mpsc::UnboundedReceiver<TxEvent>) {
+        let mut store = IdbStore::new(&random_name()).await.unwrap().unwrap();
+        let (sender, events) = mpsc::unbounded();
+        store.set_tx_events(Some(sender));
+        (store, events)
+    }
+
+    // Returns the keys of the put and delete requests in events so far, in
+    // the order they were made.
+    fn requested_keys(events: &mut mpsc::UnboundedReceiver<TxEvent>) -> Vec<String> {
+        let mut keys = vec![];
+        while let Ok(event) = events.try_recv() {
+            if let TxEvent::Put(_, key) | TxEvent::Delete(_, key) = event {
+                keys.push(key);
+            }
+        }
+        keys
+    }
+
+    #[wasm_bindgen_test]
+    async fn commit_order_is_deterministic() {
+        let (store, mut events) = new_store_with_events().await;
+        let keys: Vec<String> = (0..20).map(|i| format!("k{}", i)).collect();
+        let mut orders = vec![];
+        for keys in [keys.clone(), keys.iter().rev().cloned().collect()] {
+            store.put("gone", b"v").await.unwrap();
+            requested_keys(&mut events);
+            let wt = store.write().await.unwrap();
+            for key in keys.iter() {
+                wt.put(key, b"v").await.unwrap();
+            }
+            wt.del("gone").await.unwrap();
+            wt.commit().await.unwrap();
+            orders.push(requested_keys(&mut events));
+        }
+        assert_eq!(orders[0], orders[1]);
+        let mut sorted = keys;
+        sorted.push("gone".into());
+        sorted.sort();
+        assert_eq!(sorted, orders[0]);
+    }
+
+    #[wasm_bindgen_test]
+    async fn put_then_del_of_new_key() {
+        let (store, mut events) = new_store_with_events().await;
+        store.put("old", b"v").await.unwrap();
+        requested_keys(&mut events);
+
+        // Once a read has found a key missing, a put then del of it leaves
+        // nothing to commit, so no requests are made.
+        let wt = store.write().await.unwrap();
+        assert!(!wt.has("new").await.unwrap());
+        wt.put("new", b"v").await.unwrap();
+        wt.del("new").await.unwrap();
+        assert!(!wt.has("new").await.unwrap());
+        assert_eq!(CommitReport::default(), wt.commit().await.unwrap());
+        assert!(requested_keys(&mut events).is_empty());
+
+        // Keys no read looked up are checked on commit, so only a key that
+        // was there is deleted.
+        let wt = store.write().await.unwrap();
+        assert_eq!(None, wt.get("new").await.unwrap());
+        for key in &["new", "old", "unread"] {
+            wt.put(key, b"v").await.unwrap();
+            wt.del(key).await.unwrap();
+        }
+        wt.del("never").await.unwrap();
+        wt.commit().await.unwrap();
+        assert_eq!(vec!["old"], requested_keys(&mut events));
+        assert!(!store.has("old").await.unwrap());
+
+        // As are the keys of a commit with nothing to delete.
+        let wt = store.write().await.unwrap();
+        wt.put("unread", b"v").await.unwrap();
+        wt.del("unread").await.unwrap();
+        wt.commit().await.unwrap();
+        assert!(requested_keys(&mut events).is_empty());
+    }
+
+    #[wasm_bindgen_test]
+    async fn load_from() {
+        use replicache_client::kv::memstore::MemStore;
+
+        let snapshot = MemStore::new();
+        for i in 0..100 {
+            snapshot
+                .put(&format!("k{:02}", i), format!("v{}", i).as_bytes())
+                .await
+                .unwrap();
+        }
+        let entries = snapshot.read().await.unwrap().scan("").await.unwrap();
+
+        let store = IdbStore::new(&random_name()).await.unwrap().unwrap();
+        store.put("k00", b"old").await.unwrap();
+        store.put("other", b"kept").await.unwrap();
+        store.load_from(&entries).await.unwrap();
+        let rt = store.read().await.unwrap();
+        assert_eq!(entries, rt.scan("k").await.unwrap());
+        assert_eq!(Some(b"kept".to_vec()), rt.get("other").await.unwrap());
+        drop(rt);
+
+        store.load_from(&[]).await.unwrap();
+        assert_eq!(101, store.read().await.unwrap().count().await.unwrap());
+    }
+
+    #[wasm_bindgen_test]
+    async fn concurrent_new() {
+        let name = random_name();
+        let (a, b) = future::join(IdbStore::new(&name), IdbStore::new(&name)).await;
+        let (a, b) = (a.unwrap().unwrap(), b.unwrap().unwrap());
+        a.put("k", b"v").await.unwrap();
+        assert_eq!(Some(b"v".to_vec()), b.get("k").await.unwrap());
+    }
+
+    #[wasm_bindgen_test]
+    async fn open_existing() {
+        // A missing database is not created.
+        let name = random_name();
+        for _ in 0..2 {
+            match IdbStore::open_existing(&name).await {
+                Err(StoreError::NotInitialized) => (),
+                r => panic!("Expected NotInitialized, got {:?}", r.map(|_| ())),
+            }
+        }
+
+        // Nor is a database that isn't a store set up as one.
+        let name = random_name();
+        let factory = web_sys::window().unwrap().indexed_db().unwrap().unwrap();
+        let db: web_sys::IdbDatabase = await_request(&factory.open(&name).unwrap())
+            .await
+            .unwrap()
+            .into();
+        db.close();
+        match IdbStore::open_existing(&name).await {
+            Err(StoreError::NotInitialized) => (),
+            r => panic!("Expected NotInitialized, got {:?}", r.map(|_| ())),
+        }
+
+        // An existing store is opened with its key encoding.
+        let name = random_name();
+        let store = IdbStore::new_with_key_encoding(&name, KeyEncoding::Binary)
+            .await
+            .unwrap()
+            .unwrap();
+        store.put("k", b"v").await.unwrap();
+        drop(store);
+        let store = IdbStore::open_existing(&name).await.unwrap().unwrap();
+        assert_eq!(Some(b"v".to_vec()), store.get("k").await.unwrap());
+        store.put("k2", b"v2").await.unwrap();
+        assert_eq!(
+            vec!["k", "k2"],
+            store.read().await.unwrap().scan_keys("").await.unwrap()
+        );
+    }
+
+    #[wasm_bindgen_test]
+    async fn object_store_names() {
+        // A store's database has the stores the store uses.
+        let name = random_name();
+        drop(IdbStore::new(&name).await.unwrap().unwrap());
+        assert_eq!(
+            vec!["chunks", "log", "meta"],
+            IdbStore::object_store_names(&name).await.unwrap()
+        );
+
+        // Other databases have whatever stores they were created with.
+        let name = random_name();
+        let factory = web_sys::window().unwrap().indexed_db().unwrap().unwrap();
+        let request = factory.open(&name).unwrap();
+        let request_copy = request.clone();
+        let onupgradeneeded = Closure::once(move || {
+            let db = web_sys::IdbDatabase::from(request_copy.result().unwrap());
+            db.create_object_store("b").unwrap();
+            db.create_object_store("a").unwrap();
+        });
+        request.set_onupgradeneeded(Some(onupgradeneeded.as_ref().unchecked_ref()));
+        web_sys::IdbDatabase::from(await_request(&request).await.unwrap()).close();
+        assert_eq!(
+            vec!["a", "b"],
+            IdbStore::object_store_names(&name).await.unwrap()
+        );
+
+        // Missing databases aren't created.
+        let name = random_name();
+        for _ in 0..2 {
+            match IdbStore::object_store_names(&name).await {
+                Err(StoreError::NotInitialized) => (),
+                r => panic!("Expected NotInitialized, got {:?}", r),
+            }
+        }
+    }
+
+    #[wasm_bindgen_test]
+    async fn tx_events() {
+        use futures::stream::StreamExt;
+        use TxEvent::*;
+
+        let mut store = IdbStore::new(&random_name()).await.unwrap().unwrap();
+        let (sender, events) = mpsc::unbounded();
+        store.set_tx_events(Some(sender));
+
+        // Puts are buffered, so the first request is made by commit().
+        let wt = store.write().await.unwrap();
+        wt.put("k", b"v").await.unwrap();
+        wt.commit().await.unwrap();
+
+        let wt = store.write().await.unwrap();
+        wt.put("k", b"w").await.unwrap();
+        assert_eq!(None, wt.get("other").await.unwrap());
+        wt.rollback().await.unwrap();
+
+        store.set_tx_events(None);
+        assert_eq!(
+            vec![
+                Opened(1),
+                FirstRequest(1),
+                Put(1, "k".into()),
+                Committed(1),
+                Opened(2),
+                FirstRequest(2),
+                Aborted(2),
+            ],
+            events.collect::<Vec<_>>().await
+        );
+    }
+
     // TODO(nate): Test entering Errored state.
 
+    #[wasm_bindgen_test]
+    async fn keep_alive() {
+        let store = new_store().await;
+        store.put("foo", b"bar").await.unwrap();
+
+        // Without keep_alive() the transaction ends while we sleep.
+        let wt = store.write().await.unwrap();
+        assert_eq!(Some(b"bar".to_vec()), wt.get("foo").await.unwrap());
+        sleep(Duration::from_millis(50)).await;
+        assert!(wt.get("foo").await.is_err());
+        drop(wt);
+
+        // With it, reads interleaved with sleeps keep working, and the
+        // transaction commits.
+        let wt = store.write().await.unwrap();
+        wt.put("baz", b"qux").await.unwrap();
+        for _ in 0..3 {
+            wt.keep_alive(Box::pin(sleep(Duration::from_millis(50))))
+                .await
+                .unwrap();
+            assert_eq!(Some(b"bar".to_vec()), wt.get("foo").await.unwrap());
+        }
+        wt.commit().await.unwrap();
+        assert_eq!(Some(b"qux".to_vec()), store.get("baz").await.unwrap());
+    }
+
+    #[wasm_bindgen_test]
+    async fn await_request_result() {
+        let factory = web_sys::window().unwrap().indexed_db().unwrap().unwrap();
+        let name = random_name();
+
+        // Success resolves to the request's result.
+        let request = factory.open_with_u32(&name, 2).unwrap();
+        let db = web_sys::IdbDatabase::from(await_request(&request).await.unwrap());
+        assert_eq!(2.0, db.version());
+        db.close();
+
+        // Failure resolves to the request's error.
+        let request = factory.open_with_u32(&name, 1).unwrap();
+        match await_request(&request).await {
+            Err(StoreError::Dom { name, .. }) => assert_eq!("VersionError", name),
+            _ => panic!("Expected VersionError"),
+        }
+    }
+
+    // Makes an IDBDatabase method throw, until dropped.
+    struct FakeIdb {
+        prototype: JsValue,
+        method: &'static str,
+        original: JsValue,
+    }
+
+    impl FakeIdb {
+        fn failing(method: &'static str, throw: &str) -> FakeIdb {
+            let get = |target: &JsValue, key: &str| js_sys::Reflect::get(target, &key.into());
+            let class = get(&js_sys::global(), "IDBDatabase").unwrap();
+            let prototype = get(&class, "prototype").unwrap();
+            let original = get(&prototype, method).unwrap();
+            let throw = js_sys::Function::new_no_args(throw);
+            js_sys::Reflect::set(&prototype, &method.into(), &throw).unwrap();
+            FakeIdb {
+                prototype,
+                method,
+                original,
+            }
+        }
+
+        // Fails every transaction, as some browsers do on databases they
+        // let be opened (historically Safari in private browsing).
+        fn failing_transactions() -> FakeIdb {
+            FakeIdb::failing(
+                "transaction",
+                "throw new DOMException('Transactions fail', 'InvalidStateError');",
+            )
+        }
+
+        // Fails creating object stores for a reason other than their
+        // already existing.
+        fn failing_create_object_store() -> FakeIdb {
+            FakeIdb::failing(
+                "createObjectStore",
+                "throw new DOMException('Creation fails', 'UnknownError');",
+            )
+        }
+    }
+
+    impl Drop for FakeIdb {
+        fn drop(&mut self) {
+            js_sys::Reflect::set(&self.prototype, &self.method.into(), &self.original).unwrap();
+        }
+    }
+
+    #[wasm_bindgen_test]
+    async fn probe_transactions() {
+        let probed = OpenOptions {
+            probe_transactions: true,
+            ..OpenOptions::default()
+        };
+        {
+            let _fake = FakeIdb::failing_transactions();
+            assert!(IdbStore::new_with_options(&random_name(), probed)
+                .await
+                .unwrap()
+                .is_none());
+            // Without the probe the failure is an error instead.
+            assert!(IdbStore::new(&random_name()).await.is_err());
+        }
+
+        let store = IdbStore::new_with_options(&random_name(), probed)
+            .await
+            .unwrap()
+            .unwrap();
+        store.put("k", b"v").await.unwrap();
+        assert_eq!(Some(b"v".to_vec()), store.get("k").await.unwrap());
+    }
+
+    #[wasm_bindgen_test]
+    async fn create_object_store_fails() {
+        let name = random_name();
+        {
+            let _fake = FakeIdb::failing_create_object_store();
+            match IdbStore::new(&name).await {
+                Err(StoreError::Open { store, cause }) => {
+                    assert_eq!("chunks", store);
+                    match *cause {
+                        StoreError::Dom { name, .. } => assert_eq!("UnknownError", name),
+                        e => panic!("Expected a Dom cause, got {:?}", e),
+                    }
+                }
+                r => panic!("Expected Open, got {:?}", r.map(|_| ())),
+            }
+        }
+
+        // The failed upgrade was rolled back, so the next open sets the
+        // database up.
+        let store = IdbStore::new(&name).await.unwrap().unwrap();
+        store.put("k", b"v").await.unwrap();
+        assert_eq!(Some(b"v".to_vec()), store.get("k").await.unwrap());
+    }
+
+    #[wasm_bindgen_test]
+    async fn request_dropped() {
+        // As when a request's callbacks are dropped without being called.
+        let (sender, receiver) = oneshot::channel::<bool>();
+        drop(sender);
+        match receiver.await.map_err(StoreError::from) {
+            Err(StoreError::RequestDropped) => (),
+            r => panic!("Expected RequestDropped, got {:?}", r),
+        }
+    }
+
+    #[wasm_bindgen_test]
+    fn dom_error_details() {
+        let err = js_sys::Object::new();
+        for (key, value) in &[
+            ("name", JsValue::from("QuotaExceededError")),
+            ("message", JsValue::from("Quota exceeded")),
+            ("code", JsValue::from(22)),
+        ] {
+            js_sys::Reflect::set(&err, &(*key).into(), value).unwrap();
+        }
+        match StoreError::from(JsValue::from(err)) {
+            StoreError::Dom {
+                name,
+                message,
+                code,
+            } => {
+                assert_eq!("QuotaExceededError", name);
+                assert_eq!("Quota exceeded", message);
+                assert_eq!(22, code);
+            }
+            e => panic!("Expected Dom, got {:?}", e),
+        }
+
+        let err =
+            web_sys::DomException::new_with_message_and_name("Gone", "NotFoundError").unwrap();
+        match StoreError::from(JsValue::from(err)) {
+            StoreError::Dom { name, code, .. } => {
+                assert_eq!("NotFoundError", name);
+                assert_eq!(8, code);
+            }
+            e => panic!("Expected Dom, got {:?}", e),
+        }
+
+        // Values that aren't errors are kept as their debug representation.
+        match StoreError::from(JsValue::from("oops")) {
+            StoreError::Str(s) => assert!(s.contains("oops"), "{}", s),
+            e => panic!("Expected Str, got {:?}", e),
+        }
+    }
+
     #[wasm_bindgen_test]
     async fn simple_commit() {
         let store = new_store().await;
@@ -98,6 +719,183 @@ pub mod idbstore {
         assert_eq!(None, rt.get("bar").await.unwrap());
     }
 
+    #[wasm_bindgen_test]
+    async fn dropped_write() {
+        let store = new_store().await;
+
+        // Drop a write with a read outstanding on its tx and a pending put.
+        // The next write must not start until the first has let go of the
+        // database, and must not see its put.
+        let wt = store.write().await.unwrap();
+        wt.put("a", b"1").await.unwrap();
+        assert_eq!(None, wt.get("b").await.unwrap());
+        drop(wt);
+
+        let wt = store.write().await.unwrap();
+        wt.put("b", b"2").await.unwrap();
+        wt.commit().await.unwrap();
+
+        let rt = store.read().await.unwrap();
+        assert_eq!(None, rt.get("a").await.unwrap());
+        assert_eq!(Some(b"2".to_vec()), rt.get("b").await.unwrap());
+    }
+
+    #[wasm_bindgen_test]
+    async fn cancelled_commit() {
+        let store = new_store().await;
+        store.put("k", b"old").await.unwrap();
+
+        // Cancel the commit after it has issued its requests but before the
+        // tx completes. It must roll back rather than auto-commit.
+        let wt = store.write().await.unwrap();
+        wt.put("k", b"new").await.unwrap();
+        wt.put("k2", b"new").await.unwrap();
+        {
+            let commit = wt.commit().fuse();
+            let cancel = future::ready(()).fuse();
+            pin_mut!(commit, cancel);
+            select_biased! {
+                _ = commit => panic!("commit should not have finished"),
+                _ = cancel => (),
+            }
+            // The commit future is dropped here.
+        }
+
+        let rt = store.read().await.unwrap();
+        assert_eq!(Some(b"old".to_vec()), rt.get("k").await.unwrap());
+        assert!(!rt.has("k2").await.unwrap());
+    }
+
+    #[wasm_bindgen_test]
+    async fn max_value_bytes() {
+        let mut store = IdbStore::new(&random_name()).await.unwrap().unwrap();
+        store.set_max_value_bytes(Some(3));
+
+        let wt = store.write().await.unwrap();
+        wt.put("k", b"abc").await.unwrap();
+        match wt.put("k", b"abcd").await {
+            Err(StoreError::ValueTooLarge { len: 4, max: 3 }) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        wt.commit().await.unwrap();
+        assert_eq!(Some(b"abc".to_vec()), store.get("k").await.unwrap());
+    }
+
+    #[wasm_bindgen_test]
+    async fn max_keys() {
+        let mut store = IdbStore::new(&random_name()).await.unwrap().unwrap();
+        store.put("a", b"1").await.unwrap();
+        store.set_max_keys(Some(2));
+
+        let wt = store.write().await.unwrap();
+        wt.put("a", b"2").await.unwrap();
+        wt.put("b", b"1").await.unwrap();
+        wt.commit().await.unwrap();
+
+        let wt = store.write().await.unwrap();
+        wt.put("c", b"1").await.unwrap();
+        wt.put("a", b"3").await.unwrap();
+        match wt.commit().await {
+            Err(StoreError::KeyQuotaExceeded { count: 3, max: 2 }) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        let rt = store.read().await.unwrap();
+        assert_eq!(2, rt.count().await.unwrap());
+        assert_eq!(Some(b"2".to_vec()), rt.get("a").await.unwrap());
+    }
+
+    #[wasm_bindgen_test]
+    async fn estimate_quota() {
+        let store = new_store().await;
+        store.put("k", &[0; 1000]).await.unwrap();
+
+        // Not every browser supports estimates.
+        if let Some(estimate) = IdbStore::estimate_quota().await.unwrap() {
+            assert!(estimate.quota > 0);
+            assert!(estimate.usage <= estimate.quota);
+        }
+    }
+
+    // Upgrades the named database to a new version from a second connection,
+    // as a newer client in another tab would.
+    async fn upgrade_elsewhere(name: &str, version: u32) {
+        let factory = web_sys::window().unwrap().indexed_db().unwrap().unwrap();
+        let request = factory.open_with_u32(name, version).unwrap();
+        let db: web_sys::IdbDatabase = await_request(&request).await.unwrap().into();
+        db.close();
+    }
+
+    #[wasm_bindgen_test]
+    async fn auto_reopen() {
+        let name = random_name();
+        let mut store = IdbStore::new(&name).await.unwrap().unwrap();
+        store.put("k", b"v").await.unwrap();
+
+        // Without auto_reopen, the store closes its connection so the upgrade
+        // can proceed, and then fails.
+        upgrade_elsewhere(&name, 10).await;
+        assert!(store.get("k").await.is_err());
+
+        // With it, the next operation reopens at the new version.
+        store.set_auto_reopen(true);
+        assert_eq!(Some(b"v".to_vec()), store.get("k").await.unwrap());
+        upgrade_elsewhere(&name, 11).await;
+        store.put("k", b"w").await.unwrap();
+        upgrade_elsewhere(&name, 12).await;
+        assert_eq!(Some(b"w".to_vec()), store.get("k").await.unwrap());
+        store.append(b"log").await.unwrap();
+    }
+
+    #[wasm_bindgen_test]
+    async fn commit_after_close() {
+        let name = random_name();
+        let mut store = IdbStore::new(&name).await.unwrap().unwrap();
+        store.put("k", b"v").await.unwrap();
+        let closed = |r: std::result::Result<(), StoreError>| match r {
+            Err(StoreError::StoreClosed) => (),
+            r => panic!("Expected StoreClosed, got {:?}", r),
+        };
+
+        // The versionchange closes the connection under the transaction.
+        let wt = store.write().await.unwrap();
+        wt.put("k", b"w").await.unwrap();
+        upgrade_elsewhere(&name, 10).await;
+        closed(wt.commit().await.map(|_| ()));
+        closed(store.get("k").await.map(|_| ()));
+
+        // Reopening doesn't save a transaction of the old connection.
+        store.set_auto_reopen(true);
+        let wt = store.write().await.unwrap();
+        wt.put("k", b"x").await.unwrap();
+        upgrade_elsewhere(&name, 11).await;
+        closed(wt.commit().await.map(|_| ()));
+        assert_eq!(Some(b"v".to_vec()), store.get("k").await.unwrap());
+    }
+
+    #[wasm_bindgen_test]
+    async fn append_log() {
+        let store = IdbStore::new(&random_name()).await.unwrap().unwrap();
+        assert_eq!(0, store.scan_log(0).await.unwrap().len());
+
+        let mut seqs = vec![];
+        for value in &[b"a", b"b", b"c", b"d"] {
+            seqs.push(store.append(*value).await.unwrap());
+        }
+        assert!(seqs.windows(2).all(|w| w[0] < w[1]), "{:?}", seqs);
+
+        let expected: Vec<(u64, Vec<u8>)> = seqs
+            .iter()
+            .zip(&[b"a", b"b", b"c", b"d"])
+            .map(|(seq, v)| (*seq, v.to_vec()))
+            .collect();
+        assert_eq!(expected, store.scan_log(0).await.unwrap());
+        assert_eq!(expected[2..], store.scan_log(seqs[2]).await.unwrap()[..]);
+        assert_eq!(0, store.scan_log(seqs[3] + 1).await.unwrap().len());
+
+        // The log is separate from the key-value contents.
+        assert_eq!(0, store.read().await.unwrap().count().await.unwrap());
+    }
+
     // TODO: we should verify commit() fails if the underlying tx is
     // already auto-committed.  We can't use the idbstore to do this
     // because if you have a write tx open you can't open any other txs.
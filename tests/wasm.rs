@@ -1,5 +1,6 @@
 #![recursion_limit = "256"]
 
+use data_encoding::base64;
 use futures::join;
 use nanoserde::{DeJson, SerJson};
 use rand::Rng;
@@ -30,6 +31,12 @@ async fn dispatch(db: &str, rpc: &str, data: &str) -> Result<String, String> {
     }
 }
 
+async fn open(db_name: &str, data: &str) -> String {
+    let resp: OpenResponse =
+        DeJson::deserialize_json(&dispatch(db_name, "open", data).await.unwrap()).unwrap();
+    resp.root
+}
+
 async fn open_transaction(db_name: &str, fn_name: Option<String>) -> u32 {
     let req = SerJson::serialize_json(&OpenTransactionRequest { name: fn_name });
     let resp: OpenTransactionResponse =
@@ -54,6 +61,24 @@ async fn put(db_name: &str, txn_id: u32, key: &str, value: &str) {
     );
 }
 
+async fn put_bytes(db_name: &str, txn_id: u32, key: &str, value: &[u8]) {
+    assert_eq!(
+        dispatch(
+            db_name,
+            "putBytes",
+            &format!(
+                "{{\"transactionId\": {}, \"key\": \"{}\", \"value\": \"{}\"}}",
+                txn_id,
+                key,
+                base64::encode(value)
+            )
+        )
+        .await
+        .unwrap(),
+        "{}"
+    );
+}
+
 async fn has(db_name: &str, txn_id: u32, key: &str) -> bool {
     let result = dispatch(
         db_name,
@@ -67,6 +92,12 @@ async fn has(db_name: &str, txn_id: u32, key: &str) -> bool {
 }
 
 async fn get(db_name: &str, txn_id: u32, key: &str) -> Option<String> {
+    get_bytes(db_name, txn_id, key)
+        .await
+        .map(|v| String::from_utf8(v).unwrap())
+}
+
+async fn get_bytes(db_name: &str, txn_id: u32, key: &str) -> Option<Vec<u8>> {
     let result = dispatch(
         db_name,
         "get",
@@ -76,11 +107,22 @@ async fn get(db_name: &str, txn_id: u32, key: &str) -> Option<String> {
     .unwrap();
     let response: GetResponse = DeJson::deserialize_json(&result).unwrap();
     match response.has {
-        true => Some(response.value.unwrap()),
+        true => Some(base64::decode(response.value.unwrap().as_bytes()).unwrap()),
         false => None,
     }
 }
 
+async fn get_string(db_name: &str, txn_id: u32, key: &str) -> Result<Option<String>, String> {
+    let result = dispatch(
+        db_name,
+        "getString",
+        &format!("{{\"transactionId\": {}, \"key\": \"{}\"}}", txn_id, key),
+    )
+    .await?;
+    let response: GetResponse = DeJson::deserialize_json(&result).unwrap();
+    Ok(response.value)
+}
+
 async fn commit(db_name: &str, txn_id: u32) -> Result<(), String> {
     dispatch(
         db_name,
@@ -108,9 +150,9 @@ async fn open_close() {
         dispatch("", "open", "").await.unwrap_err(),
         "db_name must be non-empty"
     );
-    assert_eq!(dispatch("db", "open", "").await.unwrap(), "");
+    open("db", "").await;
     assert_eq!(dispatch("", "debug", "open_dbs").await.unwrap(), "[\"db\"]");
-    assert_eq!(dispatch("db2", "open", "").await.unwrap(), "");
+    open("db2", "").await;
     assert_eq!(
         dispatch("", "debug", "open_dbs").await.unwrap(),
         "[\"db\", \"db2\"]"
@@ -125,6 +167,33 @@ async fn open_close() {
     assert_eq!(dispatch("", "debug", "open_dbs").await.unwrap(), "[]");
 }
 
+#[wasm_bindgen_test]
+async fn open_idempotent() {
+    let db = &random_db();
+
+    let root = open(db, "{\"defaultHead\": \"main\"}").await;
+    assert!(!root.is_empty());
+    assert_eq!(open(db, "{\"defaultHead\": \"main\"}").await, root);
+    assert_eq!(open(db, "").await, root);
+
+    // A committed change moves the head, and re-opening leaves it alone.
+    let txn_id = open_transaction(db, "foo".to_string().into()).await;
+    put(db, txn_id, "k", "v").await;
+    commit(db, txn_id).await.unwrap();
+    let head = open(db, "{}").await;
+    assert_ne!(head, root);
+    let txn_id = open_transaction(db, None).await;
+    assert_eq!(get(db, txn_id, "k").await, Some("v".into()));
+    abort(db, txn_id).await;
+
+    // Other heads start out at the same empty root.
+    assert_eq!(open(db, "{\"defaultHead\": \"other\"}").await, root);
+
+    assert_eq!(dispatch(db, "close", "").await.unwrap(), "");
+    assert_eq!(open(db, "").await, head);
+    assert_eq!(dispatch(db, "close", "").await.unwrap(), "");
+}
+
 #[wasm_bindgen_test]
 async fn dispatch_concurrency() {
     let db = &random_db();
@@ -133,7 +202,7 @@ async fn dispatch_concurrency() {
         .performance()
         .expect("performance should be available");
 
-    assert_eq!(dispatch(db, "open", "").await.unwrap(), "");
+    open(db, "").await;
     let txn_id = open_transaction(db, "foo".to_string().into()).await;
     let now_ms = performance.now();
     join!(
@@ -155,7 +224,7 @@ async fn dispatch_concurrency() {
 async fn write_concurrency() {
     let db = &random_db();
 
-    dispatch(db, "open", "").await.unwrap();
+    open(db, "").await;
     let txn_id = open_transaction(db, "foo".to_string().into()).await;
     put(db, txn_id, "value", "1").await;
     commit(db, txn_id).await.unwrap();
@@ -205,7 +274,7 @@ async fn get_put() {
         dispatch(db, "put", "{\"k\", \"v\"}").await.unwrap_err(),
         format!("\"{}\" not open", db)
     );
-    assert_eq!(dispatch(db, "open", "").await.unwrap(), "");
+    open(db, "").await;
 
     // Check request parsing, both missing and unexpected fields.
     assert_eq!(
@@ -255,3 +324,119 @@ async fn get_put() {
 
     assert_eq!(dispatch(db, "close", "").await.unwrap(), "");
 }
+
+#[wasm_bindgen_test]
+async fn binary_values() {
+    let db = &random_db();
+    open(db, "").await;
+
+    let txn_id = open_transaction(db, "foo".to_string().into()).await;
+    put_bytes(db, txn_id, "bin", &[0xff, 0, 0xc3]).await;
+    put(db, txn_id, "str", "世界").await;
+    commit(db, txn_id).await.unwrap();
+
+    let txn_id = open_transaction(db, None).await;
+    assert_eq!(
+        get_bytes(db, txn_id, "bin").await,
+        Some(vec![0xff, 0, 0xc3])
+    );
+    assert_eq!(get(db, txn_id, "str").await, Some("世界".into()));
+    assert!(get_string(db, txn_id, "bin").await.is_err());
+    assert_eq!(get_string(db, txn_id, "str").await, Ok(Some("世界".into())));
+    assert_eq!(get_string(db, txn_id, "missing").await, Ok(None));
+    abort(db, txn_id).await;
+
+    assert_eq!(dispatch(db, "close", "").await.unwrap(), "");
+}
+
+#[wasm_bindgen_test]
+async fn scan_values() {
+    let db = &random_db();
+    open(db, "").await;
+
+    let txn_id = open_transaction(db, "foo".to_string().into()).await;
+    put(db, txn_id, "a", "{\"x\": 1}").await;
+    put_bytes(db, txn_id, "b", &[0xff, 0, 0xc3]).await;
+    put(db, txn_id, "c", "[true]").await;
+    assert_eq!(
+        dispatch(
+            db,
+            "scanValues",
+            &format!("{{\"transactionId\": {}, \"limit\": 2}}", txn_id)
+        )
+        .await
+        .unwrap(),
+        "{\"entries\":[{\"key\":\"a\",\"value\":{\"x\": 1}},{\"key\":\"b\",\"raw\":\"/wDD\"}]}"
+    );
+    assert_eq!(
+        dispatch(
+            db,
+            "scanValues",
+            &format!("{{\"transactionId\": {}, \"prefix\": \"c\"}}", txn_id)
+        )
+        .await
+        .unwrap(),
+        "{\"entries\":[{\"key\":\"c\",\"value\":[true]}]}"
+    );
+    abort(db, txn_id).await;
+
+    assert_eq!(dispatch(db, "close", "").await.unwrap(), "");
+}
+
+#[wasm_bindgen_test]
+async fn dispatch_with_codec() {
+    use replicache_client::embed::{register_codec, MsgPack, ValueCodec};
+
+    // A msgpack codec registered by the embedder, under its own name.
+    struct Embedder;
+
+    impl ValueCodec for Embedder {
+        fn decode(&self, args: &[u8]) -> Result<String, String> {
+            MsgPack.decode(args)
+        }
+
+        fn encode(&self, response: String) -> Vec<u8> {
+            MsgPack.encode(response)
+        }
+    }
+    register_codec("embedder", Box::new(Embedder));
+
+    let db = &random_db();
+    open(db, "").await;
+    let txn_id = open_transaction(db, None).await;
+    put(db, txn_id, "k", "v").await;
+
+    let get = |key: &str| {
+        let args = MsgPack.encode(format!(
+            "{{\"transactionId\": {}, \"key\": \"{}\"}}",
+            txn_id, key
+        ));
+        wasm::dispatch_with_codec("embedder".into(), db.clone(), "get".into(), args)
+    };
+    let response = MsgPack.decode(&get("k").await.unwrap()).unwrap();
+    let response: GetResponse = DeJson::deserialize_json(&response).unwrap();
+    assert!(response.has);
+    assert_eq!(
+        b"v".to_vec(),
+        base64::decode(response.value.unwrap().as_bytes()).unwrap()
+    );
+    let response = MsgPack.decode(&get("missing").await.unwrap()).unwrap();
+    assert_eq!("{\"has\":false}", response);
+
+    // Args the codec can't decode, and unknown codecs, fail the call.
+    let err = |r: Result<Vec<u8>, wasm_bindgen::JsValue>| r.unwrap_err().as_string().unwrap();
+    assert_eq!(
+        "Args are not msgpack JSON can represent",
+        err(
+            wasm::dispatch_with_codec("embedder".into(), db.clone(), "get".into(), vec![0xc1])
+                .await
+        )
+    );
+    assert_eq!(
+        "Unknown codec: nope",
+        err(wasm::dispatch_with_codec("nope".into(), db.clone(), "get".into(), vec![]).await)
+    );
+    abort(db, txn_id).await;
+
+    assert_eq!(dispatch(db, "close", "").await.unwrap(), "");
+}
@@ -0,0 +1,54 @@
+// Run tests with `wasm-pack test --chrome --headless`.
+pub mod worker {
+    use replicache_client::kv::idbstore::IdbStore;
+    use replicache_client::kv::opfs::OpfsStore;
+    use replicache_client::kv::Store;
+    use wasm_bindgen_test::wasm_bindgen_test_configure;
+    use wasm_bindgen_test::*;
+
+    // Workers have IndexedDB but no Window.
+    wasm_bindgen_test_configure!(run_in_dedicated_worker);
+
+    #[wasm_bindgen_test]
+    async fn idbstore_without_window() {
+        assert!(web_sys::window().is_none());
+        let store = IdbStore::new("worker-test")
+            .await
+            .unwrap()
+            .expect("IdbStore::new returned None");
+        store.put("foo", b"bar").await.unwrap();
+        assert_eq!(Some(b"bar".to_vec()), store.get("foo").await.unwrap());
+    }
+
+    async fn new_opfs_store(name: &str) -> OpfsStore {
+        OpfsStore::new(name)
+            .await
+            .unwrap()
+            .expect("OpfsStore::new returned None")
+    }
+
+    #[wasm_bindgen_test]
+    async fn opfs_store() {
+        let store = new_opfs_store("opfs-test").await;
+        let wt = store.write().await.unwrap();
+        wt.clear().await.unwrap();
+        wt.put("foo", b"bar").await.unwrap();
+        wt.put("baz", b"qux").await.unwrap();
+        wt.commit().await.unwrap();
+        assert_eq!(Some(b"bar".to_vec()), store.get("foo").await.unwrap());
+
+        let wt = store.write().await.unwrap();
+        wt.put("foo", b"rolled back").await.unwrap();
+        wt.del("baz").await.unwrap();
+        wt.rollback().await.unwrap();
+        assert_eq!(Some(b"bar".to_vec()), store.get("foo").await.unwrap());
+        assert!(store.has("baz").await.unwrap());
+
+        // The file is locked while the store is open, and the data survives
+        // reopening.
+        assert!(OpfsStore::new("opfs-test").await.is_err());
+        drop(store);
+        let store = new_opfs_store("opfs-test").await;
+        assert_eq!(Some(b"qux".to_vec()), store.get("baz").await.unwrap());
+    }
+}
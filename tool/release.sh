@@ -28,9 +28,9 @@ fi
     sed -i .bak 's/crate-type = \["cdylib", "rlib"\]/crate-type = ["cdylib"]/' Cargo.toml
 
     rm -rf pkg
-    wasm-pack build --profiling -t web -- --no-default-features
+    wasm-pack build --profiling -t web -- --no-default-features --features wee_alloc
     mv pkg/replicache_client_bg.wasm pkg/replicache_client_bg.wasm.debug
-    wasm-pack build --release -t web -- --no-default-features
+    wasm-pack build --release -t web -- --no-default-features --features wee_alloc
     brotli -f pkg/replicache_client.js pkg/replicache_client_bg.wasm
 
     mv Cargo.toml.bak Cargo.toml